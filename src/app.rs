@@ -1,16 +1,169 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+
 use crate::{
-    command::Command,
-    undo::{Undo, UndoAction},
+    bash_completion::BashCompletion,
+    carapace::Carapace,
+    command::{Command, Component, PIPE, REPEATABLE_FLAGS},
+    completion::{MAX_PATH_MATCHES, common_prefix, looks_like_path, path_candidates},
+    flags::{FlagAliases, FlagArities, FlagValueCounts},
+    help::HelpChoices,
+    history::{Suggestions, Wrapper, base_command, default_wrappers, wrapper_len},
+    keys::KeyMap,
+    providers::Providers,
+    session::Session,
+    specs::Spec,
+    tui::{self, char_width, get_prefix_char, wrap_text},
+    undo::{Undo, UndoAction, UndoStep},
 };
-use ratatui::widgets::ListState;
+use ratatui::{
+    style::{Modifier, Style},
+    widgets::ListState,
+};
+
+/// Width of the row prefix column: " X " where X is the shortcut key
+pub const PREFIX_WIDTH: u16 = 3;
+
+/// Keys for leaving input mode, shown in the status row while typing
+pub const INPUT_MODE_HINT: &str = "enter: confirm  esc: cancel";
+
+/// Keys for keeping or discarding the value being peeked at
+pub const PEEK_HINT: &str = "enter: keep  esc: discard";
+
+/// A component as it is drawn: its shortcut prefix, wrapped text and style
+#[derive(Debug, PartialEq)]
+pub struct ComponentSpan {
+    pub prefix: String,
+    pub lines: Vec<String>,
+    /// Line numbers drawn before `lines`, one per line, or empty for none
+    pub gutter: Vec<String>,
+    pub style: Style,
+    /// Row and column of the cursor in `lines`, for the value being edited
+    pub cursor: Option<(usize, usize)>,
+}
+
+/// Width of the line-number gutter for a value being edited: the digits of
+/// its last line number and a space, or 0 for a single-line value
+pub fn gutter_width(text: &str) -> usize {
+    match text.matches('\n').count() {
+        0 => 0,
+        breaks => (breaks + 1).to_string().len() + 1,
+    }
+}
+
+/// Wrap each line of `text` to fit `width` columns after a `gutter` columns
+/// wide, returning the wrapped lines with their gutter: the line number on a
+/// line's first row and blanks on the rows it wraps onto
+fn wrap_numbered(text: &str, width: usize, gutter: usize) -> (Vec<String>, Vec<String>) {
+    let mut lines = Vec::new();
+    let mut numbers = Vec::new();
+    for (n, line) in text.split('\n').enumerate() {
+        for (row, wrapped) in wrap_text(line, width.saturating_sub(gutter).max(1))
+            .into_iter()
+            .enumerate()
+        {
+            numbers.push(if row == 0 {
+                format!("{:>1$} ", n + 1, gutter - 1)
+            } else {
+                " ".repeat(gutter)
+            });
+            lines.push(wrapped);
+        }
+    }
+    (lines, numbers)
+}
+
+/// History values offered for one component in a dropdown under the list
+#[derive(Debug, PartialEq)]
+pub struct Picker {
+    /// Component the picked value is written to
+    pub position: usize,
+    /// Flag the values were used with
+    pub flag: String,
+    /// Whether `position` is the flag itself, so the value is appended after it
+    pub append: bool,
+    /// The values are paths completing the value being edited, and the
+    /// picked one goes back into the input
+    pub input: bool,
+    /// History values first, then the ones from other sources
+    pub candidates: Vec<String>,
+    /// Where each candidate came from, like the provider command that
+    /// printed it, or `None` for history values
+    pub tags: Vec<Option<String>>,
+    pub selected: usize,
+}
+
+/// Where the command is in a batch of piped commands edited one at a time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchProgress {
+    /// 1-based position of this command in the batch
+    pub position: usize,
+    pub total: usize,
+    /// The previous command was skipped with Esc, so pressing it again
+    /// right away cancels the whole batch
+    pub esc_cancels_all: bool,
+}
 
 pub struct App {
     pub cmd: Command,
     pub list_state: ListState,
     pub input_mode: bool,
     pub current_input: String,
+    /// Byte offset of the cursor in the input, or `None` at its end
+    pub input_cursor: Option<usize>,
     pub undo: Undo,
     pub cursor_y: u16,
+    pub suggestions: Suggestions,
+    /// Commands configured to offer values, on top of history
+    pub providers: Providers,
+    /// Values from carapace's completers, on top of history
+    pub carapace: Carapace,
+    /// Values from bash's programmable completion, on top of history
+    pub bash_completion: BashCompletion,
+    /// Values of flags learned from the program's `--help`
+    pub help: HelpChoices,
+    /// The program's completion spec, whose values are offered when nothing
+    /// else has any
+    pub spec: Option<Arc<Spec>>,
+    /// Wrappers skipped to find the program, like `sudo`
+    pub wrappers: Vec<Wrapper>,
+    /// Flags that are not reported as duplicates when repeated
+    pub repeatable_flags: Vec<String>,
+    /// Dropdown of history values for the selected component, if open
+    pub picker: Option<Picker>,
+    /// Only pick a value for the trailing component, accepting on selection
+    pub completions_only: bool,
+    /// Whether moving past the last or first component wraps around
+    pub wrap_navigation: bool,
+    /// Which action each key chord runs in Navigation Mode
+    pub keymap: KeyMap,
+    /// How many values the command's flags take
+    pub flag_arities: FlagArities,
+    /// How many values flags take, to find the script of a `jq` or `awk`
+    /// anywhere in the pipeline
+    pub flag_value_counts: FlagValueCounts,
+    /// Short and long spellings of the command's flags
+    pub flag_aliases: FlagAliases,
+    /// Progress through a batch of commands, if editing one
+    pub batch: Option<BatchProgress>,
+    /// Navigation runs from the last component towards the first
+    pub reverse: bool,
+    /// History value shown for the selected component without being written
+    /// to it yet, while peeking with Shift+Left/Right
+    pub peek: Option<String>,
+    /// Number the lines of a multi-line value while editing it
+    pub line_numbers: bool,
+    /// Show the command after brace expansion in the status row
+    pub brace_preview: bool,
+    /// Show the keys of the current mode in the status row
+    pub hints: bool,
+    /// Cycling through history values, which is undone in one step
+    cycling: bool,
+    /// Flag values written from history, by cycling, peeking or the picker
+    history_writes: Vec<(String, String)>,
+    /// Message shown in the status row until the next key
+    pub notice: Option<String>,
 }
 
 impl App {
@@ -20,109 +173,154 @@ impl App {
             list_state: ListState::default().with_selected(Some(0)),
             input_mode: false,
             current_input: String::new(),
+            input_cursor: None,
             undo: Default::default(),
             cursor_y,
+            suggestions: Suggestions::new(),
+            providers: Providers::default(),
+            carapace: Carapace::default(),
+            bash_completion: BashCompletion::default(),
+            help: HelpChoices::default(),
+            spec: None,
+            wrappers: default_wrappers(),
+            repeatable_flags: REPEATABLE_FLAGS.iter().map(|f| f.to_string()).collect(),
+            picker: None,
+            completions_only: false,
+            wrap_navigation: true,
+            keymap: KeyMap::default(),
+            flag_arities: FlagArities::default(),
+            flag_value_counts: FlagValueCounts::new(),
+            flag_aliases: FlagAliases::default(),
+            batch: None,
+            reverse: false,
+            peek: None,
+            line_numbers: false,
+            brace_preview: false,
+            hints: true,
+            cycling: false,
+            history_writes: Vec::new(),
+            notice: None,
         }
     }
 
     pub fn undo(&mut self) {
-        if let Some(last) = self.undo.pop() {
-            match last {
-                UndoAction::Insert { position } => {
-                    self.cmd.remove_component_at(position);
-                    let count = self.cmd.component_count();
-                    if count == 0 {
-                        self.list_state.select(None);
-                    } else if position >= count {
-                        self.list_state.select(Some(count - 1));
-                    } else {
-                        self.list_state.select(Some(position));
-                    }
-                    self.undo.push_redo(UndoAction::Insert { position });
-                }
-                UndoAction::Edit {
-                    position,
-                    original_value,
-                    updated_value,
-                } => {
-                    self.cmd.set_value_at(position, &original_value);
-                    self.list_state.select(Some(position));
-                    self.undo.push_redo(UndoAction::Edit {
-                        position,
-                        original_value,
-                        updated_value,
-                    });
-                }
-                UndoAction::Delete {
-                    position,
-                    deleted_value,
-                } => {
-                    self.cmd
-                        .insert_component_at(position, deleted_value.clone());
-                    self.list_state.select(Some(position));
-                    self.undo.push_redo(UndoAction::Delete {
-                        position,
-                        deleted_value,
-                    });
-                }
-            }
+        self.end_cycling();
+        if !self.undo.can_undo() {
+            self.notice = Some("nothing to undo".to_string());
+            return;
+        }
+        let Some(step) = self.undo.pop() else {
+            return;
+        };
+        if let Err(err) = self.replay(&step.action.invert()) {
+            // Older steps were recorded on top of this one, so they can't be
+            // undone either. What can be redone still fits the command.
+            self.undo.clear_undo();
+            self.notice = Some(format!("can't undo, undo history dropped: {}", err));
+            return;
+        }
+        match step.selected_before {
+            Some(position) => self.select_near(position),
+            None => self.select_after(&step.action.invert()),
         }
+        self.undo.push_redo(step);
     }
 
     pub fn redo(&mut self) {
-        if let Some(action) = self.undo.pop_redo() {
-            match action {
-                UndoAction::Insert { position } => {
-                    self.cmd.insert_component_at(position, String::new());
-                    self.list_state.select(Some(position));
-                    self.undo.push(UndoAction::Insert { position }, false);
-                }
-                UndoAction::Edit {
-                    position,
-                    original_value,
-                    updated_value,
-                } => {
-                    self.cmd.set_value_at(position, &updated_value);
-                    self.list_state.select(Some(position));
-                    self.undo.push(
-                        UndoAction::Edit {
-                            position,
-                            original_value,
-                            updated_value,
-                        },
-                        false,
-                    );
-                }
-                UndoAction::Delete {
-                    position,
-                    deleted_value,
-                } => {
-                    self.cmd.remove_component_at(position);
-                    let count = self.cmd.component_count();
-                    if count == 0 {
-                        self.list_state.select(None);
-                    } else if position >= count {
-                        self.list_state.select(Some(count - 1));
-                    } else {
-                        self.list_state.select(Some(position));
-                    }
-                    self.undo.push(
-                        UndoAction::Delete {
-                            position,
-                            deleted_value,
-                        },
-                        false,
-                    );
+        self.end_cycling();
+        if !self.undo.can_redo() {
+            self.notice = Some("nothing to redo".to_string());
+            return;
+        }
+        let Some(step) = self.undo.pop_redo() else {
+            return;
+        };
+        if let Err(err) = self.replay(&step.action) {
+            // Later steps build on this one, so they go with it
+            self.undo.clear_redo();
+            self.notice = Some(format!("can't redo, redo history dropped: {}", err));
+            return;
+        }
+        match step.selected_after {
+            Some(position) => self.select_near(position),
+            None => self.select_after(&step.action),
+        }
+        self.undo.push(step, false);
+    }
+
+    /// Performs `action` on the command, leaving it unchanged if the action
+    /// doesn't fit it
+    fn replay(&mut self, action: &UndoAction) -> Result<()> {
+        let mut components = self.cmd.components().to_vec();
+        action.apply(&mut components)?;
+        self.cmd.splice(.., components);
+        self.mark_scripts();
+        Ok(())
+    }
+
+    /// Records `action` as a new undo step, taken with `selected_before`
+    /// selected and leaving the selection where it is now
+    fn record(&mut self, action: UndoAction, selected_before: Option<usize>) {
+        let step = UndoStep {
+            action,
+            selected_before,
+            selected_after: self.list_state.selected(),
+        };
+        self.undo.push(step, true);
+        self.mark_scripts();
+    }
+
+    /// Marks the scripts of `jq` and `awk` again after the command changed,
+    /// so a typed or inserted one is quoted literally too
+    fn mark_scripts(&mut self) {
+        self.cmd
+            .mark_scripts(&self.wrappers, &self.flag_value_counts);
+    }
+
+    /// Selects the component `action` touched, once it has been applied,
+    /// for steps that don't know the selection around them
+    fn select_after(&mut self, action: &UndoAction) {
+        match action {
+            UndoAction::Insert { position, .. } | UndoAction::Edit { position, .. } => {
+                self.list_state.select(Some(*position));
+            }
+            UndoAction::Delete { position, .. } | UndoAction::Replace { position, .. } => {
+                self.select_near(*position);
+            }
+            UndoAction::Move { to, .. } => self.list_state.select(Some(*to)),
+            UndoAction::Group(actions) => {
+                if let Some(last) = actions.last() {
+                    self.select_after(last);
                 }
             }
         }
     }
 
+    /// Picks up an unfinished edit: its components, and what could be undone
+    /// and redone in it
+    pub fn restore_session(&mut self, session: Session) {
+        self.cmd.splice(.., session.components);
+        self.undo.restore(session.undo, session.redo);
+        self.mark_scripts();
+        let position = self.list_state.selected().unwrap_or_default();
+        self.select_near(position);
+    }
+
+    /// Selects the component at `position`, or the last one if there are
+    /// fewer now
+    fn select_near(&mut self, position: usize) {
+        let count = self.cmd.component_count();
+        if count == 0 {
+            self.list_state.select(None);
+        } else if position >= count {
+            self.list_state.select(Some(count - 1));
+        } else {
+            self.list_state.select(Some(position));
+        }
+    }
+
     pub fn insert_new_component(&mut self) {
-        let insert_at = match self.list_state.selected() {
-            Some(i) => i,
-            None => 0,
-        };
+        let insert_at = self.list_state.selected().unwrap_or_default();
         self.insert_new_component_at(insert_at);
     }
 
@@ -135,28 +333,89 @@ impl App {
     }
 
     fn insert_new_component_at(&mut self, insert_at: usize) {
+        let selected = self.list_state.selected();
         self.cmd.insert_component_at(insert_at, String::new());
         self.list_state.select(Some(insert_at));
 
-        self.undo.push(
+        self.record(
             UndoAction::Insert {
                 position: insert_at,
+                component: Component::new(String::new()),
             },
-            true,
+            selected,
         );
     }
 
-    pub fn delete_selected_component(&mut self) {
-        if let Some(selected) = self.list_state.selected() {
-            let value = self.cmd.remove_component_at(selected);
+    /// Inserts a shell operator such as `|` or `>` after the selection and
+    /// starts editing a new component after it: the next pipeline stage or
+    /// the file to redirect to
+    pub fn insert_operator(&mut self, operator: &str) {
+        let selected = self.list_state.selected();
+        let insert_at = match selected {
+            Some(i) => i + 1,
+            None => self.cmd.component_count(),
+        };
+        self.cmd.insert_operator_at(insert_at, operator);
+        self.list_state.select(Some(insert_at));
+        self.record(
+            UndoAction::Insert {
+                position: insert_at,
+                component: Component::operator(operator),
+            },
+            selected,
+        );
 
-            self.undo.push(
-                UndoAction::Delete {
-                    position: selected,
-                    deleted_value: value,
+        self.append_new_component();
+        self.start_input();
+    }
+
+    /// Adds a leading `sudo`, or removes it along with its options, keeping
+    /// the same component selected. The history base skips `sudo` as a
+    /// wrapper, so suggestions stay the same either way.
+    pub fn toggle_sudo(&mut self) {
+        let selected = self.list_state.selected().unwrap_or_default();
+        let components: Vec<String> = self.cmd.iter_components().cloned().collect();
+        if components.first().is_some_and(|c| c == "sudo") {
+            let sudo: Vec<Wrapper> = default_wrappers()
+                .into_iter()
+                .filter(|w| w.name == "sudo")
+                .collect();
+            let len = wrapper_len(&components, &sudo);
+            let deletes: Vec<UndoAction> = (0..len)
+                .map(|_| UndoAction::Delete {
+                    position: 0,
+                    component: self.cmd.take_component_at(0),
+                })
+                .collect();
+            let selected_before = self.list_state.selected();
+            if self.cmd.is_empty() {
+                self.list_state.select(None);
+            } else {
+                self.list_state.select(Some(selected.saturating_sub(len)));
+            }
+            self.record(UndoAction::Group(deletes), selected_before);
+        } else {
+            let selected_before = self.list_state.selected();
+            self.cmd.insert_component_at(0, "sudo".to_string());
+            let selected = if selected_before.is_some() {
+                selected + 1
+            } else {
+                0
+            };
+            self.list_state.select(Some(selected));
+            self.record(
+                UndoAction::Insert {
+                    position: 0,
+                    component: Component::new("sudo".to_string()),
                 },
-                true,
+                selected_before,
             );
+        }
+    }
+
+    pub fn delete_selected_component(&mut self) {
+        if let Some(selected) = self.list_state.selected() {
+            let component = self.cmd.take_component_at(selected);
 
             let count = self.cmd.component_count();
             if count == 0 {
@@ -164,222 +423,2414 @@ impl App {
             } else if selected >= count {
                 self.list_state.select(Some(count - 1));
             }
+
+            self.record(
+                UndoAction::Delete {
+                    position: selected,
+                    component,
+                },
+                Some(selected),
+            );
         }
     }
 
+    /// Moves the selection down, or up with `reverse`
     pub fn select_next_component(&mut self) {
-        let start = match self.list_state.selected() {
-            Some(i) => i,
-            None => 0,
-        };
+        if self.reverse {
+            self.select_component_above();
+        } else {
+            self.select_component_below();
+        }
+    }
+
+    /// Moves the selection up, or down with `reverse`
+    pub fn select_previous_component(&mut self) {
+        if self.reverse {
+            self.select_component_below();
+        } else {
+            self.select_component_above();
+        }
+    }
+
+    fn select_component_below(&mut self) {
+        if self.cmd.is_empty() {
+            return;
+        }
+        let start = self.list_state.selected().unwrap_or_default();
 
         let mut i = start;
         i = if i >= self.cmd.component_count() - 1 {
-            0
+            if self.wrap_navigation { 0 } else { i }
         } else {
             i + 1
         };
         self.list_state.select(Some(i));
     }
 
-    pub fn select_previous_component(&mut self) {
-        let start = match self.list_state.selected() {
-            Some(i) => i,
-            None => 0,
-        };
+    fn select_component_above(&mut self) {
+        if self.cmd.is_empty() {
+            return;
+        }
+        let start = self.list_state.selected().unwrap_or_default();
         let mut i = start;
         i = if i == 0 {
-            self.cmd.component_count() - 1
+            if self.wrap_navigation {
+                self.cmd.component_count() - 1
+            } else {
+                0
+            }
         } else {
             i - 1
         };
         self.list_state.select(Some(i));
     }
 
-    pub fn start_input(&mut self) {
-        if let Some(selected) = self.list_state.selected() {
-            self.input_mode = true;
-            self.current_input = self.cmd.component_at(selected).to_string();
+    pub fn select_last_component(&mut self) {
+        if !self.cmd.is_empty() {
+            self.list_state.select(Some(self.cmd.component_count() - 1));
         }
     }
 
-    pub fn confirm_input(&mut self) {
-        if let Some(selected) = self.list_state.selected() {
-            let old_value = self.cmd.set_value_at(selected, &self.current_input);
-
-            if old_value != self.current_input {
-                self.undo.push(
-                    UndoAction::Edit {
-                        position: selected,
-                        original_value: old_value,
-                        updated_value: self.current_input.clone(),
-                    },
-                    true,
-                );
-            }
+    /// Returns the flag a component is the value of, and that value.
+    ///
+    /// Handles both `--flag=value` and a value following a separate `-f`
+    /// that takes one.
+    fn flag_value_at(&self, index: usize) -> Option<(&str, &str)> {
+        let component = self.cmd.component_at(index);
+        if component.starts_with('-') {
+            return component.split_once('=');
+        }
+        let previous = self.cmd.component_at(index.checked_sub(1)?);
+        if previous.starts_with('-')
+            && previous.len() > 1
+            && !previous.contains('=')
+            && self.flag_arities.values(previous) > 0
+        {
+            Some((previous, component))
+        } else {
+            None
         }
-        self.input_mode = false;
-        self.current_input.clear();
     }
 
-    pub fn cancel_input(&mut self) {
-        self.input_mode = false;
-        self.current_input.clear();
+    /// Replaces the selected value with the next value used with its flag in history
+    pub fn next_option(&mut self) {
+        self.cycle_option(true);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Replaces the selected value with the previous value used with its flag in history
+    pub fn previous_option(&mut self) {
+        self.cycle_option(false);
+    }
 
-    fn create_app(cmd_str: &str) -> App {
-        let cmd: Command = cmd_str.try_into().unwrap();
-        App::new(cmd, 0)
+    /// Shows the next history value for the selected flag without writing it
+    pub fn peek_next_option(&mut self) {
+        self.peek_option(true);
     }
 
-    #[test]
-    fn test_delete_middle_component() {
-        let mut app = create_app("kubectl get pods -n default");
-        // Select index 2 ("pods")
-        app.list_state.select(Some(2));
+    /// Shows the previous history value for the selected flag without writing it
+    pub fn peek_previous_option(&mut self) {
+        self.peek_option(false);
+    }
 
-        app.delete_selected_component();
+    /// Writes the value being peeked at to the selected component
+    pub fn commit_peek(&mut self) {
+        let Some(value) = self.peek.take() else {
+            return;
+        };
+        if let Some(selected) = self.list_state.selected()
+            && let Some((key, _)) = self.value_key_at(selected)
+        {
+            self.write_flag_value(selected, &key, &value);
+        }
+    }
 
-        assert_eq!(app.cmd.component_count(), 4);
-        assert_eq!(app.list_state.selected(), Some(2));
-        // Now index 2 should be "-n"
-        assert_eq!(app.cmd.component_at(2), "-n");
+    /// Ends a run of cycling through history values, making the values
+    /// cycled through one undo step
+    pub fn end_cycling(&mut self) {
+        if std::mem::take(&mut self.cycling) {
+            self.undo.end_group();
+        }
     }
 
-    #[test]
-    fn test_delete_last_component() {
-        let mut app = create_app("kubectl get pods");
-        // Select last component (index 2)
-        app.list_state.select(Some(2));
+    /// Stops peeking, leaving the selected component as it was
+    pub fn cancel_peek(&mut self) {
+        self.peek = None;
+    }
 
-        app.delete_selected_component();
+    fn peek_option(&mut self, forward: bool) {
+        if let Some((_, _, value)) = self.cycled_option(forward) {
+            self.peek = Some(value);
+        }
+    }
 
-        assert_eq!(app.cmd.component_count(), 2);
-        // Selection should move to new last item (index 1)
-        assert_eq!(app.list_state.selected(), Some(1));
+    fn cycle_option(&mut self, forward: bool) {
+        self.peek = None;
+        if !self.cycling {
+            self.undo.begin_group();
+            self.cycling = true;
+        }
+        if let Some((selected, flag, value)) = self.cycled_option(forward) {
+            self.write_flag_value(selected, &flag, &value);
+        }
     }
 
-    #[test]
-    fn test_delete_only_component() {
-        let mut app = create_app("kubectl");
-        app.list_state.select(Some(0));
+    /// The history value after (or before) the selected flag's value, or the
+    /// value being peeked at, with the selected position and the flag
+    fn cycled_option(&self, forward: bool) -> Option<(usize, String, String)> {
+        let selected = self.list_state.selected()?;
+        let (key, value) = self.value_key_at(selected)?;
+        let value = self.peek.as_deref().unwrap_or(value);
+        let (candidates, _) = self.candidates(selected, &key);
+        if candidates.is_empty() {
+            return None;
+        }
 
-        app.delete_selected_component();
+        let len = candidates.len();
+        let next = match candidates.iter().position(|c| c == value) {
+            Some(i) if forward => (i + 1) % len,
+            Some(i) => (i + len - 1) % len,
+            None if forward => 0,
+            None => len - 1,
+        };
+        Some((selected, key, candidates[next].clone()))
+    }
 
-        assert_eq!(app.cmd.component_count(), 0);
-        assert_eq!(app.list_state.selected(), None);
+    /// What the component at `index` is a value of, with that value: the
+    /// flag it belongs to, or `positionalN` for the program's N-th argument
+    fn value_key_at(&self, index: usize) -> Option<(String, &str)> {
+        if let Some((flag, value)) = self.flag_value_at(index) {
+            return Some((flag.to_string(), value));
+        }
+        let n = self.positional_at(index)?;
+        Some((format!("positional{}", n), self.cmd.component_at(index)))
     }
 
-    #[test]
-    fn test_delete_with_no_selection() {
-        let mut app = create_app("kubectl get pods");
-        app.list_state.select(None);
+    /// Which argument of the program the component at `index` is, not
+    /// counting flags and their values
+    fn positional_at(&self, index: usize) -> Option<usize> {
+        let program = self.program_position();
+        let mut n = 0;
+        for i in program + 1..self.cmd.component_count() {
+            if self.cmd.is_operator_at(i) {
+                return None;
+            }
+            let positional =
+                !self.cmd.component_at(i).starts_with('-') && self.flag_value_at(i).is_none();
+            if i == index {
+                return positional.then_some(n);
+            }
+            if positional {
+                n += 1;
+            }
+        }
+        None
+    }
 
-        let count_before = app.cmd.component_count();
-        app.delete_selected_component();
+    /// Where the program is, after any wrappers like `sudo`
+    fn program_position(&self) -> usize {
+        let components: Vec<&String> = self.cmd.iter_components().collect();
+        wrapper_len(&components, &self.wrappers)
+    }
 
-        // Nothing should change
-        assert_eq!(app.cmd.component_count(), count_before);
-        assert_eq!(app.list_state.selected(), None);
+    fn program(&self) -> Option<&str> {
+        let position = self.program_position();
+        (position < self.cmd.component_count()).then(|| self.cmd.component_at(position))
     }
 
-    #[test]
-    fn test_delete_first_component() {
-        let mut app = create_app("kubectl get pods");
-        app.list_state.select(Some(0));
+    /// The values offered for the component at `position`, whose flag is
+    /// `key`: the ones from history, then the ones its provider printed and
+    /// carapace, bash's completion and its `--help` offer that history
+    /// doesn't have, each tagged with where it came from. With none of those, the values of
+    /// the program's completion spec.
+    fn candidates(&self, position: usize, key: &str) -> (Vec<String>, Vec<Option<String>>) {
+        let mut candidates: Vec<String> = self
+            .suggestions
+            .get(key)
+            .into_iter()
+            .flatten()
+            .map(|c| c.value.clone())
+            .collect();
+        let mut tags = vec![None; candidates.len()];
+        let Some(program) = self.program() else {
+            return (candidates, tags);
+        };
+        let mut add = |candidates: &mut Vec<String>, value: &str, tag: String| {
+            if !candidates.iter().any(|c| c == value) {
+                candidates.push(value.to_string());
+                tags.push(Some(tag));
+            }
+        };
+        if let Some(source) = self.providers.source(program, key) {
+            for value in self.providers.values(program, key) {
+                add(&mut candidates, &value, source.to_string());
+            }
+        }
+        if let Some((words, prefix)) = self.completion_words(position) {
+            for offered in self.carapace.values(&words) {
+                // Values for `--flag=` come back with the flag in front
+                let Some(value) = offered.value.strip_prefix(prefix.as_str()) else {
+                    continue;
+                };
+                let tag = match offered.description {
+                    Some(description) => format!("carapace: {}", description),
+                    None => "carapace".to_string(),
+                };
+                add(&mut candidates, value, tag);
+            }
+            for value in self.bash_completion.values(&words) {
+                add(&mut candidates, &value, "bash completion".to_string());
+            }
+            let base = base_command(&words, &[]);
+            for value in self.help.choices(&base, key) {
+                add(&mut candidates, &value, format!("{} --help", program));
+            }
+        }
+        if candidates.is_empty()
+            && let Some(spec) = &self.spec
+        {
+            for choice in spec.choices(key) {
+                add(&mut candidates, choice, format!("{} spec", program));
+            }
+        }
+        (candidates, tags)
+    }
 
-        app.delete_selected_component();
+    /// What carapace and bash complete the component at `position` from: the
+    /// program and the words after it up to that component, then the start
+    /// of the word being completed, which is returned too. That is `--flag=`
+    /// for a `--flag=value` component, and empty otherwise, so carapace
+    /// offers every value and not just the ones like the current one.
+    fn completion_words(&self, position: usize) -> Option<(Vec<String>, String)> {
+        let program = self.program_position();
+        if position <= program || position >= self.cmd.component_count() {
+            return None;
+        }
+        let component = self.cmd.component_at(position);
+        let (end, prefix) = match component.split_once('=') {
+            Some((flag, _)) if component.starts_with('-') => (position, format!("{}=", flag)),
+            // A flag still waiting for its value
+            _ if component.starts_with('-')
+                && component.len() > 1
+                && self.flag_arities.values(component) > 0 =>
+            {
+                (position + 1, String::new())
+            }
+            _ if component.starts_with('-') => return None,
+            _ => (position, String::new()),
+        };
+        if (program..end).any(|i| self.cmd.is_operator_at(i) || self.cmd.component_at(i) == PIPE) {
+            return None;
+        }
+        let mut words: Vec<String> = (program..end)
+            .map(|i| self.cmd.component_at(i).to_string())
+            .collect();
+        words.push(prefix.clone());
+        Some((words, prefix))
+    }
 
-        assert_eq!(app.cmd.component_count(), 2);
-        // Selection stays at 0
-        assert_eq!(app.list_state.selected(), Some(0));
-        assert_eq!(app.cmd.component_at(0), "get");
+    /// Start the provider for the selected component, the first time a
+    /// component it offers values for is selected, and ask carapace and bash
+    /// about it
+    pub fn request_provided_values(&self) {
+        let Some(selected) = self.list_state.selected() else {
+            return;
+        };
+        let key = match self.value_key_at(selected) {
+            Some((key, _)) => key,
+            // A flag still waiting for its value
+            None => self.cmd.component_at(selected).to_string(),
+        };
+        if let Some(program) = self.program() {
+            self.providers.request(program, &key);
+        }
+        if let Some((words, _)) = self.completion_words(selected) {
+            self.carapace.request(&words);
+            self.bash_completion.request(&words);
+            self.help.request(&base_command(&words, &[]));
+        }
     }
 
-    #[test]
-    fn test_insert_at_beginning() {
-        let mut app = create_app("kubectl get pods");
-        app.list_state.select(Some(0));
+    /// Writes a value offered for `flag` to the component at `position`,
+    /// keeping a `--flag=value` component in that form
+    fn write_flag_value(&mut self, position: usize, flag: &str, value: &str) {
+        let updated_value = if self.cmd.component_at(position).starts_with('-') {
+            format!("{}={}", flag, value)
+        } else {
+            value.to_string()
+        };
 
-        app.insert_new_component();
+        let original_value = self.cmd.set_value_at(position, &updated_value);
+        if original_value != updated_value {
+            let write = (flag.to_string(), value.to_string());
+            let from_history = self
+                .suggestions
+                .get(flag)
+                .is_some_and(|c| c.iter().any(|c| c.value == value));
+            if from_history && !self.history_writes.contains(&write) {
+                self.history_writes.push(write);
+            }
+            self.record(
+                UndoAction::Edit {
+                    position,
+                    original_value,
+                    updated_value,
+                },
+                Some(position),
+            );
+        }
+    }
 
-        assert_eq!(app.cmd.component_count(), 4);
-        assert_eq!(app.list_state.selected(), Some(0));
-        assert_eq!(app.cmd.component_at(0), "");
-        assert_eq!(app.cmd.component_at(1), "kubectl");
+    /// The flag values in the command that were written from history, like
+    /// `-n prod`, in command order. A value typed over or undone isn't one.
+    pub fn history_values(&self) -> Vec<String> {
+        (0..self.cmd.component_count())
+            .filter_map(|index| {
+                let (flag, value) = self.flag_value_at(index)?;
+                let write = (flag.to_string(), value.to_string());
+                self.history_writes.contains(&write).then(|| {
+                    if self.cmd.component_at(index).starts_with('-') {
+                        format!("{}={}", flag, value)
+                    } else {
+                        format!("{} {}", flag, value)
+                    }
+                })
+            })
+            .collect()
     }
 
-    #[test]
-    fn test_insert_at_middle() {
-        let mut app = create_app("kubectl get pods");
-        app.list_state.select(Some(1));
+    /// Opens the dropdown of history values for the selected component.
+    ///
+    /// The component can be a flag's value, or a flag still waiting for one.
+    /// Returns whether there were any values to offer.
+    pub fn open_picker(&mut self) -> bool {
+        let Some(selected) = self.list_state.selected() else {
+            return false;
+        };
+        let component = self.cmd.component_at(selected);
+        let (flag, value, append) = match self.value_key_at(selected) {
+            Some((flag, value)) => (flag, Some(value), false),
+            None if component.starts_with('-') && component.len() > 1 => {
+                (component.to_string(), None, true)
+            }
+            None => return false,
+        };
+        let (candidates, tags) = self.candidates(selected, &flag);
+        if candidates.is_empty() {
+            return false;
+        }
 
-        app.insert_new_component();
+        let selected_candidate = value
+            .and_then(|value| candidates.iter().position(|c| c == value))
+            .unwrap_or_default();
+        self.picker = Some(Picker {
+            position: selected,
+            flag,
+            append,
+            input: false,
+            candidates,
+            tags,
+            selected: selected_candidate,
+        });
+        true
+    }
+
+    /// Opens the dropdown for the last component, for `--completions-only`
+    pub fn open_completions(&mut self) -> bool {
+        self.select_last_component();
+        self.open_picker()
+    }
+
+    pub fn picker_next(&mut self) {
+        if let Some(picker) = &mut self.picker {
+            picker.selected = (picker.selected + 1) % picker.candidates.len();
+        }
+    }
+
+    pub fn picker_previous(&mut self) {
+        if let Some(picker) = &mut self.picker {
+            let len = picker.candidates.len();
+            picker.selected = (picker.selected + len - 1) % len;
+        }
+    }
+
+    /// Writes the highlighted value to the command and closes the dropdown
+    pub fn accept_picker(&mut self) {
+        let Some(picker) = self.picker.take() else {
+            return;
+        };
+        let value = &picker.candidates[picker.selected];
+        if picker.input {
+            self.current_input = value.clone();
+            self.input_cursor = None;
+        } else if picker.append {
+            self.list_state.select(Some(picker.position));
+            self.append_new_component();
+            self.write_flag_value(picker.position + 1, &picker.flag, value);
+        } else {
+            self.write_flag_value(picker.position, &picker.flag, value);
+        }
+    }
+
+    pub fn close_picker(&mut self) {
+        self.picker = None;
+    }
+
+    pub fn start_input(&mut self) {
+        if let Some(selected) = self.list_state.selected() {
+            self.input_mode = true;
+            self.current_input = self.cmd.component_at(selected).to_string();
+            self.input_cursor = None;
+        }
+    }
+
+    pub fn confirm_input(&mut self) {
+        if let Some(selected) = self.list_state.selected() {
+            let old_value = self.cmd.set_value_at(selected, &self.current_input);
+
+            if old_value != self.current_input {
+                self.record(
+                    UndoAction::Edit {
+                        position: selected,
+                        original_value: old_value,
+                        updated_value: self.current_input.clone(),
+                    },
+                    Some(selected),
+                );
+            }
+        }
+        self.input_mode = false;
+        self.current_input.clear();
+        self.input_cursor = None;
+    }
+
+    /// Splits the value being edited at the cursor into two components, like
+    /// a pasted `a b` that should be two arguments, and stops editing. Spaces
+    /// around the cursor go, and the second part is selected so it can be
+    /// split again. At the start or end of the value there is nothing to
+    /// split off, so the input is just confirmed.
+    pub fn split_input(&mut self) {
+        let Some(selected) = self.list_state.selected() else {
+            return;
+        };
+        let (before, after) = self.current_input.split_at(self.input_cursor());
+        if before.trim_end_matches(' ').is_empty() || after.trim_start_matches(' ').is_empty() {
+            self.confirm_input();
+            self.notice = Some("nothing to split at the start or end".to_string());
+            return;
+        }
+        let after = self.current_input.split_off(self.input_cursor());
+        let mut first = self.cmd.components()[selected].clone();
+        first.set_value(self.current_input.trim_end_matches(' '));
+        let second = Component::new(after.trim_start_matches(' ').to_string());
+        let original = vec![self.cmd.components()[selected].clone()];
+        let updated = vec![first, second];
+        self.cmd.splice(selected..selected + 1, updated.clone());
+
+        self.input_mode = false;
+        self.current_input.clear();
+        self.input_cursor = None;
+        self.list_state.select(Some(selected + 1));
+        self.record(
+            UndoAction::Replace {
+                position: selected,
+                original,
+                updated,
+            },
+            Some(selected),
+        );
+    }
+
+    /// Byte offset of the cursor in the input
+    pub fn input_cursor(&self) -> usize {
+        let len = self.current_input.len();
+        self.input_cursor.map_or(len, |cursor| cursor.min(len))
+    }
+
+    /// Types `c` at the cursor
+    pub fn insert_char(&mut self, c: char) {
+        let cursor = self.input_cursor();
+        self.current_input.insert(cursor, c);
+        if self.input_cursor.is_some() {
+            self.input_cursor = Some(cursor + c.len_utf8());
+        }
+    }
+
+    /// Moves the cursor one character left, or right with `forward`
+    pub fn move_input_cursor(&mut self, forward: bool) {
+        let cursor = self.input_cursor();
+        let moved = if forward {
+            self.current_input[cursor..]
+                .chars()
+                .next()
+                .map_or(cursor, |c| cursor + c.len_utf8())
+        } else {
+            self.current_input[..cursor]
+                .chars()
+                .next_back()
+                .map_or(cursor, |c| cursor - c.len_utf8())
+        };
+        self.set_input_cursor(moved);
+    }
+
+    /// Moves the cursor to `cursor`, where the end of the input is kept as
+    /// `None` so the cursor stays there while the input changes
+    pub fn set_input_cursor(&mut self, cursor: usize) {
+        self.input_cursor = (cursor < self.current_input.len()).then_some(cursor);
+    }
+
+    /// Empties the input
+    pub fn clear_input(&mut self) {
+        self.current_input.clear();
+        self.input_cursor = None;
+    }
+
+    /// Starts editing the selected component from an empty buffer, like
+    /// vim's `cc`. Undo restores the old value once the edit is confirmed.
+    pub fn change_selected_component(&mut self) {
+        if let Some(selected) = self.list_state.selected()
+            && !self.cmd.is_operator_at(selected)
+        {
+            self.start_input();
+            self.current_input.clear();
+        }
+    }
+
+    /// Switches the selected flag between its short and long spelling, like
+    /// `-n` and `--namespace`, keeping a value attached with `=`
+    pub fn toggle_flag_form(&mut self) {
+        let Some(selected) = self.list_state.selected() else {
+            return;
+        };
+        if self.cmd.is_operator_at(selected) {
+            return;
+        }
+        let component = self.cmd.component_at(selected);
+        if !component.starts_with('-') {
+            return;
+        }
+        let (flag, value) = match component.split_once('=') {
+            Some((flag, value)) => (flag, Some(value)),
+            None => (component, None),
+        };
+        let Some(other) = self.flag_aliases.toggle(flag) else {
+            self.notice = Some(format!("no other spelling of {} known", flag));
+            return;
+        };
+        // A short flag can't take its value with `=`, so it gets the value
+        // as the next word
+        if let Some(value) = value
+            && !other.starts_with("--")
+        {
+            let original = vec![self.cmd.components()[selected].clone()];
+            let updated = vec![
+                Component::new(other.to_string()),
+                Component::new(value.to_string()),
+            ];
+            self.cmd.splice(selected..selected + 1, updated.clone());
+            self.record(
+                UndoAction::Replace {
+                    position: selected,
+                    original,
+                    updated,
+                },
+                Some(selected),
+            );
+            return;
+        }
+        let updated_value = match value {
+            Some(value) => format!("{}={}", other, value),
+            None => other.to_string(),
+        };
+
+        let original_value = self.cmd.set_value_at(selected, &updated_value);
+        self.record(
+            UndoAction::Edit {
+                position: selected,
+                original_value,
+                updated_value,
+            },
+            Some(selected),
+        );
+    }
+
+    /// Merges the selected component with the one after it into one value,
+    /// with a space between them if `space` is set, like `foo` and `bar`
+    /// into `foobar` or `'foo bar'`. Operators are never merged.
+    pub fn merge_with_next(&mut self, space: bool) {
+        let Some(selected) = self.list_state.selected() else {
+            return;
+        };
+        if selected + 1 >= self.cmd.component_count() {
+            self.notice = Some("nothing after this to merge with".to_string());
+            return;
+        }
+        if self.cmd.is_operator_at(selected) || self.cmd.is_operator_at(selected + 1) {
+            self.notice = Some("operators can't be merged".to_string());
+            return;
+        }
+        let original = self.cmd.components()[selected..selected + 2].to_vec();
+        let separator = if space { " " } else { "" };
+        let value = format!(
+            "{}{}{}",
+            self.cmd.component_at(selected),
+            separator,
+            self.cmd.component_at(selected + 1)
+        );
+        // Keeps the first component's kind, like a `jq` script
+        let mut merged = original[0].clone();
+        merged.set_value(&value);
+        self.cmd.splice(selected..selected + 2, [merged.clone()]);
+        self.record(
+            UndoAction::Replace {
+                position: selected,
+                original,
+                updated: vec![merged],
+            },
+            Some(selected),
+        );
+    }
+
+    /// Empties the selected component without entering input mode
+    pub fn clear_selected_component(&mut self) {
+        let Some(selected) = self.list_state.selected() else {
+            return;
+        };
+        if self.cmd.is_operator_at(selected) {
+            return;
+        }
+        let old_value = self.cmd.set_value_at(selected, "");
+        if !old_value.is_empty() {
+            self.record(
+                UndoAction::Edit {
+                    position: selected,
+                    original_value: old_value,
+                    updated_value: String::new(),
+                },
+                Some(selected),
+            );
+        }
+    }
+
+    pub fn cancel_input(&mut self) {
+        self.input_mode = false;
+        self.current_input.clear();
+        self.input_cursor = None;
+    }
+
+    /// Completes the input as a file path if it looks like one, or the
+    /// selected value's flag takes one. A single match is taken as it is, so
+    /// completing again goes into it if it is a directory. Otherwise the
+    /// input is extended as far as the matches agree, and once it can't be,
+    /// they are offered in the dropdown, with the first (or last) one
+    /// highlighted.
+    pub fn complete_path(&mut self, forward: bool) {
+        let Some(selected) = self.list_state.selected() else {
+            return;
+        };
+        if !looks_like_path(&self.current_input) && !self.takes_path(selected) {
+            return;
+        }
+        let home = std::env::var_os("HOME").map(std::path::PathBuf::from);
+        let found = path_candidates(&self.current_input, home.as_deref());
+        let mut candidates = found.paths;
+        match candidates.len() {
+            0 => self.notice = Some("no matching paths".to_string()),
+            1 => {
+                self.current_input = candidates.remove(0);
+                self.input_cursor = None;
+            }
+            len => {
+                // What the kept matches share may be more than all of them do
+                let common = common_prefix(&candidates);
+                if !found.truncated && common.len() > self.current_input.len() {
+                    self.current_input = common.to_string();
+                    self.input_cursor = None;
+                    return;
+                }
+                self.picker = Some(Picker {
+                    position: selected,
+                    flag: String::new(),
+                    append: false,
+                    input: true,
+                    tags: vec![None; len],
+                    candidates,
+                    selected: if forward { 0 } else { len - 1 },
+                });
+                if found.truncated {
+                    self.notice = Some(format!(
+                        "only the first {} matching paths are shown",
+                        MAX_PATH_MATCHES
+                    ));
+                }
+            }
+        }
+    }
+
+    /// Whether the value at `position` follows a flag the program's spec
+    /// says takes a file or directory
+    fn takes_path(&self, position: usize) -> bool {
+        let Some(spec) = &self.spec else {
+            return false;
+        };
+        if self.cmd.component_at(position).starts_with('-') {
+            return false;
+        }
+        self.flag_value_at(position)
+            .is_some_and(|(flag, _)| spec.takes_path(flag))
+    }
+
+    /// Deletes the character before the cursor. An empty input stays as it
+    /// is rather than deleting the component, which is easy to do by accident
+    /// when holding the key; `d` in navigation mode does that instead.
+    pub fn delete_backward(&mut self) {
+        let cursor = self.input_cursor();
+        if let Some(c) = self.current_input[..cursor].chars().next_back() {
+            self.current_input.remove(cursor - c.len_utf8());
+            if self.input_cursor.is_some() {
+                self.input_cursor = Some(cursor - c.len_utf8());
+            }
+        }
+    }
+
+    /// Deletes the character under the cursor
+    pub fn delete_forward(&mut self) {
+        let cursor = self.input_cursor();
+        if cursor < self.current_input.len() {
+            self.current_input.remove(cursor);
+            self.set_input_cursor(cursor);
+        }
+    }
+
+    /// Builds the rendered pieces for every component in a list `width` columns wide.
+    ///
+    /// The selected component shows the input buffer while in input mode and is
+    /// styled bold (input mode) or reversed (navigation mode). A value being
+    /// peeked at is shown in its place, dimmed and in italics.
+    pub fn component_spans(&self, width: u16) -> Vec<ComponentSpan> {
+        // Nothing is highlighted without a selection, as after deleting
+        // every component
+        let selected = self.list_state.selected();
+        let text_width = width.saturating_sub(PREFIX_WIDTH) as usize;
+        let peek = selected.zip(self.peek.as_ref()).map(|(selected, value)| {
+            match self.flag_value_at(selected) {
+                Some((flag, _)) if self.cmd.component_at(selected).starts_with('-') => {
+                    format!("{}={}", flag, value)
+                }
+                _ => value.clone(),
+            }
+        });
+
+        self.cmd
+            .iter_components()
+            .enumerate()
+            .map(|(i, component)| {
+                let is_selected = selected == Some(i);
+                let text = if self.input_mode && is_selected {
+                    self.current_input.as_str()
+                } else if let Some(peek) = peek.as_deref().filter(|_| is_selected) {
+                    peek
+                } else {
+                    component.as_str()
+                };
+
+                let prefix_char = get_prefix_char(i)
+                    .map(|c| c.to_string())
+                    .unwrap_or_else(|| (i + 1).to_string());
+
+                let style = if is_selected {
+                    if peek.is_some() {
+                        Style::default().add_modifier(Modifier::DIM | Modifier::ITALIC)
+                    } else if self.input_mode {
+                        Style::default().add_modifier(Modifier::BOLD)
+                    } else {
+                        Style::default().add_modifier(Modifier::REVERSED)
+                    }
+                } else {
+                    Style::default()
+                };
+
+                let editing = self.input_mode && is_selected;
+                let gutter_width = if editing && self.line_numbers && text.contains('\n') {
+                    gutter_width(text)
+                } else {
+                    0
+                };
+                let wrap = |text: &str| {
+                    if text_width == 0 {
+                        // No column left for the text; one empty row keeps
+                        // the layout, and the cursor, in place
+                        (vec![String::new()], Vec::new())
+                    } else if gutter_width > 0 {
+                        wrap_numbered(text, text_width, gutter_width)
+                    } else {
+                        (wrap_text(text, text_width), Vec::new())
+                    }
+                };
+                let (lines, gutter) = wrap(text);
+
+                // Where the text before the cursor ends, or the start of the
+                // next row when the character after it goes there
+                let cursor = editing.then(|| {
+                    let at = self.input_cursor();
+                    let (before, _) = wrap(&text[..at]);
+                    let mut row = before.len() - 1;
+                    let mut column = before.last().map_or(0, |line| tui::text_width(line));
+                    let room = text_width.saturating_sub(gutter_width).max(1);
+                    let next_row = match text[at..].chars().next() {
+                        Some('\n') => text[..at].ends_with('\n'),
+                        Some(next) => {
+                            text[..at].ends_with('\n') || column + char_width(next) > room
+                        }
+                        None => false,
+                    };
+                    if next_row && row + 1 < lines.len() {
+                        row += 1;
+                        column = 0;
+                    }
+                    (row, column)
+                });
+                ComponentSpan {
+                    prefix: format!(" {} ", prefix_char),
+                    lines,
+                    gutter,
+                    style,
+                    cursor,
+                }
+            })
+            .collect()
+    }
+
+    /// Text for the status row below the components, if there is anything to report
+    pub fn status_line(&self) -> Option<String> {
+        let mut parts = Vec::new();
+        if let Some(notice) = &self.notice {
+            parts.push(notice.clone());
+        }
+        if let Some(batch) = &self.batch {
+            parts.push(format!("{}/{}", batch.position, batch.total));
+            if batch.esc_cancels_all {
+                parts.push("esc again to cancel all".to_string());
+            }
+        }
+        if self.brace_preview
+            && let Some(expanded) = self.cmd.brace_expanded()
+        {
+            parts.push(format!("expands to: {}", expanded));
+        }
+        let duplicates =
+            self.cmd
+                .duplicate_flags(&self.repeatable_flags, &self.flag_arities, &self.wrappers);
+        if !duplicates.is_empty() {
+            parts.push(format!("duplicate flags: {}", duplicates.join(", ")));
+        }
+        if self.input_mode && self.hints {
+            parts.push(INPUT_MODE_HINT.to_string());
+        }
+        if self.peek.is_some() && self.hints {
+            parts.push(PEEK_HINT.to_string());
+        }
+        // Steps that `u` and Ctrl+R would take, once there are any
+        if self.undo.can_undo() || self.undo.can_redo() {
+            parts.push(format!(
+                "u:{} r:{}",
+                self.undo.undo_len(),
+                self.undo.redo_len()
+            ));
+        }
+        (!parts.is_empty()).then(|| parts.join(" · "))
+    }
+
+    /// Returns the cursor position as `(column, row)` relative to the list area.
+    ///
+    /// The cursor sits where the input buffer before it ends once wrapped,
+    /// measured with [`tui::text_width`], past the line-number gutter if there is
+    /// one. Returns `None` outside input mode.
+    pub fn input_cursor_offset(&self, spans: &[ComponentSpan]) -> Option<(u16, u16)> {
+        if !self.input_mode {
+            return None;
+        }
+        let selected = self.list_state.selected()?;
+        let span = spans.get(selected)?;
+        let (row, column) = span.cursor?;
+
+        let rows_above: u16 = spans[..selected]
+            .iter()
+            .map(|span| span.lines.len() as u16)
+            .sum();
+        let gutter_width = span.gutter.get(row).map_or(0, |gutter| gutter.len() as u16);
+
+        Some((
+            PREFIX_WIDTH + gutter_width + column as u16,
+            rows_above + row as u16,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_app(cmd_str: &str) -> App {
+        let cmd: Command = cmd_str.try_into().unwrap();
+        App::new(cmd, 0)
+    }
+
+    #[test]
+    fn test_delete_middle_component() {
+        let mut app = create_app("kubectl get pods -n default");
+        // Select index 2 ("pods")
+        app.list_state.select(Some(2));
+
+        app.delete_selected_component();
 
         assert_eq!(app.cmd.component_count(), 4);
+        assert_eq!(app.list_state.selected(), Some(2));
+        // Now index 2 should be "-n"
+        assert_eq!(app.cmd.component_at(2), "-n");
+    }
+
+    #[test]
+    fn test_delete_last_component() {
+        let mut app = create_app("kubectl get pods");
+        // Select last component (index 2)
+        app.list_state.select(Some(2));
+
+        app.delete_selected_component();
+
+        assert_eq!(app.cmd.component_count(), 2);
+        // Selection should move to new last item (index 1)
         assert_eq!(app.list_state.selected(), Some(1));
-        assert_eq!(app.cmd.component_at(0), "kubectl");
-        assert_eq!(app.cmd.component_at(1), "");
-        assert_eq!(app.cmd.component_at(2), "get");
     }
 
     #[test]
-    fn test_insert_with_no_selection() {
+    fn test_delete_only_component() {
+        let mut app = create_app("kubectl");
+        app.list_state.select(Some(0));
+
+        app.delete_selected_component();
+
+        assert_eq!(app.cmd.component_count(), 0);
+        assert_eq!(app.list_state.selected(), None);
+    }
+
+    #[test]
+    fn test_delete_with_no_selection() {
         let mut app = create_app("kubectl get pods");
         app.list_state.select(None);
 
-        app.insert_new_component();
+        let count_before = app.cmd.component_count();
+        app.delete_selected_component();
 
-        assert_eq!(app.cmd.component_count(), 4);
-        assert_eq!(app.list_state.selected(), Some(0));
-        assert_eq!(app.cmd.component_at(0), "");
+        // Nothing should change
+        assert_eq!(app.cmd.component_count(), count_before);
+        assert_eq!(app.list_state.selected(), None);
     }
 
     #[test]
-    fn test_append_at_beginning() {
+    fn test_delete_first_component() {
         let mut app = create_app("kubectl get pods");
         app.list_state.select(Some(0));
 
-        app.append_new_component();
+        app.delete_selected_component();
 
-        assert_eq!(app.cmd.component_count(), 4);
-        assert_eq!(app.list_state.selected(), Some(1));
-        assert_eq!(app.cmd.component_at(0), "kubectl");
-        assert_eq!(app.cmd.component_at(1), "");
-        assert_eq!(app.cmd.component_at(2), "get");
+        assert_eq!(app.cmd.component_count(), 2);
+        // Selection stays at 0
+        assert_eq!(app.list_state.selected(), Some(0));
+        assert_eq!(app.cmd.component_at(0), "get");
     }
 
     #[test]
-    fn test_append_at_middle() {
+    fn test_navigation_on_empty_command() {
+        let mut app = create_app("kubectl");
+        app.delete_selected_component();
+        assert!(app.cmd.is_empty());
+
+        app.select_next_component();
+        app.select_previous_component();
+        app.select_last_component();
+        assert_eq!(app.list_state.selected(), None);
+    }
+
+    #[test]
+    fn test_navigation_wraps_at_boundaries() {
         let mut app = create_app("kubectl get pods");
-        app.list_state.select(Some(1));
 
-        app.append_new_component();
+        app.select_previous_component();
+        assert_eq!(app.list_state.selected(), Some(2));
+        app.select_next_component();
+        assert_eq!(app.list_state.selected(), Some(0));
+    }
 
-        assert_eq!(app.cmd.component_count(), 4);
+    #[test]
+    fn test_navigation_without_wrapping_stops_at_boundaries() {
+        let mut app = create_app("kubectl get pods");
+        app.wrap_navigation = false;
+
+        app.select_previous_component();
+        assert_eq!(app.list_state.selected(), Some(0));
+
+        app.select_last_component();
+        app.select_next_component();
         assert_eq!(app.list_state.selected(), Some(2));
-        assert_eq!(app.cmd.component_at(1), "get");
-        assert_eq!(app.cmd.component_at(2), "");
-        assert_eq!(app.cmd.component_at(3), "pods");
+
+        app.select_previous_component();
+        assert_eq!(app.list_state.selected(), Some(1));
     }
 
     #[test]
-    fn test_append_at_end() {
+    fn test_insert_at_beginning() {
         let mut app = create_app("kubectl get pods");
-        app.list_state.select(Some(2));
+        app.list_state.select(Some(0));
 
-        app.append_new_component();
+        app.insert_new_component();
 
         assert_eq!(app.cmd.component_count(), 4);
-        assert_eq!(app.list_state.selected(), Some(3));
+        assert_eq!(app.list_state.selected(), Some(0));
+        assert_eq!(app.cmd.component_at(0), "");
+        assert_eq!(app.cmd.component_at(1), "kubectl");
+    }
+
+    #[test]
+    fn test_insert_at_middle() {
+        let mut app = create_app("kubectl get pods");
+        app.list_state.select(Some(1));
+
+        app.insert_new_component();
+
+        assert_eq!(app.cmd.component_count(), 4);
+        assert_eq!(app.list_state.selected(), Some(1));
+        assert_eq!(app.cmd.component_at(0), "kubectl");
+        assert_eq!(app.cmd.component_at(1), "");
+        assert_eq!(app.cmd.component_at(2), "get");
+    }
+
+    #[test]
+    fn test_insert_with_no_selection() {
+        let mut app = create_app("kubectl get pods");
+        app.list_state.select(None);
+
+        app.insert_new_component();
+
+        assert_eq!(app.cmd.component_count(), 4);
+        assert_eq!(app.list_state.selected(), Some(0));
+        assert_eq!(app.cmd.component_at(0), "");
+    }
+
+    #[test]
+    fn test_append_at_beginning() {
+        let mut app = create_app("kubectl get pods");
+        app.list_state.select(Some(0));
+
+        app.append_new_component();
+
+        assert_eq!(app.cmd.component_count(), 4);
+        assert_eq!(app.list_state.selected(), Some(1));
+        assert_eq!(app.cmd.component_at(0), "kubectl");
+        assert_eq!(app.cmd.component_at(1), "");
+        assert_eq!(app.cmd.component_at(2), "get");
+    }
+
+    #[test]
+    fn test_append_at_middle() {
+        let mut app = create_app("kubectl get pods");
+        app.list_state.select(Some(1));
+
+        app.append_new_component();
+
+        assert_eq!(app.cmd.component_count(), 4);
+        assert_eq!(app.list_state.selected(), Some(2));
+        assert_eq!(app.cmd.component_at(1), "get");
+        assert_eq!(app.cmd.component_at(2), "");
+        assert_eq!(app.cmd.component_at(3), "pods");
+    }
+
+    #[test]
+    fn test_append_at_end() {
+        let mut app = create_app("kubectl get pods");
+        app.list_state.select(Some(2));
+
+        app.append_new_component();
+
+        assert_eq!(app.cmd.component_count(), 4);
+        assert_eq!(app.list_state.selected(), Some(3));
         assert_eq!(app.cmd.component_at(3), "");
     }
 
+    #[test]
+    fn test_component_spans_navigation_mode() {
+        let mut app = create_app("kubectl get pods");
+        app.list_state.select(Some(1));
+
+        let spans = app.component_spans(20);
+
+        assert_eq!(spans.len(), 3);
+        assert_eq!(spans[0].prefix, " 1 ");
+        assert_eq!(spans[0].lines, vec!["kubectl"]);
+        assert_eq!(spans[0].style, Style::default());
+        assert_eq!(spans[1].lines, vec!["get"]);
+        assert_eq!(
+            spans[1].style,
+            Style::default().add_modifier(Modifier::REVERSED)
+        );
+        assert_eq!(app.input_cursor_offset(&spans), None);
+    }
+
+    #[test]
+    fn test_component_spans_zero_width() {
+        let mut app = create_app("kubectl get pods");
+        app.list_state.select(Some(2));
+        app.start_input();
+        app.current_input = "deploy\nments\x07".to_string();
+        app.line_numbers = true;
+
+        for width in [0, 1, PREFIX_WIDTH] {
+            let spans = app.component_spans(width);
+            // One empty row per component, with the cursor on the edited one
+            assert!(spans.iter().all(|span| span.lines == vec![""]));
+            assert!(spans.iter().all(|span| span.gutter.is_empty()));
+            assert_eq!(app.input_cursor_offset(&spans), Some((PREFIX_WIDTH, 2)));
+        }
+    }
+
+    #[test]
+    fn test_component_spans_input_mode() {
+        let mut app = create_app("kubectl get pods");
+        app.list_state.select(Some(2));
+        app.start_input();
+        app.current_input = "deployments".to_string();
+
+        let spans = app.component_spans(20);
+
+        // The input buffer is shown instead of the committed value
+        assert_eq!(spans[2].lines, vec!["deployments"]);
+        assert_eq!(
+            spans[2].style,
+            Style::default().add_modifier(Modifier::BOLD)
+        );
+        assert_eq!(app.cmd.component_at(2), "pods");
+        // Cursor after "deployments" on the third row
+        assert_eq!(app.input_cursor_offset(&spans), Some((3 + 11, 2)));
+    }
+
+    #[test]
+    fn test_component_spans_wrapped_value() {
+        let mut app = create_app("echo abcdefghij tail");
+        app.list_state.select(Some(1));
+        app.start_input();
+
+        // 3 prefix columns leave 5 for text
+        let spans = app.component_spans(8);
+
+        assert_eq!(spans[1].lines, vec!["abcde", "fghij"]);
+        assert_eq!(spans[2].lines, vec!["tail"]);
+        // Cursor at the end of the second wrapped line of the selected value
+        assert_eq!(app.input_cursor_offset(&spans), Some((3 + 5, 2)));
+    }
+
+    #[test]
+    fn test_input_cursor_inside_value() {
+        let mut app = create_app("echo abcdefghij tail");
+        app.list_state.select(Some(1));
+        app.start_input();
+        app.move_input_cursor(false);
+        app.move_input_cursor(false);
+        app.insert_char('-');
+        assert_eq!(app.current_input, "abcdefgh-ij");
+        app.delete_backward();
+        app.delete_forward();
+        assert_eq!(app.current_input, "abcdefghj");
+
+        // Right before "j", at the end of the second wrapped line
+        app.set_input_cursor(5);
+        let spans = app.component_spans(8);
+        assert_eq!(spans[1].lines, vec!["abcde", "fghj"]);
+        // Before "f", which starts the second line
+        assert_eq!(app.input_cursor_offset(&spans), Some((3, 2)));
+        app.move_input_cursor(false);
+        let spans = app.component_spans(8);
+        assert_eq!(app.input_cursor_offset(&spans), Some((3 + 4, 1)));
+
+        // After a line break, the cursor is on the next line
+        app.current_input = "ab\ncd".to_string();
+        app.set_input_cursor(3);
+        let spans = app.component_spans(20);
+        assert_eq!(app.input_cursor_offset(&spans), Some((3, 2)));
+
+        // Moving right to the end keeps the cursor there as text is added
+        app.move_input_cursor(true);
+        app.move_input_cursor(true);
+        app.move_input_cursor(true);
+        assert_eq!(app.input_cursor, None);
+        app.insert_char('e');
+        assert_eq!(app.current_input, "ab\ncde");
+        app.clear_input();
+        assert_eq!(app.input_cursor(), 0);
+    }
+
+    #[test]
+    fn test_split_input() {
+        let mut app = create_app("echo x");
+        app.list_state.select(Some(1));
+        app.start_input();
+        app.current_input = "a b c".to_string();
+        app.set_input_cursor(1);
+        app.split_input();
+        assert!(!app.input_mode);
+        assert_eq!(app.cmd.to_shell_string(), "echo a \"b c\"");
+        assert_eq!(app.list_state.selected(), Some(2));
+
+        // The second part can be split again
+        app.start_input();
+        app.set_input_cursor(2);
+        app.split_input();
+        assert_eq!(app.cmd.to_shell_string(), "echo a b c");
+
+        app.undo();
+        assert_eq!(app.cmd.to_shell_string(), "echo a \"b c\"");
+        app.undo();
+        assert_eq!(app.cmd.to_shell_string(), "echo x");
+        assert_eq!(app.list_state.selected(), Some(1));
+        app.redo();
+        assert_eq!(app.cmd.to_shell_string(), "echo a \"b c\"");
+    }
+
+    #[test]
+    fn test_split_input_at_start_and_end() {
+        let mut app = create_app("echo x");
+        app.list_state.select(Some(1));
+        app.start_input();
+        app.current_input = "abc".to_string();
+        app.set_input_cursor(0);
+        app.split_input();
+        // No empty component, just the input confirmed
+        assert!(!app.input_mode);
+        assert!(app.notice.is_some());
+        assert_eq!(app.cmd.to_shell_string(), "echo abc");
+        assert_eq!(app.list_state.selected(), Some(1));
+
+        // The same at the end, even after spaces
+        app.start_input();
+        app.current_input = "abc d ".to_string();
+        app.split_input();
+        assert!(!app.input_mode);
+        assert_eq!(app.cmd.to_shell_string(), "echo \"abc d \"");
+        assert_eq!(app.cmd.component_count(), 2);
+    }
+
+    #[test]
+    fn test_gutter_width() {
+        assert_eq!(gutter_width(".items[]"), 0);
+        assert_eq!(gutter_width(".items[]\n| .name"), 2);
+        assert_eq!(gutter_width(&"x\n".repeat(9)), 3);
+    }
+
+    #[test]
+    fn test_component_spans_line_numbers() {
+        let mut app = create_app("jq x file.json");
+        app.line_numbers = true;
+        app.list_state.select(Some(1));
+        app.start_input();
+        app.current_input = ".items[]\n| .name".to_string();
+
+        let spans = app.component_spans(40);
+
+        assert_eq!(spans[1].lines, vec![".items[]", "| .name"]);
+        assert_eq!(spans[1].gutter, vec!["1 ", "2 "]);
+        // Other components and single-line values get no gutter
+        assert!(spans[2].gutter.is_empty());
+        // Cursor after "| .name", past the gutter, on the second line
+        assert_eq!(app.input_cursor_offset(&spans), Some((3 + 2 + 7, 2)));
+
+        // The gutter narrows the text, and wrapped rows leave it blank
+        app.current_input = format!("{}abcdefghij", "x\n".repeat(9));
+        let spans = app.component_spans(10);
+        assert_eq!(spans[1].gutter[0], " 1 ");
+        assert_eq!(spans[1].lines[9..], ["abcd", "efgh", "ij"]);
+        assert_eq!(spans[1].gutter[9..], ["10 ", "   ", "   "]);
+        assert_eq!(app.input_cursor_offset(&spans), Some((3 + 3 + 2, 1 + 11)));
+    }
+
+    #[test]
+    fn test_component_spans_line_numbers_off() {
+        let mut app = create_app("jq x");
+        app.list_state.select(Some(1));
+        app.start_input();
+        app.current_input = ".a\n.b".to_string();
+
+        let spans = app.component_spans(40);
+
+        assert!(spans[1].gutter.is_empty());
+        assert_eq!(app.input_cursor_offset(&spans), Some((3 + 2, 2)));
+    }
+
+    #[test]
+    fn test_input_cursor_after_combining_accent() {
+        let mut app = create_app("echo x");
+        app.list_state.select(Some(1));
+        app.start_input();
+        app.current_input = "cafe\u{301}".to_string();
+
+        let spans = app.component_spans(20);
+
+        assert_eq!(app.input_cursor_offset(&spans), Some((3 + 4, 1)));
+    }
+
+    fn create_app_with_suggestions(cmd_str: &str, flag: &str, values: &[&str]) -> App {
+        use crate::history::Candidate;
+
+        let mut app = create_app(cmd_str);
+        let candidates = values
+            .iter()
+            .map(|v| Candidate {
+                value: v.to_string(),
+                count: 1,
+            })
+            .collect();
+        app.suggestions.insert(flag.to_string(), candidates);
+        app
+    }
+
+    #[test]
+    fn test_complete_path() {
+        let dir = std::env::temp_dir().join(format!("te-{}-complete", std::process::id()));
+        std::fs::create_dir_all(dir.join("deploy")).unwrap();
+        std::fs::write(dir.join("deploy.yaml"), "").unwrap();
+        std::fs::write(dir.join("debug.log"), "").unwrap();
+        std::fs::write(dir.join("deploy/web.yaml"), "").unwrap();
+        let base = format!("{}/", dir.display());
+
+        let mut app = create_app("kubectl apply -f x");
+        app.list_state.select(Some(3));
+        app.start_input();
+        app.current_input = format!("{}dep", base);
+
+        // The first Tab goes as far as the matches agree
+        app.complete_path(true);
+        assert_eq!(app.current_input, format!("{}deploy", base));
+        assert_eq!(app.picker, None);
+
+        // The next one offers them
+        app.complete_path(false);
+        let picker = app.picker.as_ref().unwrap();
+        assert_eq!(
+            picker.candidates,
+            [format!("{}deploy.yaml", base), format!("{}deploy/", base)]
+        );
+        assert_eq!(picker.selected, 1);
+        app.accept_picker();
+        assert_eq!(app.current_input, format!("{}deploy/", base));
+        assert!(app.input_mode);
+        assert_eq!(app.cmd.component_at(3), "x");
+
+        // A single match is taken, and completing goes on into directories
+        app.complete_path(true);
+        assert_eq!(app.current_input, format!("{}deploy/web.yaml", base));
+        app.current_input = format!("{}nope", base);
+        app.complete_path(true);
+        assert_eq!(app.notice.as_deref(), Some("no matching paths"));
+
+        // Values that don't look like paths are left alone
+        app.current_input = "deploy".to_string();
+        app.complete_path(true);
+        assert_eq!(app.current_input, "deploy");
+        assert_eq!(app.picker, None);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_complete_path_for_flag_taking_one() {
+        let mut app = create_app("kubectl apply -f src -n src");
+        app.spec = Some(Arc::new(
+            Spec::parse(r#"{"options": [{"name": "-f", "args": {"template": "filepaths"}}]}"#)
+                .unwrap(),
+        ));
+
+        // `src` is this crate's source directory, as tests run from its root
+        app.list_state.select(Some(3));
+        app.start_input();
+        app.complete_path(true);
+        assert_eq!(app.current_input, "src/");
+
+        app.list_state.select(Some(5));
+        app.start_input();
+        app.complete_path(true);
+        assert_eq!(app.current_input, "src");
+    }
+
+    #[test]
+    fn test_cycling_is_one_undo_step() {
+        let mut app = create_app_with_suggestions(
+            "kubectl get pods -n prod",
+            "-n",
+            &["prod", "staging", "dev", "test"],
+        );
+        app.list_state.select(Some(4));
+        app.delete_selected_component();
+        app.undo();
+
+        for _ in 0..3 {
+            app.next_option();
+        }
+        app.previous_option();
+        assert_eq!(app.cmd.component_at(4), "dev");
+        app.end_cycling();
+        app.next_option();
+        assert_eq!(app.cmd.component_at(4), "test");
+
+        // The second run of cycling, then the first, each in one step
+        app.undo();
+        assert_eq!(app.cmd.component_at(4), "dev");
+        app.undo();
+        assert_eq!(app.cmd.component_at(4), "prod");
+        assert_eq!(app.cmd.component_count(), 5);
+
+        app.redo();
+        assert_eq!(app.cmd.component_at(4), "dev");
+    }
+
+    #[test]
+    fn test_toggle_flag_form() {
+        let mut app = create_app("kubectl get pods -n prod --output=wide --context x");
+        app.flag_aliases = FlagAliases::for_command(&["kubectl".to_string()], &Default::default());
+        app.list_state.select(Some(3));
+
+        app.toggle_flag_form();
+        assert_eq!(
+            app.cmd.to_shell_string(),
+            "kubectl get pods --namespace prod --output=wide --context x"
+        );
+        // The value that follows still belongs to the flag
+        assert_eq!(app.flag_value_at(4), Some(("--namespace", "prod")));
+        app.toggle_flag_form();
+        assert_eq!(app.cmd.component_at(3), "-n");
+
+        // An attached value becomes the next word of the short flag, in one
+        // undo step
+        app.list_state.select(Some(5));
+        app.toggle_flag_form();
+        assert_eq!(
+            app.cmd.to_shell_string(),
+            "kubectl get pods -n prod -o wide --context x"
+        );
+        assert_eq!(app.flag_value_at(6), Some(("-o", "wide")));
+        app.undo();
+        assert_eq!(app.cmd.component_at(5), "--output=wide");
+        assert_eq!(app.cmd.component_count(), 8);
+
+        app.list_state.select(Some(6));
+        app.toggle_flag_form();
+        assert_eq!(app.cmd.component_at(6), "--context");
+        assert_eq!(
+            app.notice.as_deref(),
+            Some("no other spelling of --context known")
+        );
+    }
+
+    #[test]
+    fn test_merge_with_next() {
+        let mut app = create_app("echo foo bar baz");
+        app.list_state.select(Some(1));
+        app.merge_with_next(false);
+        assert_eq!(app.cmd.to_shell_string(), "echo foobar baz");
+        assert_eq!(app.list_state.selected(), Some(1));
+
+        // A space in the merged value gets it quoted
+        app.merge_with_next(true);
+        assert_eq!(app.cmd.component_at(1), "foobar baz");
+        assert_eq!(app.cmd.to_shell_string(), "echo \"foobar baz\"");
+
+        app.undo();
+        assert_eq!(app.cmd.to_shell_string(), "echo foobar baz");
+        app.undo();
+        assert_eq!(app.cmd.to_shell_string(), "echo foo bar baz");
+        app.redo();
+        assert_eq!(app.cmd.to_shell_string(), "echo foobar baz");
+
+        // The last component has nothing to merge with
+        app.select_last_component();
+        app.merge_with_next(true);
+        assert_eq!(app.cmd.component_count(), 3);
+        assert!(app.notice.is_some());
+    }
+
+    #[test]
+    fn test_merge_skips_operators() {
+        let mut app = create_app("echo foo");
+        app.list_state.select(Some(1));
+        app.insert_operator(PIPE);
+        app.cancel_input();
+        app.list_state.select(Some(1));
+        app.merge_with_next(false);
+        assert_eq!(app.cmd.component_count(), 4);
+        assert_eq!(app.notice.as_deref(), Some("operators can't be merged"));
+    }
+
+    #[test]
+    fn test_status_line_without_hints() {
+        let mut app = create_app("kubectl get pods");
+        app.hints = false;
+        app.start_input();
+        assert_eq!(app.status_line(), None);
+
+        app.current_input = "svc".to_string();
+        app.confirm_input();
+        assert_eq!(app.status_line(), Some("u:1 r:0".to_string()));
+    }
+
+    /// Every action a key can run in Navigation Mode, then the ones of
+    /// Edit Mode and the picker
+    fn drive_every_action(app: &mut App) {
+        app.select_next_component();
+        app.select_previous_component();
+        app.select_last_component();
+        app.next_option();
+        app.previous_option();
+        app.peek_next_option();
+        app.commit_peek();
+        app.peek_previous_option();
+        app.cancel_peek();
+        app.end_cycling();
+        app.open_picker();
+        app.picker_next();
+        app.accept_picker();
+        app.change_selected_component();
+        app.clear_selected_component();
+        app.toggle_flag_form();
+        app.toggle_sudo();
+        app.delete_selected_component();
+        app.undo();
+        app.redo();
+        app.start_input();
+        app.current_input.push('x');
+        app.complete_path(true);
+        app.delete_backward();
+        app.confirm_input();
+        app.cancel_input();
+        app.request_provided_values();
+        app.insert_new_component();
+        app.cancel_input();
+        app.append_new_component();
+        app.insert_operator(crate::command::PIPE);
+        app.cancel_input();
+        app.undo();
+        app.undo();
+        app.undo();
+        app.status_line();
+        app.component_spans(80);
+        app.history_values();
+    }
+
+    #[test]
+    fn test_no_selection_is_harmless() {
+        let mut app =
+            create_app_with_suggestions("kubectl get pods -n prod", "-n", &["prod", "staging"]);
+        app.list_state.select(None);
+        let before = app.cmd.to_shell_string();
+        assert!(
+            app.component_spans(80)
+                .iter()
+                .all(|span| span.style == Style::default())
+        );
+
+        for _ in 0..2 {
+            app.list_state.select(None);
+            app.change_selected_component();
+            app.clear_selected_component();
+            app.toggle_flag_form();
+            app.delete_selected_component();
+            app.next_option();
+            app.open_picker();
+            app.start_input();
+            app.confirm_input();
+        }
+        assert_eq!(app.cmd.to_shell_string(), before);
+        assert!(!app.input_mode);
+        assert!(app.picker.is_none());
+
+        drive_every_action(&mut app);
+    }
+
+    #[test]
+    fn test_empty_command_is_harmless() {
+        // A command can't be parsed empty, but every word can be deleted
+        let mut app = create_app("ls");
+        app.delete_selected_component();
+        assert!(app.cmd.is_empty());
+        drive_every_action(&mut app);
+        app.list_state.select(None);
+        drive_every_action(&mut app);
+    }
+
+    #[test]
+    fn test_undo_removing_sudo_in_one_step() {
+        let mut app = create_app("sudo -u deploy systemctl restart web");
+        app.toggle_sudo();
+        assert_eq!(app.cmd.to_shell_string(), "systemctl restart web");
+
+        app.undo();
+        assert_eq!(
+            app.cmd.to_shell_string(),
+            "sudo -u deploy systemctl restart web"
+        );
+    }
+
+    #[test]
+    fn test_next_option_separated_value() {
+        let mut app =
+            create_app_with_suggestions("kubectl get pods -n prod", "-n", &["prod", "staging"]);
+        app.list_state.select(Some(4));
+
+        app.next_option();
+        assert_eq!(app.cmd.component_at(4), "staging");
+        app.next_option();
+        assert_eq!(app.cmd.component_at(4), "prod");
+
+        app.previous_option();
+        assert_eq!(app.cmd.component_at(4), "staging");
+        app.undo();
+        assert_eq!(app.cmd.component_at(4), "prod");
+    }
+
+    #[test]
+    fn test_peek_option_leaves_component_until_committed() {
+        let mut app =
+            create_app_with_suggestions("kubectl get pods -n prod", "-n", &["prod", "staging"]);
+        app.list_state.select(Some(4));
+
+        app.peek_next_option();
+        assert_eq!(app.peek.as_deref(), Some("staging"));
+        assert_eq!(app.cmd.component_at(4), "prod");
+        assert_eq!(app.component_spans(80)[4].lines, vec!["staging"]);
+        assert_eq!(app.status_line(), Some(PEEK_HINT.to_string()));
+
+        // Peeking again moves on from the peeked value
+        app.peek_next_option();
+        assert_eq!(app.peek.as_deref(), Some("prod"));
+        app.peek_previous_option();
+        assert_eq!(app.peek.as_deref(), Some("staging"));
+
+        app.commit_peek();
+        assert_eq!(app.peek, None);
+        assert_eq!(app.cmd.component_at(4), "staging");
+        app.undo();
+        assert_eq!(app.cmd.component_at(4), "prod");
+    }
+
+    #[test]
+    fn test_history_values() {
+        let mut app =
+            create_app_with_suggestions("kubectl get pods -n prod", "-n", &["prod", "staging"]);
+        app.list_state.select(Some(4));
+        assert!(app.history_values().is_empty());
+
+        app.next_option();
+        app.end_cycling();
+        assert_eq!(app.history_values(), ["-n staging"]);
+
+        // A value typed over isn't from history, until the edit is undone
+        app.start_input();
+        app.current_input = "dev".to_string();
+        app.confirm_input();
+        assert!(app.history_values().is_empty());
+        app.undo();
+        assert_eq!(app.history_values(), ["-n staging"]);
+
+        let mut app = create_app_with_suggestions(
+            "kubectl get pods --output=json",
+            "--output",
+            &["yaml", "wide"],
+        );
+        app.list_state.select(Some(3));
+        app.open_picker();
+        app.accept_picker();
+        assert_eq!(app.history_values(), ["--output=yaml"]);
+    }
+
+    /// Run the providers of `config` for the selected component of `app`
+    fn load_provided_values(app: &mut App, config: &str) {
+        app.providers = Providers::new(toml::from_str(config).unwrap());
+        app.request_provided_values();
+        while app.providers.running() {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_provided_values_follow_history() {
+        let mut app =
+            create_app_with_suggestions("kubectl get pods -n prod", "-n", &["prod", "staging"]);
+        app.list_state.select(Some(4));
+        load_provided_values(
+            &mut app,
+            "[kubectl.\"-n\"]\ncmd = \"echo staging; echo dev\"\n",
+        );
+
+        assert!(app.open_picker());
+        let picker = app.picker.as_ref().unwrap();
+        assert_eq!(picker.candidates, ["prod", "staging", "dev"]);
+        let provider = Some("echo staging; echo dev".to_string());
+        assert_eq!(picker.tags, [None, None, provider]);
+        app.close_picker();
+
+        app.next_option();
+        app.next_option();
+        assert_eq!(app.cmd.component_at(4), "dev");
+        // Only history values are reported as such
+        assert!(app.history_values().is_empty());
+    }
+
+    #[test]
+    fn test_spec_values_without_history() {
+        let spec = Spec::parse(
+            r#"{"options": [{"name": ["-o", "--output"], "args": {"suggestions": ["json", "yaml"]}}]}"#,
+        )
+        .unwrap();
+        let mut app = create_app("kubectl get pods -o wide");
+        app.spec = Some(Arc::new(spec.clone()));
+        app.list_state.select(Some(4));
+        assert!(app.open_picker());
+        let picker = app.picker.as_ref().unwrap();
+        assert_eq!(picker.candidates, ["json", "yaml"]);
+        let tag = Some("kubectl spec".to_string());
+        assert_eq!(picker.tags, [tag.clone(), tag]);
+        app.close_picker();
+
+        // History values come first, and then the spec stays out of it
+        let mut app = create_app_with_suggestions("kubectl get pods -o wide", "-o", &["name"]);
+        app.spec = Some(Arc::new(spec));
+        app.list_state.select(Some(4));
+        assert!(app.open_picker());
+        assert_eq!(app.picker.as_ref().unwrap().candidates, ["name"]);
+        assert_eq!(app.picker.as_ref().unwrap().tags, [None]);
+    }
+
+    #[test]
+    fn test_completion_words() {
+        let app = create_app("sudo kubectl get pods -n prod --output=json -A | head");
+        let words = |position: usize| {
+            app.completion_words(position)
+                .map(|(words, prefix)| (words.join(" "), prefix))
+        };
+        // A flag's value, and a flag waiting for one, complete after the flag
+        let after_n = Some(("kubectl get pods -n ".to_string(), String::new()));
+        assert_eq!(words(5), after_n);
+        assert_eq!(words(4), after_n);
+        // A --flag=value component completes the part after the `=`
+        assert_eq!(
+            words(6),
+            Some((
+                "kubectl get pods -n prod --output=".to_string(),
+                "--output=".to_string()
+            ))
+        );
+        assert_eq!(words(2), Some(("kubectl ".to_string(), String::new())));
+        // Not the program or wrappers, flags without values, or past a pipe
+        assert_eq!(words(0), None);
+        assert_eq!(words(1), None);
+        let mut app = app;
+        let counts = toml::from_str("kubectl = { \"-A\" = 0 }").unwrap();
+        app.flag_arities = FlagArities::for_program("kubectl", &counts);
+        assert_eq!(app.completion_words(7), None);
+        assert_eq!(app.completion_words(9), None);
+    }
+
+    #[test]
+    fn test_provided_values_for_positional() {
+        let mut app = create_app("sudo ssh -p 22 host1 uptime");
+        app.list_state.select(Some(4));
+        assert_eq!(
+            app.value_key_at(4),
+            Some(("positional0".to_string(), "host1"))
+        );
+        assert_eq!(
+            app.value_key_at(5),
+            Some(("positional1".to_string(), "uptime"))
+        );
+        assert_eq!(app.value_key_at(1), None);
+        load_provided_values(&mut app, "[ssh.positional0]\ncmd = \"echo host2\"\n");
+
+        app.next_option();
+        assert_eq!(app.cmd.to_shell_string(), "sudo ssh -p 22 host2 uptime");
+
+        // A provider that fails leaves nothing to offer
+        let mut app = create_app("ssh host1");
+        app.list_state.select(Some(1));
+        load_provided_values(&mut app, "[ssh.positional0]\ncmd = \"exit 1\"\n");
+        assert!(!app.open_picker());
+    }
+
+    #[test]
+    fn test_cancel_peek_keeps_value() {
+        let mut app = create_app_with_suggestions(
+            "kubectl get pods --output=json",
+            "--output",
+            &["yaml", "wide"],
+        );
+        app.list_state.select(Some(3));
+
+        app.peek_next_option();
+        assert_eq!(app.component_spans(80)[3].lines, vec!["--output=yaml"]);
+        app.cancel_peek();
+        assert_eq!(app.peek, None);
+        assert_eq!(app.cmd.component_at(3), "--output=json");
+        assert_eq!(app.status_line(), None);
+
+        // Committing without a peek changes nothing
+        app.commit_peek();
+        assert_eq!(app.cmd.component_at(3), "--output=json");
+        assert!(app.undo.pop().is_none());
+    }
+
+    #[test]
+    fn test_next_option_attached_value() {
+        let mut app = create_app_with_suggestions(
+            "kubectl get pods --output=json",
+            "--output",
+            &["yaml", "wide"],
+        );
+        app.list_state.select(Some(3));
+
+        // The current value isn't a candidate, so cycling starts at the top
+        app.next_option();
+        assert_eq!(app.cmd.component_at(3), "--output=yaml");
+    }
+
+    #[test]
+    fn test_next_option_after_manual_edit() {
+        let mut app = create_app_with_suggestions(
+            "kubectl get pods -n prod",
+            "-n",
+            &["prod", "staging", "dev", "qa"],
+        );
+        app.list_state.select(Some(4));
+        app.next_option();
+        assert_eq!(app.cmd.component_at(4), "staging");
+
+        // Cycling continues from a typed value that is a candidate
+        app.change_selected_component();
+        app.current_input = "dev".to_string();
+        app.confirm_input();
+        app.next_option();
+        assert_eq!(app.cmd.component_at(4), "qa");
+    }
+
+    #[test]
+    fn test_toggle_sudo() {
+        let mut app =
+            create_app_with_suggestions("kubectl get pods -n prod", "-n", &["prod", "staging"]);
+        app.list_state.select(Some(4));
+
+        app.toggle_sudo();
+        assert_eq!(app.cmd.to_shell_string(), "sudo kubectl get pods -n prod");
+        assert_eq!(app.list_state.selected(), Some(5));
+        // Values are still looked up by their flag
+        app.next_option();
+        assert_eq!(app.cmd.component_at(5), "staging");
+
+        app.toggle_sudo();
+        assert_eq!(app.cmd.to_shell_string(), "kubectl get pods -n staging");
+        assert_eq!(app.list_state.selected(), Some(4));
+
+        // Options of sudo go with it, and undo brings them back
+        let mut app = create_app("sudo -u root systemctl restart nginx");
+        app.toggle_sudo();
+        assert_eq!(app.cmd.to_shell_string(), "systemctl restart nginx");
+        app.undo();
+        assert_eq!(
+            app.cmd.to_shell_string(),
+            "sudo -u root systemctl restart nginx"
+        );
+        assert!(!app.undo.can_undo());
+    }
+
+    #[test]
+    fn test_toggle_sudo_undo_redo() {
+        let mut app = create_app("apt update");
+        app.toggle_sudo();
+        app.undo();
+        assert_eq!(app.cmd.to_shell_string(), "apt update");
+        app.redo();
+        assert_eq!(app.cmd.to_shell_string(), "sudo apt update");
+    }
+
+    #[test]
+    fn test_next_option_without_candidates() {
+        let mut app = create_app_with_suggestions("kubectl get pods -n prod", "-o", &["json"]);
+
+        // Flag without candidates
+        app.list_state.select(Some(4));
+        app.next_option();
+        assert_eq!(app.cmd.component_at(4), "prod");
+
+        // Not a value at all
+        app.list_state.select(Some(1));
+        app.next_option();
+        assert_eq!(app.cmd.component_at(1), "get");
+    }
+
+    #[test]
+    fn test_picker_replaces_value() {
+        let mut app =
+            create_app_with_suggestions("kubectl get pods -n prod", "-n", &["prod", "staging"]);
+        app.list_state.select(Some(4));
+
+        assert!(app.open_picker());
+        // The current value is highlighted
+        assert_eq!(app.picker.as_ref().unwrap().selected, 0);
+        app.picker_next();
+        app.accept_picker();
+
+        assert_eq!(app.picker, None);
+        assert_eq!(app.cmd.component_at(4), "staging");
+        app.undo();
+        assert_eq!(app.cmd.component_at(4), "prod");
+    }
+
+    #[test]
+    fn test_picker_appends_value_after_flag() {
+        let mut app = create_app_with_suggestions("kubectl get pods -n", "-n", &["prod"]);
+        app.list_state.select(Some(3));
+
+        assert!(app.open_picker());
+        app.accept_picker();
+
+        assert_eq!(app.cmd.to_shell_string(), "kubectl get pods -n prod");
+    }
+
+    #[test]
+    fn test_open_completions_targets_trailing_value() {
+        let mut app = create_app_with_suggestions(
+            "kubectl get pods -n prod --output=json",
+            "--output",
+            &["yaml", "json"],
+        );
+
+        assert!(app.open_completions());
+        let picker = app.picker.as_ref().unwrap();
+        assert_eq!(picker.position, 5);
+        assert_eq!(picker.flag, "--output");
+        assert_eq!(picker.selected, 1);
+
+        app.picker_next();
+        app.accept_picker();
+        assert_eq!(app.cmd.component_at(5), "--output=yaml");
+
+        // Nothing to complete for a trailing positional argument
+        let mut app = create_app_with_suggestions("kubectl get pods", "-n", &["prod"]);
+        assert!(!app.open_completions());
+        assert_eq!(app.picker, None);
+    }
+
+    #[test]
+    fn test_insert_pipe_and_type_next_stage() {
+        let mut app = create_app("kubectl get pods");
+        app.select_last_component();
+
+        app.insert_operator(crate::command::PIPE);
+        assert!(app.input_mode);
+        assert_eq!(app.list_state.selected(), Some(4));
+        app.current_input = "grep".to_string();
+        app.confirm_input();
+        app.append_new_component();
+        app.current_input = "web".to_string();
+        app.confirm_input();
+
+        assert_eq!(app.cmd.to_shell_string(), "kubectl get pods | grep web");
+    }
+
+    #[test]
+    fn test_undo_and_redo_operator() {
+        let mut app = App::new(
+            Command::from_argv(vec!["echo".to_string(), "|".to_string()]).unwrap(),
+            0,
+        );
+        app.select_last_component();
+        app.insert_operator(crate::command::REDIRECT);
+        app.current_input = "out file".to_string();
+        app.confirm_input();
+        assert_eq!(app.cmd.to_shell_string(), "echo '|' > 'out file'");
+
+        // Undo the file name, the empty component and the operator itself
+        app.undo();
+        app.undo();
+        app.undo();
+        assert_eq!(app.cmd.to_shell_string(), "echo '|'");
+
+        app.redo();
+        assert_eq!(app.cmd.to_shell_string(), "echo '|' >");
+
+        // A deleted operator comes back as an operator
+        app.delete_selected_component();
+        app.undo();
+        assert_eq!(app.cmd.to_shell_string(), "echo '|' >");
+    }
+
+    #[test]
+    fn test_status_line_counts_undo_steps() {
+        let mut app = create_app("kubectl get pods -n prod");
+        app.undo();
+        assert_eq!(app.status_line(), Some("nothing to undo".to_string()));
+        app.notice = None;
+        assert_eq!(app.status_line(), None);
+
+        app.list_state.select(Some(4));
+        app.delete_selected_component();
+        app.delete_selected_component();
+        app.undo();
+        assert_eq!(app.status_line(), Some("u:1 r:1".to_string()));
+
+        // A new action takes the place of what could be redone
+        app.delete_selected_component();
+        assert_eq!(app.status_line(), Some("u:2 r:0".to_string()));
+        app.redo();
+        assert_eq!(
+            app.status_line(),
+            Some("nothing to redo · u:2 r:0".to_string())
+        );
+        assert_eq!(app.cmd.component_count(), 3);
+    }
+
+    #[test]
+    fn test_restore_session() {
+        let mut edited = create_app("kubectl get pods -n prod");
+        edited.list_state.select(Some(4));
+        edited.delete_selected_component();
+        edited.delete_selected_component();
+        edited.undo();
+
+        let mut app = create_app("kubectl get pods -n prod");
+        app.list_state.select(Some(4));
+        app.restore_session(Session {
+            saved_at: 0,
+            components: edited.cmd.components().to_vec(),
+            redacted: Vec::new(),
+            undo: edited.undo.undo_steps().to_vec(),
+            redo: edited.undo.redo_steps().to_vec(),
+        });
+        assert_eq!(app.cmd.to_shell_string(), "kubectl get pods -n");
+        assert_eq!(app.list_state.selected(), Some(3));
+
+        // Undoing and redoing carry on where the session left off
+        app.redo();
+        assert_eq!(app.cmd.to_shell_string(), "kubectl get pods");
+        app.undo();
+        app.undo();
+        assert_eq!(app.cmd.to_shell_string(), "kubectl get pods -n prod");
+    }
+
+    #[test]
+    fn test_undo_restores_line_break_layout() {
+        // Line breaks aren't components: they are laid out again from the
+        // components on output, so undoing the edits brings them back as well
+        let fixture = "gcloud alpha pam grants create \\\n  --entitlement=secret-manager-admin \\\n  --requested-duration=28800s";
+        let mut app = create_app(fixture);
+        assert_eq!(app.cmd.to_multiline_shell_string(40), fixture);
+
+        app.list_state.select(Some(5));
+        app.delete_selected_component();
+        app.list_state.select(Some(3));
+        app.insert_operator(crate::command::PIPE);
+        app.current_input = "cat".to_string();
+        app.confirm_input();
+        assert_ne!(app.cmd.to_multiline_shell_string(40), fixture);
+
+        while app.undo.can_undo() {
+            app.undo();
+        }
+        assert_eq!(app.cmd.to_multiline_shell_string(40), fixture);
+
+        while app.undo.can_redo() {
+            app.redo();
+        }
+        app.undo();
+        app.undo();
+        app.undo();
+        app.undo();
+        assert_eq!(app.cmd.to_multiline_shell_string(40), fixture);
+    }
+
+    #[test]
+    fn test_undo_and_redo_restore_selection() {
+        let mut app = create_app("kubectl get pods -n prod -o wide");
+        app.list_state.select(Some(5));
+        app.delete_selected_component();
+        assert_eq!(app.list_state.selected(), Some(5));
+        app.list_state.select(Some(1));
+
+        app.undo();
+        assert_eq!(app.cmd.component_at(5), "-o");
+        assert_eq!(app.list_state.selected(), Some(5));
+
+        // Redo selects what was selected right after the delete
+        app.list_state.select(Some(0));
+        app.redo();
+        assert_eq!(app.list_state.selected(), Some(5));
+
+        // Clamped to the list when it has shrunk since
+        app.select_last_component();
+        app.delete_selected_component();
+        app.undo();
+        app.undo();
+        assert_eq!(app.cmd.component_count(), 7);
+        assert_eq!(app.list_state.selected(), Some(5));
+        app.list_state.select(Some(2));
+        app.redo();
+        app.redo();
+        assert_eq!(app.cmd.component_count(), 5);
+        assert_eq!(app.list_state.selected(), Some(4));
+    }
+
+    #[test]
+    fn test_typed_script_is_quoted_literally() {
+        let mut app = create_app("cat file.json | jq");
+        app.list_state.select(Some(3));
+        app.append_new_component();
+        app.start_input();
+        for c in ".[] | $v".chars() {
+            app.insert_char(c);
+        }
+        app.confirm_input();
+        assert_eq!(app.cmd.to_shell_string(), "cat file.json | jq '.[] | $v'");
+
+        app.undo();
+        app.redo();
+        assert_eq!(app.cmd.to_shell_string(), "cat file.json | jq '.[] | $v'");
+    }
+
+    #[test]
+    fn test_undo_delete_keeps_script() {
+        let mut app = create_app("jq '.[] | $v' file.json");
+        app.cmd
+            .mark_scripts(&default_wrappers(), &Default::default());
+        app.list_state.select(Some(1));
+
+        app.delete_selected_component();
+        app.undo();
+
+        // Still quoted as the jq program it is
+        assert_eq!(app.cmd.to_shell_string(), "jq '.[] | $v' file.json");
+    }
+
+    #[test]
+    fn test_undo_and_redo_steps_that_dont_fit() {
+        let mut app = create_app("ls -la");
+        let stray = UndoAction::Delete {
+            position: 5,
+            component: Component::new("x".to_string()),
+        };
+        app.undo.push(stray.clone(), true);
+        app.undo.push_redo(stray.clone());
+
+        // The command stays, and so does what can be done the other way
+        app.undo();
+        assert_eq!(app.cmd.to_shell_string(), "ls -la");
+        assert!(app.notice.as_deref().unwrap().starts_with("can't undo"));
+        assert_eq!((app.undo.undo_len(), app.undo.redo_len()), (0, 1));
+
+        app.undo.push(stray, false);
+        app.redo();
+        assert_eq!(app.cmd.to_shell_string(), "ls -la");
+        assert!(app.notice.as_deref().unwrap().starts_with("can't redo"));
+        assert_eq!((app.undo.undo_len(), app.undo.redo_len()), (1, 0));
+    }
+
+    #[test]
+    fn test_undo_and_redo_move_and_replace() {
+        let mut app = create_app("kubectl get pods -n prod");
+        let action = UndoAction::Move { from: 2, to: 4 };
+        app.replay(&action).unwrap();
+        app.undo.push(action, true);
+        assert_eq!(app.cmd.to_shell_string(), "kubectl get -n prod pods");
+
+        app.undo();
+        assert_eq!(app.cmd.to_shell_string(), "kubectl get pods -n prod");
+        assert_eq!(app.list_state.selected(), Some(2));
+        app.redo();
+        assert_eq!(app.cmd.to_shell_string(), "kubectl get -n prod pods");
+        assert_eq!(app.list_state.selected(), Some(4));
+
+        let action = UndoAction::Replace {
+            position: 2,
+            original: vec![
+                Component::new("-n".to_string()),
+                Component::new("prod".to_string()),
+            ],
+            updated: vec![Component::new("--namespace=prod".to_string())],
+        };
+        app.replay(&action).unwrap();
+        app.undo.push(action, true);
+        assert_eq!(
+            app.cmd.to_shell_string(),
+            "kubectl get --namespace=prod pods"
+        );
+
+        app.undo();
+        assert_eq!(app.cmd.to_shell_string(), "kubectl get -n prod pods");
+        app.redo();
+        assert_eq!(
+            app.cmd.to_shell_string(),
+            "kubectl get --namespace=prod pods"
+        );
+    }
+
+    #[test]
+    fn test_next_option_skips_positional_after_valueless_flag() {
+        let mut app = create_app_with_suggestions("cp -r src dst", "-r", &["other"]);
+        app.flag_arities = FlagArities::for_program("cp", &Default::default());
+        app.list_state.select(Some(2));
+
+        app.next_option();
+        assert_eq!(app.cmd.component_at(2), "src");
+        assert!(!app.open_picker());
+    }
+
+    #[test]
+    fn test_status_line_reports_duplicate_flags() {
+        let mut app = create_app("kubectl get pods -n prod");
+        assert_eq!(app.status_line(), None);
+
+        app.list_state.select(Some(4));
+        app.append_new_component();
+        app.current_input = "-n".to_string();
+        app.confirm_input();
+        assert_eq!(
+            app.status_line(),
+            Some("duplicate flags: -n · u:2 r:0".to_string())
+        );
+
+        app.repeatable_flags.push("-n".to_string());
+        assert_eq!(app.status_line(), Some("u:2 r:0".to_string()));
+    }
+
+    #[test]
+    fn test_status_line_previews_brace_expansion() {
+        let mut app = create_app("cp file.{txt,md} dir");
+        assert_eq!(app.status_line(), None);
+
+        app.brace_preview = true;
+        assert_eq!(
+            app.status_line(),
+            Some("expands to: cp file.txt file.md dir".to_string())
+        );
+        assert_eq!(app.cmd.to_shell_string(), "cp file.{txt,md} dir");
+    }
+
+    #[test]
+    fn test_status_line_shows_input_mode_hint() {
+        let mut app = create_app("kubectl get pods -n prod");
+        app.list_state.select(Some(4));
+        app.change_selected_component();
+        assert_eq!(app.status_line(), Some(INPUT_MODE_HINT.to_string()));
+
+        app.cancel_input();
+        assert_eq!(app.status_line(), None);
+    }
+
+    #[test]
+    fn test_delete_backward() {
+        let mut app = create_app("kubectl get pods -n prod");
+        app.list_state.select(Some(4));
+
+        // Empty input: nothing happens, not even to the component
+        app.change_selected_component();
+        app.delete_backward();
+        app.delete_backward();
+        assert!(app.input_mode);
+        assert_eq!(app.current_input, "");
+        assert_eq!(app.cmd.component_count(), 5);
+
+        // Non-empty input loses its last character, down to empty
+        app.cancel_input();
+        app.start_input();
+        app.delete_backward();
+        assert_eq!(app.current_input, "pro");
+        for _ in 0..5 {
+            app.delete_backward();
+        }
+        assert_eq!(app.current_input, "");
+        app.confirm_input();
+        assert_eq!(app.cmd.component_at(4), "");
+        assert_eq!(app.cmd.component_count(), 5);
+    }
+
+    #[test]
+    fn test_status_line_reports_batch_progress() {
+        let mut app = create_app("kubectl get pods -n prod -n dev");
+        app.batch = Some(BatchProgress {
+            position: 2,
+            total: 5,
+            esc_cancels_all: false,
+        });
+        assert_eq!(
+            app.status_line(),
+            Some("2/5 · duplicate flags: -n".to_string())
+        );
+
+        app.repeatable_flags.push("-n".to_string());
+        app.batch.as_mut().unwrap().esc_cancels_all = true;
+        assert_eq!(
+            app.status_line(),
+            Some("2/5 · esc again to cancel all".to_string())
+        );
+    }
+
+    #[test]
+    fn test_change_selected_component() {
+        let mut app = create_app("kubectl get pods -n prod");
+        app.list_state.select(Some(4));
+
+        app.change_selected_component();
+        assert!(app.input_mode);
+        assert_eq!(app.current_input, "");
+
+        app.current_input = "staging".to_string();
+        app.confirm_input();
+        assert_eq!(app.cmd.component_at(4), "staging");
+
+        app.undo();
+        assert_eq!(app.cmd.component_at(4), "prod");
+
+        // Leaving input mode without confirming changes nothing
+        app.change_selected_component();
+        app.cancel_input();
+        assert_eq!(app.cmd.component_at(4), "prod");
+    }
+
+    #[test]
+    fn test_clear_selected_component() {
+        let mut app = create_app("kubectl get pods -n prod");
+        app.list_state.select(Some(4));
+
+        app.clear_selected_component();
+        assert!(!app.input_mode);
+        assert_eq!(app.cmd.component_at(4), "");
+        assert_eq!(app.cmd.to_shell_string(), "kubectl get pods -n \"\"");
+
+        app.undo();
+        assert_eq!(app.cmd.component_at(4), "prod");
+        app.redo();
+        assert_eq!(app.cmd.component_at(4), "");
+    }
+
+    #[test]
+    fn test_reverse_navigation() {
+        let mut app = create_app("kubectl get pods");
+        app.reverse = true;
+        app.select_last_component();
+
+        app.select_next_component();
+        assert_eq!(app.list_state.selected(), Some(1));
+        app.select_next_component();
+        assert_eq!(app.list_state.selected(), Some(0));
+        // Wraps around to the last component
+        app.select_next_component();
+        assert_eq!(app.list_state.selected(), Some(2));
+
+        app.select_previous_component();
+        assert_eq!(app.list_state.selected(), Some(0));
+    }
+
     #[test]
     fn test_append_with_no_selection() {
         let mut app = create_app("kubectl get pods");
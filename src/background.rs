@@ -0,0 +1,94 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::{Arc, Mutex},
+};
+
+/// Results of work done on other threads, each key's at most once.
+///
+/// Clones share the results.
+#[derive(Debug)]
+pub struct BackgroundRuns<K, V> {
+    /// `None` while the work for a key is still running
+    results: Arc<Mutex<HashMap<K, Option<V>>>>,
+}
+
+impl<K, V> Clone for BackgroundRuns<K, V> {
+    fn clone(&self) -> Self {
+        Self {
+            results: Arc::clone(&self.results),
+        }
+    }
+}
+
+impl<K, V> Default for BackgroundRuns<K, V> {
+    fn default() -> Self {
+        Self {
+            results: Arc::default(),
+        }
+    }
+}
+
+impl<K, V> BackgroundRuns<K, V>
+where
+    K: Eq + Hash + Clone + Send + 'static,
+    V: Clone + Send + 'static,
+{
+    /// Run `work` on another thread for `key`, unless it has run before
+    pub fn start(&self, key: K, work: impl FnOnce() -> V + Send + 'static) {
+        let Ok(mut results) = self.results.lock() else {
+            return;
+        };
+        if results.contains_key(&key) {
+            return;
+        }
+        results.insert(key.clone(), None);
+
+        let results = Arc::clone(&self.results);
+        std::thread::spawn(move || {
+            let value = work();
+            if let Ok(mut results) = results.lock() {
+                results.insert(key, Some(value));
+            }
+        });
+    }
+
+    /// What the work for `key` returned, once it's done
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.results.lock().ok()?.get(key).cloned().flatten()
+    }
+
+    /// Whether any work is still running
+    pub fn running(&self) -> bool {
+        self.results
+            .lock()
+            .is_ok_and(|results| results.values().any(Option::is_none))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_runs_once_per_key() {
+        let runs: BackgroundRuns<&str, usize> = BackgroundRuns::default();
+        let (sender, receiver) = std::sync::mpsc::channel::<()>();
+        runs.start("a", move || {
+            receiver.recv().ok();
+            1
+        });
+        // Work for a key that is already running isn't started again
+        runs.start("a", || 2);
+        assert!(runs.running());
+        assert_eq!(runs.get(&"a"), None);
+
+        sender.send(()).unwrap();
+        while runs.running() {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        // Clones share the results
+        assert_eq!(runs.clone().get(&"a"), Some(1));
+        assert_eq!(runs.get(&"b"), None);
+    }
+}
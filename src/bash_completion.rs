@@ -0,0 +1,238 @@
+use std::{process::Command, sync::Arc, time::Duration};
+
+use anyhow::Result;
+use serde::Deserialize;
+
+use crate::{
+    background::BackgroundRuns,
+    debug,
+    providers::{MAX_PROVIDER_OUTPUT, run_capped},
+};
+
+/// How long a completion function may take
+pub const DEFAULT_BASH_COMPLETION_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Loads the completions most systems install for bash
+pub const DEFAULT_BASH_COMPLETION_SETUP: &str = "for f in /usr/share/bash-completion/bash_completion /usr/local/share/bash-completion/bash_completion /opt/homebrew/etc/profile.d/bash_completion.sh; do [ -r \"$f\" ] && . \"$f\" && break; done";
+
+/// Finds the completion bash has for `$1`, loading it on demand like
+/// bash-completion does, and prints what it offers for the last word
+const COMPLETE_SCRIPT: &str = r#"
+COMP_WORDS=("$@")
+COMP_CWORD=$(($# - 1))
+COMP_LINE="$*"
+COMP_POINT=${#COMP_LINE}
+COMP_TYPE=9
+COMP_KEY=9
+cur=${COMP_WORDS[COMP_CWORD]}
+prev=${COMP_WORDS[COMP_CWORD - 1]}
+spec=$(complete -p -- "$1" 2>/dev/null)
+for loader in _comp_complete_load __load_completion; do
+    if [ -z "$spec" ] && declare -F "$loader" >/dev/null; then
+        "$loader" "$1" >/dev/null 2>&1
+        spec=$(complete -p -- "$1" 2>/dev/null)
+    fi
+done
+[ -n "$spec" ] || exit 1
+opts=${spec#complete }
+opts=${opts% *}
+if [[ $opts =~ -F\ ([^ ]+) ]]; then
+    COMPREPLY=()
+    "${BASH_REMATCH[1]}" "$1" "$cur" "$prev" >/dev/null 2>&1
+    printf '%s\n' "${COMPREPLY[@]}"
+else
+    eval "compgen $opts -- \"\$cur\""
+fi
+"#;
+
+/// Settings of the `[bash_completion]` table
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct BashCompletionConfig {
+    /// Ask bash's programmable completion for values
+    pub enabled: Option<bool>,
+    /// Shell code that defines the completions, instead of
+    /// [`DEFAULT_BASH_COMPLETION_SETUP`]
+    pub setup: Option<String>,
+    /// Milliseconds to wait, instead of [`DEFAULT_BASH_COMPLETION_TIMEOUT`]
+    pub timeout_ms: Option<u64>,
+    /// Programs not to complete, like ones with slow completion functions
+    pub skip: Vec<String>,
+}
+
+/// Asks bash's programmable completion in the background for the values of
+/// command lines, each at most once per run of te.
+///
+/// Each request runs a fresh `bash --norc --noprofile`, so nothing the
+/// completion functions do reaches te or the user's shell.
+#[derive(Debug, Clone, Default)]
+pub struct BashCompletion {
+    /// The setup code, unless completion is off
+    setup: Option<Arc<String>>,
+    timeout: Duration,
+    skip: Arc<Vec<String>>,
+    results: BackgroundRuns<Vec<String>, Vec<String>>,
+}
+
+impl BashCompletion {
+    pub fn new(config: BashCompletionConfig) -> Self {
+        let setup = config
+            .setup
+            .unwrap_or_else(|| DEFAULT_BASH_COMPLETION_SETUP.to_string());
+        Self {
+            setup: (config.enabled == Some(true)).then(|| Arc::new(setup)),
+            timeout: config
+                .timeout_ms
+                .map_or(DEFAULT_BASH_COMPLETION_TIMEOUT, Duration::from_millis),
+            skip: Arc::new(config.skip),
+            results: BackgroundRuns::default(),
+        }
+    }
+
+    /// Start completing `words`, the program and its arguments ending with
+    /// the word being completed, unless completion is off for the program
+    /// or has already run for them
+    pub fn request(&self, words: &[String]) {
+        let Some(setup) = &self.setup else {
+            return;
+        };
+        if words.len() < 2 || self.skip.contains(&words[0]) {
+            return;
+        }
+        let setup = Arc::clone(setup);
+        let timeout = self.timeout;
+        let owned = words.to_vec();
+        self.results.start(words.to_vec(), move || {
+            run_completion(&setup, &owned, timeout).unwrap_or_else(|err| {
+                debug::log(&format!(
+                    "bash completion for {} failed: {:#}",
+                    owned.join(" "),
+                    err
+                ));
+                Vec::new()
+            })
+        });
+    }
+
+    /// The values bash offered for `words`, once it's done
+    pub fn values(&self, words: &[String]) -> Vec<String> {
+        self.results.get(&words.to_vec()).unwrap_or_default()
+    }
+
+    /// Whether a completion is still running
+    pub fn running(&self) -> bool {
+        self.results.running()
+    }
+}
+
+/// `words` split the way bash splits a command line for completion, where
+/// `--flag=` is the three words `--flag`, `=` and an empty one
+pub fn comp_words(words: &[String]) -> Vec<String> {
+    let mut comp_words = words.to_vec();
+    if let Some(last) = comp_words.pop() {
+        match last.strip_suffix('=') {
+            Some(flag) if !last.is_empty() => {
+                comp_words.extend([flag.to_string(), "=".to_string(), String::new()])
+            }
+            _ => comp_words.push(last),
+        }
+    }
+    comp_words
+}
+
+/// Run the completion for `words` in a fresh bash and return the distinct
+/// values it offered
+fn run_completion(setup: &str, words: &[String], timeout: Duration) -> Result<Vec<String>> {
+    let mut command = Command::new("bash");
+    command
+        .args(["--norc", "--noprofile", "-c"])
+        .arg(format!("{}\n{}", setup, COMPLETE_SCRIPT))
+        .arg("te")
+        .args(comp_words(words))
+        .env_remove("BASH_ENV");
+    let cmd = format!("bash completion for {}", words[0]);
+    let output = run_capped(command, &cmd, timeout, MAX_PROVIDER_OUTPUT)?;
+
+    let mut values: Vec<String> = Vec::new();
+    for line in String::from_utf8_lossy(&output).lines() {
+        let line = line.trim_end();
+        if !line.is_empty() && !values.iter().any(|value| value == line) {
+            values.push(line.to_string());
+        }
+    }
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(words: &[&str]) -> Vec<String> {
+        words.iter().map(|w| w.to_string()).collect()
+    }
+
+    fn complete(setup: &str, line: &[&str]) -> Vec<String> {
+        let completion = BashCompletion::new(BashCompletionConfig {
+            enabled: Some(true),
+            setup: Some(setup.to_string()),
+            timeout_ms: Some(5000),
+            skip: vec!["skipped".to_string()],
+        });
+        completion.request(&words(line));
+        while completion.running() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        completion.values(&words(line))
+    }
+
+    #[test]
+    fn test_comp_words() {
+        assert_eq!(
+            comp_words(&words(&["kubectl", "--output="])),
+            ["kubectl", "--output", "=", ""]
+        );
+        assert_eq!(comp_words(&words(&["kubectl", ""])), ["kubectl", ""]);
+        assert_eq!(comp_words(&words(&[])), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_complete_with_function() {
+        let setup = r#"_mytool() { COMPREPLY=("cword=$COMP_CWORD" "prev=$3" "point=$COMP_POINT" "prev=$3"); }; complete -F _mytool mytool"#;
+        assert_eq!(
+            complete(setup, &["mytool", "-n", ""]),
+            ["cword=2", "prev=-n", "point=10"]
+        );
+        assert_eq!(
+            complete(setup, &["mytool", "--output="]),
+            ["cword=3", "prev==", "point=18"]
+        );
+    }
+
+    #[test]
+    fn test_complete_with_wordlist() {
+        let setup = r#"complete -W "alpha beta" mytool"#;
+        assert_eq!(complete(setup, &["mytool", ""]), ["alpha", "beta"]);
+        // Programs without a completion, or skipped ones, offer nothing
+        assert!(complete(setup, &["other", ""]).is_empty());
+        assert!(complete("complete -W x skipped", &["skipped", ""]).is_empty());
+    }
+
+    #[test]
+    fn test_completion_off_or_slow() {
+        let completion = BashCompletion::new(BashCompletionConfig::default());
+        completion.request(&words(&["mytool", ""]));
+        assert!(!completion.running());
+
+        let completion = BashCompletion::new(BashCompletionConfig {
+            enabled: Some(true),
+            setup: Some("sleep 5; complete -W x mytool".to_string()),
+            timeout_ms: Some(50),
+            skip: Vec::new(),
+        });
+        completion.request(&words(&["mytool", ""]));
+        while completion.running() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(completion.values(&words(&["mytool", ""])).is_empty());
+    }
+}
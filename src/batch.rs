@@ -0,0 +1,44 @@
+use anyhow::Result;
+
+/// Split a `flag=value` assignment given to `te set -f` into its parts.
+///
+/// Only the first `=` separates the flag from the value, so
+/// `--env=VAR=value` sets `--env` to `VAR=value`.
+pub fn parse_flag_assignment(spec: &str) -> Result<(&str, &str)> {
+    match spec.split_once('=') {
+        Some((flag, value)) if flag.starts_with('-') && flag.len() > 1 => Ok((flag, value)),
+        _ => anyhow::bail!(
+            "Invalid flag assignment: {} (expected FLAG=VALUE, e.g. -n=staging)",
+            spec
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_flag_assignment() {
+        assert_eq!(
+            parse_flag_assignment("-n=staging").unwrap(),
+            ("-n", "staging")
+        );
+        assert_eq!(
+            parse_flag_assignment("--output=yaml").unwrap(),
+            ("--output", "yaml")
+        );
+        assert_eq!(
+            parse_flag_assignment("--env=VAR=value").unwrap(),
+            ("--env", "VAR=value")
+        );
+        assert_eq!(parse_flag_assignment("--label=").unwrap(), ("--label", ""));
+    }
+
+    #[test]
+    fn test_parse_flag_assignment_invalid() {
+        assert!(parse_flag_assignment("-n").is_err());
+        assert!(parse_flag_assignment("n=staging").is_err());
+        assert!(parse_flag_assignment("-=x").is_err());
+    }
+}
@@ -0,0 +1,85 @@
+/// The words `word` turns into under shell brace expansion, in order:
+/// `file.{txt,md}` gives `file.txt` and `file.md`.
+///
+/// Only comma lists are expanded, not sequences like `{1..3}`. A word
+/// without a list, including one with braces like `{}` or `{a}` that the
+/// shell leaves alone, comes back unchanged. Only used to preview a command,
+/// never to change what is written out.
+pub fn expand(word: &str) -> Vec<String> {
+    let Some((open, close, commas)) = find_list(word) else {
+        return vec![word.to_string()];
+    };
+    let prefix = &word[..open];
+    let suffix = &word[close + 1..];
+
+    let mut bounds = vec![open];
+    bounds.extend(commas);
+    bounds.push(close);
+    bounds
+        .windows(2)
+        .flat_map(|pair| {
+            expand(&format!(
+                "{}{}{}",
+                prefix,
+                &word[pair[0] + 1..pair[1]],
+                suffix
+            ))
+        })
+        .collect()
+}
+
+/// The first `{`…`}` in `word` with a comma at its own nesting level: the
+/// byte offsets of its braces and of those commas
+fn find_list(word: &str) -> Option<(usize, usize, Vec<usize>)> {
+    let bytes = word.as_bytes();
+    for (open, _) in word.match_indices('{') {
+        let mut depth = 0;
+        let mut commas = Vec::new();
+        for (i, &byte) in bytes.iter().enumerate().skip(open + 1) {
+            match byte {
+                b'{' => depth += 1,
+                b'}' if depth == 0 => {
+                    if !commas.is_empty() {
+                        return Some((open, i, commas));
+                    }
+                    break;
+                }
+                b'}' => depth -= 1,
+                b',' if depth == 0 => commas.push(i),
+                _ => {}
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand() {
+        assert_eq!(expand("file.{txt,md}"), ["file.txt", "file.md"]);
+        assert_eq!(expand("{a,b}{1,2}"), ["a1", "a2", "b1", "b2"]);
+        assert_eq!(expand("x{,.bak}"), ["x", "x.bak"]);
+        assert_eq!(expand("{a,{b,c}d}"), ["a", "bd", "cd"]);
+        // The first list inside a brace pair without one still expands
+        assert_eq!(expand("{x{1,2}}"), ["{x1}", "{x2}"]);
+    }
+
+    #[test]
+    fn test_expand_passes_through() {
+        for word in [
+            "file.txt",
+            "",
+            "{}",
+            "{a}",
+            "{a,b",
+            "a,b}",
+            "{1..3}",
+            "find {} ;",
+        ] {
+            assert_eq!(expand(word), [word], "{}", word);
+        }
+    }
+}
@@ -0,0 +1,257 @@
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::Result;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{background::BackgroundRuns, debug, providers::run_capped};
+
+/// How long carapace may take to complete a command line
+pub const DEFAULT_CARAPACE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How much of carapace's output is read; a larger export is dropped
+pub const MAX_CARAPACE_OUTPUT: usize = 1024 * 1024;
+
+/// Settings of the `[carapace]` table
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct CarapaceConfig {
+    /// Ask carapace for values, if it is on `$PATH`
+    pub enabled: Option<bool>,
+    /// Milliseconds to wait for it, instead of [`DEFAULT_CARAPACE_TIMEOUT`]
+    pub timeout_ms: Option<u64>,
+    /// Programs not to ask carapace about, like ones with slow completers
+    pub skip: Vec<String>,
+}
+
+/// A value carapace offers, with what it says about it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CarapaceValue {
+    pub value: String,
+    pub description: Option<String>,
+}
+
+/// Asks carapace in the background for the values of command lines, each
+/// at most once per run of te
+#[derive(Debug, Clone, Default)]
+pub struct Carapace {
+    /// The carapace binary, unless it is off or not installed
+    binary: Option<Arc<PathBuf>>,
+    timeout: Duration,
+    skip: Arc<Vec<String>>,
+    results: BackgroundRuns<Vec<String>, Vec<CarapaceValue>>,
+}
+
+impl Carapace {
+    /// Use the carapace on `$PATH`, if `config` enables it
+    pub fn new(config: CarapaceConfig) -> Self {
+        let binary = std::env::var_os("PATH").and_then(|path| {
+            std::env::split_paths(&path)
+                .map(|dir| Path::new(&dir).join("carapace"))
+                .find(|binary| binary.is_file())
+        });
+        Self::with_binary(binary, config)
+    }
+
+    fn with_binary(binary: Option<PathBuf>, config: CarapaceConfig) -> Self {
+        Self {
+            binary: binary
+                .filter(|_| config.enabled == Some(true))
+                .map(Arc::new),
+            timeout: config
+                .timeout_ms
+                .map_or(DEFAULT_CARAPACE_TIMEOUT, Duration::from_millis),
+            skip: Arc::new(config.skip),
+            results: BackgroundRuns::default(),
+        }
+    }
+
+    /// Start completing `words`, the program and its arguments ending with
+    /// the word being completed, unless carapace is off for the program or
+    /// has already been asked
+    pub fn request(&self, words: &[String]) {
+        let Some(binary) = &self.binary else {
+            return;
+        };
+        let Some(program) = words.first() else {
+            return;
+        };
+        if self.skip.contains(program) {
+            return;
+        }
+        let binary = Arc::clone(binary);
+        let timeout = self.timeout;
+        let owned = words.to_vec();
+        self.results.start(words.to_vec(), move || {
+            run_carapace(&binary, &owned, timeout).unwrap_or_else(|err| {
+                debug::log(&format!(
+                    "carapace for {} failed: {:#}",
+                    owned.join(" "),
+                    err
+                ));
+                Vec::new()
+            })
+        });
+    }
+
+    /// The values carapace offered for `words`, once it's done
+    pub fn values(&self, words: &[String]) -> Vec<CarapaceValue> {
+        self.results.get(&words.to_vec()).unwrap_or_default()
+    }
+
+    /// Whether carapace is still completing anything
+    pub fn running(&self) -> bool {
+        self.results.running()
+    }
+}
+
+/// Run `carapace <program> export <words…>` and read the values it offers
+fn run_carapace(binary: &Path, words: &[String], timeout: Duration) -> Result<Vec<CarapaceValue>> {
+    let mut command = Command::new(binary);
+    command.arg(&words[0]).arg("export").args(words);
+    let cmd = format!("carapace {} export {}", words[0], words.join(" "));
+    let output = run_capped(command, &cmd, timeout, MAX_CARAPACE_OUTPUT)?;
+    if output.len() == MAX_CARAPACE_OUTPUT {
+        anyhow::bail!("'{}' printed more than {} bytes", cmd, MAX_CARAPACE_OUTPUT);
+    }
+    Ok(parse_export(&serde_json::from_slice(&output)?))
+}
+
+/// The values of an export, as `{"values": [{"value": …, "description": …}]}`.
+///
+/// A bare list of values, or values given as plain strings, are accepted
+/// too, and entries of any other shape are skipped.
+fn parse_export(export: &Value) -> Vec<CarapaceValue> {
+    let entries = match export {
+        Value::Array(entries) => entries,
+        _ => match export.get("values") {
+            Some(Value::Array(entries)) => entries,
+            _ => return Vec::new(),
+        },
+    };
+    let mut values: Vec<CarapaceValue> = Vec::new();
+    for entry in entries {
+        let (value, description) = match entry {
+            Value::String(value) => (value.clone(), None),
+            _ => match entry.get("value").and_then(Value::as_str) {
+                Some(value) => (
+                    value.to_string(),
+                    entry
+                        .get("description")
+                        .and_then(Value::as_str)
+                        .filter(|d| !d.is_empty())
+                        .map(str::to_string),
+                ),
+                None => continue,
+            },
+        };
+        if !value.is_empty() && !values.iter().any(|v| v.value == value) {
+            values.push(CarapaceValue { value, description });
+        }
+    }
+    values
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_export() {
+        let export = serde_json::json!({
+            "version": "v1.0.0",
+            "messages": [],
+            "values": [
+                {"value": "default", "display": "default", "description": "Active"},
+                {"value": "kube-system", "description": ""},
+                "plain",
+                {"display": "no value"},
+                {"value": "default"},
+                {"value": ""},
+                42
+            ]
+        });
+        assert_eq!(
+            parse_export(&export),
+            [
+                CarapaceValue {
+                    value: "default".to_string(),
+                    description: Some("Active".to_string()),
+                },
+                CarapaceValue {
+                    value: "kube-system".to_string(),
+                    description: None,
+                },
+                CarapaceValue {
+                    value: "plain".to_string(),
+                    description: None,
+                },
+            ]
+        );
+
+        let bare = serde_json::json!(["a", {"value": "b"}]);
+        assert_eq!(parse_export(&bare).len(), 2);
+        assert!(parse_export(&serde_json::json!({"values": "x"})).is_empty());
+        assert!(parse_export(&serde_json::json!("x")).is_empty());
+    }
+
+    #[test]
+    fn test_carapace_off() {
+        let words = vec!["kubectl".to_string(), "-n".to_string(), String::new()];
+        // Off unless enabled, whether or not it's installed
+        let carapace = Carapace::new(CarapaceConfig::default());
+        carapace.request(&words);
+        assert!(!carapace.running());
+        assert!(carapace.values(&words).is_empty());
+    }
+
+    #[test]
+    fn test_carapace_runs_once() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("te-carapace-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let binary = dir.join("carapace");
+        // Offers its arguments back, so the test can see how it was called
+        std::fs::write(
+            &binary,
+            "#!/bin/sh\ncount=$(cat \"$0.count\" 2>/dev/null || echo 0)\necho $((count + 1)) > \"$0.count\"\nprintf '{\"values\": [{\"value\": \"%s\", \"description\": \"args\"}]}' \"$*\"\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&binary, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let config = CarapaceConfig {
+            enabled: Some(true),
+            timeout_ms: Some(5000),
+            skip: vec!["gh".to_string()],
+        };
+        let carapace = Carapace::with_binary(Some(binary.clone()), config);
+        let words: Vec<String> = ["kubectl", "get", "-n", ""].map(String::from).to_vec();
+        carapace.request(&words);
+        carapace.request(&["gh".to_string(), String::new()]);
+        while carapace.running() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(
+            carapace.values(&words),
+            [CarapaceValue {
+                value: "kubectl export kubectl get -n ".to_string(),
+                description: Some("args".to_string()),
+            }]
+        );
+
+        // Clones share the results, nothing runs again, and skipped
+        // programs never ran
+        carapace.clone().request(&words);
+        assert!(!carapace.running());
+        let count = std::fs::read_to_string(dir.join("carapace.count")).unwrap();
+        assert_eq!(count.trim(), "1");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
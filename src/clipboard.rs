@@ -0,0 +1,199 @@
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+
+/// How text is put on the clipboard
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum ClipboardMethod {
+    /// OSC 52, falling back to the native clipboard without a terminal
+    #[default]
+    Auto,
+    /// Only the OSC 52 escape sequence, for SSH and restricted environments
+    Osc52,
+    /// Only the native clipboard
+    Native,
+}
+
+/// Put `text` on the system clipboard.
+///
+/// The OSC 52 escape sequence is written to the terminal first, which also
+/// works over SSH and inside tmux. If there is no terminal to write to, the
+/// native clipboard is used instead.
+pub fn copy(text: &str, method: ClipboardMethod) -> Result<()> {
+    match method {
+        ClipboardMethod::Osc52 => write_osc52(text),
+        ClipboardMethod::Native => copy_native(text),
+        ClipboardMethod::Auto => {
+            let osc52 = write_osc52(text);
+            if osc52.is_ok() {
+                return osc52;
+            }
+            copy_native(text)
+        }
+    }
+}
+
+/// Put `text` on the clipboard through a local clipboard tool (pbcopy,
+/// wl-copy or xclip), or through the window system if there is none
+fn copy_native(text: &str) -> Result<()> {
+    match clipboard_tool() {
+        Some((program, args)) => copy_with(program, args, text),
+        None => copy_with_window_system(text),
+    }
+}
+
+/// On macOS and Windows the clipboard keeps what it is given after te exits
+#[cfg(any(target_os = "macos", target_os = "windows"))]
+fn copy_with_window_system(text: &str) -> Result<()> {
+    arboard::Clipboard::new()
+        .and_then(|mut clipboard| clipboard.set_text(text))
+        .context("Failed to set the clipboard")
+}
+
+/// On X11 and Wayland the clipboard is served by the program that set it, so
+/// it would be empty again as soon as te exits; a tool that stays around to
+/// serve it is needed
+#[cfg(not(any(target_os = "macos", target_os = "windows")))]
+fn copy_with_window_system(_text: &str) -> Result<()> {
+    anyhow::bail!("No clipboard tool found: install wl-copy (Wayland) or xclip (X11)")
+}
+
+/// Text on the system clipboard, or `None` if it is empty or can't be read
+pub fn paste() -> Option<String> {
+    paste_with(|| Ok(arboard::Clipboard::new()?.get_text()?))
+}
+
+/// Clipboard text from `read` with surrounding whitespace trimmed, or `None`
+/// if there is none
+fn paste_with(read: impl FnOnce() -> Result<String>) -> Option<String> {
+    let text = read().ok()?;
+    let text = text.trim();
+    (!text.is_empty()).then(|| text.to_string())
+}
+
+fn write_osc52(text: &str) -> Result<()> {
+    let mut tty = OpenOptions::new()
+        .write(true)
+        .open("/dev/tty")
+        .context("Failed to open /dev/tty")?;
+    let inside_tmux = std::env::var_os("TMUX").is_some_and(|v| !v.is_empty());
+    tty.write_all(osc52_sequence(text, inside_tmux).as_bytes())?;
+    tty.flush()?;
+    Ok(())
+}
+
+/// The OSC 52 sequence that sets the clipboard to `text`.
+///
+/// Inside tmux the sequence is wrapped in a DCS passthrough so it reaches the
+/// outer terminal.
+fn osc52_sequence(text: &str, inside_tmux: bool) -> String {
+    let sequence = format!("\x1b]52;c;{}\x07", base64(text.as_bytes()));
+    if inside_tmux {
+        format!("\x1bPtmux;{}\x1b\\", sequence.replace('\x1b', "\x1b\x1b"))
+    } else {
+        sequence
+    }
+}
+
+/// A clipboard tool available on this system, with its arguments
+fn clipboard_tool() -> Option<(&'static str, &'static [&'static str])> {
+    let has_env = |name: &str| std::env::var_os(name).is_some_and(|v| !v.is_empty());
+    if in_path("pbcopy") {
+        Some(("pbcopy", &[]))
+    } else if has_env("WAYLAND_DISPLAY") && in_path("wl-copy") {
+        Some(("wl-copy", &[]))
+    } else if has_env("DISPLAY") && in_path("xclip") {
+        Some(("xclip", &["-selection", "clipboard"]))
+    } else {
+        None
+    }
+}
+
+/// Whether an executable named `program` is on `$PATH`
+fn in_path(program: &str) -> bool {
+    std::env::var_os("PATH").is_some_and(|path| {
+        std::env::split_paths(&path).any(|dir| Path::new(&dir).join(program).is_file())
+    })
+}
+
+fn copy_with(program: &str, args: &[&str], text: &str) -> Result<()> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .with_context(|| format!("Failed to run {}", program))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(text.as_bytes())?;
+    }
+    let status = child.wait()?;
+    if !status.success() {
+        anyhow::bail!("{} failed with {}", program, status);
+    }
+    Ok(())
+}
+
+/// Standard base64 with padding
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = (chunk[0] as u32) << 16
+            | (chunk.get(1).copied().unwrap_or(0) as u32) << 8
+            | chunk.get(2).copied().unwrap_or(0) as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_base64() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"ls -la"), "bHMgLWxh");
+    }
+
+    #[test]
+    fn test_paste_with() {
+        assert_eq!(
+            paste_with(|| Ok("  kubectl get pods\n".to_string())),
+            Some("kubectl get pods".to_string())
+        );
+        assert_eq!(paste_with(|| Ok(" \n".to_string())), None);
+        assert_eq!(paste_with(|| anyhow::bail!("no clipboard")), None);
+    }
+
+    #[test]
+    fn test_osc52_sequence() {
+        assert_eq!(osc52_sequence("foo", false), "\x1b]52;c;Zm9v\x07");
+        assert_eq!(
+            osc52_sequence("foo", true),
+            "\x1bPtmux;\x1b\x1b]52;c;Zm9v\x07\x1b\\"
+        );
+        // Newlines and UTF-8 are encoded, never sent raw
+        assert_eq!(
+            osc52_sequence("ls\ncd ~/données", false),
+            "\x1b]52;c;bHMKY2Qgfi9kb25uw6llcw==\x07"
+        );
+    }
+}
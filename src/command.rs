@@ -1,9 +1,251 @@
 use anyhow::Result;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    braces,
+    flags::{FlagArities, FlagValueCounts},
+    history::{Shell, Wrapper, command_word_positions, wrapper_len},
+};
 
 pub struct Command {
-    components: Vec<String>,
+    components: Vec<Component>,
+    quote_mode: QuoteMode,
+    quote_style: QuoteStyle,
+    target_shell: TargetShell,
+    /// A trailing `# comment`, written back verbatim after the components
+    comment: Option<String>,
+}
+
+/// A single word of a command
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Component {
+    value: String,
+    /// A shell operator like `|` or `>` inserted from the editor, which is
+    /// never quoted
+    operator: bool,
+    /// The script of a `jq` or `awk` command, which is always quoted
+    /// literally: its `$`, quotes and backslashes belong to the program
+    script: bool,
+    /// Whether the word was quoted or escaped where it was parsed, so that
+    /// something like `';'` in it is text rather than an operator
+    #[serde(default)]
+    quoted: bool,
+    /// How the word was written where it was parsed, so output can keep the
+    /// original layout
+    #[serde(skip)]
+    source: Option<Source>,
+}
+
+/// A parsed word as it was written
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Source {
+    /// What separated the word from the previous one: spaces, and maybe a
+    /// `\`-continued line break
+    leading: String,
+    /// The word with its quotes and escapes, until its value changes
+    text: Option<String>,
+}
+
+impl Component {
+    pub fn new(value: String) -> Self {
+        Self {
+            value,
+            operator: false,
+            script: false,
+            quoted: false,
+            source: None,
+        }
+    }
+
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    pub fn set_value(&mut self, value: &str) {
+        self.replace_value(value.to_string());
+    }
+
+    /// Whether a leading `~` in the value was written unquoted, so the shell
+    /// expands it: always for a word that wasn't quoted, and for one that
+    /// was, like `~/"my notes"`, only while it's written as it was parsed
+    fn tilde_expands(&self) -> bool {
+        !self.quoted
+            || self
+                .source
+                .as_ref()
+                .and_then(|source| source.text.as_deref())
+                .is_some_and(|text| text.starts_with('~'))
+    }
+
+    /// Sets the value, returning the old one. The word is no longer written
+    /// as it was parsed.
+    fn replace_value(&mut self, value: String) -> String {
+        if let Some(source) = &mut self.source {
+            source.text = None;
+        }
+        std::mem::replace(&mut self.value, value)
+    }
+
+    /// A shell operator such as [`PIPE`] or [`REDIRECT`]
+    pub fn operator(operator: &str) -> Self {
+        Self {
+            value: operator.to_string(),
+            operator: true,
+            script: false,
+            quoted: false,
+            source: None,
+        }
+    }
+}
+
+/// What a component is, as far as can be told from the command alone
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClassifiedComponent<'a> {
+    /// The program or one of its subcommands, like `kubectl` and `get`
+    Base(&'a str),
+    /// An option, like `-n` or `--output=json`
+    Flag(&'a str),
+    /// The value of a flag or a positional argument
+    Value(&'a str),
+    /// A shell operator between commands, like `|`
+    Operator(&'a str),
+}
+
+/// Programs whose first positional argument is a script in their own
+/// language, like `jq '.items[]'` or `awk '{print $1}'`
+const SCRIPT_PROGRAMS: &[&str] = &["jq", "awk", "gawk", "mawk", "nawk"];
+
+/// Shell operators that can be inserted from the editor
+pub const PIPE: &str = "|";
+pub const REDIRECT: &str = ">";
+
+/// Shell operators te has no notion of, which end up as words of the command
+const UNSUPPORTED_OPERATORS: &[&str] = &[
+    "&&", "||", ";", "&", "|&", ">>", "<", "<<", "<<<", "2>", "2>>", "&>", "2>&1",
+];
+
+/// Something in a command that te can only guess at, reported by
+/// `--strict-parse`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Ambiguity {
+    /// A word after a flag that isn't known to take a value or not
+    FlagOrArgument {
+        index: usize,
+        flag: String,
+        value: String,
+    },
+    /// A shell operator other than `|` and `>`
+    Operator { index: usize, operator: String },
+}
+
+impl Ambiguity {
+    /// Position of the ambiguous component
+    pub fn index(&self) -> usize {
+        match self {
+            Ambiguity::FlagOrArgument { index, .. } | Ambiguity::Operator { index, .. } => *index,
+        }
+    }
+}
+
+impl std::fmt::Display for Ambiguity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Ambiguity::FlagOrArgument { flag, value, .. } => write!(
+                f,
+                "'{}' could be the value of {} or an argument of its own",
+                value, flag
+            ),
+            Ambiguity::Operator { operator, .. } => {
+                write!(
+                    f,
+                    "'{}' is a shell operator te doesn't understand",
+                    operator
+                )
+            }
+        }
+    }
+}
+
+/// How components are quoted when the command is turned back into a string
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QuoteMode {
+    /// Leave `$`, backticks and globs unquoted so the shell still expands them.
+    /// Used for commands parsed from a string, where they were typed that way.
+    #[default]
+    Expand,
+    /// Quote anything the shell would interpret. Used for commands taken from
+    /// argv, whose arguments have already been through shell expansion.
+    Literal,
+}
+
+/// How values that need quoting are written out, for commands that use
+/// [`QuoteMode::Expand`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum QuoteStyle {
+    /// In whichever quotes need less escaping: `"my file"`
+    #[default]
+    #[value(alias = "quotes")]
+    #[serde(alias = "quotes")]
+    Minimal,
+    /// In single quotes, unless the value has a `$` or backtick the shell
+    /// should still expand
+    PreferSingle,
+    /// In double quotes
+    PreferDouble,
+    /// With their spaces escaped, `my\ file`, when nothing else in them
+    /// needs quoting
+    Backslash,
+}
+
+/// The shell whose syntax values are quoted for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TargetShell {
+    /// sh, bash, zsh, and any shell te doesn't quote for specially
+    #[default]
+    Posix,
+    Fish,
+    Pwsh,
+}
+
+impl From<Shell> for TargetShell {
+    fn from(shell: Shell) -> Self {
+        match shell {
+            Shell::Fish => TargetShell::Fish,
+            Shell::Pwsh => TargetShell::Pwsh,
+            Shell::Zsh | Shell::Bash | Shell::Nu | Shell::Elvish | Shell::Xonsh => {
+                TargetShell::Posix
+            }
+        }
+    }
 }
 
+/// Flags that are commonly given more than once on purpose
+pub const REPEATABLE_FLAGS: &[&str] = &[
+    "-e",
+    "--env",
+    "-v",
+    "--volume",
+    "-p",
+    "--publish",
+    "--label",
+    "-f",
+    "--filename",
+    "-H",
+    "--header",
+    "--set",
+    "-I",
+    "-L",
+    "-D",
+    "-x",
+    "--mount",
+    "--add-host",
+    "--build-arg",
+    "--exclude",
+    "--include",
+];
+
 /// Quotes a string so it can be safely passed as a single shell argument.
 ///
 /// This helper chooses a quoting style and escapes only the characters required
@@ -14,13 +256,18 @@ pub struct Command {
 /// - If the string contains any whitespace or the characters `"`, `'`, `\`,
 ///   newline (`\n`), carriage return (`\r`), or tab (`\t`), it is wrapped in
 ///   quotes; otherwise it is returned unchanged.
-/// - The function counts both single (`'`) and double (`"`) quotes and chooses
-///   the quote style that minimizes escaping:
+/// - With [`QuoteStyle::Minimal`] (and [`QuoteStyle::Backslash`], for what
+///   it doesn't escape), the function counts both single (`'`) and
+///   double (`"`) quotes and chooses the quote style that minimizes escaping:
 ///   - If there are more double quotes than single quotes, the string is
 ///     wrapped in single quotes.
 ///   - Otherwise, the string is wrapped in double quotes.
+/// - [`QuoteStyle::PreferSingle`] always uses single quotes, except for
+///   strings with a `$` or backtick, which would no longer expand inside them.
+/// - [`QuoteStyle::PreferDouble`] always uses double quotes.
 ///
-/// Escaping rules:
+/// Escaping rules, for POSIX shells (see [`single_quote`] and
+/// [`double_quote`] for fish and PowerShell):
 /// - In single-quoted mode, the string is wrapped in `'...'`. Any literal
 ///   single quote inside is represented by closing the quote, adding an
 ///   escaped single quote, and reopening the quote (e.g. `abc'def` becomes
@@ -29,56 +276,180 @@ pub struct Command {
 ///   double quotes, backslashes (`\`) and double quotes (`"`) are prefixed
 ///   with a backslash; all other characters are left unchanged.
 ///
+/// Empty strings are emitted as `""` (or `''` when preferring single quotes)
+/// so the argument isn't lost.
+///
+/// A leading `~/` or `~user/` is kept outside the quotes, so `~/my notes`
+/// becomes `~/"my notes"` and the shell still expands the tilde. PowerShell
+/// gets the whole string quoted instead. Without `tilde`, for a `~` that was
+/// quoted where it was parsed, a leading `~` is quoted along with the rest
+/// so it stays literal.
+///
 /// Special characters:
 /// - Dollar signs (`$`) and backticks (`` ` ``) are intentionally *not*
 ///   escaped in either mode so that shell variable expansion and command
-///   substitution can still occur when using the resulting string.
+///   substitution can still occur when using the resulting string. In
+///   PowerShell, where a backtick is the escape character, it is escaped
+///   and also triggers quoting, as does a `,`, which would make an array.
 /// - Newlines (`\n`), tabs (`\t`), and other whitespace are preserved
 ///   literally inside the chosen quotes; their presence is what triggers
 ///   quoting in the first place.
-fn quote_if_needed(s: &str) -> String {
-    let needs_quoting = s
-        .chars()
-        .any(|c| c.is_whitespace() || matches!(c, '"' | '\'' | '\\' | '\n' | '\r' | '\t'));
+fn quote_if_needed(s: &str, style: QuoteStyle, target: TargetShell, tilde: bool) -> String {
+    let expands = match target {
+        TargetShell::Posix => s.contains(['$', '`']),
+        TargetShell::Fish | TargetShell::Pwsh => s.contains('$'),
+    };
+    if s.is_empty() {
+        return match style {
+            QuoteStyle::PreferSingle => single_quote(s, target),
+            _ => double_quote(s, target),
+        };
+    }
 
-    if needs_quoting {
+    let needs_quoting = |s: &str| {
+        s.chars().any(|c| {
+            c.is_whitespace()
+                || matches!(c, '"' | '\'' | '\\' | '\n' | '\r' | '\t')
+                || (target == TargetShell::Pwsh && matches!(c, '`' | ','))
+        })
+    };
+
+    if tilde
+        && target != TargetShell::Pwsh
+        && s.starts_with('~')
+        && let Some(slash) = s.find('/')
+        && !needs_quoting(&s[..slash])
+    {
+        let (prefix, rest) = s.split_at(slash + 1);
+        if needs_quoting(rest) {
+            return format!("{}{}", prefix, quote_if_needed(rest, style, target, true));
+        }
+    }
+
+    if needs_quoting(s) || (!tilde && s.starts_with('~')) {
         // Choose quote style based on which quote char appears more
         let double_quotes = s.chars().filter(|&c| c == '"').count();
         let single_quotes = s.chars().filter(|&c| c == '\'').count();
-
-        if double_quotes > single_quotes {
-            // Use single quotes; to include a single quote in a single-quoted
-            // shell string, close the quote, add an escaped quote, and reopen.
-            // E.g., abc'def becomes 'abc'\''def'
-            // Note: In single quotes, backslashes are literal (no escaping needed)
-            let mut quoted = String::with_capacity(s.len() + 2);
-            quoted.push('\'');
-            for ch in s.chars() {
-                match ch {
-                    '\'' => quoted.push_str("'\\''"),
-                    _ => quoted.push(ch),
-                }
-            }
-            quoted.push('\'');
-            quoted
+        let single = match style {
+            QuoteStyle::Minimal | QuoteStyle::Backslash => double_quotes > single_quotes,
+            QuoteStyle::PreferSingle => !expands,
+            QuoteStyle::PreferDouble => false,
+        };
+
+        if single {
+            single_quote(s, target)
         } else {
-            // Use double quotes, escape backslashes and double quotes
-            let mut escaped = String::with_capacity(s.len());
-            for ch in s.chars() {
-                match ch {
-                    '\\' => escaped.push_str("\\\\"),
-                    '"' => escaped.push_str("\\\""),
-                    _ => escaped.push(ch),
-                }
-            }
-            format!("\"{}\"", escaped)
+            double_quote(s, target)
         }
     } else {
         s.to_string()
     }
 }
 
+/// Wraps `s` in single quotes, inside which nothing expands.
+///
+/// A single quote inside is written `'\''` for POSIX shells (close the
+/// quote, add an escaped one, reopen), `\'` for fish, which also needs its
+/// backslashes doubled, and `''` for PowerShell.
+fn single_quote(s: &str, target: TargetShell) -> String {
+    let escaped = match target {
+        TargetShell::Posix => s.replace('\'', "'\\''"),
+        TargetShell::Fish => s.replace('\\', "\\\\").replace('\'', "\\'"),
+        TargetShell::Pwsh => s.replace('\'', "''"),
+    };
+    format!("'{}'", escaped)
+}
+
+/// Wraps `s` in double quotes, escaping the double quotes inside and the
+/// shell's escape character: a backslash for POSIX shells and fish, and a
+/// backtick for PowerShell. `$` is left alone, so variables still expand.
+fn double_quote(s: &str, target: TargetShell) -> String {
+    let escape = match target {
+        TargetShell::Posix | TargetShell::Fish => '\\',
+        TargetShell::Pwsh => '`',
+    };
+    let mut escaped = String::with_capacity(s.len() + 2);
+    escaped.push('"');
+    for ch in s.chars() {
+        if ch == '"' || ch == escape {
+            escaped.push(escape);
+        }
+        escaped.push(ch);
+    }
+    escaped.push('"');
+    escaped
+}
+
+/// Escapes the spaces of `s` with backslashes, if spaces are all that
+/// `quote` would quote it for
+fn escape_spaces(s: &str, quote: impl Fn(&str) -> String) -> Option<String> {
+    let rest = s.replace(' ', "");
+    if !s.contains(' ') || s.starts_with('#') || quote(&rest) != rest {
+        return None;
+    }
+    Some(s.replace(' ', "\\ "))
+}
+
+/// Quotes a string so the shell passes it through exactly as-is.
+///
+/// Unlike [`quote_if_needed`], characters that trigger expansion (`$`,
+/// backticks, globs, a leading `~`, etc.) also cause quoting. Strings that
+/// need it are wrapped in single quotes, inside which nothing is special
+/// (see [`single_quote`]).
+fn quote_literal(s: &str, target: TargetShell) -> String {
+    let needs_quoting = s.is_empty()
+        || s.starts_with(['~', '#'])
+        || s.chars().any(|c| {
+            c.is_whitespace()
+                || matches!(
+                    c,
+                    '"' | '\''
+                        | '\\'
+                        | '$'
+                        | '`'
+                        | '*'
+                        | '?'
+                        | '['
+                        | ']'
+                        | '{'
+                        | '}'
+                        | '('
+                        | ')'
+                        | '<'
+                        | '>'
+                        | '|'
+                        | '&'
+                        | ';'
+                        | '!'
+                )
+                || (target == TargetShell::Pwsh && matches!(c, ',' | '@'))
+        });
+
+    if needs_quoting {
+        single_quote(s, target)
+    } else {
+        s.to_string()
+    }
+}
+
 impl Command {
+    /// Builds a command from already-split arguments, such as te's own argv.
+    ///
+    /// Each argument becomes exactly one component, so spaces and empty
+    /// arguments survive. The command uses [`QuoteMode::Literal`].
+    pub fn from_argv(args: Vec<String>) -> Result<Self, ParseError> {
+        if args.is_empty() {
+            return Err(ParseError::Empty);
+        }
+        Ok(Command {
+            components: args.into_iter().map(Component::new).collect(),
+            quote_mode: QuoteMode::Literal,
+            quote_style: QuoteStyle::default(),
+            target_shell: TargetShell::default(),
+            comment: None,
+        })
+    }
+
     /// Removes the component at the given `index`.
     ///
     /// # Panics
@@ -86,18 +457,45 @@ impl Command {
     /// Panics if `index` is out of bounds (i.e., `index >= self.component_count()`).
     /// Callers must ensure that `index` is a valid component index before calling
     /// this method.
+    #[allow(dead_code)]
     pub fn remove_component_at(&mut self, index: usize) -> String {
+        self.components.remove(index).value
+    }
+
+    /// Removes the component at `index` along with what it is (an operator,
+    /// a script), so undoing can put it back as it was
+    pub fn take_component_at(&mut self, index: usize) -> Component {
         self.components.remove(index)
     }
 
+    /// Replaces the components in `range` with `components`, returning the
+    /// ones taken out
+    pub fn splice(
+        &mut self,
+        range: impl std::ops::RangeBounds<usize>,
+        components: impl IntoIterator<Item = Component>,
+    ) -> Vec<Component> {
+        self.components.splice(range, components).collect()
+    }
+
     pub fn set_value_at(&mut self, index: usize, new_value: &str) -> String {
-        std::mem::replace(&mut self.components[index], new_value.to_string())
+        self.components[index].replace_value(new_value.to_string())
     }
 
     pub fn component_count(&self) -> usize {
         self.components.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.components.is_empty()
+    }
+
+    /// Removes all components
+    #[allow(dead_code)]
+    pub fn clear(&mut self) {
+        self.components.clear();
+    }
+
     /// Returns a reference to the component at the given `index`.
     ///
     /// # Panics
@@ -106,65 +504,813 @@ impl Command {
     /// Callers must ensure that `index` is a valid component index before calling
     /// this method.
     pub fn component_at(&self, index: usize) -> &str {
-        &self.components[index]
+        &self.components[index].value
+    }
+
+    pub fn components(&self) -> &[Component] {
+        &self.components
     }
 
     pub fn iter_components(&self) -> impl Iterator<Item = &String> {
-        self.components.iter()
+        self.components.iter().map(|c| &c.value)
     }
 
     pub fn insert_component_at(&mut self, index: usize, value: String) {
-        self.components.insert(index, value);
+        self.components.insert(index, Component::new(value));
+    }
+
+    /// Inserts a shell operator such as [`PIPE`] or [`REDIRECT`], which is
+    /// written out unquoted even for commands taken from argv
+    pub fn insert_operator_at(&mut self, index: usize, operator: &str) {
+        self.components.insert(index, Component::operator(operator));
+    }
+
+    /// Whether the component at `index` comes after a `find -exec` (or
+    /// `-execdir`, `-ok`, `-okdir`) whose command hasn't ended yet
+    fn in_find_exec(&self, index: usize) -> bool {
+        for component in self.components[..index].iter().rev() {
+            match component.value.as_str() {
+                "-exec" | "-execdir" | "-ok" | "-okdir" => return true,
+                value
+                    if !component.quoted
+                        && (value == PIPE
+                            || value == REDIRECT
+                            || UNSUPPORTED_OPERATORS.contains(&value)) =>
+                {
+                    return false;
+                }
+                _ => {}
+            }
+        }
+        false
+    }
+
+    /// Each component with its index and what it is. Every command of a
+    /// pipeline starts with its own base words.
+    pub fn enumerate_classified(&self) -> impl Iterator<Item = (usize, ClassifiedComponent<'_>)> {
+        let mut base = Vec::new();
+        let mut start = 0;
+        while start < self.components.len() {
+            let end = self.components[start..]
+                .iter()
+                .position(|c| c.operator || c.value == PIPE)
+                .map_or(self.components.len(), |i| start + i);
+            let values: Vec<&str> = self.components[start..end]
+                .iter()
+                .map(|c| c.value.as_str())
+                .collect();
+            base.extend(
+                command_word_positions(&values)
+                    .into_iter()
+                    .map(|i| start + i),
+            );
+            start = end + 1;
+        }
+
+        self.components.iter().enumerate().map(move |(i, c)| {
+            let value = c.value.as_str();
+            let kind = if c.operator || value == PIPE {
+                ClassifiedComponent::Operator(value)
+            } else if base.contains(&i) {
+                ClassifiedComponent::Base(value)
+            } else if value.starts_with('-') && value.len() > 1 {
+                ClassifiedComponent::Flag(value)
+            } else {
+                ClassifiedComponent::Value(value)
+            };
+            (i, kind)
+        })
+    }
+
+    /// Everything te has to guess at in the command, in order.
+    ///
+    /// A word after a separate flag is taken to be its value unless `arities`
+    /// knows better, which may be wrong. Operators other than `|` and `>`
+    /// aren't understood at all.
+    pub fn ambiguities(&self, arities: &FlagArities) -> Vec<Ambiguity> {
+        let classified: Vec<_> = self.enumerate_classified().collect();
+        let mut ambiguities = Vec::new();
+        for (i, kind) in &classified {
+            let value = match kind {
+                ClassifiedComponent::Operator(_) => continue,
+                ClassifiedComponent::Base(value)
+                | ClassifiedComponent::Flag(value)
+                | ClassifiedComponent::Value(value) => *value,
+            };
+            // Words from an argv, quoted or escaped words, and the `;` that
+            // ends `find -exec` are never operators
+            let literal = self.quote_mode == QuoteMode::Literal
+                || self.components[*i].quoted
+                || (value == ";" && self.in_find_exec(*i));
+            if !literal
+                && (UNSUPPORTED_OPERATORS.contains(&value)
+                    || (value.len() > 1 && value.ends_with(';')))
+            {
+                ambiguities.push(Ambiguity::Operator {
+                    index: *i,
+                    operator: value.to_string(),
+                });
+            } else if let ClassifiedComponent::Value(value) = kind
+                && let Some((_, ClassifiedComponent::Flag(flag))) = i.checked_sub(1).map(|p| classified[p])
+                // `--flag=value`, and clusters like `-rf`, take no separate value
+                && !flag.contains('=')
+                && (flag.starts_with("--") || flag.len() == 2)
+                && !arities.is_known(flag)
+            {
+                ambiguities.push(Ambiguity::FlagOrArgument {
+                    index: *i,
+                    flag: flag.to_string(),
+                    value: value.to_string(),
+                });
+            }
+        }
+        ambiguities
+    }
+
+    /// The command on one line with the component at `index` underlined by
+    /// `^` on the line below
+    pub fn point_at(&self, index: usize) -> String {
+        use unicode_width::UnicodeWidthStr;
+
+        let quoted = self.quoted_components();
+        let before: usize = quoted[..index].iter().map(|c| c.width() + 1).sum();
+        format!(
+            "{}\n{}{}",
+            quoted.join(" "),
+            " ".repeat(before),
+            "^".repeat(quoted[index].width().max(1))
+        )
+    }
+
+    /// Marks the script argument of each `jq` or `awk` in the pipeline, so it
+    /// is quoted literally whatever the quote mode. The script is the first
+    /// positional after the program and its options, unless one comes from a
+    /// file with `-f`. Earlier marks are cleared, so this can run again after
+    /// the command is edited.
+    pub fn mark_scripts(&mut self, wrappers: &[Wrapper], counts: &FlagValueCounts) {
+        for component in &mut self.components {
+            component.script = false;
+        }
+        let mut start = 0;
+        while start < self.components.len() {
+            let end = self.components[start..]
+                .iter()
+                .position(|c| c.operator || c.value == PIPE)
+                .map_or(self.components.len(), |i| start + i);
+            if let Some(index) = self.script_index(start..end, wrappers, counts) {
+                self.components[index].script = true;
+            }
+            start = end + 1;
+        }
+    }
+
+    fn script_index(
+        &self,
+        stage: std::ops::Range<usize>,
+        wrappers: &[Wrapper],
+        counts: &FlagValueCounts,
+    ) -> Option<usize> {
+        let values: Vec<&str> = self.components[stage.clone()]
+            .iter()
+            .map(|c| c.value.as_str())
+            .collect();
+        let program_index = stage.start + wrapper_len(&values, wrappers);
+        let program = self.components.get(program_index)?.value.as_str();
+        let program = program.rsplit('/').next().unwrap_or(program);
+        if !SCRIPT_PROGRAMS.contains(&program) {
+            return None;
+        }
+        let arities = FlagArities::for_program(program, counts);
+        let mut i = program_index + 1;
+        while i < stage.end {
+            let value = self.components[i].value.as_str();
+            if value == "--" {
+                return (i + 1 < stage.end).then_some(i + 1);
+            }
+            if !value.starts_with('-') || value == "-" {
+                return Some(i);
+            }
+            if matches!(value, "-f" | "--from-file") || value.starts_with("--from-file=") {
+                return None;
+            }
+            // `--flag=value`, an attached `-F:` and a cluster like `-rc`
+            // take no separate value
+            let attached = value.contains('=') || (!value.starts_with("--") && value.len() > 2);
+            i += 1 + if attached { 0 } else { arities.values(value) };
+        }
+        None
+    }
+
+    /// Whether the component at `index` was inserted as a shell operator
+    pub fn is_operator_at(&self, index: usize) -> bool {
+        self.components[index].operator
+    }
+
+    /// The index of the first shell operator, inserted or written unquoted
+    /// in a parsed string, if any
+    pub fn find_operator(&self) -> Option<usize> {
+        self.components.iter().enumerate().position(|(i, c)| {
+            c.operator
+                || (self.quote_mode == QuoteMode::Expand
+                    && !c.quoted
+                    && (c.value == PIPE
+                        || c.value == REDIRECT
+                        || UNSUPPORTED_OPERATORS.contains(&c.value.as_str()))
+                    && !(c.value == ";" && self.in_find_exec(i)))
+        })
+    }
+
+    /// Sets the value of `flag`, keeping the form it was written in.
+    ///
+    /// An attached `--flag=old` becomes `--flag=value`, a separated `-f old`
+    /// has its following component replaced, and a flag without a value gets
+    /// one inserted after it. A missing flag is appended as `flag value`
+    /// (before a `--` separator, if any). Returns an error when the flag
+    /// appears more than once, since there is no way to tell which one to set.
+    /// `arities` and `wrappers` tell where the options end, as for
+    /// [`Command::remove_flag`].
+    pub fn set_flag_value(
+        &mut self,
+        flag: &str,
+        value: &str,
+        arities: &FlagArities,
+        wrappers: &[Wrapper],
+    ) -> Result<()> {
+        let end = self.options_end(arities, wrappers);
+        let attached_prefix = format!("{}=", flag);
+        let positions: Vec<usize> = (0..end)
+            .filter(|&i| {
+                let c = &self.components[i].value;
+                c == flag || c.starts_with(&attached_prefix)
+            })
+            .collect();
+
+        match positions.as_slice() {
+            [] => {
+                self.insert_component_at(end, flag.to_string());
+                self.insert_component_at(end + 1, value.to_string());
+            }
+            [i] => {
+                let i = *i;
+                if self.components[i].value != flag {
+                    self.components[i].set_value(&format!("{}{}", attached_prefix, value));
+                } else if i + 1 < end && !self.components[i + 1].value.starts_with('-') {
+                    self.components[i + 1].set_value(value);
+                } else {
+                    self.insert_component_at(i + 1, value.to_string());
+                }
+            }
+            _ => anyhow::bail!(
+                "Flag {} appears {} times; cannot tell which one to set",
+                flag,
+                positions.len()
+            ),
+        }
+        Ok(())
+    }
+
+    /// Removes every occurrence of `flag` together with its value.
+    ///
+    /// Attached `--flag=value` forms are removed as a single component. For the
+    /// separated form, as many following components as the flag takes values
+    /// (see [`FlagArities`]) are removed too, stopping at anything that looks
+    /// like another flag. Components after a `--` separator are never touched;
+    /// a `--` that is the value of a flag, after the program that follows
+    /// any of `wrappers`, is no separator. Returns the number of occurrences
+    /// removed.
+    pub fn remove_flag(
+        &mut self,
+        flag: &str,
+        arities: &FlagArities,
+        wrappers: &[Wrapper],
+    ) -> usize {
+        let attached_prefix = format!("{}=", flag);
+        let mut removed = 0;
+        let mut i = 0;
+
+        while i < self.options_end(arities, wrappers) {
+            let component = &self.components[i].value;
+            if component.starts_with(&attached_prefix) {
+                self.remove_keeping_line_break(i);
+                removed += 1;
+            } else if component == flag {
+                self.remove_keeping_line_break(i);
+                for _ in 0..arities.values(flag) {
+                    if i < self.options_end(arities, wrappers)
+                        && !self.components[i].value.starts_with('-')
+                    {
+                        self.remove_keeping_line_break(i);
+                    }
+                }
+                removed += 1;
+            } else {
+                i += 1;
+            }
+        }
+        removed
+    }
+
+    /// Removes the component at `index`, handing a line break written before
+    /// it to the component after it, so the lines of a `\`-continued command
+    /// stay apart
+    fn remove_keeping_line_break(&mut self, index: usize) {
+        let removed = self.components.remove(index);
+        if let Some(removed) = removed.source
+            && removed.leading.contains('\n')
+            && let Some(next) = self.components.get_mut(index)
+            && let Some(source) = &mut next.source
+            && !source.leading.contains('\n')
+        {
+            source.leading = removed.leading;
+        }
+    }
+
+    /// Flags that appear more than once, in order of first appearance.
+    ///
+    /// Flags listed in `repeatable` are expected to repeat and are ignored, as
+    /// is everything after a `--` separator, found with `arities` and
+    /// `wrappers`. `--flag=value` counts as `--flag`.
+    pub fn duplicate_flags<S: AsRef<str>>(
+        &self,
+        repeatable: &[S],
+        arities: &FlagArities,
+        wrappers: &[Wrapper],
+    ) -> Vec<String> {
+        let mut seen: Vec<(&str, usize)> = Vec::new();
+        for component in &self.components[..self.options_end(arities, wrappers)] {
+            let component = &component.value;
+            if !component.starts_with('-') || component == "-" {
+                continue;
+            }
+            let flag = component
+                .split_once('=')
+                .map_or(component.as_str(), |(f, _)| f);
+            if repeatable.iter().any(|r| r.as_ref() == flag) {
+                continue;
+            }
+            match seen.iter_mut().find(|(f, _)| *f == flag) {
+                Some((_, count)) => *count += 1,
+                None => seen.push((flag, 1)),
+            }
+        }
+        seen.into_iter()
+            .filter(|(_, count)| *count > 1)
+            .map(|(flag, _)| flag.to_string())
+            .collect()
+    }
+
+    /// Index of the `--` options separator, or the component count if there is none.
+    ///
+    /// A `--` right after a flag of the program that `arities` knows to take
+    /// a value, as in `grep -e -- file`, is that flag's value rather than the
+    /// separator. The program comes after any of `wrappers`.
+    fn options_end(&self, arities: &FlagArities, wrappers: &[Wrapper]) -> usize {
+        let values: Vec<&str> = self.components.iter().map(|c| c.value.as_str()).collect();
+        let program_index = wrapper_len(&values, wrappers);
+        (0..values.len())
+            .find(|&i| {
+                values[i] == "--"
+                    && !(i > program_index && arities.known_to_take_values(values[i - 1]))
+            })
+            .unwrap_or(values.len())
+    }
+
+    pub fn set_quote_style(&mut self, style: QuoteStyle) {
+        self.quote_style = style;
+    }
+
+    /// Quote for `shell` instead of a POSIX shell
+    pub fn set_target_shell(&mut self, shell: TargetShell) {
+        self.target_shell = shell;
+    }
+
+    /// Drops the trailing comment, if any
+    pub fn strip_comment(&mut self) {
+        self.comment = None;
     }
 
     /// Convert command to a shell-safe string with proper quoting
     pub fn to_shell_string(&self) -> String {
+        self.with_comment(self.quoted_components().join(" "))
+    }
+
+    /// The command as the shell runs it after brace expansion, e.g.
+    /// `cp file.txt file.md dir` for `cp file.{txt,md} dir`, or `None` if
+    /// nothing in it expands. Only words written out unquoted expand.
+    pub fn brace_expanded(&self) -> Option<String> {
+        let mut expanded = false;
+        let mut words = Vec::new();
+        for (component, quoted) in self.components.iter().zip(self.quoted_components()) {
+            if component.operator || component.script || quoted != component.value {
+                words.push(quoted);
+                continue;
+            }
+            let expansion = braces::expand(&quoted);
+            expanded |= expansion.len() > 1;
+            words.extend(expansion);
+        }
+        expanded.then(|| self.with_comment(words.join(" ")))
+    }
+
+    /// Like [`Command::to_shell_string`], but each parsed word that hasn't
+    /// changed is written as it was, quotes and all, after the spaces and
+    /// line breaks that were before it. Other words are quoted as usual.
+    pub fn to_source_string(&self) -> String {
+        let mut command = String::new();
+        for (i, (component, quoted)) in self
+            .components
+            .iter()
+            .zip(self.quoted_components())
+            .enumerate()
+        {
+            let source = component.source.as_ref();
+            if i > 0 {
+                command.push_str(source.map_or(" ", |s| s.leading.as_str()));
+            }
+            command.push_str(source.and_then(|s| s.text.as_deref()).unwrap_or(&quoted));
+        }
+        self.with_comment(command)
+    }
+
+    fn with_comment(&self, mut command: String) -> String {
+        if let Some(comment) = &self.comment {
+            command.push(' ');
+            command.push_str(comment);
+        }
+        command
+    }
+
+    /// Like [`Command::to_shell_string`], but split over `\`-continued lines
+    /// when a single line would be wider than `width` columns.
+    ///
+    /// Lines only break before a flag or an operator, so each flag stays on
+    /// the same line as its value and a pipe or redirect stays with the word
+    /// after it. Continuation lines are indented by two
+    /// spaces. A `width` of 0 always gives a single line.
+    pub fn to_multiline_shell_string(&self, width: usize) -> String {
+        use unicode_width::UnicodeWidthStr;
+
+        let single_line = self.to_shell_string();
+        if width == 0 || single_line.width() <= width {
+            return single_line;
+        }
+
+        // Group words that belong together: the leading command words, then
+        // each flag with the words after it, and each operator with the word
+        // after it
+        let quoted = self.quoted_components();
+        let mut groups: Vec<String> = Vec::new();
+        for (i, (component, word)) in self.components.iter().zip(&quoted).enumerate() {
+            let starts_group = i > 0
+                && !self.components[i - 1].operator
+                && (component.operator || component.value.starts_with('-'));
+            match groups.last_mut() {
+                Some(group) if !starts_group => {
+                    group.push(' ');
+                    group.push_str(word);
+                }
+                _ => groups.push(word.clone()),
+            }
+        }
+
+        // Pack the groups into lines, leaving room for the trailing " \"
+        let mut lines: Vec<String> = Vec::new();
+        for group in groups {
+            match lines.last_mut() {
+                Some(line) if line.width() + 1 + group.width() + 2 <= width => {
+                    line.push(' ');
+                    line.push_str(&group);
+                }
+                Some(_) => lines.push(format!("  {}", group)),
+                None => lines.push(group),
+            }
+        }
+        self.with_comment(lines.join(" \\\n"))
+    }
+
+    fn quoted_components(&self) -> Vec<String> {
         self.components
             .iter()
-            .map(|c| quote_if_needed(c))
-            .collect::<Vec<_>>()
-            .join(" ")
+            .map(|c| {
+                let quote = |s: &str| match self.quote_mode {
+                    QuoteMode::Expand => {
+                        quote_if_needed(s, self.quote_style, self.target_shell, c.tilde_expands())
+                    }
+                    QuoteMode::Literal => quote_literal(s, self.target_shell),
+                };
+                if c.operator {
+                    c.value.clone()
+                } else if c.script {
+                    quote_literal(&c.value, self.target_shell)
+                } else if self.quote_style == QuoteStyle::Backslash
+                    // A backslash is no escape character in PowerShell
+                    && self.target_shell != TargetShell::Pwsh
+                    && let Some(escaped) = escape_spaces(&c.value, quote)
+                {
+                    escaped
+                } else {
+                    quote(&c.value)
+                }
+            })
+            .collect()
+    }
+}
+
+/// One difference between two versions of a command
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ComponentChange {
+    /// A component at `index` of the new command that wasn't in the old one
+    Added { index: usize, value: String },
+    /// A component at `index` of the old command that is gone
+    Removed { index: usize, value: String },
+    /// A component at `index` of the new command that replaced `from`
+    Changed {
+        index: usize,
+        from: String,
+        to: String,
+    },
+}
+
+/// Differences between the components of two commands.
+///
+/// Components are aligned by their longest common subsequence. Between two
+/// aligned components, removals and additions at the same spot are paired up
+/// as changes.
+pub fn diff_components(original: &[String], updated: &[String]) -> Vec<ComponentChange> {
+    // lcs[i][j]: length of the common subsequence of original[i..] and updated[j..]
+    let mut lcs = vec![vec![0usize; updated.len() + 1]; original.len() + 1];
+    for i in (0..original.len()).rev() {
+        for j in (0..updated.len()).rev() {
+            lcs[i][j] = if original[i] == updated[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut changes = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < original.len() || j < updated.len() {
+        if i < original.len() && j < updated.len() && original[i] == updated[j] {
+            i += 1;
+            j += 1;
+            continue;
+        }
+
+        // Collect the run of removals and additions up to the next aligned pair
+        let (removed_start, added_start) = (i, j);
+        while i < original.len() || j < updated.len() {
+            if i < original.len() && j < updated.len() && original[i] == updated[j] {
+                break;
+            }
+            if j == updated.len() || (i < original.len() && lcs[i + 1][j] >= lcs[i][j + 1]) {
+                i += 1;
+            } else {
+                j += 1;
+            }
+        }
+
+        let removed = removed_start..i;
+        let added = added_start..j;
+        let paired = removed.len().min(added.len());
+        for k in 0..paired {
+            changes.push(ComponentChange::Changed {
+                index: added.start + k,
+                from: original[removed.start + k].clone(),
+                to: updated[added.start + k].clone(),
+            });
+        }
+        changes.extend((removed.start + paired..removed.end).map(|index| {
+            ComponentChange::Removed {
+                index,
+                value: original[index].clone(),
+            }
+        }));
+        changes.extend(
+            (added.start + paired..added.end).map(|index| ComponentChange::Added {
+                index,
+                value: updated[index].clone(),
+            }),
+        );
+    }
+    changes
+}
+
+/// Splits off a trailing `# comment` on the last line of `command_str`.
+///
+/// As in the shell, `#` only starts a comment at the beginning of a word and
+/// outside of quotes. Comments on earlier lines are left in place.
+fn split_trailing_comment(command_str: &str) -> (&str, Option<&str>) {
+    let mut quote = None;
+    let mut escaped = false;
+    let mut at_word_start = true;
+    let mut chars = command_str.char_indices();
+
+    while let Some((i, ch)) = chars.next() {
+        if escaped {
+            escaped = false;
+            at_word_start = false;
+            continue;
+        }
+        match (quote, ch) {
+            (Some('\''), '\'') | (Some('"'), '"') => quote = None,
+            (Some('\''), _) => {}
+            (_, '\\') => escaped = true,
+            (Some(_), _) => {}
+            (None, '\'' | '"') => quote = Some(ch),
+            (None, '#') if at_word_start => {
+                let line_end = command_str[i..]
+                    .find('\n')
+                    .map_or(command_str.len(), |n| i + n);
+                if command_str[line_end..].trim().is_empty() {
+                    return (&command_str[..i], Some(command_str[i..line_end].trim_end()));
+                }
+                // A comment on an earlier line: skip the rest of it, up to
+                // the newline that starts the next word
+                for _ in command_str[i + 1..line_end].chars() {
+                    chars.next();
+                }
+            }
+            _ => {}
+        }
+        at_word_start = quote.is_none() && ch.is_whitespace();
+    }
+    (command_str, None)
+}
+
+/// Why a command string couldn't be parsed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// Nothing but whitespace (and maybe a comment)
+    Empty,
+    /// A quote that is never closed, holding the quote character
+    Unbalanced(char),
+    /// A backslash at the very end, with nothing to escape
+    TrailingBackslash,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::Empty => write!(f, "Empty command"),
+            ParseError::Unbalanced(quote) => write!(
+                f,
+                "Failed to parse command string: unclosed {} quote",
+                quote
+            ),
+            ParseError::TrailingBackslash => {
+                write!(f, "Failed to parse command string: trailing backslash")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// For each word of `line`, as shlex splits it, its byte range and whether
+/// any of it was quoted or escaped
+fn word_spans(line: &str) -> Vec<(std::ops::Range<usize>, bool)> {
+    let mut words = Vec::new();
+    let mut start = None;
+    let mut quoted = false;
+    let mut quote: Option<char> = None;
+    let mut chars = line.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some('"'), '\\') => {
+                chars.next();
+            }
+            (Some(_), _) => {}
+            (None, c) if c.is_whitespace() => {
+                if let Some(start) = start.take() {
+                    words.push((start..i, quoted));
+                }
+                quoted = false;
+            }
+            // A comment, which shlex skips up to the end of its line
+            (None, '#') if start.is_none() => {
+                for (_, c) in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            }
+            (None, c) => {
+                start.get_or_insert(i);
+                match c {
+                    '\'' | '"' => {
+                        quote = Some(c);
+                        quoted = true;
+                    }
+                    '\\' => {
+                        chars.next();
+                        quoted = true;
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    if let Some(start) = start {
+        words.push((start..line.len(), quoted));
+    }
+    words
+}
+
+/// Work out why shlex rejected `line`: an open quote or a dangling escape
+fn diagnose_parse_failure(line: &str) -> ParseError {
+    let mut quote = None;
+    let mut escaped = false;
+    for ch in line.chars() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match (quote, ch) {
+            (Some('\''), '\'') | (Some('"'), '"') => quote = None,
+            (Some('\''), _) => {}
+            (_, '\\') => escaped = true,
+            (None, '\'' | '"') => quote = Some(ch),
+            _ => {}
+        }
+    }
+    match quote {
+        Some(quote) => ParseError::Unbalanced(quote),
+        None => ParseError::TrailingBackslash,
     }
 }
 
 impl TryFrom<&str> for Command {
-    type Error = anyhow::Error;
-    fn try_from(command_str: &str) -> Result<Self> {
-        // Split by line continuations (backslash followed by newline)
-        let lines: Vec<&str> = command_str.split("\\\n").collect();
+    type Error = ParseError;
+    fn try_from(command_str: &str) -> Result<Self, ParseError> {
+        let (command_str, comment) = split_trailing_comment(command_str);
 
-        let mut components = Vec::new();
+        let mut components: Vec<Component> = Vec::new();
+        // Where the last word ended, and whether every word was found in the
+        // text, for keeping the layout
+        let mut previous_end = None;
+        let mut layout_known = true;
+        let mut line_start = 0;
+
+        // Split by line continuations (backslash followed by newline)
+        for line in command_str.split("\\\n") {
+            let trimmed_start = line_start + (line.len() - line.trim_start().len());
+            line_start += line.len() + 2;
 
-        for line in lines.iter() {
             // Parse this line segment
             let trimmed = line.trim();
             if trimmed.is_empty() {
                 continue;
             }
 
-            let tokens = shlex::split(trimmed)
-                .ok_or_else(|| anyhow::anyhow!("Failed to parse command string"))?;
+            let tokens = shlex::split(trimmed).ok_or_else(|| diagnose_parse_failure(trimmed))?;
 
             if tokens.is_empty() {
                 continue;
             }
 
-            for token in tokens {
-                components.push(token);
+            let spans = word_spans(trimmed);
+            layout_known &= spans.len() == tokens.len();
+            for (i, token) in tokens.into_iter().enumerate() {
+                let mut component = Component::new(token);
+                if let Some((span, quoted)) = spans.get(i) {
+                    let (start, end) = (trimmed_start + span.start, trimmed_start + span.end);
+                    component.quoted = *quoted;
+                    component.source = Some(Source {
+                        leading: command_str[previous_end.unwrap_or(start)..start].to_string(),
+                        text: Some(command_str[start..end].to_string()),
+                    });
+                    previous_end = Some(end);
+                }
+                components.push(component);
             }
         }
 
         if components.is_empty() {
-            anyhow::bail!("Empty command");
+            return Err(ParseError::Empty);
+        }
+        if !layout_known {
+            for component in &mut components {
+                component.source = None;
+            }
         }
 
-        Ok(Command { components })
+        Ok(Command {
+            components,
+            quote_mode: QuoteMode::Expand,
+            quote_style: QuoteStyle::default(),
+            target_shell: TargetShell::default(),
+            comment: comment.map(String::from),
+        })
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::history::default_wrappers;
 
     #[test]
     fn test_parse_simple_command() {
@@ -229,82 +1375,342 @@ mod tests {
         assert_eq!(cmd.component_at(6), "--requested-duration=28800s");
     }
 
+    #[test]
+    fn test_parse_errors() {
+        let parse = |s: &str| Command::try_from(s).err();
+
+        assert_eq!(parse(""), Some(ParseError::Empty));
+        assert_eq!(parse("  \\\n  "), Some(ParseError::Empty));
+        assert_eq!(parse("# just a comment"), Some(ParseError::Empty));
+        assert_eq!(
+            parse("echo 'unbalanced"),
+            Some(ParseError::Unbalanced('\''))
+        );
+        assert_eq!(parse("echo \"it's"), Some(ParseError::Unbalanced('"')));
+        assert_eq!(parse("echo done\\"), Some(ParseError::TrailingBackslash));
+        assert_eq!(
+            Command::from_argv(Vec::new()).err(),
+            Some(ParseError::Empty)
+        );
+    }
+
+    #[test]
+    fn test_parse_trailing_comment() {
+        let mut cmd: Command = "kubectl get pods # prod cluster".try_into().unwrap();
+        assert_eq!(cmd.component_count(), 3);
+        assert_eq!(cmd.comment.as_deref(), Some("# prod cluster"));
+        assert_eq!(cmd.to_shell_string(), "kubectl get pods # prod cluster");
+
+        cmd.strip_comment();
+        assert_eq!(cmd.to_shell_string(), "kubectl get pods");
+    }
+
+    #[test]
+    fn test_parse_hash_that_is_not_a_comment() {
+        for input in [
+            "echo '# not a comment'",
+            "echo \"a # b\"",
+            "echo a#b",
+            "echo \\# literal",
+        ] {
+            let cmd: Command = input.try_into().unwrap();
+            assert_eq!(cmd.comment, None, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_parse_comment_after_line_continuation() {
+        let cmd: Command = "kubectl get pods \\\n  -n prod  # staging is down\n"
+            .try_into()
+            .unwrap();
+        assert_eq!(cmd.component_count(), 5);
+        assert_eq!(cmd.comment.as_deref(), Some("# staging is down"));
+    }
+
+    #[test]
+    fn test_parse_comment_lines_before_command() {
+        let cmd: Command = "# Deploy\n# don't run twice\nkubectl get pods # prod\n"
+            .try_into()
+            .unwrap();
+        assert_eq!(cmd.component_count(), 3);
+        assert_eq!(cmd.comment.as_deref(), Some("# prod"));
+    }
+
     #[test]
     fn test_quote_if_needed() {
+        let quote = |s: &str| quote_if_needed(s, QuoteStyle::Minimal, TargetShell::Posix, true);
         // Simple strings without spaces - no quoting needed
-        assert_eq!(quote_if_needed("kubectl"), "kubectl");
-        assert_eq!(quote_if_needed("--name"), "--name");
-        assert_eq!(quote_if_needed("myapp"), "myapp");
+        assert_eq!(quote("kubectl"), "kubectl");
+        assert_eq!(quote("--name"), "--name");
+        assert_eq!(quote("myapp"), "myapp");
 
-        // Empty string - no quoting needed
-        assert_eq!(quote_if_needed(""), "");
+        // Empty string - quoted so the argument isn't lost
+        assert_eq!(quote(""), "\"\"");
 
         // String with spaces - use double quotes (default)
-        assert_eq!(quote_if_needed("hello world"), "\"hello world\"");
+        assert_eq!(quote("hello world"), "\"hello world\"");
 
         // String with double quotes (2 > 0 single) - use single quotes
         // Single quotes preserve the double quotes literally
-        assert_eq!(quote_if_needed("say \"hello\""), "'say \"hello\"'");
+        assert_eq!(quote("say \"hello\""), "'say \"hello\"'");
 
         // String with more double quotes than single - use single quotes
         assert_eq!(
-            quote_if_needed("say \"hello\" and \"world\""),
+            quote("say \"hello\" and \"world\""),
             "'say \"hello\" and \"world\"'"
         );
 
         // String with single quote - use double quotes
-        assert_eq!(quote_if_needed("it's fine"), "\"it's fine\"");
+        assert_eq!(quote("it's fine"), "\"it's fine\"");
 
         // String with more single quotes than double - use double quotes
-        assert_eq!(quote_if_needed("it's Bob's day"), "\"it's Bob's day\"");
+        assert_eq!(quote("it's Bob's day"), "\"it's Bob's day\"");
 
         // More double quotes (2) than single (1) - use single quotes with '\'' escape
-        assert_eq!(quote_if_needed("it's \"ok\""), "'it'\\''s \"ok\"'");
+        assert_eq!(quote("it's \"ok\""), "'it'\\''s \"ok\"'");
 
         // Equal single and double quotes (1 each) - prefer double quotes
-        assert_eq!(quote_if_needed("it's x\""), "\"it's x\\\"\"");
+        assert_eq!(quote("it's x\""), "\"it's x\\\"\"");
 
         // String with only backslashes - needs quoting and escaping
-        assert_eq!(quote_if_needed("path\\to\\file"), "\"path\\\\to\\\\file\"");
+        assert_eq!(quote("path\\to\\file"), "\"path\\\\to\\\\file\"");
 
         // String with dollar sign - quoted due to space, but $ not escaped (allow variable expansion)
-        assert_eq!(quote_if_needed("test $HOME"), "\"test $HOME\"");
+        assert_eq!(quote("test $HOME"), "\"test $HOME\"");
 
         // String with backtick - quoted due to space, but ` not escaped (allow command substitution)
-        assert_eq!(quote_if_needed("run `cmd`"), "\"run `cmd`\"");
+        assert_eq!(quote("run `cmd`"), "\"run `cmd`\"");
 
         // Dollar sign alone - no quoting needed
-        assert_eq!(quote_if_needed("$HOME"), "$HOME");
+        assert_eq!(quote("$HOME"), "$HOME");
 
         // Backtick alone - no quoting needed
-        assert_eq!(quote_if_needed("`cmd`"), "`cmd`");
+        assert_eq!(quote("`cmd`"), "`cmd`");
+
+        // A leading tilde stays unquoted so the shell still expands it
+        assert_eq!(quote("~/notes"), "~/notes");
+        assert_eq!(quote("~/my notes"), "~/\"my notes\"");
+        assert_eq!(quote("~bob/my notes"), "~bob/\"my notes\"");
+        assert_eq!(quote("~ x/y"), "\"~ x/y\"");
+        assert_eq!(quote("~/a \"b\""), "~/'a \"b\"'");
 
         // String with newline - needs quoting (preserved in quotes)
-        assert_eq!(quote_if_needed("line1\nline2"), "\"line1\nline2\"");
+        assert_eq!(quote("line1\nline2"), "\"line1\nline2\"");
 
         // String with tab - needs quoting (preserved in quotes)
-        assert_eq!(quote_if_needed("col1\tcol2"), "\"col1\tcol2\"");
+        assert_eq!(quote("col1\tcol2"), "\"col1\tcol2\"");
 
         // Single quote inside single-quoted string uses '\'' technique
         // When we have more double quotes than single quotes, we use single quotes
         // and escape single quotes with '\''
         assert_eq!(
-            quote_if_needed("say \"hello\" it's \"great\""),
+            quote("say \"hello\" it's \"great\""),
             "'say \"hello\" it'\\''s \"great\"'"
         );
 
         // String with newline AND more double quotes - uses single quotes
         // (single quotes can contain literal newlines)
         assert_eq!(
-            quote_if_needed("line1\nline2 \"quoted\""),
+            quote("line1\nline2 \"quoted\""),
             "'line1\nline2 \"quoted\"'"
         );
 
         // String with tab AND more double quotes - uses single quotes
+        assert_eq!(quote("col1\tcol2 \"data\""), "'col1\tcol2 \"data\"'");
+    }
+
+    #[test]
+    fn test_quote_prefer_single() {
+        let quote =
+            |s: &str| quote_if_needed(s, QuoteStyle::PreferSingle, TargetShell::Posix, true);
+        assert_eq!(quote("kubectl"), "kubectl");
+        assert_eq!(quote(""), "''");
+        assert_eq!(quote("hello world"), "'hello world'");
+        assert_eq!(quote("path\\to file"), "'path\\to file'");
+        assert_eq!(quote("say \"hi\""), "'say \"hi\"'");
+        // Embedded single quotes close the quote, escape, and reopen
+        assert_eq!(quote("it's fine"), "'it'\\''s fine'");
+        assert_eq!(quote("'a' b"), "''\\''a'\\'' b'");
+        assert_eq!(quote("~/it's here"), "~/'it'\\''s here'");
+        // Values the shell should still expand keep double quotes
+        assert_eq!(quote("test $HOME"), "\"test $HOME\"");
+        assert_eq!(quote("run `cmd`"), "\"run `cmd`\"");
+        assert_eq!(quote("$HOME"), "$HOME");
+    }
+
+    #[test]
+    fn test_quote_prefer_double() {
+        let quote =
+            |s: &str| quote_if_needed(s, QuoteStyle::PreferDouble, TargetShell::Posix, true);
+        assert_eq!(quote("kubectl"), "kubectl");
+        assert_eq!(quote(""), "\"\"");
+        assert_eq!(quote("hello world"), "\"hello world\"");
+        assert_eq!(quote("it's fine"), "\"it's fine\"");
+        // Double quotes and backslashes inside are escaped
+        assert_eq!(quote("say \"hi\""), "\"say \\\"hi\\\"\"");
+        assert_eq!(quote("a\\b c"), "\"a\\\\b c\"");
+        assert_eq!(quote("~/a \"b\""), "~/\"a \\\"b\\\"\"");
+        assert_eq!(quote("test $HOME"), "\"test $HOME\"");
+    }
+
+    #[test]
+    fn test_quote_for_fish() {
+        let quote = |s: &str| quote_if_needed(s, QuoteStyle::Minimal, TargetShell::Fish, true);
+        // Variables still expand in double quotes
+        assert_eq!(quote("a $HOME"), r#""a $HOME""#);
+        assert_eq!(quote("my file"), r#""my file""#);
+        assert_eq!(quote(r"a\b c"), r#""a\\b c""#);
+        assert_eq!(quote("~/my notes"), r#"~/"my notes""#);
+        // Single quotes take backslash escapes
+        assert_eq!(quote(r#"it's "a\b""#), r#"'it\'s "a\\b"'"#);
+        let single =
+            |s: &str| quote_if_needed(s, QuoteStyle::PreferSingle, TargetShell::Fish, true);
+        assert_eq!(single("it's here"), r"'it\'s here'");
+        assert_eq!(single("a $HOME"), r#""a $HOME""#);
+        // Backticks mean nothing to fish
+        assert_eq!(single("run `cmd`"), "'run `cmd`'");
+
+        assert_eq!(quote_literal("a $HOME", TargetShell::Fish), "'a $HOME'");
+        assert_eq!(quote_literal(r"it's\", TargetShell::Fish), r"'it\'s\\'");
+    }
+
+    #[test]
+    fn test_quote_for_pwsh() {
+        let quote = |s: &str| quote_if_needed(s, QuoteStyle::Minimal, TargetShell::Pwsh, true);
+        assert_eq!(quote("a $HOME"), r#""a $HOME""#);
+        assert_eq!(quote("my file"), r#""my file""#);
+        // Backslashes are ordinary; backticks and double quotes are escaped
+        // with a backtick
+        assert_eq!(quote(r"C:\my dir"), r#""C:\my dir""#);
+        assert_eq!(quote("say `hi`"), "\"say ``hi``\"");
+        assert_eq!(quote("it's Bob's \"x\""), "\"it's Bob's `\"x`\"\"");
+        assert_eq!(quote("a,b"), "\"a,b\"");
+        assert_eq!(quote(""), "\"\"");
+        // No tilde kept outside the quotes
+        assert_eq!(quote("~/my notes"), "\"~/my notes\"");
+        // Single quotes are doubled
+        assert_eq!(quote(r#"it's "a" "b""#), r#"'it''s "a" "b"'"#);
+        let single =
+            |s: &str| quote_if_needed(s, QuoteStyle::PreferSingle, TargetShell::Pwsh, true);
+        assert_eq!(single("it's here"), "'it''s here'");
+        assert_eq!(single(""), "''");
+
+        assert_eq!(quote_literal("a $HOME", TargetShell::Pwsh), "'a $HOME'");
+        assert_eq!(quote_literal("@a", TargetShell::Pwsh), "'@a'");
+        assert_eq!(quote_literal("@a", TargetShell::Posix), "@a");
+    }
+
+    #[test]
+    fn test_target_shell() {
+        let quoted = |shell: Shell, style: QuoteStyle| {
+            let mut cmd = Command::try_from(r#"echo "my file" "$HOME dir""#).unwrap();
+            cmd.set_target_shell(shell.into());
+            cmd.set_quote_style(style);
+            cmd.to_shell_string()
+        };
+        assert_eq!(
+            quoted(Shell::Fish, QuoteStyle::Backslash),
+            r#"echo my\ file $HOME\ dir"#
+        );
+        // PowerShell has no backslash escapes, so values stay quoted
+        assert_eq!(
+            quoted(Shell::Pwsh, QuoteStyle::Backslash),
+            r#"echo "my file" "$HOME dir""#
+        );
         assert_eq!(
-            quote_if_needed("col1\tcol2 \"data\""),
-            "'col1\tcol2 \"data\"'"
+            quoted(Shell::Nu, QuoteStyle::Minimal),
+            r#"echo "my file" "$HOME dir""#
         );
+
+        let mut argv =
+            Command::from_argv(vec!["echo".to_string(), "it's $HOME".to_string()]).unwrap();
+        argv.set_target_shell(TargetShell::Pwsh);
+        assert_eq!(argv.to_shell_string(), "echo 'it''s $HOME'");
+    }
+
+    #[test]
+    fn test_quoting_preference() {
+        let quoted = |style: QuoteStyle| {
+            let mut cmd = Command::try_from(r#"grep -e "a b" 'say "hi"' file"#).unwrap();
+            cmd.set_quote_style(style);
+            cmd.to_shell_string()
+        };
+        assert_eq!(
+            quoted(QuoteStyle::Minimal),
+            r#"grep -e "a b" 'say "hi"' file"#
+        );
+        assert_eq!(
+            quoted(QuoteStyle::PreferSingle),
+            r#"grep -e 'a b' 'say "hi"' file"#
+        );
+        assert_eq!(
+            quoted(QuoteStyle::PreferDouble),
+            r#"grep -e "a b" "say \"hi\"" file"#
+        );
+
+        // Commands from argv always quote literally
+        let mut argv = Command::from_argv(vec!["echo".to_string(), "a b".to_string()]).unwrap();
+        argv.set_quote_style(QuoteStyle::PreferDouble);
+        assert_eq!(argv.to_shell_string(), "echo 'a b'");
+    }
+
+    #[test]
+    fn test_quote_literal() {
+        assert_eq!(quote_literal("kubectl", TargetShell::Posix), "kubectl");
+        assert_eq!(
+            quote_literal("--name=myapp", TargetShell::Posix),
+            "--name=myapp"
+        );
+        assert_eq!(quote_literal("a~b", TargetShell::Posix), "a~b");
+        assert_eq!(quote_literal("", TargetShell::Posix), "''");
+        assert_eq!(
+            quote_literal("hello world", TargetShell::Posix),
+            "'hello world'"
+        );
+        assert_eq!(quote_literal("$HOME", TargetShell::Posix), "'$HOME'");
+        assert_eq!(quote_literal("`cmd`", TargetShell::Posix), "'`cmd`'");
+        assert_eq!(quote_literal("*.txt", TargetShell::Posix), "'*.txt'");
+        assert_eq!(quote_literal("~/notes", TargetShell::Posix), "'~/notes'");
+        assert_eq!(quote_literal("a|b", TargetShell::Posix), "'a|b'");
+        assert_eq!(quote_literal("it's", TargetShell::Posix), "'it'\\''s'");
+        assert_eq!(
+            quote_literal("say \"hi\"", TargetShell::Posix),
+            "'say \"hi\"'"
+        );
+    }
+
+    #[test]
+    fn test_from_argv() {
+        let args = ["echo", "hello world", "", "*.txt", "$HOME"];
+        let cmd = Command::from_argv(args.iter().map(|s| s.to_string()).collect()).unwrap();
+
+        assert_eq!(cmd.component_count(), 5);
+        assert_eq!(cmd.component_at(1), "hello world");
+        assert_eq!(cmd.component_at(2), "");
+        assert_eq!(
+            cmd.to_shell_string(),
+            "echo 'hello world' '' '*.txt' '$HOME'"
+        );
+
+        assert!(Command::from_argv(Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_brace_expanded() {
+        let cmd: Command = "cp file.{txt,md} dir # backup".try_into().unwrap();
+        assert_eq!(
+            cmd.brace_expanded().as_deref(),
+            Some("cp file.txt file.md dir # backup")
+        );
+        // The command itself is written out as typed
+        assert_eq!(cmd.to_shell_string(), "cp file.{txt,md} dir # backup");
+
+        let cmd: Command = "find . -exec rm {} ;".try_into().unwrap();
+        assert_eq!(cmd.brace_expanded(), None);
+
+        // A quoted word doesn't expand
+        let cmd: Command = "echo \"a {b,c}\"".try_into().unwrap();
+        assert_eq!(cmd.brace_expanded(), None);
     }
 
     #[test]
@@ -320,6 +1726,526 @@ mod tests {
         // Command with --flag=value syntax (now kept as single token)
         let cmd: Command = "docker run --name=myapp image".try_into().unwrap();
         assert_eq!(cmd.to_shell_string(), "docker run --name=myapp image");
+
+        // A tilde path with a space keeps expanding
+        let cmd: Command = "ls ~/\"my notes\"".try_into().unwrap();
+        assert_eq!(cmd.component_at(1), "~/my notes");
+        assert_eq!(cmd.to_shell_string(), "ls ~/\"my notes\"");
+
+        // From argv the tilde was already expanded, so it stays literal
+        let cmd = Command::from_argv(vec!["ls".into(), "~/my notes".into()]).unwrap();
+        assert_eq!(cmd.to_shell_string(), "ls '~/my notes'");
+
+        // A quoted tilde stays quoted, also once the word is rewritten
+        let mut cmd: Command = "ls '~/my notes' '~/notes' ~/a\\ b".try_into().unwrap();
+        assert_eq!(
+            cmd.to_shell_string(),
+            "ls \"~/my notes\" \"~/notes\" ~/\"a b\""
+        );
+        cmd.set_quote_style(QuoteStyle::Backslash);
+        assert_eq!(
+            cmd.to_shell_string(),
+            "ls \"~/my notes\" \"~/notes\" ~/a\\ b"
+        );
+        cmd.set_value_at(1, "~/other notes");
+        assert_eq!(
+            cmd.to_shell_string(),
+            "ls \"~/other notes\" \"~/notes\" ~/a\\ b"
+        );
+    }
+
+    fn marked(cmd_str: &str) -> Command {
+        let mut cmd: Command = cmd_str.try_into().unwrap();
+        cmd.mark_scripts(&default_wrappers(), &FlagValueCounts::new());
+        cmd
+    }
+
+    #[test]
+    fn test_find_operator() {
+        let find = |s: &str| Command::try_from(s).unwrap().find_operator();
+        assert_eq!(find("ls | grep x"), Some(1));
+        assert_eq!(find("make && make install"), Some(1));
+        assert_eq!(find("grep '|' file > out"), Some(3));
+        assert_eq!(find("find . -exec rm {} ;"), None);
+
+        let mut cmd = Command::from_argv(vec!["echo".into(), "|".into()]).unwrap();
+        assert_eq!(cmd.find_operator(), None);
+        cmd.insert_operator_at(2, PIPE);
+        assert_eq!(cmd.find_operator(), Some(2));
+    }
+
+    #[test]
+    fn test_ambiguities() {
+        let cmd: Command = "kubectl get pods -n prod && rm -rf tmp".try_into().unwrap();
+        let arities = FlagArities::for_program("kubectl", &FlagValueCounts::new());
+        assert_eq!(
+            cmd.ambiguities(&arities),
+            vec![
+                Ambiguity::FlagOrArgument {
+                    index: 4,
+                    flag: "-n".to_string(),
+                    value: "prod".to_string(),
+                },
+                Ambiguity::Operator {
+                    index: 5,
+                    operator: "&&".to_string(),
+                },
+            ]
+        );
+        assert_eq!(
+            cmd.point_at(4),
+            "kubectl get pods -n prod && rm -rf tmp\n                    ^^^^"
+        );
+
+        // Known flags, attached values and clusters are clear
+        let cmd: Command = "grep -i -e foo --color=auto -rn file".try_into().unwrap();
+        let arities = FlagArities::for_program("grep", &FlagValueCounts::new());
+        assert_eq!(cmd.ambiguities(&arities), vec![]);
+
+        // Quoted or escaped operators are text, and so is the end of -exec
+        let arities = FlagArities::for_program("echo", &FlagValueCounts::new());
+        for input in [
+            "echo 'done;'",
+            "echo '&&'",
+            "echo \\&\\&",
+            "find . -exec rm {} \\;",
+            "find . -exec rm {} ';'",
+            "find . -name x -exec rm {} ;",
+        ] {
+            let cmd: Command = input.try_into().unwrap();
+            assert_eq!(cmd.ambiguities(&arities), vec![], "{}", input);
+        }
+        let cmd: Command = "echo done; ls".try_into().unwrap();
+        assert_eq!(
+            cmd.ambiguities(&arities),
+            vec![Ambiguity::Operator {
+                index: 1,
+                operator: "done;".to_string(),
+            }]
+        );
+        // The words of an argv are never operators
+        let cmd = Command::from_argv(vec!["echo".to_string(), "&&".to_string()]).unwrap();
+        assert_eq!(cmd.ambiguities(&arities), vec![]);
+    }
+
+    #[test]
+    fn test_word_spans() {
+        let line = r#"echo 'a b' c\ d "e\"f" g"#;
+        let spans = word_spans(line);
+        let words: Vec<&str> = spans.iter().map(|(span, _)| &line[span.clone()]).collect();
+        assert_eq!(words, vec!["echo", "'a b'", r"c\ d", r#""e\"f""#, "g"]);
+        let quoted: Vec<bool> = spans.iter().map(|(_, quoted)| *quoted).collect();
+        assert_eq!(quoted, vec![false, true, true, true, false]);
+
+        let quoted: Vec<bool> = word_spans("ls ''  -la")
+            .into_iter()
+            .map(|(_, q)| q)
+            .collect();
+        assert_eq!(quoted, vec![false, true, false]);
+        // Comments are skipped, as shlex does
+        assert_eq!(word_spans("# note\nls a#b").len(), 2);
+    }
+
+    #[test]
+    fn test_enumerate_classified() {
+        use ClassifiedComponent::*;
+
+        let cmd: Command = "kubectl get pods -n prod --output=json - | grep web"
+            .try_into()
+            .unwrap();
+        assert_eq!(
+            cmd.enumerate_classified().collect::<Vec<_>>(),
+            vec![
+                (0, Base("kubectl")),
+                (1, Base("get")),
+                (2, Base("pods")),
+                (3, Flag("-n")),
+                (4, Value("prod")),
+                (5, Flag("--output=json")),
+                (6, Value("-")),
+                (7, Operator("|")),
+                // As for history, words before the first option are the base
+                (8, Base("grep")),
+                (9, Base("web")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_scripts_are_quoted_literally() {
+        // Without the mark, the `$v` would be left to the shell in double quotes
+        let mut cmd = marked("jq -r --arg v x '.[] | $v'");
+        assert_eq!(cmd.to_shell_string(), "jq -r --arg v x '.[] | $v'");
+        cmd.set_value_at(5, ".items[] | select(.name == $v) | .id");
+        assert_eq!(
+            cmd.to_shell_string(),
+            "jq -r --arg v x '.items[] | select(.name == $v) | .id'"
+        );
+        let reparsed: Command = cmd.to_shell_string().as_str().try_into().unwrap();
+        assert_eq!(
+            reparsed.component_at(5),
+            ".items[] | select(.name == $v) | .id"
+        );
+
+        // After a pipe and wrappers, with attached and clustered options
+        let cmd = marked("curl -s url | sudo awk -F: '{print $1}' /etc/passwd");
+        assert_eq!(
+            cmd.to_shell_string(),
+            "curl -s url | sudo awk -F: '{print $1}' /etc/passwd"
+        );
+        let cmd = marked("jq -rc '\"a b\"' file.json");
+        assert_eq!(cmd.to_shell_string(), "jq -rc '\"a b\"' file.json");
+
+        // A script read from a file leaves the positionals alone
+        let cmd = marked("awk -f prog.awk \"my file\"");
+        assert_eq!(cmd.to_shell_string(), "awk -f prog.awk \"my file\"");
+
+        // `--args` takes no value, so the script follows it
+        let cmd = marked("jq -n --args '$ARGS.positional' a b");
+        assert_eq!(cmd.to_shell_string(), "jq -n --args '$ARGS.positional' a b");
+
+        // Marking again picks up a script inserted after parsing
+        let mut cmd = marked("cat file.json | jq");
+        cmd.insert_component_at(4, ".[] | $v".to_string());
+        cmd.mark_scripts(&default_wrappers(), &FlagValueCounts::new());
+        assert_eq!(cmd.to_shell_string(), "cat file.json | jq '.[] | $v'");
+    }
+
+    #[test]
+    fn test_backslash_quote_style() {
+        let backslash = |mut cmd: Command| {
+            cmd.set_quote_style(QuoteStyle::Backslash);
+            cmd.to_shell_string()
+        };
+        let parsed = |s: &str| Command::try_from(s).unwrap();
+
+        assert_eq!(
+            backslash(parsed(r"cp my\ file ~/my\ dir")),
+            r"cp my\ file ~/my\ dir"
+        );
+        // Values that need quoting for more than their spaces stay quoted
+        assert_eq!(
+            backslash(parsed(r##"echo "it's here" "a\tb c" "# no" " ""##)),
+            r##"echo "it's here" "a\\tb c" "# no" " ""##
+        );
+        // Quoting styles stay the default
+        assert_eq!(
+            parsed(r"cp my\ file x").to_shell_string(),
+            r#"cp "my file" x"#
+        );
+
+        let argv = Command::from_argv(vec![
+            "cp".to_string(),
+            "my file".to_string(),
+            "$HOME x".to_string(),
+        ])
+        .unwrap();
+        assert_eq!(backslash(argv), r"cp my\ file '$HOME x'");
+    }
+
+    #[test]
+    fn test_to_multiline_shell_string() {
+        let cmd: Command = "docker run -d -p 8080:80 --name=myapp -e ENV=prod nginx"
+            .try_into()
+            .unwrap();
+
+        // Fits, or wrapping turned off
+        assert_eq!(cmd.to_multiline_shell_string(80), cmd.to_shell_string());
+        assert_eq!(cmd.to_multiline_shell_string(0), cmd.to_shell_string());
+
+        assert_eq!(
+            cmd.to_multiline_shell_string(30),
+            "docker run -d -p 8080:80 \\\n  --name=myapp \\\n  -e ENV=prod nginx"
+        );
+        assert_eq!(
+            cmd.to_multiline_shell_string(10),
+            "docker run \\\n  -d \\\n  -p 8080:80 \\\n  --name=myapp \\\n  -e ENV=prod nginx"
+        );
+
+        // The result parses back into the same command
+        let reparsed: Command = cmd
+            .to_multiline_shell_string(10)
+            .as_str()
+            .try_into()
+            .unwrap();
+        assert_eq!(reparsed.to_shell_string(), cmd.to_shell_string());
+    }
+
+    #[test]
+    fn test_set_flag_value_separated() {
+        let mut cmd: Command = "kubectl get pods -n prod -o json".try_into().unwrap();
+        cmd.set_flag_value(
+            "-n",
+            "staging",
+            &FlagArities::default(),
+            &default_wrappers(),
+        )
+        .unwrap();
+        assert_eq!(cmd.to_shell_string(), "kubectl get pods -n staging -o json");
+    }
+
+    #[test]
+    fn test_set_flag_value_attached() {
+        let mut cmd: Command = "kubectl get pods --output=json".try_into().unwrap();
+        cmd.set_flag_value(
+            "--output",
+            "yaml",
+            &FlagArities::default(),
+            &default_wrappers(),
+        )
+        .unwrap();
+        assert_eq!(cmd.to_shell_string(), "kubectl get pods --output=yaml");
+    }
+
+    #[test]
+    fn test_set_flag_value_without_value() {
+        let mut cmd: Command = "ls -l --color -a".try_into().unwrap();
+        cmd.set_flag_value(
+            "--color",
+            "never",
+            &FlagArities::default(),
+            &default_wrappers(),
+        )
+        .unwrap();
+        assert_eq!(cmd.to_shell_string(), "ls -l --color never -a");
+    }
+
+    #[test]
+    fn test_set_flag_value_appends_missing_flag() {
+        let mut cmd: Command = "kubectl get pods".try_into().unwrap();
+        cmd.set_flag_value(
+            "--output",
+            "yaml",
+            &FlagArities::default(),
+            &default_wrappers(),
+        )
+        .unwrap();
+        assert_eq!(cmd.to_shell_string(), "kubectl get pods --output yaml");
+
+        // Appended before the options separator
+        let mut cmd: Command = "git log -- src".try_into().unwrap();
+        cmd.set_flag_value("-n", "5", &FlagArities::default(), &default_wrappers())
+            .unwrap();
+        assert_eq!(cmd.to_shell_string(), "git log -n 5 -- src");
+    }
+
+    #[test]
+    fn test_options_end_skips_double_dash_values() {
+        let wrappers = default_wrappers();
+        let none = FlagArities::default();
+        let grep = FlagArities::for_program("grep", &FlagValueCounts::new());
+        let end = |s: &str, arities: &FlagArities, wrappers: &[Wrapper]| {
+            Command::try_from(s).unwrap().options_end(arities, wrappers)
+        };
+
+        // The first `--` ends the options, the second is an argument
+        assert_eq!(end("echo -- --", &none, &wrappers), 1);
+
+        // `--` is the pattern given to -e, so there is no separator
+        assert_eq!(end("grep -e -- file", &grep, &wrappers), 4);
+        assert_eq!(end("sudo grep -e -- -- file", &grep, &wrappers), 4);
+
+        // Only flags known to take a value count
+        assert_eq!(end("grep -i -- file", &grep, &wrappers), 2);
+        assert_eq!(end("grep -e -- file", &none, &wrappers), 2);
+
+        // Flag counts from the config count too
+        let counts = FlagValueCounts::from([(
+            "mytool".to_string(),
+            std::collections::HashMap::from([("--pattern".to_string(), 1)]),
+        )]);
+        let mytool = FlagArities::for_program("mytool", &counts);
+        assert_eq!(end("mytool --pattern -- file", &mytool, &wrappers), 4);
+        assert_eq!(end("mytool --pattern -- file", &none, &wrappers), 2);
+
+        let mut cmd: Command = "echo -- --".try_into().unwrap();
+        cmd.set_flag_value("-n", "1", &none, &wrappers).unwrap();
+        assert_eq!(cmd.to_shell_string(), "echo -n 1 -- --");
+        let mut cmd: Command = "grep -e -- file".try_into().unwrap();
+        cmd.set_flag_value("-m", "1", &grep, &wrappers).unwrap();
+        assert_eq!(cmd.to_shell_string(), "grep -e -- file -m 1");
+    }
+
+    #[test]
+    fn test_set_flag_value_ambiguous() {
+        let mut cmd: Command = "docker run -e A=1 -e B=2 image".try_into().unwrap();
+        assert!(
+            cmd.set_flag_value("-e", "C=3", &FlagArities::default(), &default_wrappers())
+                .is_err()
+        );
+        assert_eq!(cmd.to_shell_string(), "docker run -e A=1 -e B=2 image");
+    }
+
+    #[test]
+    fn test_remove_flag() {
+        let mut cmd: Command = "kubectl get pods -n prod --context=dev -o json"
+            .try_into()
+            .unwrap();
+        assert_eq!(
+            cmd.remove_flag("-n", &FlagArities::default(), &default_wrappers()),
+            1
+        );
+        assert_eq!(
+            cmd.remove_flag("--context", &FlagArities::default(), &default_wrappers()),
+            1
+        );
+        assert_eq!(cmd.to_shell_string(), "kubectl get pods -o json");
+    }
+
+    #[test]
+    fn test_remove_flag_keeps_source_layout() {
+        let text = "kubectl get pods \\\n  -n prod \\\n  -l 'app=web x' --context=dev -o json # ci";
+        let remove = |flag: &str| {
+            let mut cmd: Command = text.try_into().unwrap();
+            cmd.remove_flag(flag, &FlagArities::default(), &default_wrappers());
+            cmd.to_source_string()
+        };
+
+        // Untouched words keep their quotes, and the lines stay apart
+        assert_eq!(
+            remove("-n"),
+            "kubectl get pods \\\n  -l 'app=web x' --context=dev -o json # ci"
+        );
+        assert_eq!(
+            remove("--context"),
+            "kubectl get pods \\\n  -n prod \\\n  -l 'app=web x' -o json # ci"
+        );
+        // A line break before what was removed moves to what follows
+        assert_eq!(
+            remove("-l"),
+            "kubectl get pods \\\n  -n prod \\\n  --context=dev -o json # ci"
+        );
+
+        // A changed word is quoted as usual
+        let mut cmd: Command = "ls 'a b'  c".try_into().unwrap();
+        assert_eq!(cmd.to_source_string(), "ls 'a b'  c");
+        cmd.set_value_at(2, "c d");
+        assert_eq!(cmd.to_source_string(), "ls 'a b'  \"c d\"");
+
+        // Words from argv were never written out
+        let cmd = Command::from_argv(vec!["ls".into(), "a b".into()]).unwrap();
+        assert_eq!(cmd.to_source_string(), cmd.to_shell_string());
+    }
+
+    #[test]
+    fn test_remove_flag_respects_arities() {
+        let arities = FlagArities::for_program("cp", &Default::default());
+
+        // `-t` takes exactly one value, leaving `src` as a positional
+        let mut cmd: Command = "cp -r -t dir src".try_into().unwrap();
+        assert_eq!(cmd.remove_flag("-t", &arities, &default_wrappers()), 1);
+        assert_eq!(cmd.to_shell_string(), "cp -r src");
+
+        // `-r` takes none
+        assert_eq!(cmd.remove_flag("-r", &arities, &default_wrappers()), 1);
+        assert_eq!(cmd.to_shell_string(), "cp src");
+    }
+
+    #[test]
+    fn test_remove_flag_all_occurrences() {
+        let mut cmd: Command = "docker run -e A=1 --rm -e=B=2 image".try_into().unwrap();
+        assert_eq!(
+            cmd.remove_flag("-e", &FlagArities::default(), &default_wrappers()),
+            2
+        );
+        assert_eq!(cmd.to_shell_string(), "docker run --rm image");
+    }
+
+    #[test]
+    fn test_remove_flag_missing_or_after_separator() {
+        let mut cmd: Command = "git log -- -n".try_into().unwrap();
+        assert_eq!(
+            cmd.remove_flag("-n", &FlagArities::default(), &default_wrappers()),
+            0
+        );
+        assert_eq!(cmd.to_shell_string(), "git log -- -n");
+    }
+
+    #[test]
+    fn test_duplicate_flags() {
+        let cmd: Command =
+            "kubectl get pods -n prod -o json --namespace=x -n staging --output=yaml -o wide"
+                .try_into()
+                .unwrap();
+        assert_eq!(
+            cmd.duplicate_flags(
+                REPEATABLE_FLAGS,
+                &FlagArities::default(),
+                &default_wrappers()
+            ),
+            vec!["-n", "-o"]
+        );
+    }
+
+    #[test]
+    fn test_duplicate_flags_ignores_repeatable_and_separator() {
+        let cmd: Command = "docker run -e A=1 -e B=2 --rm -- -x -x".try_into().unwrap();
+        assert!(
+            cmd.duplicate_flags(
+                REPEATABLE_FLAGS,
+                &FlagArities::default(),
+                &default_wrappers()
+            )
+            .is_empty()
+        );
+
+        // A custom allowlist replaces the defaults
+        assert_eq!(
+            cmd.duplicate_flags(&["--rm"], &FlagArities::default(), &default_wrappers()),
+            vec!["-e"]
+        );
+
+        let cmd: Command = "curl -H a -H b --data=x --data=y".try_into().unwrap();
+        assert_eq!(
+            cmd.duplicate_flags(&["-H"], &FlagArities::default(), &default_wrappers()),
+            vec!["--data"]
+        );
+    }
+
+    fn components(s: &str) -> Vec<String> {
+        s.split(' ').map(String::from).collect()
+    }
+
+    #[test]
+    fn test_diff_components() {
+        let original = components("kubectl get pods -n prod -o json");
+        let updated = components("kubectl get pods -n staging -o json --watch");
+
+        assert_eq!(
+            diff_components(&original, &updated),
+            vec![
+                ComponentChange::Changed {
+                    index: 4,
+                    from: "prod".to_string(),
+                    to: "staging".to_string(),
+                },
+                ComponentChange::Added {
+                    index: 7,
+                    value: "--watch".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_diff_components_removed() {
+        let original = components("ls -l -a dir");
+        let updated = components("ls dir");
+
+        assert_eq!(
+            diff_components(&original, &updated),
+            vec![
+                ComponentChange::Removed {
+                    index: 1,
+                    value: "-l".to_string(),
+                },
+                ComponentChange::Removed {
+                    index: 2,
+                    value: "-a".to_string(),
+                },
+            ]
+        );
+        assert_eq!(diff_components(&original, &original), vec![]);
     }
 
     #[test]
@@ -358,6 +2284,24 @@ mod tests {
         assert_eq!(cmd.component_at(1), "get");
     }
 
+    #[test]
+    fn test_is_empty_and_clear() {
+        let mut cmd: Command = "kubectl get pods".try_into().unwrap();
+        assert!(!cmd.is_empty());
+
+        cmd.clear();
+        assert!(cmd.is_empty());
+        assert_eq!(cmd.component_count(), 0);
+        assert_eq!(cmd.to_shell_string(), "");
+
+        // Clearing an empty command is a no-op
+        cmd.clear();
+        assert!(cmd.is_empty());
+
+        cmd.insert_component_at(0, "ls".to_string());
+        assert!(!cmd.is_empty());
+    }
+
     #[test]
     fn test_remove_all_components() {
         let mut cmd: Command = "kubectl".try_into().unwrap();
@@ -366,5 +2310,6 @@ mod tests {
         cmd.remove_component_at(0);
 
         assert_eq!(cmd.component_count(), 0);
+        assert!(cmd.is_empty());
     }
 }
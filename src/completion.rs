@@ -0,0 +1,184 @@
+use std::path::{Path, PathBuf};
+
+/// How many matching paths are offered when completing, so a huge
+/// directory doesn't flood the dropdown
+pub const MAX_PATH_MATCHES: usize = 5000;
+
+/// The paths that complete a value, as found by [`path_candidates`]
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct PathCandidates {
+    pub paths: Vec<String>,
+    /// Whether more entries matched than the [`MAX_PATH_MATCHES`] kept
+    pub truncated: bool,
+}
+
+/// Whether `value` looks like a file path worth completing: it starts with
+/// `/`, `./`, `../` or `~`, or has a `/` in it
+pub fn looks_like_path(value: &str) -> bool {
+    value.starts_with(['/', '~']) || value.starts_with("./") || value.contains('/')
+}
+
+/// Paths that complete `value`, in the order Tab cycles through them.
+///
+/// Entries of the directory `value` points into whose names start with what
+/// follows its last `/`, written with the directory as typed (a leading `~`
+/// stays) and with a `/` after directories. Hidden entries only show up once
+/// a `.` has been typed. The directory is only read when this is called,
+/// and only the first [`MAX_PATH_MATCHES`] matches in it are kept.
+pub fn path_candidates(value: &str, home: Option<&Path>) -> PathCandidates {
+    let (dir, prefix) = match value.rfind('/') {
+        Some(slash) => value.split_at(slash + 1),
+        // `~` alone completes in the home directory
+        None if value == "~" => ("~/", ""),
+        None => ("", value),
+    };
+    let dir_path = match dir.strip_prefix("~/") {
+        Some(rest) => match home {
+            Some(home) => home.join(rest),
+            None => return PathCandidates::default(),
+        },
+        None if dir.is_empty() => PathBuf::from("."),
+        None => PathBuf::from(dir),
+    };
+    let Ok(entries) = std::fs::read_dir(&dir_path) else {
+        return PathCandidates::default();
+    };
+
+    let mut matches = entries.filter_map(Result::ok).filter_map(|entry| {
+        let name = entry.file_name().into_string().ok()?;
+        if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) {
+            return None;
+        }
+        // Follows symlinks, so a link to a directory completes like one
+        let is_dir = entry.path().is_dir();
+        Some(format!("{}{}{}", dir, name, if is_dir { "/" } else { "" }))
+    });
+    let mut paths: Vec<String> = matches.by_ref().take(MAX_PATH_MATCHES).collect();
+    let truncated = matches.next().is_some();
+    paths.sort();
+    PathCandidates { paths, truncated }
+}
+
+/// The longest start all of `candidates` share
+pub fn common_prefix(candidates: &[String]) -> &str {
+    let Some((first, rest)) = candidates.split_first() else {
+        return "";
+    };
+    let mut len = first.len();
+    for candidate in rest {
+        len = first
+            .char_indices()
+            .zip(candidate.chars())
+            .find(|((_, a), b)| a != b)
+            .map_or(len.min(candidate.len()), |((i, _), _)| i.min(len));
+    }
+    &first[..len]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_looks_like_path() {
+        for value in [
+            "/etc",
+            "./run.sh",
+            "../x",
+            "~",
+            "~/.config",
+            "conf/app.yaml",
+        ] {
+            assert!(looks_like_path(value), "{}", value);
+        }
+        for value in ["prod", "-n", "--file=x", "a.txt"] {
+            assert!(!looks_like_path(value), "{}", value);
+        }
+    }
+
+    #[test]
+    fn test_path_candidates() {
+        let dir = std::env::temp_dir().join(format!("te-{}-paths", std::process::id()));
+        std::fs::create_dir_all(dir.join("deploy")).unwrap();
+        for file in ["deploy.yaml", "debug.log", "values.yaml", ".env"] {
+            std::fs::write(dir.join(file), "").unwrap();
+        }
+        let base = format!("{}/", dir.display());
+
+        assert_eq!(
+            path_candidates(&format!("{}de", base), None).paths,
+            [
+                format!("{}debug.log", base),
+                format!("{}deploy.yaml", base),
+                format!("{}deploy/", base),
+            ]
+        );
+        // Everything but hidden entries, until a `.` is typed
+        assert_eq!(path_candidates(&base, None).paths.len(), 4);
+        assert_eq!(
+            path_candidates(&format!("{}.", base), None).paths,
+            [format!("{}.env", base)]
+        );
+        assert!(
+            path_candidates(&format!("{}nope", base), None)
+                .paths
+                .is_empty()
+        );
+        assert!(
+            path_candidates(&format!("{}missing/x", base), None)
+                .paths
+                .is_empty()
+        );
+
+        // `~` is read from the home directory but kept as typed
+        assert_eq!(
+            path_candidates("~/val", Some(&dir)).paths,
+            ["~/values.yaml".to_string()]
+        );
+        assert!(path_candidates("~/val", None).paths.is_empty());
+
+        assert!(!path_candidates(&base, None).truncated);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_path_candidates_are_capped_after_matching() {
+        let dir = std::env::temp_dir().join(format!("te-{}-many-paths", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for i in 0..MAX_PATH_MATCHES + 1 {
+            std::fs::write(dir.join(format!("log-{}", i)), "").unwrap();
+        }
+        std::fs::write(dir.join("values.yaml"), "").unwrap();
+        let base = format!("{}/", dir.display());
+
+        // However many other entries come first, the one match is found
+        let found = path_candidates(&format!("{}val", base), None);
+        assert_eq!(found.paths, [format!("{}values.yaml", base)]);
+        assert!(!found.truncated);
+
+        let found = path_candidates(&format!("{}log-", base), None);
+        assert_eq!(found.paths.len(), MAX_PATH_MATCHES);
+        assert!(found.truncated);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_common_prefix() {
+        let strings = |values: &[&str]| values.iter().map(|v| v.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            common_prefix(&strings(&["deploy/", "deploy.yaml"])),
+            "deploy"
+        );
+        assert_eq!(
+            common_prefix(&strings(&["a/debug.log", "a/deploy/", "a/db"])),
+            "a/d"
+        );
+        assert_eq!(common_prefix(&strings(&["x", "xyz"])), "x");
+        assert_eq!(common_prefix(&strings(&["only"])), "only");
+        assert_eq!(common_prefix(&strings(&["é1", "é2"])), "é");
+        assert_eq!(common_prefix(&strings(&["a", "b"])), "");
+        assert_eq!(common_prefix(&[]), "");
+    }
+}
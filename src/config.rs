@@ -0,0 +1,410 @@
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::{
+    bash_completion::BashCompletionConfig,
+    carapace::CarapaceConfig,
+    command::QuoteStyle,
+    flags::{FlagAliasConfig, FlagValueCounts},
+    help::HelpConfig,
+    keys::KeyBindings,
+    providers::ProviderConfigs,
+    specs::SpecsConfig,
+};
+
+/// Settings read from `config.toml`.
+///
+/// Every setting is optional; a missing one falls back to the environment
+/// or the built-in default (see [`resolve`]).
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct Config {
+    pub history: HistoryConfig,
+    pub navigation: NavigationConfig,
+    /// Key chords for Navigation Mode actions, as `action = "chord"` or a list
+    pub keys: KeyBindings,
+    pub output: OutputConfig,
+    pub undo: UndoConfig,
+    pub features: FeaturesConfig,
+    /// How many values flags take, as `[flags.<program>]` tables of `"flag" = count`
+    pub flags: FlagValueCounts,
+    /// Long spellings of short flags, as `[flag_aliases.<program>]` tables of `"-n" = "--namespace"`
+    pub flag_aliases: FlagAliasConfig,
+    /// Commands that print values for a flag, as `[providers.<program>.<flag>]`
+    pub providers: ProviderConfigs,
+    /// Completion specs describing programs' flags
+    pub specs: SpecsConfig,
+    /// Asking carapace for values
+    pub carapace: CarapaceConfig,
+    /// Asking bash's programmable completion for values
+    pub bash_completion: BashCompletionConfig,
+    /// Programs whose `--help` may be read for the values of their flags
+    pub help: HelpConfig,
+    /// Problems that don't stop the config from loading, like unknown keys
+    #[serde(skip)]
+    pub warnings: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct HistoryConfig {
+    /// Milliseconds to wait for suggestions before opening the editor
+    pub budget_ms: Option<u64>,
+    /// How many of the most recent history entries to learn from
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct UndoConfig {
+    /// How many undo steps to keep
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct NavigationConfig {
+    /// Whether moving past the last or first component wraps around
+    pub wrap: Option<bool>,
+}
+
+/// Switches for behavior some terminals don't get along with
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct FeaturesConfig {
+    /// Capture the mouse while the editor is open
+    pub mouse: Option<bool>,
+    /// Show the keys of the current mode in the status row
+    pub hints: Option<bool>,
+    /// Learn suggestions from shell history
+    pub history: Option<bool>,
+    /// Copy to the clipboard with the OSC 52 escape sequence
+    pub osc52: Option<bool>,
+}
+
+/// Which of the behaviors under `[features]` are on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Features {
+    pub mouse: bool,
+    pub hints: bool,
+    pub history: bool,
+    pub osc52: bool,
+}
+
+impl Default for Features {
+    fn default() -> Self {
+        FeaturesConfig::default().resolve()
+    }
+}
+
+impl FeaturesConfig {
+    /// The effective features: everything not switched off is on
+    pub fn resolve(&self) -> Features {
+        Features {
+            mouse: self.mouse.unwrap_or(true),
+            hints: self.hints.unwrap_or(true),
+            history: self.history.unwrap_or(true),
+            osc52: self.osc52.unwrap_or(true),
+        }
+    }
+}
+
+impl Features {
+    /// Each feature with `on` or `off`, like `mouse on, hints off, …`
+    pub fn describe(&self) -> String {
+        [
+            ("mouse", self.mouse),
+            ("hints", self.hints),
+            ("history", self.history),
+            ("osc52", self.osc52),
+        ]
+        .iter()
+        .map(|(name, on)| format!("{} {}", name, if *on { "on" } else { "off" }))
+        .collect::<Vec<_>>()
+        .join(", ")
+    }
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct OutputConfig {
+    /// Shell command that gets the accepted command on stdin and prints the
+    /// command to output instead, like a formatter
+    pub post_command: Option<String>,
+    /// How values that need quoting are written out; also read from
+    /// `quoting`, its name in earlier configs
+    #[serde(alias = "quoting")]
+    pub quote_style: Option<QuoteStyle>,
+}
+
+/// Tables of the config file whose keys are names chosen by the user, or
+/// checked when they are read, like the action names under `[keys]`
+const FREEFORM_TABLES: [&str; 4] = ["flags", "flag_aliases", "keys", "providers"];
+
+/// Keys each table of the config file understands
+const KNOWN_KEYS: [(&str, &[&str]); 9] = [
+    ("history", &["budget_ms", "limit"]),
+    ("navigation", &["wrap"]),
+    ("output", &["post_command", "quote_style", "quoting"]),
+    ("undo", &["limit"]),
+    ("features", &["mouse", "hints", "history", "osc52"]),
+    ("specs", &["dir"]),
+    ("carapace", &["enabled", "timeout_ms", "skip"]),
+    (
+        "bash_completion",
+        &["enabled", "setup", "timeout_ms", "skip"],
+    ),
+    ("help", &["commands", "timeout_ms"]),
+];
+
+impl Config {
+    /// Parse the contents of a config file.
+    ///
+    /// Unknown keys are collected in `warnings` instead of failing, so a
+    /// config written for a newer te still loads.
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut config: Config = toml::from_str(text)?;
+        let table: toml::Table = text.parse()?;
+        config.warnings = unknown_keys(&table)
+            .into_iter()
+            .map(|key| format!("unknown config key '{}'", key))
+            .collect();
+        Ok(config)
+    }
+
+    /// Load the config file at `path`, or the default location if `None`.
+    ///
+    /// A missing file at the default location is not an error.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let (path, required) = match path {
+            Some(path) => (path.to_path_buf(), true),
+            None => match default_config_path() {
+                Some(path) => (path, false),
+                None => return Ok(Config::default()),
+            },
+        };
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(err) if !required && err.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Config::default());
+            }
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("Failed to read config file {}", path.display()));
+            }
+        };
+        Config::parse(&text)
+            .with_context(|| format!("Failed to load config file {}", path.display()))
+    }
+}
+
+/// `$XDG_CONFIG_HOME/te`, falling back to `~/.config/te`
+pub fn config_dir() -> Option<PathBuf> {
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(config_home.join("te"))
+}
+
+/// `$XDG_CONFIG_HOME/te/config.toml`, falling back to `~/.config/te/config.toml`
+pub fn default_config_path() -> Option<PathBuf> {
+    Some(config_dir()?.join("config.toml"))
+}
+
+/// Dotted names of the keys in `table` that te doesn't know
+fn unknown_keys(table: &toml::Table) -> Vec<String> {
+    let mut unknown = Vec::new();
+    for (name, value) in table {
+        if FREEFORM_TABLES.contains(&name.as_str()) {
+            continue;
+        }
+        match KNOWN_KEYS.iter().find(|(known, _)| known == name) {
+            Some((_, keys)) => {
+                if let Some(section) = value.as_table() {
+                    unknown.extend(
+                        section
+                            .keys()
+                            .filter(|key| !keys.contains(&key.as_str()))
+                            .map(|key| format!("{}.{}", name, key)),
+                    );
+                }
+            }
+            None => unknown.push(name.clone()),
+        }
+    }
+    unknown
+}
+
+/// Pick a setting by precedence: CLI flag, then the environment variable
+/// `env`, then the config file. Returns `None` if none of them set it.
+pub fn resolve<T>(cli: Option<T>, env: &str, config: Option<T>) -> Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    if cli.is_some() {
+        return Ok(cli);
+    }
+    if let Some(value) = std::env::var(env).ok().filter(|v| !v.is_empty()) {
+        return value
+            .parse()
+            .map(Some)
+            .map_err(|err| anyhow::anyhow!("Invalid value '{}' for {}: {}", value, env, err));
+    }
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::{Action, Chords};
+
+    #[test]
+    fn test_parse_config() {
+        let config = Config::parse(
+            "[history]\nbudget_ms = 100\nlimit = 5000\n\n[navigation]\nwrap = false\n\n[keys]\naccept = \"alt-enter\"\n\n[output]\npost_command = \"shfmt\"\nquote_style = \"prefer-single\"\n\n[undo]\nlimit = 50\n",
+        )
+        .unwrap();
+
+        assert_eq!(config.history.budget_ms, Some(100));
+        assert_eq!(config.history.limit, Some(5000));
+        assert_eq!(config.navigation.wrap, Some(false));
+        assert_eq!(
+            config.keys[&Action::Accept],
+            "alt-enter".parse::<Chords>().unwrap()
+        );
+        assert_eq!(config.output.post_command.as_deref(), Some("shfmt"));
+        assert_eq!(config.output.quote_style, Some(QuoteStyle::PreferSingle));
+        assert_eq!(config.undo.limit, Some(50));
+        assert!(config.warnings.is_empty());
+    }
+
+    #[test]
+    fn test_parse_config_quoting() {
+        for (value, style) in [
+            ("minimal", QuoteStyle::Minimal),
+            ("prefer-single", QuoteStyle::PreferSingle),
+            ("prefer-double", QuoteStyle::PreferDouble),
+        ] {
+            let config = Config::parse(&format!("[output]\nquoting = \"{}\"\n", value)).unwrap();
+            assert_eq!(config.output.quote_style, Some(style));
+            assert!(config.warnings.is_empty(), "{:?}", config.warnings);
+        }
+    }
+
+    #[test]
+    fn test_parse_flag_value_counts() {
+        let config = Config::parse("[flags.cp]\n\"-t\" = 1\n\"-r\" = 0\n").unwrap();
+
+        assert_eq!(config.flags["cp"]["-t"], 1);
+        assert_eq!(config.flags["cp"]["-r"], 0);
+        assert!(config.warnings.is_empty());
+
+        let config = Config::parse("[flag_aliases.kubectl]\n\"-n\" = \"--namespace\"\n").unwrap();
+        assert_eq!(config.flag_aliases["kubectl"]["-n"], "--namespace");
+        assert!(config.warnings.is_empty());
+    }
+
+    #[test]
+    fn test_parse_providers() {
+        let config = Config::parse(
+            "[providers.kubectl.\"-n\"]\ncmd = \"kubectl get ns -o name\"\n\n[providers.ssh.positional0]\ncmd = \"cut -d' ' -f1 ~/.ssh/known_hosts\"\ntimeout_ms = 500\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.providers["kubectl"]["-n"].cmd,
+            "kubectl get ns -o name"
+        );
+        assert_eq!(config.providers["kubectl"]["-n"].timeout_ms, None);
+        assert_eq!(config.providers["ssh"]["positional0"].timeout_ms, Some(500));
+        assert!(config.warnings.is_empty());
+    }
+
+    #[test]
+    fn test_features() {
+        let config = Config::parse("[features]\nmouse = false\nosc52 = false\n").unwrap();
+        let features = config.features.resolve();
+
+        assert!(!features.mouse && !features.osc52);
+        assert!(features.hints && features.history);
+        assert_eq!(
+            features.describe(),
+            "mouse off, hints on, history on, osc52 off"
+        );
+        assert_eq!(Config::default().features.resolve(), Features::default());
+        assert!(config.warnings.is_empty());
+    }
+
+    #[test]
+    fn test_parse_empty_config() {
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn test_unknown_keys_warn() {
+        let config =
+            Config::parse("[history]\nlimit = 10\ncolour = 1\n\n[theme]\nname = \"x\"\n").unwrap();
+
+        assert_eq!(config.history.limit, Some(10));
+        assert_eq!(
+            config.warnings,
+            vec![
+                "unknown config key 'history.colour'",
+                "unknown config key 'theme'"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_key_bindings() {
+        let config = Config::parse("[keys]\nundo = [\"u\", \"ctrl-z\"]\nredo = \"U\"\n").unwrap();
+
+        assert_eq!(config.keys[&Action::Undo].0.len(), 2);
+        assert_eq!(config.keys[&Action::Redo].0[0].0, "U");
+        assert!(config.warnings.is_empty());
+    }
+
+    #[test]
+    fn test_key_binding_errors_report_line() {
+        let err = Config::parse("[keys]\nundo = \"u\"\njump = \"J\"\n").unwrap_err();
+        let message = format!("{:#}", err);
+        assert!(message.contains("line 3"), "{}", message);
+        assert!(message.contains("unknown key action 'jump'"), "{}", message);
+
+        let err = Config::parse("[keys]\nundo = [\"u\", \"hyper-u\"]\n").unwrap_err();
+        let message = format!("{:#}", err);
+        assert!(message.contains("line 2"), "{}", message);
+        assert!(message.contains("Unknown modifier 'hyper'"), "{}", message);
+    }
+
+    #[test]
+    fn test_type_error_reports_line() {
+        let err = Config::parse("[history]\n\nlimit = \"many\"\n").unwrap_err();
+        assert!(err.to_string().contains("line 3"), "{}", err);
+    }
+
+    #[test]
+    fn test_resolve_precedence() {
+        // An environment variable name no other test uses
+        let env = "TE_TEST_RESOLVE_PRECEDENCE";
+
+        assert_eq!(resolve(None::<u64>, env, None).unwrap(), None);
+        assert_eq!(resolve(None, env, Some(3u64)).unwrap(), Some(3));
+
+        unsafe { std::env::set_var(env, "2") };
+        assert_eq!(resolve(None, env, Some(3u64)).unwrap(), Some(2));
+        assert_eq!(resolve(Some(1), env, Some(3u64)).unwrap(), Some(1));
+
+        unsafe { std::env::set_var(env, "two") };
+        assert!(resolve(None, env, Some(3u64)).is_err());
+        unsafe { std::env::remove_var(env) };
+    }
+}
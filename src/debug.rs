@@ -0,0 +1,14 @@
+use std::{fs::OpenOptions, io::Write};
+
+/// Append `message` to the file named by `$TE_DEBUG_LOG`, if it is set.
+///
+/// For problems te works around without telling the user, like a value
+/// provider that fails; the terminal belongs to the editor meanwhile.
+pub fn log(message: &str) {
+    let Some(path) = std::env::var_os("TE_DEBUG_LOG").filter(|path| !path.is_empty()) else {
+        return;
+    };
+    if let Ok(mut file) = OpenOptions::new().create(true).append(true).open(path) {
+        let _ = writeln!(file, "te: {}", message);
+    }
+}
@@ -0,0 +1,454 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::{
+    config::{self, Config, Features},
+    history::{self, HistorySource, Shell, ShellOrigin},
+    tui,
+};
+
+/// How long the terminal gets to answer the cursor position query
+const CURSOR_QUERY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long a shell gets to start up when looking for the integration
+const SHELL_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl Status {
+    fn label(self) -> &'static str {
+        match self {
+            Status::Pass => "pass",
+            Status::Warn => "warn",
+            Status::Fail => "fail",
+        }
+    }
+}
+
+/// The outcome of one check, with a hint on what to do when it didn't pass
+#[derive(Debug, PartialEq)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+    pub detail: String,
+    pub hint: Option<String>,
+}
+
+impl Check {
+    fn pass(name: &'static str, detail: impl Into<String>) -> Self {
+        Check {
+            name,
+            status: Status::Pass,
+            detail: detail.into(),
+            hint: None,
+        }
+    }
+
+    fn warn(name: &'static str, detail: impl Into<String>, hint: impl Into<String>) -> Self {
+        Check {
+            name,
+            status: Status::Warn,
+            detail: detail.into(),
+            hint: Some(hint.into()),
+        }
+    }
+
+    fn fail(name: &'static str, detail: impl Into<String>, hint: impl Into<String>) -> Self {
+        Check {
+            name,
+            status: Status::Fail,
+            detail: detail.into(),
+            hint: Some(hint.into()),
+        }
+    }
+
+    /// The check as printed: a status line, then the hint indented under it
+    pub fn report(&self) -> String {
+        let mut report = format!("[{}] {}: {}", self.status.label(), self.name, self.detail);
+        if let Some(hint) = &self.hint {
+            report.push_str(&format!("\n       hint: {}", hint));
+        }
+        report
+    }
+}
+
+/// Run every check, in the order they are printed
+pub fn run_checks(
+    config_path: Option<&Path>,
+    history_source: &HistorySource,
+    features: &Features,
+) -> Vec<Check> {
+    let resolved = history::resolve_shell();
+    let shell = resolved
+        .as_ref()
+        .ok()
+        .copied()
+        .flatten()
+        .map(|(shell, _)| shell);
+    let mut checks = vec![check_tty()];
+    if checks[0].status == Status::Pass {
+        checks.push(check_cursor_query());
+    }
+    checks.push(check_shell(resolved));
+    checks.push(check_history_file(shell, history_source));
+    checks.push(check_integration(shell));
+    checks.push(check_config(config_path));
+    checks.push(check_features(features));
+    checks
+}
+
+fn check_tty() -> Check {
+    match OpenOptions::new().read(true).write(true).open("/dev/tty") {
+        Ok(_) => Check::pass("terminal", "/dev/tty can be opened"),
+        Err(err) => Check::fail(
+            "terminal",
+            format!("can't open /dev/tty: {}", err),
+            "te draws on the controlling terminal; run it from an interactive shell",
+        ),
+    }
+}
+
+fn check_cursor_query() -> Check {
+    let name = "cursor query";
+    match query_cursor_position(CURSOR_QUERY_TIMEOUT) {
+        Ok(Some(elapsed)) => Check::pass(
+            name,
+            format!("the terminal answered in {} ms", elapsed.as_millis()),
+        ),
+        Ok(None) => Check::fail(
+            name,
+            format!("no answer within {} ms", CURSOR_QUERY_TIMEOUT.as_millis()),
+            "te waits for this answer before drawing; try another terminal or disable any multiplexer in between",
+        ),
+        Err(err) => Check::fail(
+            name,
+            format!("{:#}", err),
+            "make sure the terminal supports raw mode",
+        ),
+    }
+}
+
+/// Ask the terminal where the cursor is, returning how long the answer took,
+/// or `None` if it didn't come within `timeout`
+#[cfg(unix)]
+fn query_cursor_position(timeout: Duration) -> anyhow::Result<Option<Duration>> {
+    use crossterm::terminal::{disable_raw_mode, enable_raw_mode};
+
+    let mut tty = OpenOptions::new().read(true).write(true).open("/dev/tty")?;
+    enable_raw_mode()?;
+    let result = read_cursor_response(&mut tty, timeout);
+    disable_raw_mode()?;
+    result
+}
+
+#[cfg(unix)]
+fn read_cursor_response(tty: &mut File, timeout: Duration) -> anyhow::Result<Option<Duration>> {
+    use std::os::fd::AsRawFd;
+
+    let start = Instant::now();
+    tty.write_all(b"\x1b[6n")?;
+    tty.flush()?;
+
+    let mut response = Vec::new();
+    let mut byte = [0u8; 1];
+    while response.len() < 32 {
+        let remaining = timeout.saturating_sub(start.elapsed());
+        let mut fds = libc::pollfd {
+            fd: tty.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // SAFETY: fds is a single valid pollfd that outlives the call
+        if unsafe { libc::poll(&mut fds, 1, remaining.as_millis() as libc::c_int) } <= 0 {
+            return Ok(None);
+        }
+        if tty.read(&mut byte)? == 0 {
+            return Ok(None);
+        }
+        response.push(byte[0]);
+        if byte[0] == b'R' {
+            break;
+        }
+    }
+    Ok(tui::parse_cursor_position(&response).map(|_| start.elapsed()))
+}
+
+#[cfg(not(unix))]
+fn query_cursor_position(_timeout: Duration) -> anyhow::Result<Option<Duration>> {
+    anyhow::bail!("the cursor query check is only supported on unix platforms")
+}
+
+fn check_shell(resolved: anyhow::Result<Option<(Shell, ShellOrigin)>>) -> Check {
+    match resolved {
+        Ok(Some((shell, origin))) => {
+            let origin = match origin {
+                ShellOrigin::Flag => "set with --shell",
+                ShellOrigin::Env => "set with TE_SHELL",
+                ShellOrigin::Detected => "detected from $SHELL",
+            };
+            Check::pass("shell", format!("{} ({})", shell.name(), origin))
+        }
+        Ok(None) => Check::warn(
+            "shell",
+            format!(
+                "unknown shell ($SHELL={})",
+                std::env::var("SHELL").unwrap_or_default()
+            ),
+            format!(
+                "set TE_SHELL or pass --shell ({}), or pass --histfile",
+                history::SHELL_NAMES
+            ),
+        ),
+        Err(err) => Check::fail("shell", err.to_string(), "fix or unset TE_SHELL"),
+    }
+}
+
+fn check_history_file(shell: Option<Shell>, source: &HistorySource) -> Check {
+    let name = "history file";
+    let path = match source {
+        HistorySource::Disabled => {
+            return Check::warn(
+                name,
+                "history is disabled",
+                "drop --no-history to get suggestions",
+            );
+        }
+        HistorySource::File(path) => path.clone(),
+        HistorySource::Detected => match shell.and_then(history::get_history_file) {
+            Some(path) => path,
+            None => {
+                return Check::warn(
+                    name,
+                    "no history file found",
+                    "set $HISTFILE or pass --histfile",
+                );
+            }
+        },
+    };
+    describe_history_file(&path)
+}
+
+fn describe_history_file(path: &Path) -> Check {
+    let name = "history file";
+    let metadata = match std::fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) => {
+            return Check::warn(
+                name,
+                format!("{}: {}", path.display(), err),
+                "check $HISTFILE, or pass --histfile with the file your shell writes",
+            );
+        }
+    };
+    if let Err(err) = File::open(path) {
+        return Check::fail(
+            name,
+            format!("{} can't be read: {}", path.display(), err),
+            "check the file's permissions",
+        );
+    }
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+        .map_or_else(|| "unknown".to_string(), format_age);
+    let detail = format!(
+        "{} ({} bytes, modified {})",
+        path.display(),
+        metadata.len(),
+        modified
+    );
+    if metadata.len() == 0 {
+        Check::warn(
+            name,
+            detail,
+            "the file is empty; make sure your shell saves history to it",
+        )
+    } else {
+        Check::pass(name, detail)
+    }
+}
+
+/// A rough age like "5 minutes ago"
+fn format_age(age: Duration) -> String {
+    let seconds = age.as_secs();
+    let (count, unit) = match seconds {
+        0..60 => (seconds, "second"),
+        60..3600 => (seconds / 60, "minute"),
+        3600..86400 => (seconds / 3600, "hour"),
+        _ => (seconds / 86400, "day"),
+    };
+    let plural = if count == 1 { "" } else { "s" };
+    format!("{} {}{} ago", count, unit, plural)
+}
+
+fn check_integration(shell: Option<Shell>) -> Check {
+    let name = "shell integration";
+    let Some(shell) = shell else {
+        return Check::warn(
+            name,
+            "skipped for an unknown shell",
+            "see `te init --help` for the supported shells",
+        );
+    };
+    // Interactive shells read the rc files the integration is installed in
+    let (function, args): (&str, &[&str]) = match shell {
+        Shell::Zsh => ("te-widget", &["-ic", "whence -w te-widget"]),
+        Shell::Bash => ("te-widget", &["-ic", "declare -F te-widget"]),
+        Shell::Fish => ("te-widget", &["-ic", "functions -q te-widget"]),
+        // -Command still loads the profile the integration is installed in
+        Shell::Pwsh => (
+            "te-widget",
+            &[
+                "-NoLogo",
+                "-Command",
+                "if (-not (Get-Command te-widget -ErrorAction SilentlyContinue)) { exit 1 }",
+            ],
+        ),
+        Shell::Nu => {
+            return Check::warn(
+                name,
+                format!("te has no integration for {}", shell.name()),
+                "run te directly, e.g. `te kubectl get pods`",
+            );
+        }
+        // No cheap way to ask these whether the widget is defined
+        Shell::Elvish | Shell::Xonsh => {
+            return Check::warn(
+                name,
+                format!("can't check the integration for {}", shell.name()),
+                format!(
+                    "see the top of `te init {}` for how to load it",
+                    shell.name()
+                ),
+            );
+        }
+    };
+    let hint = match shell {
+        Shell::Fish => "add `te init fish | source` to ~/.config/fish/config.fish".to_string(),
+        Shell::Pwsh => {
+            "add `te init pwsh | Out-String | Invoke-Expression` to $PROFILE".to_string()
+        }
+        _ => format!(
+            "add `eval \"$(te init {})\"` to ~/.{}rc",
+            shell.name(),
+            shell.name()
+        ),
+    };
+    match run_with_timeout(shell.name(), args, SHELL_TIMEOUT) {
+        Some(true) => Check::pass(
+            name,
+            format!("{} is defined in interactive {}", function, shell.name()),
+        ),
+        Some(false) => Check::warn(
+            name,
+            format!("{} isn't defined in interactive {}", function, shell.name()),
+            hint,
+        ),
+        None => Check::warn(
+            name,
+            format!("couldn't start an interactive {} to look", shell.name()),
+            hint,
+        ),
+    }
+}
+
+/// Whether `program` exits successfully, or `None` if it can't be run or
+/// doesn't finish within `timeout`
+fn run_with_timeout(program: &str, args: &[&str], timeout: Duration) -> Option<bool> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .ok()?;
+    let start = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait().ok()? {
+            return Some(status.success());
+        }
+        if start.elapsed() > timeout {
+            let _ = child.kill();
+            let _ = child.wait();
+            return None;
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+}
+
+fn check_config(path: Option<&Path>) -> Check {
+    let name = "config";
+    let shown: Option<PathBuf> = path
+        .map(Path::to_path_buf)
+        .or_else(config::default_config_path);
+    let shown = shown.map_or_else(|| "config.toml".to_string(), |p| p.display().to_string());
+    if path.is_none() && !Path::new(&shown).exists() {
+        return Check::pass(name, format!("no file at {}, using defaults", shown));
+    }
+    match Config::load(path) {
+        Ok(config) if config.warnings.is_empty() => Check::pass(name, format!("{} loaded", shown)),
+        Ok(config) => Check::warn(
+            name,
+            format!("{} loaded with {}", shown, config.warnings.join(", ")),
+            "remove or rename the keys te doesn't know",
+        ),
+        Err(err) => Check::fail(
+            name,
+            format!("{:#}", err),
+            "fix the file, or pass --config with another one",
+        ),
+    }
+}
+
+/// The features in effect, as switched under `[features]`
+fn check_features(features: &Features) -> Check {
+    Check::pass("features", features.describe())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        assert_eq!(
+            Check::pass("shell", "detected zsh").report(),
+            "[pass] shell: detected zsh"
+        );
+        assert_eq!(
+            Check::warn("history file", "history is disabled", "drop --no-history").report(),
+            "[warn] history file: history is disabled\n       hint: drop --no-history"
+        );
+    }
+
+    #[test]
+    fn test_format_age() {
+        assert_eq!(format_age(Duration::from_secs(1)), "1 second ago");
+        assert_eq!(format_age(Duration::from_secs(59)), "59 seconds ago");
+        assert_eq!(format_age(Duration::from_secs(120)), "2 minutes ago");
+        assert_eq!(format_age(Duration::from_secs(3600)), "1 hour ago");
+        assert_eq!(format_age(Duration::from_secs(3 * 86400)), "3 days ago");
+    }
+
+    #[test]
+    fn test_check_history_file() {
+        let check = check_history_file(None, &HistorySource::Disabled);
+        assert_eq!(check.status, Status::Warn);
+
+        let missing = HistorySource::File(PathBuf::from("/nonexistent/te_history"));
+        let check = check_history_file(None, &missing);
+        assert_eq!(check.status, Status::Warn);
+        assert!(check.detail.starts_with("/nonexistent/te_history"));
+    }
+}
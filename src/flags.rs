@@ -0,0 +1,329 @@
+use std::collections::HashMap;
+
+/// Value counts for flags of particular programs, keyed by program then flag.
+///
+/// Read from the `[flags.<program>]` tables of the config file.
+pub type FlagValueCounts = HashMap<String, HashMap<String, usize>>;
+
+/// Long spellings of short flags for particular programs, keyed by program
+/// (or program and subcommands, like `"git commit"`) then short flag.
+///
+/// Read from the `[flag_aliases.<program>]` tables of the config file.
+pub type FlagAliasConfig = HashMap<String, HashMap<String, String>>;
+
+/// Short and long spellings of the same flag in common commands, keyed by
+/// the program and, where a short flag means different things in different
+/// subcommands, the subcommand
+const BUILTIN_ALIASES: [(&str, &[(&str, &str)]); 7] = [
+    (
+        "kubectl",
+        &[
+            ("-n", "--namespace"),
+            ("-o", "--output"),
+            ("-l", "--selector"),
+            ("-f", "--filename"),
+            ("-c", "--container"),
+            ("-A", "--all-namespaces"),
+            ("-w", "--watch"),
+        ],
+    ),
+    (
+        "docker",
+        &[
+            ("-e", "--env"),
+            ("-v", "--volume"),
+            ("-p", "--publish"),
+            ("-d", "--detach"),
+            ("-i", "--interactive"),
+            ("-t", "--tty"),
+            ("-w", "--workdir"),
+            ("-u", "--user"),
+        ],
+    ),
+    (
+        "curl",
+        &[
+            ("-X", "--request"),
+            ("-H", "--header"),
+            ("-d", "--data"),
+            ("-o", "--output"),
+            ("-s", "--silent"),
+            ("-L", "--location"),
+            ("-u", "--user"),
+        ],
+    ),
+    (
+        "grep",
+        &[
+            ("-i", "--ignore-case"),
+            ("-v", "--invert-match"),
+            ("-n", "--line-number"),
+            ("-r", "--recursive"),
+            ("-e", "--regexp"),
+            ("-f", "--file"),
+        ],
+    ),
+    (
+        "jq",
+        &[
+            ("-r", "--raw-output"),
+            ("-c", "--compact-output"),
+            ("-n", "--null-input"),
+            ("-s", "--slurp"),
+            ("-e", "--exit-status"),
+        ],
+    ),
+    (
+        "git commit",
+        &[("-m", "--message"), ("-a", "--all"), ("-q", "--quiet")],
+    ),
+    ("git tag", &[("-a", "--annotate"), ("-m", "--message")]),
+];
+
+/// Flags of common commands that don't take the default single value
+const BUILTIN_VALUE_COUNTS: [(&str, &[(&str, usize)]); 6] = [
+    (
+        "cp",
+        &[
+            ("-r", 0),
+            ("-R", 0),
+            ("-a", 0),
+            ("-f", 0),
+            ("-i", 0),
+            ("-n", 0),
+            ("-p", 0),
+            ("-u", 0),
+            ("-v", 0),
+        ],
+    ),
+    (
+        "mv",
+        &[("-f", 0), ("-i", 0), ("-n", 0), ("-u", 0), ("-v", 0)],
+    ),
+    (
+        "ln",
+        &[("-s", 0), ("-f", 0), ("-n", 0), ("-r", 0), ("-v", 0)],
+    ),
+    (
+        "rm",
+        &[
+            ("-r", 0),
+            ("-R", 0),
+            ("-f", 0),
+            ("-i", 0),
+            ("-v", 0),
+            ("-d", 0),
+        ],
+    ),
+    (
+        "grep",
+        &[
+            ("-i", 0),
+            ("-v", 0),
+            ("-n", 0),
+            ("-r", 0),
+            ("-l", 0),
+            ("-c", 0),
+            ("-w", 0),
+            ("-x", 0),
+            ("-o", 0),
+            ("-q", 0),
+            ("-E", 0),
+            ("-F", 0),
+            ("-e", 1),
+            ("--regexp", 1),
+            ("-f", 1),
+            ("--file", 1),
+        ],
+    ),
+    (
+        "jq",
+        &[
+            ("-r", 0),
+            ("--raw-output", 0),
+            ("-j", 0),
+            ("--join-output", 0),
+            ("-c", 0),
+            ("--compact-output", 0),
+            ("-n", 0),
+            ("--null-input", 0),
+            ("-s", 0),
+            ("--slurp", 0),
+            ("-R", 0),
+            ("--raw-input", 0),
+            ("-e", 0),
+            ("--exit-status", 0),
+            ("-S", 0),
+            ("--sort-keys", 0),
+            ("-C", 0),
+            ("--color-output", 0),
+            ("-M", 0),
+            ("--monochrome-output", 0),
+            ("-a", 0),
+            ("--ascii-output", 0),
+            ("--tab", 0),
+            ("--seq", 0),
+            ("--stream", 0),
+            ("--args", 0),
+            ("--jsonargs", 0),
+            ("--arg", 2),
+            ("--argjson", 2),
+            ("--slurpfile", 2),
+            ("--rawfile", 2),
+        ],
+    ),
+];
+
+/// How many values each flag of one program takes.
+///
+/// A flag takes one value unless the built-in table or the config says
+/// otherwise, so in `cp -r src dst` the `-r` doesn't swallow `src`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FlagArities {
+    counts: HashMap<String, usize>,
+}
+
+impl FlagArities {
+    /// Value counts for `program`, with `overrides` taking precedence over
+    /// the built-in table
+    pub fn for_program(program: &str, overrides: &FlagValueCounts) -> Self {
+        let mut counts: HashMap<String, usize> = BUILTIN_VALUE_COUNTS
+            .iter()
+            .filter(|(name, _)| *name == program)
+            .flat_map(|(_, flags)| flags.iter())
+            .map(|(flag, count)| (flag.to_string(), *count))
+            .collect();
+        if let Some(flags) = overrides.get(program) {
+            counts.extend(flags.iter().map(|(flag, count)| (flag.clone(), *count)));
+        }
+        Self { counts }
+    }
+
+    /// Number of separate values `flag` takes
+    pub fn values(&self, flag: &str) -> usize {
+        self.counts.get(flag).copied().unwrap_or(1)
+    }
+
+    /// Whether the built-in table or the config says how many values `flag`
+    /// takes
+    pub fn is_known(&self, flag: &str) -> bool {
+        self.counts.contains_key(flag)
+    }
+
+    /// Whether the built-in table or the config says `flag` takes a value,
+    /// rather than that being assumed
+    pub fn known_to_take_values(&self, flag: &str) -> bool {
+        self.counts.get(flag).is_some_and(|&count| count > 0)
+    }
+}
+
+/// The short and long spellings of one program's flags
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FlagAliases {
+    /// Short spelling to long
+    long: HashMap<String, String>,
+}
+
+impl FlagAliases {
+    /// Aliases for `base_command`, like `["git", "commit"]`: those of the
+    /// program and then of its subcommands, with `overrides` taking
+    /// precedence over the built-in table at each level
+    pub fn for_command(base_command: &[String], overrides: &FlagAliasConfig) -> Self {
+        let mut long = HashMap::new();
+        for len in 1..=base_command.len() {
+            let key = base_command[..len].join(" ");
+            long.extend(
+                BUILTIN_ALIASES
+                    .iter()
+                    .filter(|(name, _)| *name == key)
+                    .flat_map(|(_, flags)| flags.iter())
+                    .map(|(short, long)| (short.to_string(), long.to_string())),
+            );
+            if let Some(flags) = overrides.get(&key) {
+                long.extend(flags.iter().map(|(short, l)| (short.clone(), l.clone())));
+            }
+        }
+        Self { long }
+    }
+
+    /// The other spelling of `flag`: the long one for a short flag and the
+    /// other way around
+    pub fn toggle(&self, flag: &str) -> Option<&str> {
+        if let Some(long) = self.long.get(flag) {
+            return Some(long);
+        }
+        self.long
+            .iter()
+            .find(|(_, long)| *long == flag)
+            .map(|(short, _)| short.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flag_arities() {
+        let arities = FlagArities::for_program("cp", &FlagValueCounts::new());
+        assert_eq!(arities.values("-r"), 0);
+        assert_eq!(arities.values("-t"), 1);
+
+        // Unknown programs take the default
+        let arities = FlagArities::for_program("kubectl", &FlagValueCounts::new());
+        assert_eq!(arities.values("-r"), 1);
+    }
+
+    #[test]
+    fn test_flag_aliases() {
+        let command =
+            |words: &[&str]| -> Vec<String> { words.iter().map(|w| w.to_string()).collect() };
+        let aliases =
+            FlagAliases::for_command(&command(&["kubectl", "get"]), &FlagAliasConfig::new());
+        assert_eq!(aliases.toggle("-n"), Some("--namespace"));
+        assert_eq!(aliases.toggle("--namespace"), Some("-n"));
+        assert_eq!(aliases.toggle("--context"), None);
+
+        let overrides = FlagAliasConfig::from([(
+            "kubectl".to_string(),
+            HashMap::from([("-n".to_string(), "--ns".to_string())]),
+        )]);
+        let aliases = FlagAliases::for_command(&command(&["kubectl"]), &overrides);
+        assert_eq!(aliases.toggle("-n"), Some("--ns"));
+        assert_eq!(aliases.toggle("--ns"), Some("-n"));
+        assert_eq!(aliases.toggle("-o"), Some("--output"));
+
+        let aliases = FlagAliases::for_command(&command(&["ls"]), &FlagAliasConfig::new());
+        assert_eq!(aliases.toggle("-n"), None);
+
+        // The same short flag means different things in different subcommands
+        let none = FlagAliasConfig::new();
+        let commit = FlagAliases::for_command(&command(&["git", "commit"]), &none);
+        assert_eq!(commit.toggle("-a"), Some("--all"));
+        let tag = FlagAliases::for_command(&command(&["git", "tag"]), &none);
+        assert_eq!(tag.toggle("-a"), Some("--annotate"));
+        let log = FlagAliases::for_command(&command(&["git", "log"]), &none);
+        assert_eq!(log.toggle("-a"), None);
+
+        let overrides = FlagAliasConfig::from([(
+            "git tag".to_string(),
+            HashMap::from([("-s".to_string(), "--sign".to_string())]),
+        )]);
+        let tag = FlagAliases::for_command(&command(&["git", "tag"]), &overrides);
+        assert_eq!(tag.toggle("-s"), Some("--sign"));
+        assert_eq!(tag.toggle("-a"), Some("--annotate"));
+    }
+
+    #[test]
+    fn test_flag_arities_overrides() {
+        let overrides = FlagValueCounts::from([(
+            "cp".to_string(),
+            HashMap::from([("-r".to_string(), 1), ("--pair".to_string(), 2)]),
+        )]);
+
+        let arities = FlagArities::for_program("cp", &overrides);
+        assert_eq!(arities.values("-r"), 1);
+        assert_eq!(arities.values("--pair"), 2);
+        assert_eq!(arities.values("-v"), 0);
+    }
+}
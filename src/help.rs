@@ -0,0 +1,514 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::Arc,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{background::BackgroundRuns, debug, providers::run_capped};
+
+/// How long `--help` may take
+pub const DEFAULT_HELP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How much of the help text is read
+pub const MAX_HELP_OUTPUT: usize = 256 * 1024;
+
+/// Phrases that introduce the values a flag takes, in lower case
+const CHOICE_PHRASES: [&str; 7] = [
+    "one of:",
+    "one of",
+    "possible values:",
+    "legal values",
+    "valid values",
+    "allowed values",
+    "supported values",
+];
+
+/// Settings of the `[help]` table
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct HelpConfig {
+    /// Commands, like `kubectl` or `kubectl get`, whose `--help` is safe to
+    /// run to learn the values of their flags. Nothing is run unless listed,
+    /// and a subcommand's help only if it is listed itself.
+    pub commands: Vec<String>,
+    /// Milliseconds to wait, instead of [`DEFAULT_HELP_TIMEOUT`]
+    pub timeout_ms: Option<u64>,
+}
+
+/// What the help of a command says, as kept in the cache
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq)]
+struct HelpCache {
+    /// The program's path, modification time and size when it was run
+    version: String,
+    /// Values of each flag, under every spelling
+    choices: HashMap<String, Vec<String>>,
+}
+
+/// Learns the values of flags from the `--help` of the commands allowed to
+/// run it, in the background and at most once per run of te. What was
+/// learned is kept on disk until the program changes.
+#[derive(Debug, Clone, Default)]
+pub struct HelpChoices {
+    commands: Arc<Vec<Vec<String>>>,
+    timeout: Duration,
+    cache_dir: Option<Arc<PathBuf>>,
+    results: BackgroundRuns<Vec<String>, HashMap<String, Vec<String>>>,
+}
+
+impl HelpChoices {
+    /// Cache what's learned in `cache_dir`, if there is one
+    pub fn new(config: HelpConfig, cache_dir: Option<PathBuf>) -> Self {
+        Self {
+            commands: Arc::new(
+                config
+                    .commands
+                    .iter()
+                    .map(|command| command.split_whitespace().map(String::from).collect())
+                    .collect(),
+            ),
+            timeout: config
+                .timeout_ms
+                .map_or(DEFAULT_HELP_TIMEOUT, Duration::from_millis),
+            cache_dir: cache_dir.map(Arc::new),
+            results: BackgroundRuns::default(),
+        }
+    }
+
+    /// Start learning from the help of the program `base` starts with, and
+    /// of its subcommand if it has one, each unless it's not allowed or
+    /// already done
+    pub fn request(&self, base: &[String]) {
+        for len in 1..=base.len().min(2) {
+            let words = base[..len].to_vec();
+            // `ssh host --help` or `python deploy.py --help` would run
+            // something else, so only listed commands are
+            if !self.commands.contains(&words) {
+                continue;
+            }
+            let timeout = self.timeout;
+            let cache_dir = self.cache_dir.clone();
+            self.results.start(words.clone(), move || {
+                learn(&words, timeout, cache_dir.as_deref().map(PathBuf::as_path))
+            });
+        }
+    }
+
+    /// The values of `flag` the subcommand's help gives, or else the
+    /// program's, once they are known
+    pub fn choices(&self, base: &[String], flag: &str) -> Vec<String> {
+        (1..=base.len().min(2))
+            .rev()
+            .filter_map(|len| self.results.get(&base[..len].to_vec()))
+            .find_map(|choices| choices.get(flag).cloned())
+            .unwrap_or_default()
+    }
+
+    /// Whether any help is still being read
+    pub fn running(&self) -> bool {
+        self.results.running()
+    }
+}
+
+/// The choices in the help of `words`, from the cache if the program hasn't
+/// changed since, or else by running it
+fn learn(
+    words: &[String],
+    timeout: Duration,
+    cache_dir: Option<&Path>,
+) -> HashMap<String, Vec<String>> {
+    let version = program_version(&words[0]);
+    let cache_path = cache_dir
+        .zip(version.as_ref())
+        .and_then(|(dir, _)| cache_path(dir, words));
+    if let Some(path) = &cache_path
+        && let Ok(json) = std::fs::read_to_string(path)
+        && let Ok(cache) = serde_json::from_str::<HelpCache>(&json)
+        && Some(&cache.version) == version.as_ref()
+    {
+        return cache.choices;
+    }
+
+    let choices = match run_help(words, timeout) {
+        Ok(help) => parse_help(&help),
+        Err(err) => {
+            debug::log(&format!("help of {} failed: {:#}", words.join(" "), err));
+            return HashMap::new();
+        }
+    };
+    if let Some((path, version)) = cache_path.zip(version) {
+        let cache = HelpCache {
+            version,
+            choices: choices.clone(),
+        };
+        let written = path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| std::fs::write(&path, serde_json::to_string(&cache).unwrap_or_default()));
+        if let Err(err) = written {
+            debug::log(&format!(
+                "could not cache help in {}: {}",
+                path.display(),
+                err
+            ));
+        }
+    }
+    choices
+}
+
+/// The program's path, modification time and size, which change when it is
+/// upgraded, if it can be found
+fn program_version(program: &str) -> Option<String> {
+    let path = if program.contains('/') {
+        PathBuf::from(program)
+    } else {
+        let path = std::env::var_os("PATH")?;
+        std::env::split_paths(&path)
+            .map(|dir| dir.join(program))
+            .find(|path| path.is_file())?
+    };
+    let metadata = std::fs::metadata(&path).ok()?;
+    let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some(format!(
+        "{} {} {}",
+        path.display(),
+        modified.as_nanos(),
+        metadata.len()
+    ))
+}
+
+/// `<dir>/<program> <subcommand>.json`, named after the program's file name,
+/// unless a word can't be part of a file name
+fn cache_path(dir: &Path, words: &[String]) -> Option<PathBuf> {
+    let program = Path::new(&words[0]).file_name()?.to_str()?;
+    let mut names = vec![program];
+    names.extend(words[1..].iter().map(String::as_str));
+    let plain = |name: &&str| {
+        !name.is_empty()
+            && !name.starts_with('.')
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "._-".contains(c))
+    };
+    names
+        .iter()
+        .all(plain)
+        .then(|| dir.join(format!("{}.json", names.join(" "))))
+}
+
+/// Run `<words…> --help` without a terminal, so it can't prompt or page
+fn run_help(words: &[String], timeout: Duration) -> Result<String> {
+    let mut command = Command::new(&words[0]);
+    command
+        .args(&words[1..])
+        .arg("--help")
+        .env("NO_COLOR", "1")
+        .env("PAGER", "cat")
+        .env("TERM", "dumb");
+    let cmd = format!("{} --help", words.join(" "));
+    let output = run_capped(command, &cmd, timeout, MAX_HELP_OUTPUT)?;
+    Ok(String::from_utf8_lossy(&output).into_owned())
+}
+
+/// The values each flag takes, from help text.
+///
+/// Each flag's entry is the line that starts with it and the lines under
+/// it, up to a blank line or the next flag. The values are the first list
+/// found in it: one after a phrase like `one of:` or `possible values:`,
+/// `{a,b,c}`, `[a|b|c]` or `<a|b|c>`, or `=a|b|c`.
+pub fn parse_help(help: &str) -> HashMap<String, Vec<String>> {
+    let mut choices = HashMap::new();
+    let mut flags: Vec<String> = Vec::new();
+    let mut entry = String::new();
+    for line in help.lines().chain([""]) {
+        let line = line.trim();
+        let names = flag_names(line);
+        if line.is_empty() || !names.is_empty() {
+            let values = entry_choices(&entry);
+            if values.len() > 1 {
+                for flag in &flags {
+                    choices
+                        .entry(flag.clone())
+                        .or_insert_with(|| values.clone());
+                }
+            }
+            flags = names;
+            entry.clear();
+        }
+        if !flags.is_empty() {
+            entry.push_str(line);
+            entry.push(' ');
+        }
+    }
+    choices
+}
+
+/// The flags a help line starts with, like `-o` and `--output` in
+/// `-o, --output=FORMAT  Output format`
+fn flag_names(line: &str) -> Vec<String> {
+    if !line.starts_with('-') {
+        return Vec::new();
+    }
+    // The flags end where the description starts
+    let end = ["  ", "\t", ": "]
+        .iter()
+        .filter_map(|separator| line.find(separator))
+        .min()
+        .unwrap_or(line.len());
+    line[..end]
+        .split([' ', ','])
+        .filter(|word| word.starts_with('-'))
+        .filter_map(|word| {
+            let name = word.split(['=', '[', '<', ':']).next().unwrap_or_default();
+            let dashes = name.len() - name.trim_start_matches('-').len();
+            (dashes <= 2
+                && name.len() > dashes
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_?".contains(c)))
+            .then(|| name.to_string())
+        })
+        .collect()
+}
+
+/// The first list of values in a flag's entry
+fn entry_choices(entry: &str) -> Vec<String> {
+    let lower = entry.to_ascii_lowercase();
+    for phrase in CHOICE_PHRASES {
+        if let Some(start) = lower.find(phrase) {
+            let rest = entry[start + phrase.len()..].trim_start_matches([' ', ':']);
+            let list = match rest.chars().next() {
+                Some(open @ ('(' | '[' | '{')) => {
+                    let close = closing(open);
+                    rest[1..].split(close).next().unwrap_or_default()
+                }
+                // Or it ends the bracket the phrase is in
+                _ => rest.split([';', ')', ']', '}']).next().unwrap_or_default(),
+            };
+            // A sentence ends the list
+            let list = list.split(". ").next().unwrap_or_default();
+            let values = split_choices(list.trim_end_matches('.'), &[',', '|']);
+            if values.len() > 1 {
+                return values;
+            }
+        }
+    }
+
+    for (i, open) in entry.char_indices() {
+        if !matches!(open, '{' | '[' | '<' | '(') {
+            continue;
+        }
+        let Some(inside) = entry[i + 1..].split(closing(open)).next() else {
+            continue;
+        };
+        let separators: &[char] = if open == '{' { &[',', '|'] } else { &['|'] };
+        let values = split_choices(inside, separators);
+        if values.len() > 1 && !inside.contains(' ') {
+            return values;
+        }
+    }
+
+    entry
+        .split_whitespace()
+        .filter_map(|word| word.rsplit_once('='))
+        .map(|(_, value)| split_choices(value.trim_end_matches([':', ',']), &['|']))
+        .find(|values| values.len() > 1)
+        .unwrap_or_default()
+}
+
+fn closing(open: char) -> char {
+    match open {
+        '(' => ')',
+        '[' => ']',
+        '{' => '}',
+        _ => '>',
+    }
+}
+
+/// The values of `list` split at `separators`, if every one of them looks
+/// like a value rather than prose
+fn split_choices(list: &str, separators: &[char]) -> Vec<String> {
+    let mut values: Vec<String> = Vec::new();
+    for value in list.split(separators) {
+        let value = value.trim();
+        let value = value
+            .strip_prefix("or ")
+            .or_else(|| value.strip_prefix("and "))
+            .unwrap_or(value)
+            .trim_matches(['\'', '"', '`']);
+        let plain = !value.is_empty()
+            && value.chars().any(|c| c.is_ascii_alphanumeric())
+            && value
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_./+:@".contains(c));
+        if !plain {
+            return Vec::new();
+        }
+        if !values.iter().any(|v| v == value) {
+            values.push(value.to_string());
+        }
+    }
+    values
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KUBECTL: &str = "\
+Options:
+    --allow-missing-template-keys=true:
+\tIf true, ignore any errors in templates.
+
+    -o, --output='':
+\tOutput format. One of: (json, yaml, name, go-template,
+\tjsonpath-as-json, wide). See custom columns [https://kubernetes.io].
+
+    --restart='Always':
+\tThe restart policy for this Pod.  Legal values [Always, OnFailure, Never].
+
+    -w, --watch=false:
+\tAfter listing/getting the requested object, watch for changes.
+";
+
+    #[test]
+    fn test_parse_kubectl_help() {
+        let choices = parse_help(KUBECTL);
+        let output = [
+            "json",
+            "yaml",
+            "name",
+            "go-template",
+            "jsonpath-as-json",
+            "wide",
+        ];
+        assert_eq!(choices["-o"], output);
+        assert_eq!(choices["--output"], output);
+        assert_eq!(choices["--restart"], ["Always", "OnFailure", "Never"]);
+        assert!(!choices.contains_key("--watch"));
+        assert!(!choices.contains_key("--allow-missing-template-keys"));
+    }
+
+    #[test]
+    fn test_parse_other_help_styles() {
+        let help = "\
+usage: tool [-h] [--format {csv,json}]
+
+options:
+  -h, --help            show this help message and exit
+  -f {csv,json}, --format {csv,json}
+                        Output format
+  --color=<always|never|auto>  When to use colors
+  --level=debug|info|warn  Log level
+  -p, --policy <POLICY>  Policy to apply [possible values: strict, lax]
+  --mode MODE           One of the modes in the config. Defaults to none
+  --name [NAME]         The name
+  - a bullet that is not a flag
+";
+        let choices = parse_help(help);
+        assert_eq!(choices["-f"], ["csv", "json"]);
+        assert_eq!(choices["--format"], ["csv", "json"]);
+        assert_eq!(choices["--color"], ["always", "never", "auto"]);
+        assert_eq!(choices["--level"], ["debug", "info", "warn"]);
+        assert_eq!(choices["--policy"], ["strict", "lax"]);
+        assert!(!choices.contains_key("--mode"));
+        assert!(!choices.contains_key("--name"));
+        assert!(!choices.contains_key("-h"));
+    }
+
+    #[test]
+    fn test_flag_names() {
+        assert_eq!(
+            flag_names("-o, --output=FORMAT  Output format"),
+            ["-o", "--output"]
+        );
+        assert_eq!(flag_names("--output='':"), ["--output"]);
+        assert_eq!(flag_names("-n NAME, --name NAME"), ["-n", "--name"]);
+        assert!(flag_names("- bullet").is_empty());
+        assert!(flag_names("--- heading ---").is_empty());
+        assert!(flag_names("Output format").is_empty());
+    }
+
+    #[test]
+    fn test_help_runs_once_and_is_cached() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("te-help-test-{}", std::process::id()));
+        let cache_dir = dir.join("cache");
+        std::fs::create_dir_all(&dir).unwrap();
+        let program = dir.join("mytool");
+        // Counts its runs, and only answers for its `get` subcommand
+        let script = "#!/bin/sh\necho run >> \"$0.count\"\n[ \"$1\" = get ] && echo '  -o, --output=<json|yaml>  Output format'\necho '  --color=always|never'\n";
+        std::fs::write(&program, script).unwrap();
+        std::fs::set_permissions(&program, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let program = program.to_str().unwrap().to_string();
+
+        let allowed = vec![program.clone(), format!("{} get", program)];
+        let learn = |base: &[&str]| {
+            let config = HelpConfig {
+                commands: allowed.clone(),
+                timeout_ms: Some(5000),
+            };
+            let help = HelpChoices::new(config, Some(cache_dir.clone()));
+            let base: Vec<String> = base.iter().map(|w| w.to_string()).collect();
+            help.request(&base);
+            while help.running() {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            (
+                help.choices(&base, "--output"),
+                help.choices(&base, "--color"),
+            )
+        };
+        let runs = || {
+            std::fs::read_to_string(dir.join("mytool.count"))
+                .unwrap()
+                .lines()
+                .count()
+        };
+
+        let (output, color) = learn(&[&program, "get"]);
+        assert_eq!(output, ["json", "yaml"]);
+        assert_eq!(color, ["always", "never"]);
+        assert_eq!(runs(), 2);
+        assert!(cache_dir.join("mytool get.json").is_file());
+
+        // Another run of te reads the cache, until the program changes
+        assert_eq!(learn(&[&program, "get"]).0, ["json", "yaml"]);
+        assert_eq!(runs(), 2);
+        std::fs::write(&program, format!("{}\n", script)).unwrap();
+        assert_eq!(learn(&[&program, "get"]).0, ["json", "yaml"]);
+        assert_eq!(runs(), 4);
+
+        // Commands that aren't allowed never run
+        let help = HelpChoices::new(HelpConfig::default(), Some(cache_dir.clone()));
+        help.request(std::slice::from_ref(&program));
+        assert!(!help.running());
+        assert_eq!(runs(), 4);
+
+        // Nor do subcommands that aren't listed, even if the program is
+        let (output, color) = learn(&[&program, "deploy"]);
+        assert!(output.is_empty());
+        assert_eq!(color, ["always", "never"]);
+        assert_eq!(runs(), 4);
+        assert!(!cache_dir.join("mytool deploy.json").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_cache_path() {
+        let dir = Path::new("/cache");
+        let words = |words: &[&str]| words.iter().map(|w| w.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            cache_path(dir, &words(&["/usr/bin/kubectl", "get"])),
+            Some(PathBuf::from("/cache/kubectl get.json"))
+        );
+        assert_eq!(cache_path(dir, &words(&["git", "../x"])), None);
+        assert_eq!(cache_path(dir, &words(&["git", "a b"])), None);
+    }
+}
@@ -0,0 +1,1211 @@
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fmt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock, mpsc},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+
+use crate::{
+    flags::FlagArities,
+    profile::{self, Phase},
+};
+
+/// Default time budget for loading history before the TUI opens
+pub const DEFAULT_HISTORY_BUDGET: Duration = Duration::from_millis(50);
+
+/// A value previously used with a flag, with how often it appeared
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Candidate {
+    pub value: String,
+    pub count: usize,
+}
+
+/// Candidate values from history, keyed by flag
+pub type Suggestions = HashMap<String, Vec<Candidate>>;
+
+/// Shells te knows about: to read the history of, to generate a `te init`
+/// script for, or both
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Shell {
+    Zsh,
+    Bash,
+    Fish,
+    Nu,
+    Pwsh,
+    Elvish,
+    Xonsh,
+}
+
+/// Names accepted for `--shell`, `TE_SHELL` and `te init`
+pub const SHELL_NAMES: &str = "zsh, bash, fish, nu, pwsh, elvish, xonsh";
+
+impl Shell {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Shell::Zsh => "zsh",
+            Shell::Bash => "bash",
+            Shell::Fish => "fish",
+            Shell::Nu => "nu",
+            Shell::Pwsh => "pwsh",
+            Shell::Elvish => "elvish",
+            Shell::Xonsh => "xonsh",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "zsh" => Some(Shell::Zsh),
+            "bash" => Some(Shell::Bash),
+            "fish" => Some(Shell::Fish),
+            "nu" => Some(Shell::Nu),
+            "pwsh" => Some(Shell::Pwsh),
+            "elvish" => Some(Shell::Elvish),
+            "xonsh" => Some(Shell::Xonsh),
+            _ => None,
+        }
+    }
+
+    /// Whether `te init` generates a script for this shell
+    pub fn has_init_script(self) -> bool {
+        self != Shell::Nu
+    }
+}
+
+impl fmt::Display for Shell {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// What determined the shell te works with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShellOrigin {
+    /// The `--shell` flag
+    Flag,
+    /// The `TE_SHELL` environment variable
+    Env,
+    /// The basename of `$SHELL`
+    Detected,
+}
+
+static SHELL_OVERRIDE: OnceLock<Shell> = OnceLock::new();
+
+/// Use `shell` instead of looking at the environment, as given with `--shell`
+pub fn override_shell(shell: Shell) {
+    let _ = SHELL_OVERRIDE.set(shell);
+}
+
+/// The shell te works with: `--shell`, then `$TE_SHELL`, then the basename
+/// of `$SHELL`. An unknown `$TE_SHELL` is an error, an unknown `$SHELL` isn't.
+pub fn resolve_shell() -> Result<Option<(Shell, ShellOrigin)>> {
+    if let Some(shell) = SHELL_OVERRIDE.get() {
+        return Ok(Some((*shell, ShellOrigin::Flag)));
+    }
+    if let Some(name) = std::env::var("TE_SHELL").ok().filter(|v| !v.is_empty()) {
+        return match Shell::from_name(&name) {
+            Some(shell) => Ok(Some((shell, ShellOrigin::Env))),
+            None => anyhow::bail!(
+                "Invalid value '{}' for TE_SHELL: expected one of {}",
+                name,
+                SHELL_NAMES
+            ),
+        };
+    }
+    let detected = std::env::var("SHELL")
+        .ok()
+        .and_then(|shell| Shell::from_name(shell.rsplit('/').next()?));
+    Ok(detected.map(|shell| (shell, ShellOrigin::Detected)))
+}
+
+/// Basename of the parent process's executable, like `zsh` or `-zsh` for a
+/// login shell, from `/proc` or else `ps`
+#[cfg(unix)]
+pub fn parent_process_name() -> Option<String> {
+    let ppid = std::os::unix::process::parent_id();
+    let name = match std::fs::read_to_string(format!("/proc/{}/comm", ppid)) {
+        Ok(name) => name,
+        Err(_) => {
+            let output = std::process::Command::new("ps")
+                .args(["-o", "comm=", "-p", &ppid.to_string()])
+                .stderr(std::process::Stdio::null())
+                .output()
+                .ok()?;
+            String::from_utf8(output.stdout).ok()?
+        }
+    };
+    let name = name.trim();
+    let name = name.rsplit('/').next().unwrap_or(name);
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+#[cfg(not(unix))]
+pub fn parent_process_name() -> Option<String> {
+    None
+}
+
+/// The shell te works with, see [`resolve_shell`]
+pub fn detect_shell() -> Option<Shell> {
+    resolve_shell().ok().flatten().map(|(shell, _)| shell)
+}
+
+/// Resolve the history file for `shell`.
+///
+/// `$HISTFILE` takes precedence for zsh and bash. It often isn't exported,
+/// though, so te run from a script or `$(...)` doesn't see it: zsh then
+/// looks for `.zsh_history` in `$ZDOTDIR` and `$HOME`, and `~/.histfile`.
+/// The other shells keep their history at a fixed place under the XDG
+/// directories.
+pub fn get_history_file(shell: Shell) -> Option<PathBuf> {
+    resolve_history_file(shell, |var| std::env::var_os(var), |path| path.exists())
+}
+
+/// [`get_history_file`] with the environment and file system passed in
+fn resolve_history_file(
+    shell: Shell,
+    var: impl Fn(&str) -> Option<OsString>,
+    exists: impl Fn(&Path) -> bool,
+) -> Option<PathBuf> {
+    let var = |name: &str| var(name).filter(|v| !v.is_empty()).map(PathBuf::from);
+    let home = var("HOME");
+    let xdg_dir = |name: &str, default: &str| {
+        var(name).or_else(|| home.as_ref().map(|home| home.join(default)))
+    };
+    match shell {
+        Shell::Zsh | Shell::Bash => {
+            if let Some(histfile) = var("HISTFILE") {
+                return Some(histfile);
+            }
+            if shell == Shell::Bash {
+                return home.map(|home| home.join(".bash_history"));
+            }
+            let zdotdir = var("ZDOTDIR");
+            let candidates: Vec<PathBuf> = [
+                zdotdir.as_ref().map(|dir| dir.join(".zsh_history")),
+                home.as_ref().map(|home| home.join(".zsh_history")),
+                home.as_ref().map(|home| home.join(".histfile")),
+            ]
+            .into_iter()
+            .flatten()
+            .collect();
+            // Where zsh would write it, if there is no history yet
+            candidates
+                .iter()
+                .find(|path| exists(path))
+                .or(candidates.first())
+                .cloned()
+        }
+        Shell::Fish => Some(xdg_dir("XDG_DATA_HOME", ".local/share")?.join("fish/fish_history")),
+        Shell::Nu => Some(xdg_dir("XDG_CONFIG_HOME", ".config")?.join("nushell/history.txt")),
+        Shell::Pwsh => Some(
+            xdg_dir("XDG_DATA_HOME", ".local/share")?
+                .join("powershell/PSReadLine/ConsoleHost_history.txt"),
+        ),
+        // Both keep history in a database rather than a file te can read
+        Shell::Elvish | Shell::Xonsh => None,
+    }
+}
+
+/// Read all history entries of `shell`, oldest first
+pub fn read_history(shell: Shell) -> Result<Vec<String>> {
+    let path = get_history_file(shell)
+        .with_context(|| format!("Could not locate the {} history file", shell.name()))?;
+    read_history_file(shell, &path)
+}
+
+/// Read all entries of the history file at `path` in `shell`'s format, oldest first
+pub fn read_history_file(shell: Shell, path: &Path) -> Result<Vec<String>> {
+    let content = std::fs::read(path)
+        .with_context(|| format!("Failed to read history file {}", path.display()))?;
+    Ok(parse_history(shell, &content))
+}
+
+/// Where history entries come from
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum HistorySource {
+    /// The history file of the shell from `--shell`, `$TE_SHELL` or `$SHELL`
+    #[default]
+    Detected,
+    /// A given history file, read in the detected shell's format (bash if unknown)
+    File(PathBuf),
+    /// History is not read at all
+    Disabled,
+}
+
+impl HistorySource {
+    /// Read all entries from this source, oldest first
+    pub fn read(&self) -> Result<Vec<String>> {
+        match self {
+            HistorySource::Detected => {
+                let shell = detect_shell().ok_or_else(|| {
+                    anyhow::anyhow!("Could not detect shell from $SHELL; set TE_SHELL or --shell")
+                })?;
+                read_history(shell)
+            }
+            HistorySource::File(path) => {
+                read_history_file(detect_shell().unwrap_or(Shell::Bash), path)
+            }
+            HistorySource::Disabled => Ok(Vec::new()),
+        }
+    }
+}
+
+/// Parse raw history file content into entries, oldest first
+pub fn parse_history(shell: Shell, content: &[u8]) -> Vec<String> {
+    match shell {
+        Shell::Zsh => parse_zsh_history(&unmetafy(content)),
+        // A file given for these is read as one command per line
+        Shell::Bash | Shell::Elvish | Shell::Xonsh => {
+            parse_bash_history(&String::from_utf8_lossy(content))
+        }
+        Shell::Fish => parse_fish_history(&String::from_utf8_lossy(content)),
+        Shell::Nu => parse_nu_history(&String::from_utf8_lossy(content)),
+        Shell::Pwsh => parse_pwsh_history(&String::from_utf8_lossy(content)),
+    }
+}
+
+/// Undo zsh's metafication of non-ASCII bytes in the history file.
+///
+/// zsh writes bytes that collide with its internal tokens (NUL and
+/// 0x83..=0xa2) as the meta byte 0x83 followed by the byte XOR 0x20.
+fn unmetafy(content: &[u8]) -> String {
+    const META: u8 = 0x83;
+    let mut bytes = Vec::with_capacity(content.len());
+    let mut iter = content.iter();
+    while let Some(&b) = iter.next() {
+        if b == META {
+            if let Some(&next) = iter.next() {
+                bytes.push(next ^ 0x20);
+            }
+        } else {
+            bytes.push(b);
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Parse zsh history in plain or extended (`: <start>:<elapsed>;<command>`) format.
+///
+/// Multi-line commands are stored with a backslash before each embedded
+/// newline; those lines are joined back into one entry.
+fn parse_zsh_history(content: &str) -> Vec<String> {
+    let mut entries = Vec::new();
+    let mut current: Option<String> = None;
+
+    for line in content.lines() {
+        let text = match current.take() {
+            Some(mut pending) => {
+                pending.push_str(line);
+                pending
+            }
+            None => strip_zsh_extended_prefix(line).to_string(),
+        };
+
+        if text.ends_with('\\') {
+            current = Some(format!("{}\n", text));
+        } else if !text.is_empty() {
+            entries.push(text);
+        }
+    }
+    if let Some(pending) = current {
+        entries.push(pending.trim_end_matches('\n').to_string());
+    }
+    entries
+}
+
+fn strip_zsh_extended_prefix(line: &str) -> &str {
+    if let Some(rest) = line.strip_prefix(": ")
+        && let Some((meta, command)) = rest.split_once(';')
+        && meta
+            .split(':')
+            .all(|part| part.chars().all(|c| c.is_ascii_digit()))
+    {
+        return command;
+    }
+    line
+}
+
+/// Parse bash history, skipping the `#<timestamp>` lines written with HISTTIMEFORMAT
+fn parse_bash_history(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .filter(|line| !line.is_empty() && !is_bash_timestamp(line))
+        .map(str::to_string)
+        .collect()
+}
+
+fn is_bash_timestamp(line: &str) -> bool {
+    line.strip_prefix('#')
+        .is_some_and(|ts| !ts.is_empty() && ts.chars().all(|c| c.is_ascii_digit()))
+}
+
+/// Parse fish history, which stores entries as `- cmd: <escaped command>`
+fn parse_fish_history(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .filter_map(|line| line.strip_prefix("- cmd: "))
+        .map(unescape_fish)
+        .collect()
+}
+
+/// Parse nushell's plain text history, which writes newlines inside an
+/// entry as `<\n>`
+fn parse_nu_history(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| line.replace("<\\n>", "\n"))
+        .collect()
+}
+
+/// Parse PSReadLine history, where a line ending in a backtick continues
+/// the entry on the next line
+fn parse_pwsh_history(content: &str) -> Vec<String> {
+    let mut entries = Vec::new();
+    let mut current = String::new();
+    for line in content.lines() {
+        match line.strip_suffix('`') {
+            Some(continued) => {
+                current.push_str(continued);
+                current.push('\n');
+            }
+            None => {
+                current.push_str(line);
+                if !current.is_empty() {
+                    entries.push(std::mem::take(&mut current));
+                }
+            }
+        }
+    }
+    if !current.is_empty() {
+        entries.push(current);
+    }
+    entries
+}
+
+fn unescape_fish(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('n') => result.push('\n'),
+                Some('\\') => result.push('\\'),
+                Some(other) => {
+                    result.push('\\');
+                    result.push(other);
+                }
+                None => result.push('\\'),
+            }
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
+/// Whether a history entry is an invocation of te itself
+fn is_te_invocation(entry: &str) -> bool {
+    let first = entry.split_whitespace().next().unwrap_or("");
+    let name = first.rsplit('/').next().unwrap_or(first);
+    matches!(name, "te" | "te-run")
+}
+
+/// Returns the `n`th most recent entry (1 = newest), skipping invocations of te
+pub fn last_command(entries: &[String], n: usize) -> Option<&str> {
+    entries
+        .iter()
+        .rev()
+        .filter(|entry| !entry.trim().is_empty() && !is_te_invocation(entry))
+        .nth(n.checked_sub(1)?)
+        .map(String::as_str)
+}
+
+/// A command that runs another command given after its own arguments, like `sudo`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Wrapper {
+    pub name: String,
+    /// Options of the wrapper that take a separate value (e.g. `-n` of `watch`)
+    pub value_flags: Vec<String>,
+    /// Positional arguments of the wrapper before the wrapped command
+    /// (e.g. the duration of `timeout`)
+    pub positionals: usize,
+}
+
+impl Wrapper {
+    /// A wrapper that takes no positional arguments and only valueless options
+    pub fn simple(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            value_flags: Vec::new(),
+            positionals: 0,
+        }
+    }
+
+    fn with(name: &str, value_flags: &[&str], positionals: usize) -> Self {
+        Self {
+            name: name.to_string(),
+            value_flags: value_flags.iter().map(|f| f.to_string()).collect(),
+            positionals,
+        }
+    }
+}
+
+/// Wrappers recognized by default
+pub fn default_wrappers() -> Vec<Wrapper> {
+    vec![
+        Wrapper::with(
+            "sudo",
+            &["-u", "-g", "-C", "-D", "-h", "-p", "-r", "-t", "-U"],
+            0,
+        ),
+        Wrapper::with("doas", &["-u", "-C"], 0),
+        Wrapper::with("watch", &["-n", "--interval"], 0),
+        Wrapper::with("timeout", &["-s", "--signal", "-k", "--kill-after"], 1),
+        Wrapper::with("nice", &["-n", "--adjustment"], 0),
+        Wrapper::with(
+            "ionice",
+            &["-c", "--class", "-n", "--classdata", "-p", "--pid"],
+            0,
+        ),
+        Wrapper::simple("time"),
+        Wrapper::simple("nohup"),
+        Wrapper::simple("command"),
+        Wrapper::simple("exec"),
+    ]
+}
+
+/// Number of leading tokens that belong to wrappers rather than the real command.
+///
+/// Wrappers may be nested, as in `sudo timeout 5 kubectl get pods`.
+pub fn wrapper_len<S: AsRef<str>>(tokens: &[S], wrappers: &[Wrapper]) -> usize {
+    let mut i = 0;
+    while let Some(wrapper) = tokens
+        .get(i)
+        .and_then(|token| wrappers.iter().find(|w| w.name == token.as_ref()))
+    {
+        i += 1;
+        let mut positionals = wrapper.positionals;
+        while let Some(token) = tokens.get(i).map(AsRef::as_ref) {
+            if token == "--" {
+                i += 1;
+                break;
+            } else if token.starts_with('-') && token.len() > 1 {
+                i += 1;
+                if wrapper.value_flags.iter().any(|f| f == token) {
+                    i += 1;
+                }
+            } else if positionals > 0 {
+                positionals -= 1;
+                i += 1;
+            } else {
+                break;
+            }
+        }
+    }
+    i.min(tokens.len())
+}
+
+/// Options that some tools take before their subcommand, and that have a
+/// separate value (e.g. `-H` in `docker -H unix:///run/docker.sock run`)
+const GLOBAL_VALUE_FLAGS: [(&str, &[&str]); 4] = [
+    (
+        "docker",
+        &[
+            "-H",
+            "--host",
+            "-c",
+            "--context",
+            "--config",
+            "-l",
+            "--log-level",
+        ],
+    ),
+    (
+        "git",
+        &["-C", "-c", "--git-dir", "--work-tree", "--namespace"],
+    ),
+    (
+        "kubectl",
+        &[
+            "-n",
+            "--namespace",
+            "--context",
+            "--kubeconfig",
+            "--cluster",
+            "--user",
+            "-s",
+            "--server",
+        ],
+    ),
+    (
+        "helm",
+        &["-n", "--namespace", "--kube-context", "--kubeconfig"],
+    ),
+];
+
+/// Positions of the words that name a command: the program and its subcommands.
+///
+/// For tools in `GLOBAL_VALUE_FLAGS`, options between the program and its
+/// first subcommand are skipped, so `run` is the subcommand of
+/// `docker -H x run -it image`. Otherwise the words end at the first option.
+pub fn command_word_positions<S: AsRef<str>>(tokens: &[S]) -> Vec<usize> {
+    let Some(program) = tokens.first() else {
+        return Vec::new();
+    };
+    let global_flags = GLOBAL_VALUE_FLAGS
+        .iter()
+        .find(|(name, _)| *name == program.as_ref())
+        .map(|(_, flags)| *flags);
+
+    let mut positions = vec![0];
+    let mut i = 1;
+    while let Some(token) = tokens.get(i).map(AsRef::as_ref) {
+        if !token.starts_with('-') {
+            positions.push(i);
+            i += 1;
+            continue;
+        }
+        match global_flags {
+            // Global options only come before the first subcommand
+            Some(flags) if positions.len() == 1 && token != "--" => {
+                i += if flags.contains(&token) { 2 } else { 1 };
+            }
+            _ => break,
+        }
+    }
+    positions
+}
+
+/// The words of a command that identify what it is, without its options.
+///
+/// For `kubectl get pods -n prod` this is `["kubectl", "get", "pods"]`. Leading
+/// wrappers are skipped, so `timeout 5 kubectl get pods` has the same base, and
+/// so are global options before a subcommand, so `docker -H x run -it image`
+/// has the base `["docker", "run"]`.
+pub fn base_command(components: &[String], wrappers: &[Wrapper]) -> Vec<String> {
+    let components = &components[wrapper_len(components, wrappers)..];
+    command_word_positions(components)
+        .into_iter()
+        .map(|i| components[i].clone())
+        .collect()
+}
+
+/// The tokens of a history entry other than its command words, if those start
+/// with `base_command`.
+///
+/// An empty base command matches nothing rather than every entry.
+fn strip_base_command(tokens: &[String], base_command: &[String]) -> Option<Vec<String>> {
+    if base_command.is_empty() {
+        return None;
+    }
+    let positions = command_word_positions(tokens);
+    let matched = positions.get(..base_command.len())?;
+    if !matched
+        .iter()
+        .zip(base_command)
+        .all(|(&i, b)| tokens[i] == *b)
+    {
+        return None;
+    }
+    Some(
+        tokens
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !matched.contains(i))
+            .map(|(_, token)| token.clone())
+            .collect(),
+    )
+}
+
+/// Words that mark a flag's values as secrets, which are never learned from history
+const SENSITIVE_FLAG_WORDS: [&str; 6] =
+    ["password", "passwd", "token", "secret", "apikey", "api-key"];
+
+/// Whether values of `flag` may be secrets, like `--password` or `--auth-token`
+pub fn is_sensitive_flag(flag: &str) -> bool {
+    let name = flag.trim_start_matches('-').to_ascii_lowercase();
+    SENSITIVE_FLAG_WORDS.iter().any(|word| name.contains(word))
+}
+
+/// Flag/value pairs in a tokenized command, in either `--flag=value` or `-f value` form.
+///
+/// A separate flag takes as many following words as `arities` says. Flags
+/// without a value leave the next word alone, and values of flags taking
+/// several are skipped rather than learned one by one.
+fn extract_flag_values(tokens: &[String], arities: &FlagArities) -> Vec<(String, String)> {
+    let mut pairs = Vec::new();
+    let mut iter = tokens.iter().peekable();
+    while let Some(token) = iter.next() {
+        if token == "--" {
+            break;
+        }
+        if !token.starts_with('-') || token == "-" {
+            continue;
+        }
+        if let Some((flag, value)) = token.split_once('=') {
+            pairs.push((flag.to_string(), value.to_string()));
+            continue;
+        }
+        let values: Vec<&String> = (0..arities.values(token))
+            .map_while(|_| iter.next_if(|next| !next.starts_with('-')))
+            .collect();
+        if let [value] = values.as_slice() {
+            pairs.push((token.clone(), (*value).clone()));
+        }
+    }
+    pairs
+}
+
+/// Collect the values used with each flag in history entries matching `base_command`.
+///
+/// Wrappers in front of history entries are skipped before matching, so
+/// `sudo kubectl get pods -n x` counts towards `kubectl get pods`. Entries are
+/// consumed newest first. Candidates are ranked by how often they
+/// were used, with ties going to the most recently used value. If `deadline`
+/// passes, whatever was gathered so far is returned. An empty base command
+/// yields no suggestions, and values of sensitive flags are skipped.
+pub fn load_history_for_command(
+    base_command: &[String],
+    wrappers: &[Wrapper],
+    arities: &FlagArities,
+    entries: impl Iterator<Item = String>,
+    deadline: Option<Instant>,
+) -> Suggestions {
+    if base_command.is_empty() {
+        return Suggestions::new();
+    }
+
+    // flag -> (value -> (count, recency rank of first sighting))
+    let mut seen: HashMap<String, HashMap<String, (usize, usize)>> = HashMap::new();
+
+    for (recency, entry) in entries.enumerate() {
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            break;
+        }
+        let Some(tokens) = shlex::split(&entry.replace("\\\n", " ")) else {
+            continue;
+        };
+        let tokens = &tokens[wrapper_len(&tokens, wrappers)..];
+        let Some(rest) = strip_base_command(tokens, base_command) else {
+            continue;
+        };
+        for (flag, value) in extract_flag_values(&rest, arities) {
+            if is_sensitive_flag(&flag) {
+                continue;
+            }
+            let stats = seen
+                .entry(flag)
+                .or_default()
+                .entry(value)
+                .or_insert((0, recency));
+            stats.0 += 1;
+        }
+    }
+
+    seen.into_iter()
+        .map(|(flag, values)| {
+            let mut ranked: Vec<_> = values.into_iter().collect();
+            ranked.sort_by(|(_, (count_a, recency_a)), (_, (count_b, recency_b))| {
+                count_b.cmp(count_a).then(recency_a.cmp(recency_b))
+            });
+            let candidates = ranked
+                .into_iter()
+                .map(|(value, (count, _))| Candidate { value, count })
+                .collect();
+            (flag, candidates)
+        })
+        .collect()
+}
+
+/// History entries and the suggestions learned from them for each base
+/// command, kept for the rest of the run so that editing several commands
+/// reads the history file once. Nothing in it is invalidated.
+#[derive(Debug, Default)]
+pub struct HistoryCache {
+    entries: Option<Vec<String>>,
+    suggestions: HashMap<Vec<String>, Suggestions>,
+}
+
+impl HistoryCache {
+    /// The suggestions for `base_command`, learned with `learn` the first
+    /// time it is asked for. The entries are only read, with `read`, the
+    /// first time any base command is.
+    pub fn suggestions(
+        &mut self,
+        base_command: &[String],
+        read: impl FnOnce() -> Vec<String>,
+        learn: impl FnOnce(&[String]) -> Suggestions,
+    ) -> Suggestions {
+        if let Some(suggestions) = self.suggestions.get(base_command) {
+            return suggestions.clone();
+        }
+        let entries = self.entries.get_or_insert_with(read);
+        let suggestions = learn(entries);
+        self.suggestions
+            .insert(base_command.to_vec(), suggestions.clone());
+        suggestions
+    }
+}
+
+/// Load suggestions for `base_command` on a background thread.
+///
+/// Only the `limit` most recent entries are used, if given. Waits up to
+/// `budget` for the result. If loading takes longer, returns a
+/// receiver that delivers the suggestions once the thread finishes. What
+/// was read and learned is kept in `cache` for the next command.
+pub fn load_history_in_background(
+    source: HistorySource,
+    cache: Arc<Mutex<HistoryCache>>,
+    base_command: Vec<String>,
+    wrappers: Vec<Wrapper>,
+    arities: FlagArities,
+    limit: Option<usize>,
+    budget: Duration,
+) -> std::result::Result<Suggestions, mpsc::Receiver<Suggestions>> {
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        let Ok(mut cache) = cache.lock() else {
+            return;
+        };
+        let suggestions = cache.suggestions(
+            &base_command,
+            || profile::time(Phase::HistoryRead, || source.read().unwrap_or_default()),
+            |entries| {
+                let entries = entries
+                    .iter()
+                    .rev()
+                    .take(limit.unwrap_or(usize::MAX))
+                    .cloned();
+                profile::time(Phase::History, || {
+                    load_history_for_command(&base_command, &wrappers, &arities, entries, None)
+                })
+            },
+        );
+        let _ = tx.send(suggestions);
+    });
+
+    match rx.recv_timeout(budget) {
+        Ok(suggestions) => Ok(suggestions),
+        Err(mpsc::RecvTimeoutError::Timeout) => Err(rx),
+        Err(mpsc::RecvTimeoutError::Disconnected) => Ok(Suggestions::new()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve_with(shell: Shell, vars: &[(&str, &str)], existing: &[&str]) -> Option<PathBuf> {
+        resolve_history_file(
+            shell,
+            |name| {
+                vars.iter()
+                    .find(|(var, _)| *var == name)
+                    .map(|(_, value)| OsString::from(value))
+            },
+            |path| existing.iter().any(|e| Path::new(e) == path),
+        )
+    }
+
+    #[test]
+    fn test_resolve_zsh_history_file() {
+        let home = ("HOME", "/home/u");
+        let zdotdir = ("ZDOTDIR", "/home/u/.config/zsh");
+        let path = |p: &str| Some(PathBuf::from(p));
+
+        // $HISTFILE wins, even over an existing file elsewhere
+        assert_eq!(
+            resolve_with(
+                Shell::Zsh,
+                &[home, ("HISTFILE", "/h")],
+                &["/home/u/.zsh_history"]
+            ),
+            path("/h")
+        );
+        // Not exported: the first file that exists
+        assert_eq!(
+            resolve_with(
+                Shell::Zsh,
+                &[home, zdotdir],
+                &["/home/u/.config/zsh/.zsh_history"]
+            ),
+            path("/home/u/.config/zsh/.zsh_history")
+        );
+        assert_eq!(
+            resolve_with(Shell::Zsh, &[home, zdotdir], &["/home/u/.zsh_history"]),
+            path("/home/u/.zsh_history")
+        );
+        assert_eq!(
+            resolve_with(Shell::Zsh, &[home], &["/home/u/.histfile"]),
+            path("/home/u/.histfile")
+        );
+        // None yet: where zsh would put it
+        assert_eq!(
+            resolve_with(Shell::Zsh, &[home, zdotdir], &[]),
+            path("/home/u/.config/zsh/.zsh_history")
+        );
+        assert_eq!(
+            resolve_with(Shell::Zsh, &[home, ("HISTFILE", "")], &[]),
+            path("/home/u/.zsh_history")
+        );
+        assert_eq!(resolve_with(Shell::Zsh, &[], &[]), None);
+        // bash doesn't look at the zsh locations
+        assert_eq!(
+            resolve_with(Shell::Bash, &[home, zdotdir], &["/home/u/.histfile"]),
+            path("/home/u/.bash_history")
+        );
+    }
+
+    #[test]
+    fn test_parse_zsh_extended_history() {
+        let content = b": 1700000000:0;kubectl get pods\n: 1700000001:3;ls -la\n";
+        assert_eq!(
+            parse_history(Shell::Zsh, content),
+            vec!["kubectl get pods", "ls -la"]
+        );
+    }
+
+    #[test]
+    fn test_parse_zsh_plain_and_multiline_history() {
+        let content = b"echo one\n: 1700000000:0;gcloud create \\\n  --flag=x\necho two\n";
+        assert_eq!(
+            parse_history(Shell::Zsh, content),
+            vec!["echo one", "gcloud create \\\n  --flag=x", "echo two"]
+        );
+    }
+
+    #[test]
+    fn test_parse_zsh_metafied_history() {
+        // "—" is 0xe2 0x80 0x94; zsh writes 0x94 as 0x83 0xb4
+        let content = b"echo \xe2\x80\x83\xb4\n";
+        assert_eq!(parse_history(Shell::Zsh, content), vec!["echo —"]);
+    }
+
+    #[test]
+    fn test_parse_bash_history() {
+        let content = b"#1700000000\nls -la\n\n# a comment\ngit status\n";
+        assert_eq!(
+            parse_history(Shell::Bash, content),
+            vec!["ls -la", "# a comment", "git status"]
+        );
+    }
+
+    #[test]
+    fn test_parse_fish_history() {
+        let content =
+            b"- cmd: ls -la\n  when: 1700000000\n- cmd: echo a\\nb \\\\ c\n  when: 1700000001\n";
+        assert_eq!(
+            parse_history(Shell::Fish, content),
+            vec!["ls -la", "echo a\nb \\ c"]
+        );
+    }
+
+    #[test]
+    fn test_parse_nu_history() {
+        let content = b"ls | where size > 1kb\nlet x = 1<\\n>$x\n";
+        assert_eq!(
+            parse_history(Shell::Nu, content),
+            vec!["ls | where size > 1kb", "let x = 1\n$x"]
+        );
+    }
+
+    #[test]
+    fn test_parse_pwsh_history() {
+        let content = b"Get-ChildItem -Force\r\nforeach ($x in 1..2) {`\r\n  $x`\r\n}\r\n";
+        assert_eq!(
+            parse_history(Shell::Pwsh, content),
+            vec!["Get-ChildItem -Force", "foreach ($x in 1..2) {\n  $x\n}"]
+        );
+    }
+
+    #[test]
+    fn test_last_command_skips_te() {
+        let entries: Vec<String> = ["kubectl get pods", "ls", "te ls", "/usr/bin/te --last"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        assert_eq!(last_command(&entries, 1), Some("ls"));
+        assert_eq!(last_command(&entries, 2), Some("kubectl get pods"));
+        assert_eq!(last_command(&entries, 3), None);
+        assert_eq!(last_command(&entries, 0), None);
+    }
+
+    fn entries(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn values(suggestions: &Suggestions, flag: &str) -> Vec<String> {
+        suggestions[flag].iter().map(|c| c.value.clone()).collect()
+    }
+
+    #[test]
+    fn test_base_command() {
+        let components = entries(&["kubectl", "get", "pods", "-n", "prod", "web"]);
+        assert_eq!(
+            base_command(&components, &default_wrappers()),
+            vec!["kubectl", "get", "pods"]
+        );
+    }
+
+    #[test]
+    fn test_base_command_skips_global_flags() {
+        let base = |s: &str| base_command(&entries(&s.split(' ').collect::<Vec<_>>()), &[]);
+
+        assert_eq!(base("docker -H x run -it image"), vec!["docker", "run"]);
+        assert_eq!(
+            base("docker --debug --host=x run -it image"),
+            vec!["docker", "run"]
+        );
+        assert_eq!(
+            base("kubectl -n prod get pods -o wide"),
+            vec!["kubectl", "get", "pods"]
+        );
+        assert_eq!(base("git -C repo log --oneline"), vec!["git", "log"]);
+        // Other tools stop at the first flag
+        assert_eq!(base("ls -la dir"), vec!["ls"]);
+    }
+
+    #[test]
+    fn test_load_history_with_global_flags() {
+        let history = entries(&[
+            "docker -H ssh://prod run -it nginx",
+            "docker run -it --name web nginx",
+            "docker -H ssh://staging ps -a",
+        ]);
+        let base = entries(&["docker", "run"]);
+
+        let suggestions = load_history_for_command(
+            &base,
+            &[],
+            &FlagArities::default(),
+            history.into_iter().rev(),
+            None,
+        );
+
+        assert_eq!(values(&suggestions, "-H"), vec!["ssh://prod"]);
+        assert_eq!(values(&suggestions, "--name"), vec!["web"]);
+    }
+
+    #[test]
+    fn test_base_command_skips_wrappers() {
+        let wrappers = default_wrappers();
+        let base = |s: &str| base_command(&entries(&s.split(' ').collect::<Vec<_>>()), &wrappers);
+
+        assert_eq!(
+            base("timeout 5 kubectl get pods"),
+            vec!["kubectl", "get", "pods"]
+        );
+        assert_eq!(
+            base("timeout -s KILL 5s kubectl get pods"),
+            vec!["kubectl", "get", "pods"]
+        );
+        assert_eq!(
+            base("sudo -u root systemctl restart nginx"),
+            vec!["systemctl", "restart", "nginx"]
+        );
+        assert_eq!(
+            base("watch -n 2 kubectl get pods"),
+            vec!["kubectl", "get", "pods"]
+        );
+        // `-d` of watch is a switch, not an option with a value
+        assert_eq!(
+            base("watch -d kubectl get pods"),
+            vec!["kubectl", "get", "pods"]
+        );
+        assert_eq!(base("nice -n 10 ionice -c 3 make"), vec!["make"]);
+        assert_eq!(base("sudo timeout 5 ls"), vec!["ls"]);
+        // Not a wrapper without configuration
+        assert_eq!(base("xargs kubectl"), vec!["xargs", "kubectl"]);
+        assert_eq!(
+            base_command(
+                &entries(&["xargs", "-0", "kubectl"]),
+                &[Wrapper::simple("xargs")]
+            ),
+            vec!["kubectl"]
+        );
+    }
+
+    #[test]
+    fn test_history_cache_reads_once() {
+        let history = ["kubectl get pods -n prod", "kubectl logs -n staging web"];
+        let reads = std::cell::Cell::new(0);
+        let read = || {
+            reads.set(reads.get() + 1);
+            history.iter().map(|e| e.to_string()).collect()
+        };
+        let learns = std::cell::Cell::new(0);
+        fn learn<'a>(
+            base: &'a [String],
+            learns: &'a std::cell::Cell<usize>,
+        ) -> impl FnOnce(&[String]) -> Suggestions + 'a {
+            move |entries| {
+                learns.set(learns.get() + 1);
+                load_history_for_command(
+                    base,
+                    &[],
+                    &FlagArities::default(),
+                    entries.iter().cloned(),
+                    None,
+                )
+            }
+        }
+        let get_pods: Vec<String> = ["kubectl", "get", "pods"].map(String::from).into();
+        let logs: Vec<String> = ["kubectl", "logs"].map(String::from).into();
+
+        let mut cache = HistoryCache::default();
+        let first = cache.suggestions(&get_pods, read, learn(&get_pods, &learns));
+        assert_eq!(first["-n"][0].value, "prod");
+        assert_eq!(
+            cache.suggestions(&get_pods, read, learn(&get_pods, &learns)),
+            first
+        );
+        assert_eq!((reads.get(), learns.get()), (1, 1));
+
+        // Another base command learns from the entries already read
+        let second = cache.suggestions(&logs, read, learn(&logs, &learns));
+        assert_eq!(second["-n"][0].value, "staging");
+        assert_eq!((reads.get(), learns.get()), (1, 2));
+    }
+
+    #[test]
+    fn test_load_history_for_command() {
+        let history = entries(&[
+            "kubectl get pods -n prod",
+            "kubectl get svc -n other",
+            "kubectl get pods -n staging --output=json",
+            "kubectl get pods -n prod -o wide",
+        ]);
+        let base = entries(&["kubectl", "get", "pods"]);
+
+        let suggestions = load_history_for_command(
+            &base,
+            &[],
+            &FlagArities::default(),
+            history.into_iter().rev(),
+            None,
+        );
+
+        assert_eq!(values(&suggestions, "-n"), vec!["prod", "staging"]);
+        assert_eq!(suggestions["-n"][0].count, 2);
+        assert_eq!(values(&suggestions, "--output"), vec!["json"]);
+        assert_eq!(values(&suggestions, "-o"), vec!["wide"]);
+        assert!(!suggestions.contains_key("other"));
+    }
+
+    #[test]
+    fn test_load_history_empty_base_command() {
+        let history = entries(&["kubectl get pods -n prod", "ls --color=auto"]);
+
+        let suggestions = load_history_for_command(
+            &[],
+            &[],
+            &FlagArities::default(),
+            history.into_iter().rev(),
+            None,
+        );
+
+        assert!(suggestions.is_empty());
+        assert_eq!(strip_base_command(&entries(&["ls"]), &[]), None);
+    }
+
+    #[test]
+    fn test_load_history_skips_sensitive_flags() {
+        let history = entries(&[
+            "mysql -u root --password=hunter2",
+            "mysql -u admin --auth-token abc",
+            "mysql -u root -h db",
+        ]);
+        let base = entries(&["mysql"]);
+
+        let suggestions = load_history_for_command(
+            &base,
+            &[],
+            &FlagArities::default(),
+            history.into_iter().rev(),
+            None,
+        );
+
+        assert_eq!(values(&suggestions, "-u"), vec!["root", "admin"]);
+        assert!(!suggestions.contains_key("--password"));
+        assert!(!suggestions.contains_key("--auth-token"));
+        assert!(is_sensitive_flag("--API-KEY"));
+        assert!(!is_sensitive_flag("--host"));
+    }
+
+    #[test]
+    fn test_load_history_respects_flag_arities() {
+        let history = entries(&["cp -r src dst", "cp -v -t backup src", "cp -r -t dir other"]);
+        let base = entries(&["cp"]);
+        let arities = FlagArities::for_program("cp", &Default::default());
+
+        let suggestions =
+            load_history_for_command(&base, &[], &arities, history.into_iter().rev(), None);
+
+        // `-r` and `-v` take no value, `-t` exactly one
+        assert!(!suggestions.contains_key("-r"));
+        assert!(!suggestions.contains_key("-v"));
+        assert_eq!(values(&suggestions, "-t"), vec!["dir", "backup"]);
+    }
+
+    #[test]
+    fn test_load_history_skips_wrappers_in_entries() {
+        let history = entries(&[
+            "sudo kubectl get pods -n kube-system",
+            "timeout 5 kubectl get pods -n prod",
+        ]);
+        let base = entries(&["kubectl", "get", "pods"]);
+
+        let suggestions = load_history_for_command(
+            &base,
+            &default_wrappers(),
+            &FlagArities::default(),
+            history.into_iter().rev(),
+            None,
+        );
+
+        assert_eq!(values(&suggestions, "-n"), vec!["prod", "kube-system"]);
+    }
+
+    #[test]
+    fn test_load_history_ranks_ties_by_recency() {
+        let history = entries(&["ls --color=never", "ls --color=auto"]);
+        let base = entries(&["ls"]);
+
+        let suggestions = load_history_for_command(
+            &base,
+            &[],
+            &FlagArities::default(),
+            history.into_iter().rev(),
+            None,
+        );
+
+        assert_eq!(values(&suggestions, "--color"), vec!["auto", "never"]);
+    }
+
+    #[test]
+    fn test_load_history_respects_deadline() {
+        // A slow source: each entry takes 20ms to produce
+        let slow = (0..100).map(|i| {
+            std::thread::sleep(Duration::from_millis(20));
+            format!("kubectl get pods -n ns{}", i)
+        });
+        let base = entries(&["kubectl", "get", "pods"]);
+
+        let start = Instant::now();
+        let suggestions = load_history_for_command(
+            &base,
+            &[],
+            &FlagArities::default(),
+            slow,
+            Some(start + Duration::from_millis(100)),
+        );
+
+        assert!(start.elapsed() < Duration::from_millis(500));
+        let gathered = suggestions.get("-n").map_or(0, Vec::len);
+        assert!(gathered > 0 && gathered < 100);
+    }
+}
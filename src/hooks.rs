@@ -0,0 +1,70 @@
+use std::{
+    process::{Command, Stdio},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+
+use crate::providers::run_capped_with_input;
+
+/// How long `post_command` may run before it is given up on
+const POST_COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How much of `post_command`'s output is read; the rest is dropped
+const MAX_POST_COMMAND_OUTPUT: usize = 1024 * 1024;
+
+/// Run the configured `post_command` with `sh -c`, feeding it `command` on
+/// stdin. Its stdout, without the trailing newline, becomes the result.
+pub fn run_post_command(post_command: &str, command: &str) -> Result<String> {
+    run_post_command_with_timeout(post_command, command, POST_COMMAND_TIMEOUT)
+}
+
+fn run_post_command_with_timeout(
+    post_command: &str,
+    command: &str,
+    timeout: Duration,
+) -> Result<String> {
+    let mut child = Command::new("sh");
+    child.arg("-c").arg(post_command).stderr(Stdio::inherit());
+    let output = run_capped_with_input(
+        child,
+        post_command,
+        Some(&format!("{}\n", command)),
+        timeout,
+        MAX_POST_COMMAND_OUTPUT,
+    )?;
+    let stdout = String::from_utf8(output)
+        .with_context(|| format!("'{}' printed invalid UTF-8", post_command))?;
+    let result = stdout.strip_suffix('\n').unwrap_or(&stdout);
+    if result.trim().is_empty() {
+        anyhow::bail!("'{}' printed nothing", post_command);
+    }
+    Ok(result.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_post_command() {
+        assert_eq!(run_post_command("tr a-z A-Z", "ls -la").unwrap(), "LS -LA");
+        assert!(run_post_command("exit 3", "ls").is_err());
+        assert!(run_post_command("true", "ls").is_err());
+    }
+
+    #[test]
+    fn test_run_post_command_times_out() {
+        let err =
+            run_post_command_with_timeout("sleep 5", "ls", Duration::from_millis(50)).unwrap_err();
+        assert!(err.to_string().contains("timed out"), "{}", err);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_post_command_leaves_background_jobs() {
+        let started = std::time::Instant::now();
+        assert_eq!(run_post_command("sleep 20 & cat", "ls").unwrap(), "ls");
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
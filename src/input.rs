@@ -0,0 +1,366 @@
+use std::{fs::OpenOptions, io::ErrorKind, path::Path};
+
+use anyhow::{Context, Result};
+
+/// Normalize piped input: CRLF line endings become LF and a single trailing
+/// newline is dropped. Other whitespace is left for the parser to handle.
+pub fn normalize_newlines(raw: &str) -> String {
+    let text = raw.replace("\r\n", "\n");
+    match text.strip_suffix('\n') {
+        Some(stripped) => stripped.to_string(),
+        None => text,
+    }
+}
+
+/// Split input into separate commands at line breaks.
+///
+/// A newline only ends a command when it is outside quotes and not escaped
+/// by a backslash (a line continuation). Blank lines are dropped. Lines with
+/// only a `# comment` go with the command after them, and are dropped after
+/// the last one; quotes in comments don't count.
+pub fn split_commands(text: &str) -> Vec<String> {
+    let mut commands = Vec::new();
+    let mut current = String::new();
+    // Whether `current` has more than comments
+    let mut has_command = false;
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+    let mut comment = false;
+
+    for c in text.chars() {
+        if comment && c != '\n' {
+            current.push(c);
+            continue;
+        }
+        comment = false;
+        if escaped {
+            escaped = false;
+        } else if c == '\\' && quote != Some('\'') {
+            escaped = true;
+        } else if let Some(q) = quote {
+            if c == q {
+                quote = None;
+            }
+        } else if c == '\'' || c == '"' {
+            quote = Some(c);
+        } else if c == '#' && current.chars().last().is_none_or(char::is_whitespace) {
+            comment = true;
+            current.push(c);
+            continue;
+        } else if c == '\n' {
+            if has_command {
+                commands.push(std::mem::take(&mut current));
+                has_command = false;
+            } else if current.trim().is_empty() {
+                current.clear();
+            } else {
+                current.push(c);
+            }
+            continue;
+        }
+        has_command |= !c.is_whitespace();
+        current.push(c);
+    }
+    if has_command {
+        commands.push(current);
+    }
+    commands
+}
+
+/// The comment lines a command from [`split_commands`] starts with, each
+/// with its newline
+pub fn leading_comments(command: &str) -> &str {
+    let mut end = 0;
+    for line in command.split_inclusive('\n') {
+        if !line.trim_start().starts_with('#') || !line.ends_with('\n') {
+            break;
+        }
+        end += line.len();
+    }
+    &command[..end]
+}
+
+/// Read the command string from an inherited file descriptor.
+///
+/// The descriptor is validated with `fcntl(F_GETFD)` before use, so an fd that
+/// isn't open in this process produces an error instead of undefined behavior.
+/// Ownership of the descriptor is taken over and it is closed after reading.
+#[cfg(unix)]
+pub fn read_from_fd(fd: i32) -> Result<String> {
+    use std::fs::File;
+    use std::io::Read;
+    use std::os::fd::FromRawFd;
+
+    // SAFETY: fcntl with F_GETFD only queries descriptor flags
+    if fd < 0 || unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+        anyhow::bail!("Invalid file descriptor: {}", fd);
+    }
+
+    // SAFETY: the descriptor was verified to be open above and nothing else in
+    // te uses it, so the File can take ownership
+    let mut file = unsafe { File::from_raw_fd(fd) };
+    let mut buffer = String::new();
+    file.read_to_string(&mut buffer)?;
+    Ok(buffer)
+}
+
+#[cfg(not(unix))]
+pub fn read_from_fd(_fd: i32) -> Result<String> {
+    anyhow::bail!("--fd is only supported on unix platforms")
+}
+
+/// Whether stdin is a pipe, as in `echo ls | te`, rather than a terminal, a
+/// file or /dev/null
+#[cfg(unix)]
+pub fn stdin_is_pipe() -> bool {
+    use std::os::fd::AsFd;
+    use std::os::unix::fs::FileTypeExt;
+
+    std::io::stdin()
+        .as_fd()
+        .try_clone_to_owned()
+        .and_then(|fd| std::fs::File::from(fd).metadata())
+        .is_ok_and(|meta| meta.file_type().is_fifo())
+}
+
+#[cfg(not(unix))]
+pub fn stdin_is_pipe() -> bool {
+    false
+}
+
+/// Open an inherited file descriptor to write the result to, validated the
+/// same way as in [`read_from_fd`]. The descriptor is closed when the returned
+/// File is dropped.
+#[cfg(unix)]
+pub fn open_result_fd(fd: i32) -> Result<std::fs::File> {
+    use std::fs::File;
+    use std::os::fd::FromRawFd;
+
+    // SAFETY: fcntl with F_GETFD only queries descriptor flags
+    if fd < 0 || unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+        anyhow::bail!("Invalid file descriptor: {}", fd);
+    }
+
+    // SAFETY: the descriptor was verified to be open above and nothing else in
+    // te uses it, so the File can take ownership
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+#[cfg(not(unix))]
+pub fn open_result_fd(_fd: i32) -> Result<std::fs::File> {
+    anyhow::bail!("--result-fd is only supported on unix platforms")
+}
+
+/// Split a leading `#!` line off a script, so a file like `deploy.sh` can
+/// hold a shebang above its command
+pub fn split_shebang(text: &str) -> (Option<&str>, &str) {
+    if !text.starts_with("#!") {
+        return (None, text);
+    }
+    match text.split_once('\n') {
+        Some((shebang, rest)) => (Some(shebang), rest),
+        None => (Some(text), ""),
+    }
+}
+
+/// Split `text` into what comes before the region from character `start` to
+/// `end`, the region and what comes after it. Blanks around the region are
+/// moved to the parts around it, so they survive the region being edited.
+pub fn split_region(text: &str, start: usize, end: usize) -> Result<(String, String, String)> {
+    let len = text.chars().count();
+    if start > end || end > len {
+        anyhow::bail!(
+            "Region {}..{} doesn't fit in a command of {} characters",
+            start,
+            end,
+            len
+        );
+    }
+    let chars: Vec<char> = text.chars().collect();
+    let region = &chars[start..end];
+    let leading = region.iter().take_while(|c| c.is_whitespace()).count();
+    let trailing = region[leading..]
+        .iter()
+        .rev()
+        .take_while(|c| c.is_whitespace())
+        .count();
+    let collect = |chars: &[char]| chars.iter().collect::<String>();
+    Ok((
+        collect(&chars[..start + leading]),
+        collect(&chars[start + leading..end - trailing]),
+        collect(&chars[end - trailing..]),
+    ))
+}
+
+/// Read a command file, with an error that names the file and the reason in
+/// plain words
+pub fn read_command_file(path: &Path) -> Result<String> {
+    std::fs::read_to_string(path)
+        .map_err(|err| anyhow::anyhow!("Cannot read {}: {}", path.display(), io_reason(&err)))
+}
+
+/// Check that `path` can be written back to, before anything is edited
+pub fn check_writable(path: &Path) -> Result<()> {
+    // Opening for append doesn't touch the contents
+    OpenOptions::new()
+        .append(true)
+        .open(path)
+        .map(drop)
+        .map_err(|err| anyhow::anyhow!("Cannot write {}: {}", path.display(), io_reason(&err)))
+}
+
+/// Replace the contents of `path` with `text`, first copying the old contents
+/// to `<path>.bak` if `backup` is set
+pub fn write_in_place(path: &Path, text: &str, backup: bool) -> Result<()> {
+    if backup {
+        let mut backup_path = path.as_os_str().to_owned();
+        backup_path.push(".bak");
+        std::fs::copy(path, &backup_path)
+            .with_context(|| format!("Failed to back up {}", path.display()))?;
+    }
+    std::fs::write(path, text)
+        .map_err(|err| anyhow::anyhow!("Cannot write {}: {}", path.display(), io_reason(&err)))
+}
+
+fn io_reason(err: &std::io::Error) -> String {
+    match err.kind() {
+        ErrorKind::NotFound => "no such file".to_string(),
+        ErrorKind::PermissionDenied => "permission denied".to_string(),
+        ErrorKind::IsADirectory => "is a directory".to_string(),
+        _ => err.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_region() {
+        let split =
+            |start, end| split_region("kubectl get pods | tee out.json", start, end).unwrap();
+        assert_eq!(
+            split(0, 17),
+            (
+                String::new(),
+                "kubectl get pods".to_string(),
+                " | tee out.json".to_string()
+            )
+        );
+        assert_eq!(
+            split(18, 31),
+            (
+                "kubectl get pods | ".to_string(),
+                "tee out.json".to_string(),
+                String::new()
+            )
+        );
+        // Offsets count characters, not bytes
+        assert_eq!(split_region("echo é | wc", 0, 6).unwrap().1, "echo é");
+        assert!(split_region("ls", 1, 3).is_err());
+        assert!(split_region("ls", 2, 1).is_err());
+    }
+
+    #[test]
+    fn test_normalize_newlines() {
+        assert_eq!(normalize_newlines("ls -la\r\n"), "ls -la");
+        assert_eq!(
+            normalize_newlines("cmd \\\r\n  --flag x\r\n"),
+            "cmd \\\n  --flag x"
+        );
+        // Only a single trailing newline is stripped
+        assert_eq!(normalize_newlines("ls\n\n"), "ls\n");
+        assert_eq!(normalize_newlines("ls"), "ls");
+    }
+
+    #[test]
+    fn test_split_commands() {
+        assert_eq!(split_commands("ls -la"), vec!["ls -la"]);
+        assert_eq!(split_commands("ls\npwd"), vec!["ls", "pwd"]);
+        // Blank lines are dropped
+        assert_eq!(split_commands("ls\n\n  \npwd\n"), vec!["ls", "pwd"]);
+        // Continuations and quoted newlines don't split
+        assert_eq!(
+            split_commands("cmd \\\n  --flag x\npwd"),
+            vec!["cmd \\\n  --flag x", "pwd"]
+        );
+        assert_eq!(
+            split_commands("echo \"a\nb\" 'c\nd'\nls"),
+            vec!["echo \"a\nb\" 'c\nd'", "ls"]
+        );
+        // Backslashes are literal inside single quotes
+        assert_eq!(split_commands("echo 'a\\'\nls"), vec!["echo 'a\\'", "ls"]);
+    }
+
+    #[test]
+    fn test_split_commands_with_comments() {
+        // Comment lines go with the next command, and none is only a comment
+        assert_eq!(
+            split_commands("# Deploy\nkubectl get pods\n\n# Check\n  # it\nls # all\n# end"),
+            vec!["# Deploy\nkubectl get pods", "# Check\n  # it\nls # all"]
+        );
+        assert!(split_commands("# nothing to run").is_empty());
+        // An apostrophe in a comment doesn't open a quote
+        assert_eq!(
+            split_commands("# don't\nls\npwd # it's\ndate"),
+            vec!["# don't\nls", "pwd # it's", "date"]
+        );
+        // A `#` inside a word or quotes isn't a comment
+        assert_eq!(
+            split_commands("echo a#b '#c\nd'\nls"),
+            vec!["echo a#b '#c\nd'", "ls"]
+        );
+    }
+
+    #[test]
+    fn test_leading_comments() {
+        assert_eq!(
+            leading_comments("# Deploy\n  # it\nkubectl get pods"),
+            "# Deploy\n  # it\n"
+        );
+        assert_eq!(leading_comments("ls # all"), "");
+    }
+
+    #[test]
+    fn test_split_shebang() {
+        assert_eq!(
+            split_shebang("#!/bin/sh\nkubectl get pods\n"),
+            (Some("#!/bin/sh"), "kubectl get pods\n")
+        );
+        assert_eq!(
+            split_shebang("kubectl get pods"),
+            (None, "kubectl get pods")
+        );
+    }
+
+    #[test]
+    fn test_read_command_file_errors() {
+        let err = read_command_file(Path::new("/nonexistent/deploy.sh")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Cannot read /nonexistent/deploy.sh: no such file"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_read_from_pipe_fd() {
+        let mut fds = [0i32; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let [read_fd, write_fd] = fds;
+
+        let data = b"kubectl get pods\n";
+        let written = unsafe { libc::write(write_fd, data.as_ptr().cast(), data.len()) };
+        assert_eq!(written, data.len() as isize);
+        unsafe { libc::close(write_fd) };
+
+        assert_eq!(read_from_fd(read_fd).unwrap(), "kubectl get pods\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_read_from_invalid_fd() {
+        assert!(read_from_fd(-1).is_err());
+        assert!(read_from_fd(9999).is_err());
+    }
+}
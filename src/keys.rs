@@ -0,0 +1,451 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    str::FromStr,
+};
+
+use anyhow::Result;
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use serde::{Deserialize, Deserializer, de};
+
+/// Something a key does in Navigation Mode. `accept` and `accept_and_run`
+/// work in every mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Action {
+    Accept,
+    AcceptAndRun,
+    Cancel,
+    Interrupt,
+    SkipRemaining,
+    Undo,
+    Redo,
+    NextComponent,
+    PreviousComponent,
+    FirstComponent,
+    LastComponent,
+    NextOption,
+    PreviousOption,
+    PeekNextOption,
+    PeekPreviousOption,
+    OpenPicker,
+    EditComponent,
+    ChangeComponent,
+    ClearComponent,
+    InsertComponent,
+    AppendComponent,
+    DeleteComponent,
+    ToggleSudo,
+    ToggleFlagForm,
+    JoinWithNext,
+    MergeWithNext,
+    InsertPipe,
+    InsertRedirect,
+}
+
+/// Every action with its name in the `[keys]` table and its default chords
+pub const DEFAULT_KEYMAP: [(Action, &str, &[&str]); 28] = [
+    (Action::Accept, "accept", &["ctrl-x", "ctrl-enter"]),
+    (Action::AcceptAndRun, "accept_and_run", &["ctrl-o"]),
+    (Action::Cancel, "cancel", &["esc", "q"]),
+    (Action::Interrupt, "interrupt", &["ctrl-c"]),
+    (Action::SkipRemaining, "skip_remaining", &["S"]),
+    (Action::Undo, "undo", &["u", "ctrl-z"]),
+    (Action::Redo, "redo", &["ctrl-r", "ctrl-y", "ctrl-Z"]),
+    (
+        Action::NextComponent,
+        "next_component",
+        &["down", "j", "ctrl-n"],
+    ),
+    (
+        Action::PreviousComponent,
+        "previous_component",
+        &["up", "k", "ctrl-p"],
+    ),
+    (
+        Action::FirstComponent,
+        "first_component",
+        &["home", "ctrl-a"],
+    ),
+    (
+        Action::LastComponent,
+        "last_component",
+        &["end", "G", "ctrl-e"],
+    ),
+    (Action::NextOption, "next_option", &["right"]),
+    (Action::PreviousOption, "previous_option", &["left"]),
+    (Action::PeekNextOption, "peek_next_option", &["shift-right"]),
+    (
+        Action::PeekPreviousOption,
+        "peek_previous_option",
+        &["shift-left"],
+    ),
+    (Action::OpenPicker, "open_picker", &["tab"]),
+    (Action::EditComponent, "edit_component", &["enter"]),
+    (Action::ChangeComponent, "change_component", &["c"]),
+    (Action::ClearComponent, "clear_component", &["D"]),
+    (Action::InsertComponent, "insert_component", &["i"]),
+    (Action::AppendComponent, "append_component", &["a"]),
+    (
+        Action::DeleteComponent,
+        "delete_component",
+        &["d", "delete", "backspace", "ctrl-d"],
+    ),
+    (Action::ToggleSudo, "toggle_sudo", &["s"]),
+    (Action::ToggleFlagForm, "toggle_flag_form", &["e"]),
+    (Action::JoinWithNext, "join_with_next", &["J"]),
+    (Action::MergeWithNext, "merge_with_next", &["M"]),
+    (Action::InsertPipe, "insert_pipe", &["|"]),
+    (Action::InsertRedirect, "insert_redirect", &[">"]),
+];
+
+impl Action {
+    pub fn name(self) -> &'static str {
+        DEFAULT_KEYMAP
+            .iter()
+            .find(|(action, _, _)| *action == self)
+            .map(|(_, name, _)| *name)
+            .unwrap_or_default()
+    }
+}
+
+impl FromStr for Action {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        DEFAULT_KEYMAP
+            .iter()
+            .find(|(_, known, _)| *known == name)
+            .map(|(action, _, _)| *action)
+            .ok_or_else(|| anyhow::anyhow!("unknown key action '{}'", name))
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl<'de> Deserialize<'de> for Action {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+/// The chords bound to one action, written as one chord or a list of them
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chords(pub Vec<(String, KeyEvent)>);
+
+impl FromStr for Chords {
+    type Err = anyhow::Error;
+
+    fn from_str(spec: &str) -> Result<Self> {
+        Ok(Chords(vec![(spec.to_string(), parse_key(spec)?)]))
+    }
+}
+
+impl<'de> Deserialize<'de> for Chords {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Specs {
+            One(String),
+            Many(Vec<String>),
+        }
+        let specs = match Specs::deserialize(deserializer)
+            .map_err(|_| de::Error::custom("expected a key chord or a list of them"))?
+        {
+            Specs::One(spec) => vec![spec],
+            Specs::Many(specs) => specs,
+        };
+        specs
+            .into_iter()
+            .map(|spec| {
+                let key = parse_key(&spec).map_err(de::Error::custom)?;
+                Ok((spec, key))
+            })
+            .collect::<Result<_, _>>()
+            .map(Chords)
+    }
+}
+
+/// The `[keys]` table of the config file
+pub type KeyBindings = BTreeMap<Action, Chords>;
+
+/// Which action each key chord runs
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyMap {
+    bindings: HashMap<(KeyCode, KeyModifiers), Action>,
+}
+
+impl Default for KeyMap {
+    fn default() -> Self {
+        let mut bindings = HashMap::new();
+        for (action, _, specs) in DEFAULT_KEYMAP {
+            for spec in specs {
+                let key = parse_key(spec).expect("default key chords parse");
+                bindings.insert(normalize(key), action);
+            }
+        }
+        KeyMap { bindings }
+    }
+}
+
+impl KeyMap {
+    /// The default keymap with `overrides` applied. Chords given for an
+    /// action replace its default ones and win over other actions' defaults.
+    ///
+    /// Also returns a warning for each chord given to more than one action,
+    /// and for each that takes another action's default, since that action
+    /// may be left without a key.
+    pub fn new(overrides: &KeyBindings) -> (Self, Vec<String>) {
+        let mut keymap = KeyMap::default();
+        keymap
+            .bindings
+            .retain(|_, action| !overrides.contains_key(action));
+
+        let mut warnings = Vec::new();
+        let mut assigned: HashMap<(KeyCode, KeyModifiers), (Action, &str)> = HashMap::new();
+        for (&action, Chords(chords)) in overrides {
+            for (spec, key) in chords {
+                let key = normalize(*key);
+                if let Some((other, _)) = assigned.get(&key) {
+                    if *other != action {
+                        warnings.push(format!(
+                            "key '{}' is bound to both {} and {}; using {}",
+                            spec, other, action, action
+                        ));
+                    }
+                } else if let Some(&other) = keymap.bindings.get(&key) {
+                    warnings.push(format!(
+                        "key '{}' is {}'s default; using {}",
+                        spec, other, action
+                    ));
+                }
+                assigned.insert(key, (action, spec));
+                keymap.bindings.insert(key, action);
+            }
+        }
+        (keymap, warnings)
+    }
+
+    /// The action bound to exactly this chord
+    pub fn exact(&self, key: KeyEvent) -> Option<Action> {
+        self.bindings.get(&normalize(key)).copied()
+    }
+
+    /// The action for a key press. A chord with extra modifiers falls back to
+    /// the chord with just Ctrl, then to the bare key, so `ctrl-alt-r` redoes
+    /// and `alt-j` moves down.
+    pub fn lookup(&self, key: KeyEvent) -> Option<Action> {
+        let (code, modifiers) = normalize(key);
+        [
+            modifiers,
+            modifiers & KeyModifiers::CONTROL,
+            KeyModifiers::NONE,
+        ]
+        .into_iter()
+        .find_map(|modifiers| self.bindings.get(&(code, modifiers)).copied())
+    }
+}
+
+/// Terminals report `G` as Shift+`G`; a character already says whether Shift
+/// was held, so the modifier is folded into it
+fn normalize(key: KeyEvent) -> (KeyCode, KeyModifiers) {
+    match key.code {
+        KeyCode::Char(c) if key.modifiers.contains(KeyModifiers::SHIFT) => (
+            KeyCode::Char(c.to_uppercase().next().unwrap_or(c)),
+            key.modifiers - KeyModifiers::SHIFT,
+        ),
+        code => (code, key.modifiers),
+    }
+}
+
+/// Parse a key chord such as `j`, `enter`, `ctrl-x`, `alt-enter` or `f2`.
+///
+/// Modifiers (`ctrl`, `alt`, `shift`) are joined to the key name with `-`.
+/// Key names are case-insensitive, except for single characters.
+pub fn parse_key(spec: &str) -> Result<KeyEvent> {
+    let mut modifiers = KeyModifiers::NONE;
+    let mut rest = spec;
+
+    // A trailing "-" is the minus key itself, not a separator
+    while let Some((modifier, key)) = rest.split_once('-').filter(|(_, key)| !key.is_empty()) {
+        modifiers |= match modifier.to_ascii_lowercase().as_str() {
+            "ctrl" | "c" => KeyModifiers::CONTROL,
+            "alt" | "meta" | "m" => KeyModifiers::ALT,
+            "shift" | "s" => KeyModifiers::SHIFT,
+            _ => anyhow::bail!("Unknown modifier '{}' in key '{}'", modifier, spec),
+        };
+        rest = key;
+    }
+
+    let code = if rest.chars().count() == 1 {
+        KeyCode::Char(rest.chars().next().unwrap_or_default())
+    } else {
+        match rest.to_ascii_lowercase().as_str() {
+            "enter" | "return" => KeyCode::Enter,
+            "esc" | "escape" => KeyCode::Esc,
+            "tab" => KeyCode::Tab,
+            "backtab" => KeyCode::BackTab,
+            "backspace" | "bs" => KeyCode::Backspace,
+            "delete" | "del" => KeyCode::Delete,
+            "insert" | "ins" => KeyCode::Insert,
+            "up" => KeyCode::Up,
+            "down" => KeyCode::Down,
+            "left" => KeyCode::Left,
+            "right" => KeyCode::Right,
+            "home" => KeyCode::Home,
+            "end" => KeyCode::End,
+            "pageup" => KeyCode::PageUp,
+            "pagedown" => KeyCode::PageDown,
+            "space" => KeyCode::Char(' '),
+            name => match name.strip_prefix('f').and_then(|n| n.parse::<u8>().ok()) {
+                Some(n) if (1..=12).contains(&n) => KeyCode::F(n),
+                _ => anyhow::bail!("Unknown key '{}'", spec),
+            },
+        }
+    };
+
+    Ok(KeyEvent::new(code, modifiers))
+}
+
+/// Parse a whitespace-separated sequence of key chords
+pub fn parse_key_sequence(keys: &str) -> Result<Vec<KeyEvent>> {
+    keys.split_whitespace().map(parse_key).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_plain_keys() {
+        assert_eq!(parse_key("j").unwrap(), KeyEvent::from(KeyCode::Char('j')));
+        assert_eq!(parse_key("G").unwrap(), KeyEvent::from(KeyCode::Char('G')));
+        assert_eq!(parse_key("-").unwrap(), KeyEvent::from(KeyCode::Char('-')));
+        assert_eq!(parse_key("Enter").unwrap(), KeyEvent::from(KeyCode::Enter));
+        assert_eq!(parse_key("esc").unwrap(), KeyEvent::from(KeyCode::Esc));
+        assert_eq!(parse_key("F2").unwrap(), KeyEvent::from(KeyCode::F(2)));
+        assert_eq!(
+            parse_key("space").unwrap(),
+            KeyEvent::from(KeyCode::Char(' '))
+        );
+    }
+
+    #[test]
+    fn test_parse_chords() {
+        assert_eq!(
+            parse_key("ctrl-x").unwrap(),
+            KeyEvent::new(KeyCode::Char('x'), KeyModifiers::CONTROL)
+        );
+        assert_eq!(
+            parse_key("alt-enter").unwrap(),
+            KeyEvent::new(KeyCode::Enter, KeyModifiers::ALT)
+        );
+        assert_eq!(
+            parse_key("ctrl-shift-z").unwrap(),
+            KeyEvent::new(
+                KeyCode::Char('z'),
+                KeyModifiers::CONTROL | KeyModifiers::SHIFT
+            )
+        );
+        assert_eq!(
+            parse_key("ctrl--").unwrap(),
+            KeyEvent::new(KeyCode::Char('-'), KeyModifiers::CONTROL)
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid_keys() {
+        assert!(parse_key("hyper-x").is_err());
+        assert!(parse_key("f13").is_err());
+        assert!(parse_key("nope").is_err());
+    }
+
+    #[test]
+    fn test_default_keymap() {
+        let keymap = KeyMap::default();
+        let key = |spec| parse_key(spec).unwrap();
+
+        assert_eq!(keymap.lookup(key("j")), Some(Action::NextComponent));
+        assert_eq!(keymap.lookup(key("ctrl-z")), Some(Action::Undo));
+        assert_eq!(keymap.lookup(key("ctrl-shift-z")), Some(Action::Redo));
+        assert_eq!(keymap.lookup(key("shift-g")), Some(Action::LastComponent));
+        assert_eq!(keymap.lookup(key("G")), Some(Action::LastComponent));
+        assert_eq!(
+            keymap.lookup(key("shift-right")),
+            Some(Action::PeekNextOption)
+        );
+        assert_eq!(keymap.lookup(key("ctrl-enter")), Some(Action::Accept));
+        assert_eq!(keymap.lookup(key("J")), Some(Action::JoinWithNext));
+        // Extra modifiers fall back to Ctrl alone, then the bare key
+        assert_eq!(keymap.lookup(key("ctrl-alt-r")), Some(Action::Redo));
+        assert_eq!(keymap.lookup(key("alt-j")), Some(Action::NextComponent));
+        assert_eq!(keymap.exact(key("alt-j")), None);
+        // Prefix shortcuts stay unbound
+        assert_eq!(keymap.lookup(key("b")), None);
+    }
+
+    #[test]
+    fn test_action_names() {
+        for (action, name, _) in DEFAULT_KEYMAP {
+            assert_eq!(name.parse::<Action>().unwrap(), action);
+            assert_eq!(action.name(), name);
+        }
+        assert!("jump".parse::<Action>().is_err());
+    }
+
+    #[test]
+    fn test_keymap_overrides() {
+        let bindings: KeyBindings = toml::from_str(
+            "undo = [\"ctrl-u\", \"U\"]\naccept = \"alt-enter\"\nredo = \"ctrl-u\"\n",
+        )
+        .unwrap();
+        let (keymap, warnings) = KeyMap::new(&bindings);
+        let key = |spec| parse_key(spec).unwrap();
+
+        // Given chords replace the action's defaults
+        assert_eq!(keymap.lookup(key("U")), Some(Action::Undo));
+        assert_eq!(keymap.lookup(key("u")), None);
+        assert_eq!(keymap.exact(key("alt-enter")), Some(Action::Accept));
+        assert_eq!(keymap.exact(key("ctrl-x")), None);
+        assert_eq!(keymap.lookup(key("ctrl-r")), None);
+        // Other actions keep theirs
+        assert_eq!(keymap.lookup(key("ctrl-n")), Some(Action::NextComponent));
+        assert_eq!(
+            warnings,
+            ["key 'ctrl-u' is bound to both undo and redo; using redo"]
+        );
+    }
+
+    #[test]
+    fn test_keymap_overrides_take_defaults() {
+        let bindings: KeyBindings = toml::from_str("open_picker = \"j\"\n").unwrap();
+        let (keymap, warnings) = KeyMap::new(&bindings);
+
+        assert_eq!(
+            keymap.lookup(parse_key("j").unwrap()),
+            Some(Action::OpenPicker)
+        );
+        assert_eq!(keymap.lookup(parse_key("tab").unwrap()), None);
+        assert_eq!(
+            warnings,
+            vec!["key 'j' is next_component's default; using open_picker"]
+        );
+
+        // A chord no other action has by default is taken quietly
+        let bindings: KeyBindings = toml::from_str("open_picker = \"f5\"\n").unwrap();
+        let (_, warnings) = KeyMap::new(&bindings);
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn test_parse_key_sequence() {
+        let keys = parse_key_sequence("j  j\tenter").unwrap();
+        assert_eq!(keys.len(), 3);
+        assert_eq!(keys[2], KeyEvent::from(KeyCode::Enter));
+    }
+}
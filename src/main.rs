@@ -1,15 +1,214 @@
-use std::io::{self, IsTerminal, Read};
+use std::io::{self, IsTerminal, Read, Write};
 
-use anyhow::Result;
-use clap::{Parser, Subcommand};
+use anyhow::{Context, Result};
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
 
 mod app;
+mod background;
+mod bash_completion;
+mod batch;
+mod braces;
+mod carapace;
+mod clipboard;
 mod command;
+mod completion;
+mod config;
+mod debug;
+mod doctor;
+mod flags;
+mod help;
+mod history;
+mod hooks;
+mod input;
+mod keys;
+mod profile;
+mod providers;
+mod session;
 mod shell;
+mod specs;
+mod templates;
 mod tui;
 mod undo;
 
-use tui::run_tui;
+use app::BatchProgress;
+use profile::Phase;
+use session::SessionFile;
+use tui::{Outcome, TuiOptions, run_headless, run_tui};
+
+// Exit codes. An accepted command exits with 0 so shell integrations can
+// tell a deliberate cancel apart from a failure.
+
+/// Something went wrong
+const EXIT_ERROR: i32 = 1;
+/// The command or te's own arguments could not be parsed
+const EXIT_PARSE_ERROR: i32 = 2;
+/// The command was accepted with Ctrl+O, for the shell to run right away
+const EXIT_RUN: i32 = 3;
+/// The user cancelled editing (matches the SIGINT convention)
+const EXIT_CANCELLED: i32 = 130;
+
+/// Build the command from wrapped arguments.
+///
+/// A single argument is parsed as a command line, which is how the shell
+/// integrations pass their buffer. Several arguments, or any after a `--`
+/// (`as_argv`), are taken as the argv of the command, keeping each one as a
+/// component exactly as the shell passed it.
+fn command_from_args(mut args: Vec<String>, as_argv: bool) -> command::Command {
+    if args.len() == 1 && !as_argv {
+        parse_or_exit(&args.remove(0))
+    } else {
+        profile::time(Phase::Parse, || command::Command::from_argv(args)).unwrap_or_else(|err| {
+            eprintln!("Error: {}", err);
+            std::process::exit(EXIT_PARSE_ERROR);
+        })
+    }
+}
+
+/// Whether the `wrapped` arguments at the end of the command line came right
+/// after a `--`, as in `te -- ls "my file"`
+fn follows_double_dash(raw: &[std::ffi::OsString], wrapped: &[String]) -> bool {
+    raw.len() > wrapped.len() && raw[raw.len() - wrapped.len() - 1] == "--"
+}
+
+/// Parse piped input holding one command per line, each with its text as
+/// given, exiting with `EXIT_PARSE_ERROR` if any of them can't be parsed
+fn parse_inputs_or_exit(raw: &str) -> Vec<(command::Command, String)> {
+    let text = input::normalize_newlines(raw);
+    let commands = input::split_commands(&text);
+    if commands.is_empty() {
+        // Reports the empty command
+        parse_or_exit(&text);
+    }
+    commands
+        .into_iter()
+        .map(|command_str| (parse_or_exit(&command_str), command_str))
+        .collect()
+}
+
+/// Parse the command string, exiting with `EXIT_PARSE_ERROR` on failure
+fn parse_or_exit(command_str: &str) -> command::Command {
+    match profile::time(Phase::Parse, || command_str.try_into()) {
+        Ok(cmd) => cmd,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            std::process::exit(EXIT_PARSE_ERROR);
+        }
+    }
+}
+
+/// Exit with `EXIT_PARSE_ERROR` if `cmd` has a shell operator, whose
+/// words `--null` can't print: `xargs` would run `|` as an argument
+fn reject_null_operators(cmd: &command::Command) {
+    if let Some(index) = cmd.find_operator() {
+        eprintln!("Error: --null can't print a command with shell operators");
+        eprintln!("{}", cmd.point_at(index));
+        std::process::exit(EXIT_PARSE_ERROR);
+    }
+}
+
+/// What became of one command of the input
+enum Edited {
+    /// Accepted, along with the components it started out with
+    Accepted(command::Command, Vec<String>),
+    /// Accepted and then rewritten by the configured post command, which
+    /// printed `text`; `cmd` is that text parsed, if it parses
+    PostProcessed {
+        text: String,
+        cmd: Option<command::Command>,
+        original: Vec<String>,
+    },
+    /// Skipped in a batch, kept exactly as it was given
+    Skipped(String),
+}
+
+impl Edited {
+    fn to_shell_string(&self) -> String {
+        match self {
+            Edited::Accepted(cmd, _) => cmd.to_shell_string(),
+            Edited::PostProcessed { text, .. } | Edited::Skipped(text) => text.clone(),
+        }
+    }
+
+    /// Pass an accepted command through `post_command`, keeping it as it
+    /// was if that fails
+    fn post_process(self, post_command: &str) -> Self {
+        let Edited::Accepted(cmd, original) = self else {
+            return self;
+        };
+        match hooks::run_post_command(post_command, &cmd.to_shell_string()) {
+            Ok(text) => {
+                let parsed = command::Command::try_from(text.as_str());
+                if let Err(err) = &parsed {
+                    eprintln!(
+                        "te: warning: the output of post_command doesn't parse as a command: {}",
+                        err
+                    );
+                }
+                Edited::PostProcessed {
+                    text,
+                    cmd: parsed.ok(),
+                    original,
+                }
+            }
+            Err(err) => {
+                eprintln!(
+                    "te: warning: post_command failed, keeping the command as edited: {:#}",
+                    err
+                );
+                Edited::Accepted(cmd, original)
+            }
+        }
+    }
+}
+
+/// How the accepted command is printed
+#[derive(Clone, Copy, Default, ValueEnum)]
+enum OutputFormat {
+    /// The command line, ready to run
+    #[default]
+    Shell,
+    /// A JSON object with the command, its components and what changed
+    Json,
+}
+
+/// The comment --annotate prints above an edited command
+fn annotation(from_history: &[String]) -> String {
+    if from_history.is_empty() {
+        return "# edited with te".to_string();
+    }
+    // A multi-line value would end the comment early
+    let values: Vec<String> = from_history
+        .iter()
+        .map(|value| value.split_whitespace().collect::<Vec<_>>().join(" "))
+        .collect();
+    format!("# edited with te; from history: {}", values.join(", "))
+}
+
+/// The accepted command as JSON, with the changes made to `original`
+fn command_json(cmd: &command::Command, original: &[String]) -> serde_json::Value {
+    let components: Vec<String> = cmd.iter_components().cloned().collect();
+    let mut added = Vec::new();
+    let mut removed = Vec::new();
+    let mut changed = Vec::new();
+    for change in command::diff_components(original, &components) {
+        match change {
+            command::ComponentChange::Added { index, value } => {
+                added.push(serde_json::json!({ "index": index, "value": value }))
+            }
+            command::ComponentChange::Removed { index, value } => {
+                removed.push(serde_json::json!({ "index": index, "value": value }))
+            }
+            command::ComponentChange::Changed { index, from, to } => {
+                changed.push(serde_json::json!({ "index": index, "from": from, "to": to }))
+            }
+        }
+    }
+    serde_json::json!({
+        "command": cmd.to_shell_string(),
+        "components": components,
+        "diff": { "added": added, "removed": removed, "changed": changed },
+    })
+}
 
 #[derive(Parser)]
 #[command(name = "te")]
@@ -18,7 +217,219 @@ struct Cli {
     #[command(subcommand)]
     pub command: Option<Command>,
 
-    #[arg(allow_hyphen_values = true)]
+    /// Edit the given command string, like `sh -c` (stdin must not be piped)
+    #[arg(
+        short = 'c',
+        long = "command",
+        value_name = "COMMAND",
+        allow_hyphen_values = true,
+        conflicts_with = "wrapped_command"
+    )]
+    pub command_string: Option<String>,
+
+    /// Read the command from the given file descriptor instead of stdin
+    #[arg(
+        long,
+        value_name = "N",
+        conflicts_with_all = ["wrapped_command", "command_string"]
+    )]
+    pub fd: Option<i32>,
+
+    /// Read the command from a file (`-` for stdin)
+    #[arg(
+        short = 'f',
+        long,
+        value_name = "PATH",
+        conflicts_with_all = ["wrapped_command", "command_string", "fd"]
+    )]
+    pub file: Option<std::path::PathBuf>,
+
+    /// Write the accepted command back to the --file instead of printing it
+    #[arg(long, requires = "file", conflicts_with_all = ["copy_only", "output"])]
+    pub in_place: bool,
+
+    /// Don't keep a copy of the original file as <PATH>.bak with --in-place
+    #[arg(long, requires = "in_place")]
+    pub no_backup: bool,
+
+    /// Edit the Nth most recent command from shell history (default: 1)
+    #[arg(
+        long,
+        value_name = "N",
+        num_args = 0..=1,
+        default_missing_value = "1",
+        conflicts_with_all = ["wrapped_command", "command_string", "fd", "file"]
+    )]
+    pub last: Option<usize>,
+
+    /// Edit the command saved with `te save NAME`
+    #[arg(
+        long,
+        value_name = "NAME",
+        conflicts_with_all = ["wrapped_command", "command_string", "fd", "file", "last"]
+    )]
+    pub template: Option<String>,
+
+    /// Read shell history from this file instead of the detected shell's
+    #[arg(
+        long,
+        value_name = "PATH",
+        global = true,
+        conflicts_with = "no_history"
+    )]
+    pub histfile: Option<std::path::PathBuf>,
+
+    /// Don't read shell history
+    #[arg(long, global = true)]
+    pub no_history: bool,
+
+    /// Shell whose history te reads, instead of the one in $TE_SHELL or $SHELL,
+    /// and that the printed command is quoted for
+    #[arg(long = "shell", id = "shell_override", value_enum, global = true)]
+    pub shell: Option<history::Shell>,
+
+    /// Milliseconds to wait for history suggestions before opening the editor [default: 50]
+    #[arg(long, value_name = "MS")]
+    pub history_budget: Option<u64>,
+
+    /// Only learn from this many of the most recent history entries
+    #[arg(long, value_name = "N", global = true)]
+    pub history_limit: Option<usize>,
+
+    /// Read settings from this file instead of ~/.config/te/config.toml
+    #[arg(long, value_name = "PATH", global = true)]
+    pub config: Option<std::path::PathBuf>,
+
+    /// Don't warn when this flag is repeated (repeatable)
+    #[arg(long, value_name = "FLAG", allow_hyphen_values = true)]
+    pub allow_repeat: Vec<String>,
+
+    /// Treat this command as a wrapper (like sudo) when matching history (repeatable)
+    #[arg(long, value_name = "NAME", global = true)]
+    pub wrapper: Vec<String>,
+
+    /// How to print the accepted command
+    #[arg(long, value_enum, default_value_t = OutputFormat::Shell)]
+    pub output: OutputFormat,
+
+    /// Print the words of the accepted command each followed by a NUL byte,
+    /// unquoted, for `xargs -0`
+    #[arg(long, conflicts_with_all = ["output", "annotate", "in_place", "copy_only"])]
+    pub null: bool,
+
+    /// Print a `# edited with te` comment above each edited command, naming
+    /// the flag values taken from history
+    #[arg(long)]
+    pub annotate: bool,
+
+    /// Wrap the printed command over `\`-continued lines wider than this
+    /// (default: the terminal width, or no wrapping when stdout isn't a terminal; 0: never wrap)
+    #[arg(long, value_name = "COLS")]
+    pub width: Option<usize>,
+
+    /// Put these words in front of the accepted command, like `sudo` or `time -p`
+    #[arg(long, value_name = "PREFIX", allow_hyphen_values = true)]
+    pub wrap_output: Option<String>,
+
+    /// Only edit the command string from this character on, keeping the rest
+    /// around it as it is
+    #[arg(
+        long,
+        value_name = "N",
+        requires = "region_end",
+        conflicts_with_all = ["fd", "file", "last", "output", "null"]
+    )]
+    pub region_start: Option<usize>,
+
+    /// Only edit the command string up to this character (with --region-start)
+    #[arg(long, value_name = "N", requires = "region_start")]
+    pub region_end: Option<usize>,
+
+    /// Write the result to this inherited file descriptor instead of stdout
+    #[arg(long, value_name = "N", conflicts_with_all = ["copy_only", "in_place"])]
+    pub result_fd: Option<i32>,
+
+    /// Also copy the accepted command to the clipboard
+    #[arg(long)]
+    pub copy: bool,
+
+    /// Without a command or piped input, edit the command on the clipboard
+    #[arg(long)]
+    pub clipboard: bool,
+
+    /// Copy the accepted command to the clipboard instead of printing it
+    #[arg(long, conflicts_with = "copy")]
+    pub copy_only: bool,
+
+    /// How values that need quoting are printed: in whichever quotes need
+    /// less escaping, preferring single or double quotes, or with their
+    /// spaces escaped (`my\ file`) when nothing else needs quoting
+    #[arg(long, value_enum, value_name = "STYLE")]
+    pub quote_style: Option<command::QuoteStyle>,
+
+    /// How --copy and --copy-only reach the clipboard
+    #[arg(long, value_name = "METHOD", default_value = "auto")]
+    pub clipboard_method: clipboard::ClipboardMethod,
+
+    /// Fail on anything te would have to guess at, like a word that could be
+    /// a flag's value or an argument, instead of guessing
+    #[arg(long)]
+    pub strict_parse: bool,
+
+    /// Keep a trailing `# comment` in the printed command (always with
+    /// --in-place)
+    #[arg(long, global = true)]
+    pub keep_comments: bool,
+
+    /// Without a terminal, print the command unchanged instead of failing
+    #[arg(long)]
+    pub passthrough: bool,
+
+    /// Start on the last component, with j/down moving towards the first
+    #[arg(long)]
+    pub reverse: bool,
+
+    /// Draw over the whole terminal instead of from the cursor's line, as in
+    /// a tmux popup
+    #[arg(long)]
+    pub fullscreen: bool,
+
+    /// Draw at the top of the alternate screen instead of from the cursor's
+    /// line, and bring back the screen as it was on exit
+    #[arg(long, conflicts_with = "fullscreen")]
+    pub top: bool,
+
+    /// Number the lines of multi-line values, like jq programs, while editing them
+    #[arg(long)]
+    pub line_numbers: bool,
+
+    /// Show what brace lists like `file.{txt,md}` expand to below the
+    /// command; the command itself is written out as typed
+    #[arg(long)]
+    pub brace_preview: bool,
+
+    /// Print how long parsing, loading history and the first draw took to
+    /// stderr once editing is done
+    #[arg(long)]
+    pub profile: bool,
+
+    /// Stop at the first and last component instead of wrapping around
+    #[arg(long)]
+    pub no_wrap_nav: bool,
+
+    /// Only pick a history value for the last argument, printing the command on selection
+    #[arg(long, conflicts_with = "keys")]
+    pub completions_only: bool,
+
+    /// Run without a terminal, feeding the given space-separated keys (for testing)
+    #[arg(long, hide = true, value_name = "KEYS")]
+    pub keys: Option<String>,
+
+    #[arg(
+        allow_hyphen_values = true,
+        trailing_var_arg = true,
+        value_hint = clap::ValueHint::CommandWithArguments
+    )]
     pub wrapped_command: Vec<String>,
 }
 
@@ -26,54 +437,914 @@ struct Cli {
 enum Command {
     /// Initialize shell integration
     Init {
-        /// Shell to generate integration for (zsh, bash, fish)
-        shell: String,
-        /// Optional key binding for zsh (default: ^T)
+        /// Shell to generate integration for (any but nu; default: --shell or
+        /// TE_SHELL, else the shell running te init)
+        #[arg(value_enum)]
+        shell: Option<history::Shell>,
+        /// Optional key binding for the widget (default: ^T for zsh, \C-t for bash,
+        /// \ct for fish, Ctrl+t for pwsh, Ctrl-T for elvish, c-t for xonsh)
         #[arg(short, long)]
         bindkey: Option<String>,
+        /// Only put the edited command on the command line, never run it
+        #[arg(long)]
+        no_eval: bool,
+        /// What the widget does with the edited command (zsh)
+        #[arg(long, value_enum, default_value_t)]
+        mode: shell::WidgetMode,
+        /// Inside tmux, open te in a popup instead of inline (zsh, bash, fish)
+        #[arg(long)]
+        tmux_popup: bool,
+        /// Define the widget without binding it to a key
+        #[arg(long, conflicts_with = "bindkey")]
+        no_bind: bool,
+        /// Name the functions `<PREFIX>-run` and `<PREFIX>-widget` instead
+        #[arg(long, default_value = shell::DEFAULT_PREFIX)]
+        prefix: String,
     },
+    /// Set flag values without opening the TUI and print the result
+    Set {
+        /// Flag assignment in FLAG=VALUE form (repeatable)
+        #[arg(
+            short,
+            long = "flag",
+            value_name = "FLAG=VALUE",
+            allow_hyphen_values = true
+        )]
+        flags: Vec<String>,
+        /// Command to edit
+        #[arg(last = true, required = true)]
+        command: Vec<String>,
+    },
+    /// Remove flags and their values without opening the TUI and print the result
+    Rm {
+        /// Flag to remove (repeatable)
+        #[arg(short, long = "flag", value_name = "FLAG", allow_hyphen_values = true)]
+        flags: Vec<String>,
+        /// Fail if a flag is not present in the command
+        #[arg(long)]
+        strict: bool,
+        /// Command to edit
+        #[arg(last = true, required = true)]
+        command: Vec<String>,
+    },
+    /// Save a command as a template, to edit later with `te --template NAME`
+    Save {
+        /// Name of the template
+        name: String,
+        /// Replace a template of the same name
+        #[arg(long)]
+        force: bool,
+        /// Command to save (default: read from stdin)
+        #[arg(last = true)]
+        command: Vec<String>,
+    },
+    /// Print a completion script for te itself
+    Completions {
+        /// Shell to generate completions for
+        shell: clap_complete::Shell,
+    },
+    /// Print the detected shell and the history file te reads
+    WhichShell,
+    /// Check the terminal, shell history, shell integration and config for problems
+    Doctor,
+    /// Print the values learned from shell history for a command
+    History {
+        /// Only print values of this flag
+        #[arg(long, value_name = "NAME", allow_hyphen_values = true)]
+        flag: Option<String>,
+        /// Print JSON instead of text
+        #[arg(long)]
+        json: bool,
+        /// Command whose history to look up
+        #[arg(last = true, required = true)]
+        command: Vec<String>,
+    },
+}
+
+impl Cli {
+    /// Where history is read from, if `--no-history` or the `history`
+    /// feature doesn't turn it off
+    fn history_source(&self, features: &config::Features) -> history::HistorySource {
+        if self.no_history || !features.history {
+            history::HistorySource::Disabled
+        } else if let Some(path) = &self.histfile {
+            history::HistorySource::File(path.clone())
+        } else {
+            history::HistorySource::Detected
+        }
+    }
+
+    fn wrappers(&self) -> Vec<history::Wrapper> {
+        let mut wrappers = history::default_wrappers();
+        wrappers.extend(
+            self.wrapper
+                .iter()
+                .map(|name| history::Wrapper::simple(name)),
+        );
+        wrappers
+    }
+}
+
+/// How many values the flags of the command `cmd` runs take, from the
+/// config and then its completion spec
+fn flag_arities(
+    cmd: &command::Command,
+    wrappers: &[history::Wrapper],
+    counts: &flags::FlagValueCounts,
+    specs: &specs::Specs,
+) -> flags::FlagArities {
+    let components: Vec<String> = cmd.iter_components().cloned().collect();
+    let base_command = history::base_command(&components, wrappers);
+    match base_command.first() {
+        Some(program) => {
+            flags::FlagArities::for_program(program, &specs.value_counts(&base_command, counts))
+        }
+        None => flags::FlagArities::default(),
+    }
+}
+
+/// `path` with a leading `~` replaced by `$HOME`
+fn expand_home(path: std::path::PathBuf) -> std::path::PathBuf {
+    match (path.strip_prefix("~"), std::env::var_os("HOME")) {
+        (Ok(rest), Some(home)) => std::path::PathBuf::from(home).join(rest),
+        _ => path,
+    }
+}
+
+/// Print suggestions as flags followed by their ranked values, or as JSON
+fn print_suggestions(suggestions: &history::Suggestions, json: bool) {
+    let mut flags: Vec<&String> = suggestions.keys().collect();
+    flags.sort();
+
+    if json {
+        let map: serde_json::Map<String, serde_json::Value> = flags
+            .into_iter()
+            .map(|flag| {
+                let candidates = suggestions[flag]
+                    .iter()
+                    .map(|c| serde_json::json!({ "value": c.value, "count": c.count }))
+                    .collect();
+                (flag.clone(), serde_json::Value::Array(candidates))
+            })
+            .collect();
+        println!("{}", serde_json::Value::Object(map));
+    } else {
+        for flag in flags {
+            println!("{}", flag);
+            for candidate in &suggestions[flag] {
+                println!("  {} ({})", candidate.value, candidate.count);
+            }
+        }
+    }
 }
 
 fn main() -> Result<()> {
-    let cli = Cli::parse();
+    let raw_args: Vec<std::ffi::OsString> = std::env::args_os().collect();
+    let mut cli = Cli::parse_from(&raw_args);
+    if cli.profile {
+        profile::enable();
+    }
+    if let Some(shell) = cli.shell {
+        history::override_shell(shell);
+    }
+    // Runs before the config is loaded, so a broken config is reported instead of failing
+    if let Some(Command::Doctor) = cli.command {
+        let features = config::Config::load(cli.config.as_deref())
+            .map(|config| config.features.resolve())
+            .unwrap_or_default();
+        let history_source = cli.history_source(&features);
+        let checks = doctor::run_checks(cli.config.as_deref(), &history_source, &features);
+        for check in &checks {
+            println!("{}", check.report());
+        }
+        if checks.iter().any(|c| c.status == doctor::Status::Fail) {
+            std::process::exit(EXIT_ERROR);
+        }
+        return Ok(());
+    }
+    if let Err(err) = history::resolve_shell() {
+        eprintln!("Error: {}", err);
+        std::process::exit(EXIT_ERROR);
+    }
+    let mut config = config::Config::load(cli.config.as_deref())?;
+    for warning in &config.warnings {
+        eprintln!("te: warning: {}", warning);
+    }
+    let features = config.features.resolve();
+    let history_source = cli.history_source(&features);
+    let wrappers = cli.wrappers();
+    let specs = specs::Specs::new(config.specs.dir.take().map(expand_home));
+    let history_limit =
+        config::resolve(cli.history_limit, "TE_HISTORY_LIMIT", config.history.limit)?;
+    let quote_style = cli
+        .quote_style
+        .or(config.output.quote_style)
+        .unwrap_or_default();
+    // Quote for the shell that reads the output: the integration's, which
+    // passes --shell, or one named with --shell. Not $SHELL, which doesn't
+    // say where the output goes, and never for a file, which its own
+    // interpreter reads.
+    let target_shell = match (cli.shell, &cli.file) {
+        (Some(shell), None) => command::TargetShell::from(shell),
+        _ => command::TargetShell::default(),
+    };
+    let set_output_style = |cmd: &mut command::Command| {
+        cmd.set_quote_style(quote_style);
+        cmd.set_target_shell(target_shell);
+    };
 
-    // Handle init subcommand
-    if let Some(Command::Init { shell, bindkey }) = cli.command {
-        if let Some(script) = shell::generate_init_script(&shell, bindkey) {
-            print!("{}", script);
+    match cli.command {
+        // Handle init subcommand
+        Some(Command::Init {
+            shell,
+            bindkey,
+            no_eval,
+            mode,
+            tmux_popup,
+            no_bind,
+            prefix,
+        }) => {
+            let supported = || {
+                history::Shell::value_variants()
+                    .iter()
+                    .filter(|shell| shell.has_init_script())
+                    .map(history::Shell::name)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            };
+            let shell = match shell {
+                Some(shell) => shell,
+                None => match shell::detect_init_shell()? {
+                    Some(shell) => {
+                        eprintln!("te: generating the integration for {}", shell);
+                        shell
+                    }
+                    None => {
+                        eprintln!(
+                            "Error: Could not detect the shell; name it with `te init <SHELL>`"
+                        );
+                        eprintln!("Supported shells: {}", supported());
+                        std::process::exit(EXIT_ERROR);
+                    }
+                },
+            };
+            if !shell.has_init_script() {
+                eprintln!("Error: Unsupported shell: {}", shell);
+                eprintln!("Supported shells: {}", supported());
+                std::process::exit(EXIT_ERROR);
+            }
+            if !shell::is_valid_prefix(&prefix) {
+                eprintln!(
+                    "Error: Invalid --prefix '{}': use letters, digits, - and _, starting with a letter",
+                    prefix
+                );
+                std::process::exit(EXIT_ERROR);
+            }
+            if mode != shell::WidgetMode::Buffer && shell != history::Shell::Zsh {
+                eprintln!("Error: --mode is only supported for zsh");
+                std::process::exit(EXIT_ERROR);
+            }
+            if tmux_popup && mode == shell::WidgetMode::Region {
+                eprintln!("Error: --tmux-popup can't be combined with --mode region");
+                std::process::exit(EXIT_ERROR);
+            }
+            if tmux_popup
+                && !matches!(
+                    shell,
+                    history::Shell::Zsh | history::Shell::Bash | history::Shell::Fish
+                )
+            {
+                eprintln!("Error: --tmux-popup is only supported for zsh, bash and fish");
+                std::process::exit(EXIT_ERROR);
+            }
+            let bindkey = match bindkey.map(|spec| shell::translate_bindkey(shell, &spec)) {
+                Some(Err(err)) => {
+                    eprintln!("Error: {}", err);
+                    std::process::exit(EXIT_ERROR);
+                }
+                bindkey => bindkey.transpose()?,
+            };
+            let options = shell::InitOptions {
+                bindkey,
+                eval: !no_eval,
+                mode,
+                tmux_popup,
+                bind: !no_bind,
+                prefix,
+                ..shell::InitOptions::new(shell)
+            };
+            print!("{}", shell::generate_init_script(&options));
             return Ok(());
-        } else {
-            eprintln!("Error: Unsupported shell: {}", shell);
-            eprintln!("Supported shells: zsh, bash, fish");
-            std::process::exit(1);
+        }
+        // Handle set subcommand
+        Some(Command::Set { flags, command }) => {
+            let mut cmd = command_from_args(command, false);
+            if !cli.keep_comments {
+                cmd.strip_comment();
+            }
+            set_output_style(&mut cmd);
+            let arities = flag_arities(&cmd, &wrappers, &config.flags, &specs);
+            for spec in &flags {
+                let result = batch::parse_flag_assignment(spec)
+                    .and_then(|(flag, value)| cmd.set_flag_value(flag, value, &arities, &wrappers));
+                if let Err(err) = result {
+                    eprintln!("Error: {}", err);
+                    std::process::exit(EXIT_ERROR);
+                }
+            }
+            cmd.mark_scripts(&wrappers, &config.flags);
+            println!("{}", cmd.to_shell_string());
+            return Ok(());
+        }
+        // Handle rm subcommand
+        Some(Command::Rm {
+            flags,
+            strict,
+            command,
+        }) => {
+            let mut cmd = command_from_args(command, false);
+            if !cli.keep_comments {
+                cmd.strip_comment();
+            }
+            set_output_style(&mut cmd);
+            let arities = flag_arities(&cmd, &wrappers, &config.flags, &specs);
+            for flag in &flags {
+                if cmd.remove_flag(flag, &arities, &wrappers) == 0 && strict {
+                    eprintln!("Error: Flag not found: {}", flag);
+                    std::process::exit(EXIT_ERROR);
+                }
+            }
+            cmd.mark_scripts(&wrappers, &config.flags);
+            // Everything that isn't removed stays as it was written
+            println!("{}", cmd.to_source_string());
+            return Ok(());
+        }
+        // Handle save subcommand
+        Some(Command::Save {
+            name,
+            force,
+            command,
+        }) => {
+            let text = if !command.is_empty() {
+                command_from_args(command, false).to_shell_string()
+            } else if !io::stdin().is_terminal() {
+                let mut buffer = String::new();
+                io::stdin().lock().read_to_string(&mut buffer)?;
+                let text = input::normalize_newlines(&buffer);
+                parse_inputs_or_exit(&text);
+                text.trim_end_matches('\n').to_string()
+            } else {
+                eprintln!("Error: No command to save");
+                eprintln!("Usage: te save <name> -- <command> [args...]");
+                eprintln!("       echo '<command>' | te save <name>");
+                std::process::exit(EXIT_ERROR);
+            };
+            let Some(dir) = templates::templates_dir() else {
+                eprintln!("Error: Could not locate the config directory (set $HOME)");
+                std::process::exit(EXIT_ERROR);
+            };
+            match templates::save(&dir, &name, &text, force) {
+                Ok(path) => eprintln!("te: saved template '{}' to {}", name, path.display()),
+                Err(err) => {
+                    eprintln!("Error: {:#}", err);
+                    std::process::exit(EXIT_ERROR);
+                }
+            }
+            return Ok(());
+        }
+        // Handle completions subcommand
+        Some(Command::Completions { shell }) => {
+            clap_complete::generate(shell, &mut Cli::command(), "te", &mut io::stdout());
+            return Ok(());
+        }
+        // Handle which-shell subcommand
+        Some(Command::WhichShell) => {
+            let shell = history::detect_shell();
+            match shell {
+                Some(shell) => println!("shell: {}", shell.name()),
+                None => println!(
+                    "shell: unknown ($SHELL={})",
+                    std::env::var("SHELL").unwrap_or_default()
+                ),
+            }
+            let path = match &history_source {
+                history::HistorySource::Detected => shell.and_then(history::get_history_file),
+                history::HistorySource::File(path) => Some(path.clone()),
+                history::HistorySource::Disabled => {
+                    println!("history: disabled");
+                    return Ok(());
+                }
+            };
+            let Some(path) = path else {
+                println!("history file: unknown");
+                return Ok(());
+            };
+            println!("history file: {}", path.display());
+            match std::fs::metadata(&path) {
+                Ok(metadata) => {
+                    println!("exists: yes");
+                    println!("size: {} bytes", metadata.len());
+                }
+                Err(_) => println!("exists: no"),
+            }
+            return Ok(());
+        }
+        // Handle history subcommand
+        Some(Command::History {
+            flag,
+            json,
+            command,
+        }) => {
+            let cmd = command_from_args(command, false);
+            let components: Vec<String> = cmd.iter_components().cloned().collect();
+            let base_command = history::base_command(&components, &wrappers);
+            let entries = history_source.read()?;
+            let entries = entries
+                .into_iter()
+                .rev()
+                .take(history_limit.unwrap_or(usize::MAX));
+            let arities = flag_arities(&cmd, &wrappers, &config.flags, &specs);
+            let mut suggestions = history::load_history_for_command(
+                &base_command,
+                &wrappers,
+                &arities,
+                entries,
+                None,
+            );
+            if let Some(flag) = flag {
+                suggestions.retain(|f, _| *f == flag);
+            }
+            print_suggestions(&suggestions, json);
+            return Ok(());
+        }
+        Some(Command::Doctor) => unreachable!("doctor runs before the config is loaded"),
+        None => {}
+    }
+
+    let mut result_out: Box<dyn Write> = match cli.result_fd {
+        Some(fd) => match input::open_result_fd(fd) {
+            Ok(file) => Box::new(file),
+            Err(err) => {
+                eprintln!("Error: {} (--result-fd)", err);
+                std::process::exit(EXIT_ERROR);
+            }
+        },
+        None => Box::new(io::stdout()),
+    };
+
+    // Tokenized like a command, so a quoted word of the prefix stays one word
+    let wrap_output: Vec<String> = match cli.wrap_output.as_deref() {
+        Some(prefix) => match command::Command::try_from(prefix) {
+            Ok(prefix) => prefix.iter_components().cloned().collect(),
+            Err(err) => {
+                eprintln!("Error: Invalid --wrap-output: {}", err);
+                std::process::exit(EXIT_ERROR);
+            }
+        },
+        None => Vec::new(),
+    };
+
+    // Read the file up front, so problems with it are reported before the TUI starts
+    let stdin_file = cli.file.as_deref() == Some(std::path::Path::new("-"));
+    let file_text = match cli.file.as_deref() {
+        Some(path) if !stdin_file => {
+            let text = input::read_command_file(path).and_then(|text| {
+                if cli.in_place {
+                    input::check_writable(path)?;
+                }
+                Ok(input::normalize_newlines(&text))
+            });
+            match text {
+                Ok(text) => Some(text),
+                Err(err) => {
+                    eprintln!("Error: {}", err);
+                    std::process::exit(EXIT_ERROR);
+                }
+            }
+        }
+        _ => None,
+    };
+    // Piped input would be silently dropped in favor of the command string
+    if cli.command_string.is_some() && input::stdin_is_pipe() {
+        eprintln!("Error: -c can't be combined with a command piped to stdin");
+        std::process::exit(EXIT_PARSE_ERROR);
+    }
+    if stdin_file && cli.in_place {
+        eprintln!("Error: --in-place needs a file to write to, not stdin");
+        std::process::exit(EXIT_ERROR);
+    }
+    let shebang = file_text
+        .as_deref()
+        .and_then(|text| input::split_shebang(text).0);
+
+    // With a region, only that part of the command string is edited, and what
+    // was around it is put back on output
+    let mut around_region = None;
+    if let (Some(start), Some(end)) = (cli.region_start, cli.region_end) {
+        let text = match (
+            cli.command_string.take(),
+            cli.wrapped_command.as_mut_slice(),
+        ) {
+            (Some(text), _) => text,
+            (None, [text]) => std::mem::take(text),
+            _ => {
+                eprintln!("Error: --region-start and --region-end need a single command string");
+                std::process::exit(EXIT_ERROR);
+            }
+        };
+        match input::split_region(&text, start, end) {
+            Ok((before, region, after)) => {
+                cli.command_string = Some(region);
+                cli.wrapped_command.clear();
+                around_region = Some((before, after));
+            }
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                std::process::exit(EXIT_ERROR);
+            }
         }
     }
+    let splice = |text: String| match &around_region {
+        Some((before, after)) => format!("{}{}{}", before, text, after),
+        None => text,
+    };
 
-    // Handle wrapped command
-    let command_str = if cli.wrapped_command.is_empty() {
+    // The commands to edit, each with its text for leaving it unchanged
+    let single = |cmd: command::Command| {
+        let text = cmd.to_shell_string();
+        vec![(cmd, text)]
+    };
+    let inputs = if let Some(command_str) = cli.command_string {
+        vec![(parse_or_exit(&command_str), command_str)]
+    } else if !cli.wrapped_command.is_empty() {
+        let as_argv = follows_double_dash(&raw_args, &cli.wrapped_command);
+        single(command_from_args(cli.wrapped_command, as_argv))
+    } else if let Some(n) = cli.last {
+        if history_source == history::HistorySource::Disabled {
+            eprintln!("Error: --last needs shell history, which --no-history disables");
+            std::process::exit(EXIT_ERROR);
+        }
+        let entries = history_source.read()?;
+        match history::last_command(&entries, n) {
+            Some(entry) => vec![(parse_or_exit(entry), entry.to_string())],
+            None => {
+                eprintln!("Error: No matching entry in shell history");
+                std::process::exit(EXIT_ERROR);
+            }
+        }
+    } else if let Some(name) = &cli.template {
+        let text = templates::templates_dir()
+            .context("Could not locate the config directory (set $HOME)")
+            .and_then(|dir| templates::load(&dir, name));
+        match text {
+            Ok(text) => parse_inputs_or_exit(&text),
+            Err(err) => {
+                eprintln!("Error: {:#}", err);
+                std::process::exit(EXIT_ERROR);
+            }
+        }
+    } else if let Some(text) = &file_text {
+        parse_inputs_or_exit(input::split_shebang(text).1)
+    } else if let Some(fd) = cli.fd {
+        parse_inputs_or_exit(&input::read_from_fd(fd)?)
+    } else {
         // Check if stdin is piped (not a terminal)
         let stdin = io::stdin();
         if !stdin.is_terminal() {
             // Read from stdin
             let mut buffer = String::new();
             stdin.lock().read_to_string(&mut buffer)?;
-            buffer.trim().to_string()
+            parse_inputs_or_exit(&buffer)
+        } else if cli.clipboard
+            && let Some(text) = clipboard::paste()
+        {
+            parse_inputs_or_exit(&text)
         } else {
+            if cli.clipboard {
+                eprintln!("Error: No command specified, and the clipboard has no text");
+                std::process::exit(EXIT_ERROR);
+            }
             eprintln!("Error: No command specified");
             eprintln!("Usage: te <command> [args...]");
             eprintln!("       echo '<command>' | te");
-            std::process::exit(1);
+            std::process::exit(EXIT_ERROR);
         }
-    } else {
-        cli.wrapped_command.join(" ")
     };
 
-    let final_command = run_tui(&command_str)?;
+    if cli.null {
+        for (cmd, _) in &inputs {
+            reject_null_operators(cmd);
+        }
+    }
 
-    if let Some(cmd) = final_command {
-        println!("{}", cmd);
-    } else {
-        std::process::exit(1);
+    if cli.strict_parse {
+        let mut ambiguous = false;
+        let mut flag_ambiguous = false;
+        for (cmd, _) in &inputs {
+            let arities = flag_arities(cmd, &wrappers, &config.flags, &specs);
+            for ambiguity in cmd.ambiguities(&arities) {
+                eprintln!("Error: {}", ambiguity);
+                eprintln!("{}", cmd.point_at(ambiguity.index()));
+                ambiguous = true;
+                flag_ambiguous |= matches!(ambiguity, command::Ambiguity::FlagOrArgument { .. });
+            }
+        }
+        if flag_ambiguous {
+            eprintln!(
+                "Set how many values a flag takes under [flags.<program>] in the config, or leave out --strict-parse"
+            );
+        }
+        if ambiguous {
+            std::process::exit(EXIT_PARSE_ERROR);
+        }
+    }
+
+    let history_budget = config::resolve(
+        cli.history_budget,
+        "TE_HISTORY_BUDGET",
+        config.history.budget_ms,
+    )?
+    .map_or(
+        history::DEFAULT_HISTORY_BUDGET,
+        std::time::Duration::from_millis,
+    );
+    let mut key_bindings = config.keys;
+    if let Some(chords) = config::resolve(
+        None,
+        "TE_ACCEPT_KEY",
+        key_bindings.remove(&keys::Action::Accept),
+    )? {
+        key_bindings.insert(keys::Action::Accept, chords);
+    }
+    let (keymap, keymap_warnings) = keys::KeyMap::new(&key_bindings);
+    for warning in &keymap_warnings {
+        eprintln!("te: warning: {}", warning);
+    }
+    let mut options = TuiOptions {
+        history: history_source,
+        history_budget,
+        history_cache: Default::default(),
+        history_limit,
+        repeatable_flags: cli.allow_repeat,
+        wrappers,
+        completions_only: cli.completions_only,
+        wrap_navigation: !cli.no_wrap_nav && config.navigation.wrap.unwrap_or(true),
+        keymap,
+        providers: providers::Providers::new(config.providers),
+        carapace: carapace::Carapace::new(config.carapace),
+        bash_completion: bash_completion::BashCompletion::new(config.bash_completion),
+        help: help::HelpChoices::new(
+            config.help,
+            session::cache_dir().map(|dir| dir.join("help")),
+        ),
+        specs,
+        mouse: features.mouse,
+        hints: features.hints,
+        flag_value_counts: config.flags,
+        flag_aliases: config.flag_aliases,
+        batch: None,
+        reverse: cli.reverse,
+        fullscreen: cli.fullscreen,
+        top: cli.top,
+        line_numbers: cli.line_numbers,
+        brace_preview: cli.brace_preview,
+        undo_limit: config.undo.limit.unwrap_or(undo::DEFAULT_UNDO_LIMIT),
+        session: None,
+    };
+    // Check for a terminal before loading history or touching terminal state
+    if cli.keys.is_none()
+        && let Err(err) = tui::open_tty()
+    {
+        if cli.passthrough {
+            for (_, text) in &inputs {
+                writeln!(result_out, "{}", text)?;
+            }
+            return Ok(());
+        }
+        eprintln!("Error: {:#}", err);
+        eprintln!("Pass --passthrough to print the command unchanged when there is no terminal");
+        std::process::exit(EXIT_ERROR);
+    }
+
+    // Several piped commands are edited one after another; Esc skips one
+    let total = inputs.len();
+    let mut key_script = cli
+        .keys
+        .as_deref()
+        .map(keys::parse_key_sequence)
+        .transpose()?
+        .map(Vec::into_iter);
+    let mut results = Vec::with_capacity(total);
+    // The flag values each result took from history, for --annotate
+    let mut from_history = Vec::with_capacity(total);
+    // The comment lines above each command, kept along with its trailing
+    // comment
+    let comments: Vec<String> = inputs
+        .iter()
+        .map(|(_, text)| match cli.keep_comments || cli.in_place {
+            true => input::leading_comments(text).to_string(),
+            false => String::new(),
+        })
+        .collect();
+    let mut skip_remaining = false;
+    let mut esc_cancels_all = false;
+    let mut run = false;
+    for (position, (mut cmd, text)) in inputs.into_iter().enumerate() {
+        if skip_remaining {
+            results.push(Edited::Skipped(text));
+            from_history.push(None);
+            continue;
+        }
+        // A file written back keeps its comments
+        if !cli.keep_comments && !cli.in_place {
+            cmd.strip_comment();
+        }
+        set_output_style(&mut cmd);
+        options.batch = (total > 1).then_some(BatchProgress {
+            position: position + 1,
+            total,
+            esc_cancels_all,
+        });
+        // A single command's edit is saved as it goes, to be offered back
+        // if te or the terminal dies before it is done
+        options.session = (total == 1)
+            .then(session::default_session_dir)
+            .flatten()
+            .map(|dir| SessionFile::new(&dir, &text));
+        let original: Vec<String> = cmd.iter_components().cloned().collect();
+        let outcome = match &mut key_script {
+            Some(keys) => run_headless(cmd, keys, &options)?,
+            None => run_tui(cmd, &options)?,
+        };
+        // Nothing is waiting for the values of commands still running
+        providers::kill_running();
+        esc_cancels_all = false;
+        match outcome {
+            Outcome::Accepted(cmd, values) => {
+                results.push(Edited::Accepted(cmd, original));
+                from_history.push(Some(values));
+            }
+            Outcome::AcceptedToRun(cmd, values) => {
+                run = true;
+                results.push(Edited::Accepted(cmd, original));
+                from_history.push(Some(values));
+            }
+            Outcome::Cancelled if total > 1 => {
+                esc_cancels_all = true;
+                results.push(Edited::Skipped(text));
+                from_history.push(None);
+            }
+            Outcome::SkipRemaining => {
+                skip_remaining = true;
+                results.push(Edited::Skipped(text));
+                from_history.push(None);
+            }
+            Outcome::Cancelled | Outcome::Interrupted => {
+                profile::report();
+                std::process::exit(EXIT_CANCELLED)
+            }
+        }
+    }
+    profile::report();
+
+    if !wrap_output.is_empty() {
+        for result in &mut results {
+            if let Edited::Accepted(cmd, _) = result {
+                for (i, word) in wrap_output.iter().enumerate() {
+                    cmd.insert_component_at(i, word.clone());
+                }
+            }
+        }
+    }
+    if let Some(post_command) = &config.output.post_command {
+        results = results
+            .into_iter()
+            .map(|result| result.post_process(post_command))
+            .collect();
+    }
+    let shell_strings: Vec<String> = results
+        .iter()
+        .zip(&comments)
+        .map(|(result, comments)| match result {
+            // Kept as given, comments and all
+            Edited::Skipped(text) => splice(text.clone()),
+            _ => format!("{}{}", comments, splice(result.to_shell_string())),
+        })
+        .collect();
+    if (cli.copy || cli.copy_only)
+        && let Err(err) = clipboard::copy(
+            &shell_strings.join("\n"),
+            match cli.clipboard_method {
+                clipboard::ClipboardMethod::Auto if !features.osc52 => {
+                    clipboard::ClipboardMethod::Native
+                }
+                method => method,
+            },
+        )
+    {
+        eprintln!("te: warning: could not copy to the clipboard: {:#}", err);
+    }
+    if cli.copy_only {
+        return Ok(());
+    }
+    if cli.in_place
+        && let Some(path) = &cli.file
+    {
+        // Commands keep the lines they were written over, unless --width
+        // asks for them to be wrapped
+        let commands: Vec<String> = results
+            .iter()
+            .zip(&comments)
+            .map(|(result, comments)| match result {
+                Edited::Accepted(cmd, _) => format!(
+                    "{}{}",
+                    comments,
+                    splice(match cli.width {
+                        Some(width) => cmd.to_multiline_shell_string(width),
+                        None => cmd.to_source_string(),
+                    })
+                ),
+                Edited::PostProcessed { text, .. } => {
+                    format!("{}{}", comments, splice(text.clone()))
+                }
+                Edited::Skipped(text) => splice(text.clone()),
+            })
+            .collect();
+        let mut text = commands.join("\n") + "\n";
+        if let Some(shebang) = shebang {
+            text = format!("{}\n{}", shebang, text);
+        }
+        if let Err(err) = input::write_in_place(path, &text, !cli.no_backup) {
+            eprintln!("Error: {:#}", err);
+            std::process::exit(EXIT_ERROR);
+        }
+        return Ok(());
+    }
+    let width = cli.width.unwrap_or_else(|| {
+        if cli.result_fd.is_none() && io::stdout().is_terminal() {
+            crossterm::terminal::size().map_or(0, |(cols, _)| cols as usize)
+        } else {
+            0
+        }
+    });
+    for ((result, from_history), comments) in results.into_iter().zip(from_history).zip(comments) {
+        if cli.annotate
+            && let OutputFormat::Shell = cli.output
+            && let Some(values) = from_history
+        {
+            writeln!(result_out, "{}", annotation(&values))?;
+        }
+        if cli.null {
+            let cmd = match result {
+                Edited::Accepted(cmd, _) | Edited::PostProcessed { cmd: Some(cmd), .. } => cmd,
+                Edited::PostProcessed { text, .. } | Edited::Skipped(text) => parse_or_exit(&text),
+            };
+            // Operators can also be inserted while editing
+            reject_null_operators(&cmd);
+            for word in cmd.iter_components() {
+                write!(result_out, "{}\0", word)?;
+            }
+            continue;
+        }
+        let line = match (cli.output, result) {
+            (OutputFormat::Shell, Edited::Accepted(cmd, _)) => {
+                format!(
+                    "{}{}",
+                    comments,
+                    splice(cmd.to_multiline_shell_string(width))
+                )
+            }
+            (OutputFormat::Shell, Edited::PostProcessed { text, .. })
+            | (OutputFormat::Shell, Edited::Skipped(text)) => splice(text),
+            (
+                OutputFormat::Json,
+                Edited::PostProcessed {
+                    text,
+                    cmd,
+                    original,
+                },
+            ) => {
+                let mut json = match cmd {
+                    Some(cmd) => command_json(&cmd, &original),
+                    None => serde_json::json!({}),
+                };
+                json["command"] = serde_json::Value::String(text);
+                json.to_string()
+            }
+            (OutputFormat::Json, Edited::Accepted(cmd, original)) => {
+                command_json(&cmd, &original).to_string()
+            }
+            (OutputFormat::Json, Edited::Skipped(text)) => {
+                let cmd = parse_or_exit(&text);
+                let original: Vec<String> = cmd.iter_components().cloned().collect();
+                command_json(&cmd, &original).to_string()
+            }
+        };
+        writeln!(result_out, "{}", line)?;
+    }
+    if run {
+        result_out.flush()?;
+        std::process::exit(EXIT_RUN);
     }
 
     Ok(())
@@ -0,0 +1,139 @@
+use std::{
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+/// A phase of a run that is timed with `--profile`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Parsing the commands to edit
+    Parse,
+    /// Reading the history file
+    HistoryRead,
+    /// Learning suggestions from the history entries
+    History,
+    /// Drawing the first frame of the editor
+    FirstDraw,
+}
+
+/// How long each phase took, summed over the commands of a batch.
+/// `None` for a phase that didn't run.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Timings {
+    pub parse: Option<Duration>,
+    pub history_read: Option<Duration>,
+    pub history: Option<Duration>,
+    /// Only the very first frame, which is the one the user waits for
+    pub first_draw: Option<Duration>,
+    /// From enabling profiling to the end of the first frame
+    pub until_first_draw: Option<Duration>,
+}
+
+impl Timings {
+    pub fn record(&mut self, phase: Phase, elapsed: Duration) {
+        let add = |total: &mut Option<Duration>| *total = Some(total.unwrap_or_default() + elapsed);
+        match phase {
+            Phase::Parse => add(&mut self.parse),
+            Phase::HistoryRead => add(&mut self.history_read),
+            Phase::History => add(&mut self.history),
+            Phase::FirstDraw => {
+                self.first_draw.get_or_insert(elapsed);
+            }
+        }
+    }
+
+    /// One `te: profile:` line per phase, for stderr
+    pub fn report(&self) -> String {
+        [
+            ("parse", self.parse),
+            ("history read", self.history_read),
+            ("history", self.history),
+            ("first draw", self.first_draw),
+            ("until first draw", self.until_first_draw),
+        ]
+        .iter()
+        .map(|(name, elapsed)| match elapsed {
+            Some(elapsed) => format!(
+                "te: profile: {:<16} {:>9.3}ms",
+                name,
+                elapsed.as_secs_f64() * 1000.0
+            ),
+            None => format!("te: profile: {:<16} {:>11}", name, "-"),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+    }
+}
+
+struct Profile {
+    start: Instant,
+    timings: Mutex<Timings>,
+}
+
+static PROFILE: OnceLock<Profile> = OnceLock::new();
+
+/// Start recording timings
+pub fn enable() {
+    PROFILE.get_or_init(|| Profile {
+        start: Instant::now(),
+        timings: Mutex::default(),
+    });
+}
+
+/// Run `f`, recording how long it took as `phase` if profiling is enabled.
+/// With profiling off this costs a single check.
+pub fn time<T>(phase: Phase, f: impl FnOnce() -> T) -> T {
+    let Some(profile) = PROFILE.get() else {
+        return f();
+    };
+    let start = Instant::now();
+    let result = f();
+    let end = Instant::now();
+    if let Ok(mut timings) = profile.timings.lock() {
+        timings.record(phase, end - start);
+        if phase == Phase::FirstDraw {
+            timings.until_first_draw.get_or_insert(end - profile.start);
+        }
+    }
+    result
+}
+
+/// Print the timings to stderr if profiling is enabled
+pub fn report() {
+    if let Some(profile) = PROFILE.get()
+        && let Ok(timings) = profile.timings.lock()
+    {
+        eprintln!("{}", timings.report());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let mut timings = Timings::default();
+        timings.record(Phase::Parse, Duration::from_millis(2));
+        timings.record(Phase::Parse, Duration::from_millis(3));
+        timings.record(Phase::FirstDraw, Duration::from_millis(7));
+        timings.record(Phase::FirstDraw, Duration::from_millis(1));
+
+        assert_eq!(timings.parse, Some(Duration::from_millis(5)));
+        assert_eq!(timings.first_draw, Some(Duration::from_millis(7)));
+        assert_eq!(timings.history, None);
+    }
+
+    #[test]
+    fn test_time_records_when_enabled() {
+        enable();
+        assert_eq!(time(Phase::History, || 42), 42);
+        time(Phase::FirstDraw, || ());
+
+        let timings = PROFILE.get().unwrap().timings.lock().unwrap().clone();
+        assert!(timings.history.is_some());
+        assert!(timings.first_draw.is_some());
+        assert!(timings.until_first_draw >= timings.first_draw);
+        assert!(timings.report().contains("te: profile: history "));
+    }
+}
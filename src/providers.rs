@@ -0,0 +1,329 @@
+use std::{
+    collections::BTreeMap,
+    io::Read,
+    process::{Command, Stdio},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::{background::BackgroundRuns, debug};
+
+/// How long a provider may run before it is given up on
+pub const DEFAULT_PROVIDER_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How much of a provider's output is read; the rest is dropped
+pub const MAX_PROVIDER_OUTPUT: usize = 64 * 1024;
+
+/// A command whose output, one value per line, offers values for a flag
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ProviderConfig {
+    /// Run with `sh -c`
+    pub cmd: String,
+    /// Milliseconds to wait for it, instead of [`DEFAULT_PROVIDER_TIMEOUT`]
+    pub timeout_ms: Option<u64>,
+}
+
+/// Providers as `[providers.<program>.<flag>]` tables, where the flag can
+/// also be `positional0`, `positional1`, … for the arguments
+pub type ProviderConfigs = BTreeMap<String, BTreeMap<String, ProviderConfig>>;
+
+/// Runs providers in the background, each at most once per run of te
+#[derive(Debug, Clone, Default)]
+pub struct Providers {
+    configs: Arc<ProviderConfigs>,
+    results: BackgroundRuns<(String, String), Vec<String>>,
+}
+
+impl Providers {
+    pub fn new(configs: ProviderConfigs) -> Self {
+        Self {
+            configs: Arc::new(configs),
+            results: BackgroundRuns::default(),
+        }
+    }
+
+    /// The command that provides values for `key` of `program`, if any
+    pub fn source(&self, program: &str, key: &str) -> Option<&str> {
+        Some(self.configs.get(program)?.get(key)?.cmd.as_str())
+    }
+
+    /// Start the provider for `key` of `program`, unless there is none or
+    /// it has already run
+    pub fn request(&self, program: &str, key: &str) {
+        let Some(config) = self.configs.get(program).and_then(|keys| keys.get(key)) else {
+            return;
+        };
+        let config = config.clone();
+        let (program, key) = (program.to_string(), key.to_string());
+        self.results.start((program.clone(), key.clone()), move || {
+            let timeout = config
+                .timeout_ms
+                .map_or(DEFAULT_PROVIDER_TIMEOUT, Duration::from_millis);
+            // A provider that fails leaves just the history values
+            run_provider(&config.cmd, timeout).unwrap_or_else(|err| {
+                debug::log(&format!(
+                    "provider for {} {} failed: {:#}",
+                    program, key, err
+                ));
+                Vec::new()
+            })
+        });
+    }
+
+    /// The values the provider for `key` of `program` printed, once it's done
+    pub fn values(&self, program: &str, key: &str) -> Vec<String> {
+        self.results
+            .get(&(program.to_string(), key.to_string()))
+            .unwrap_or_default()
+    }
+
+    /// Whether any provider is still running
+    pub fn running(&self) -> bool {
+        self.results.running()
+    }
+}
+
+/// Run `cmd` with `sh -c` and return the distinct non-empty lines it
+/// printed, reading at most [`MAX_PROVIDER_OUTPUT`] bytes of it
+pub fn run_provider(cmd: &str, timeout: Duration) -> Result<Vec<String>> {
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(cmd);
+    let output = run_capped(command, cmd, timeout, MAX_PROVIDER_OUTPUT)?;
+
+    let mut text = String::from_utf8_lossy(&output).into_owned();
+    // A line cut off by the size limit isn't a value
+    if output.len() == MAX_PROVIDER_OUTPUT
+        && let Some(end) = text.rfind('\n')
+    {
+        text.truncate(end);
+    }
+    let mut values: Vec<String> = Vec::new();
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        if !values.iter().any(|value| value == line) {
+            values.push(line.to_string());
+        }
+    }
+    Ok(values)
+}
+
+/// Process groups of the commands [`run_capped`] is waiting for
+static RUNNING: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+
+/// Run `command`, described as `cmd` in errors, and return the first `limit`
+/// bytes of its output. It is killed if it runs past `timeout`, and fails if
+/// it exits unsuccessfully.
+pub fn run_capped(
+    mut command: Command,
+    cmd: &str,
+    timeout: Duration,
+    limit: usize,
+) -> Result<Vec<u8>> {
+    command.stderr(Stdio::null());
+    run_capped_with_input(command, cmd, None, timeout, limit)
+}
+
+/// [`run_capped`], writing `input` to the command's stdin and leaving its
+/// stderr as `command` set it up.
+///
+/// The command runs in a session of its own, so it has no terminal to open,
+/// and everything it starts is killed with it on timeout or by
+/// [`kill_running`]. Whatever is still running once it exits is killed too,
+/// so a background job holding its output open can't keep te waiting.
+pub fn run_capped_with_input(
+    mut command: Command,
+    cmd: &str,
+    input: Option<&str>,
+    timeout: Duration,
+    limit: usize,
+) -> Result<Vec<u8>> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        unsafe {
+            command.pre_exec(|| {
+                libc::setsid();
+                Ok(())
+            });
+        }
+    }
+    let stdin = match input {
+        Some(_) => Stdio::piped(),
+        None => Stdio::null(),
+    };
+    let mut child = command
+        .stdin(stdin)
+        .stdout(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to run '{}'", cmd))?;
+    let group = child.id();
+    RUNNING.lock().unwrap().push(group);
+    let result = wait_capped(&mut child, cmd, input, timeout, limit);
+    RUNNING.lock().unwrap().retain(|running| *running != group);
+    result
+}
+
+/// Kill the commands [`run_capped`] is still waiting for, with everything
+/// they started
+pub fn kill_running() {
+    for group in RUNNING.lock().unwrap().drain(..) {
+        kill_group(group);
+    }
+}
+
+fn kill_group(group: u32) {
+    #[cfg(unix)]
+    unsafe {
+        libc::kill(-(group as libc::pid_t), libc::SIGKILL);
+    }
+    #[cfg(not(unix))]
+    let _ = group;
+}
+
+fn wait_capped(
+    child: &mut std::process::Child,
+    cmd: &str,
+    input: Option<&str>,
+    timeout: Duration,
+    limit: usize,
+) -> Result<Vec<u8>> {
+    if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+        let input = input.to_string();
+        // Write on another thread, so a command that doesn't read can't block
+        std::thread::spawn(move || {
+            use std::io::Write;
+            // A command that doesn't read its input may exit before it's
+            // written
+            let _ = stdin.write_all(input.as_bytes());
+        });
+    }
+    let mut stdout = child.stdout.take().context("No output to read")?;
+    // Read on another thread, so a command that never exits can be killed
+    let reader = std::thread::spawn(move || {
+        let mut output = Vec::new();
+        (&mut stdout).take(limit as u64).read_to_end(&mut output)?;
+        // Keep draining, so the command doesn't block on a full pipe
+        std::io::copy(&mut stdout, &mut std::io::sink())?;
+        Ok::<_, std::io::Error>(output)
+    });
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            kill_group(child.id());
+            let _ = child.kill();
+            let _ = child.wait();
+            anyhow::bail!("'{}' timed out after {}ms", cmd, timeout.as_millis());
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    };
+    // Whatever the command left running in the background may hold its
+    // output open; it goes with the command, so reading ends here
+    kill_group(child.id());
+    if !status.success() {
+        anyhow::bail!("'{}' failed with {}", cmd, status);
+    }
+    let output = reader
+        .join()
+        .map_err(|_| anyhow::anyhow!("Failed to read the output of '{}'", cmd))??;
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_provider() {
+        let values = run_provider(
+            "printf 'prod\\n\\nstaging\\n prod \\ndev'",
+            Duration::from_secs(5),
+        );
+        assert_eq!(values.unwrap(), ["prod", "staging", "dev"]);
+
+        assert!(run_provider("echo prod; exit 1", Duration::from_secs(5)).is_err());
+        let err = run_provider("sleep 5", Duration::from_millis(50)).unwrap_err();
+        assert!(err.to_string().contains("timed out"), "{}", err);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_run_capped_kills_the_group() {
+        let dir = std::env::temp_dir().join(format!("te-group-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let pid_file = dir.join("pid");
+        let script = format!("sleep 30 & echo $! > {}; wait", pid_file.display());
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(&script);
+        let err = run_capped(command, &script, Duration::from_millis(200), 1024).unwrap_err();
+        assert!(err.to_string().contains("timed out"), "{}", err);
+
+        let pid = std::fs::read_to_string(&pid_file).unwrap();
+        let stat = format!("/proc/{}/stat", pid.trim());
+        // Killed, and gone or a zombie waiting for init to reap it
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while std::fs::read_to_string(&stat).is_ok_and(|stat| !stat.contains(") Z ")) {
+            assert!(Instant::now() < deadline, "the grandchild is still running");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_provider_ends_with_the_command() {
+        // The background sleep holds the output open after sh exits
+        let started = Instant::now();
+        let values = run_provider("sleep 20 & echo prod", Duration::from_secs(10)).unwrap();
+        assert_eq!(values, ["prod"]);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_run_provider_caps_output() {
+        let values =
+            run_provider("yes value-line | head -n 100000", Duration::from_secs(5)).unwrap();
+        assert_eq!(values, ["value-line"]);
+
+        let values = run_provider("seq 1 100000", Duration::from_secs(5)).unwrap();
+        assert!(values.len() < 100000);
+        // The last line read is whole
+        let last: usize = values.last().unwrap().parse().unwrap();
+        assert_eq!(last, values.len());
+    }
+
+    #[test]
+    fn test_providers_run_once() {
+        let configs: ProviderConfigs = toml::from_str(
+            "[kubectl.\"-n\"]\ncmd = \"echo prod; echo staging\"\n\n[ssh.positional0]\ncmd = \"exit 1\"\n",
+        )
+        .unwrap();
+        let providers = Providers::new(configs);
+        assert_eq!(
+            providers.source("kubectl", "-n"),
+            Some("echo prod; echo staging")
+        );
+        assert_eq!(providers.source("kubectl", "-o"), None);
+
+        providers.request("kubectl", "-n");
+        providers.request("ssh", "positional0");
+        providers.request("kubectl", "-o");
+        while providers.running() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(providers.values("kubectl", "-n"), ["prod", "staging"]);
+        assert!(providers.values("ssh", "positional0").is_empty());
+        assert!(providers.values("kubectl", "-o").is_empty());
+
+        // Clones share the results, and nothing runs again
+        let clone = providers.clone();
+        clone.request("kubectl", "-n");
+        assert!(!clone.running());
+        assert_eq!(clone.values("kubectl", "-n"), ["prod", "staging"]);
+    }
+}
@@ -0,0 +1,407 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    command::Component,
+    history::is_sensitive_flag,
+    undo::{Undo, UndoAction, UndoStep},
+};
+
+/// How long an unfinished edit is offered back before it is forgotten
+const SESSION_MAX_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// `$XDG_CACHE_HOME/te`, falling back to `~/.cache/te`
+pub fn cache_dir() -> Option<PathBuf> {
+    let cache_home = std::env::var_os("XDG_CACHE_HOME")
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))?;
+    Some(cache_home.join("te"))
+}
+
+/// `$XDG_CACHE_HOME/te/sessions`, falling back to `~/.cache/te/sessions`
+pub fn default_session_dir() -> Option<PathBuf> {
+    Some(cache_dir()?.join("sessions"))
+}
+
+/// An unfinished edit, as written to the session file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Session {
+    /// When the session was written, in seconds since the Unix epoch
+    pub saved_at: u64,
+    pub components: Vec<Component>,
+    /// Positions of the secret values left out of `components`, which are
+    /// filled in again from the command as given
+    pub redacted: Vec<usize>,
+    pub undo: Vec<UndoStep>,
+    pub redo: Vec<UndoStep>,
+}
+
+/// Where the edit of one command is kept while it is in progress, so it can
+/// be picked up again if te or the terminal dies.
+///
+/// Each command has its own file, named by a hash of the command so the file
+/// name doesn't give the command away.
+#[derive(Debug, Clone)]
+pub struct SessionFile {
+    path: PathBuf,
+}
+
+impl SessionFile {
+    /// The session file in `dir` for editing `original`
+    pub fn new(dir: &Path, original: &str) -> Self {
+        Self {
+            path: dir.join(format!("{:016x}.json", fnv1a(original))),
+        }
+    }
+
+    /// The session left behind by an earlier edit of the same command, if
+    /// it isn't too old. Secret values come back from `given`, the
+    /// components of the command as given.
+    pub fn load(&self, given: &[Component]) -> Option<Session> {
+        let text = std::fs::read_to_string(&self.path).ok()?;
+        let mut session: Session = serde_json::from_str(&text).ok()?;
+        if is_expired(session.saved_at, now()) {
+            self.remove();
+            return None;
+        }
+        for &position in &session.redacted {
+            let Some(value) = secret_value(&session.components, position, given) else {
+                continue;
+            };
+            if let Some(component) = session.components.get_mut(position) {
+                component.set_value(&value);
+            }
+        }
+        Some(session)
+    }
+
+    /// Write the current state of the edit, replacing any earlier session.
+    ///
+    /// Values of flags like `--password` or `--token` are left out. If the
+    /// edit has touched one, its undo history isn't written either, since the
+    /// steps would hold the secret.
+    pub fn save(&self, components: &[Component], undo: &Undo) -> Result<()> {
+        let (components, redacted) = redact(components);
+        let keep_history = redacted.is_empty()
+            && !undo
+                .undo_steps()
+                .iter()
+                .chain(undo.redo_steps())
+                .any(|step| mentions_secret(&step.action));
+        let (undo, redo) = match keep_history {
+            true => (undo.undo_steps().to_vec(), undo.redo_steps().to_vec()),
+            false => (Vec::new(), Vec::new()),
+        };
+        let session = Session {
+            saved_at: now(),
+            components,
+            redacted,
+            undo,
+            redo,
+        };
+        if let Some(dir) = self.path.parent() {
+            create_private_dir(dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        // Write next to the file and rename, so a crash mid-write leaves the
+        // previous session intact
+        let temp = self.path.with_extension("json.tmp");
+        write_private(&temp, &serde_json::to_string(&session)?)
+            .with_context(|| format!("Failed to write {}", temp.display()))?;
+        std::fs::rename(&temp, &self.path)
+            .with_context(|| format!("Failed to write {}", self.path.display()))
+    }
+
+    /// Forget the session once the edit has ended normally, along with any
+    /// other session that has expired
+    pub fn remove(&self) {
+        let _ = std::fs::remove_file(&self.path);
+        let Some(entries) = self
+            .path
+            .parent()
+            .and_then(|dir| std::fs::read_dir(dir).ok())
+        else {
+            return;
+        };
+        let now = now();
+        for entry in entries.flatten() {
+            let expired = std::fs::read_to_string(entry.path())
+                .ok()
+                .and_then(|text| serde_json::from_str::<Session>(&text).ok())
+                .is_some_and(|session| is_expired(session.saved_at, now));
+            if expired {
+                let _ = std::fs::remove_file(entry.path());
+            }
+        }
+    }
+}
+
+/// The current time in seconds since the Unix epoch
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// Whether a session saved at `saved_at` is too old to offer at `now`
+fn is_expired(saved_at: u64, now: u64) -> bool {
+    now.saturating_sub(saved_at) > SESSION_MAX_AGE.as_secs()
+}
+
+/// The 64-bit FNV-1a hash of `text`, which stays the same across builds
+fn fnv1a(text: &str) -> u64 {
+    text.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// Whether `word` is a flag whose values may be secrets, alone or with its
+/// value attached
+fn is_secret_flag(word: &str) -> bool {
+    word.starts_with('-') && is_sensitive_flag(word.split_once('=').map_or(word, |(flag, _)| flag))
+}
+
+/// `components` with the values of secret flags emptied, and the positions
+/// that were emptied. An attached value keeps its `--flag=`.
+fn redact(components: &[Component]) -> (Vec<Component>, Vec<usize>) {
+    let mut redacted = Vec::new();
+    let mut components = components.to_vec();
+    for position in 0..components.len() {
+        let value = components[position].value().to_string();
+        if let Some((flag, _)) = value.split_once('=')
+            && is_secret_flag(flag)
+        {
+            components[position].set_value(&format!("{}=", flag));
+            redacted.push(position);
+        } else if position > 0
+            && !value.starts_with('-')
+            && is_secret_flag(components[position - 1].value())
+            && !components[position - 1].value().contains('=')
+        {
+            components[position].set_value("");
+            redacted.push(position);
+        }
+    }
+    (components, redacted)
+}
+
+/// The value that was left out at `position` of `components`, from the
+/// same flag in `given`
+fn secret_value(components: &[Component], position: usize, given: &[Component]) -> Option<String> {
+    let value = components.get(position)?.value();
+    if let Some(flag) = value.strip_suffix('=') {
+        return given
+            .iter()
+            .find(|component| component.value().starts_with(value))
+            .map(|component| format!("{}={}", flag, &component.value()[value.len()..]));
+    }
+    let flag = components.get(position.checked_sub(1)?)?.value();
+    given
+        .windows(2)
+        .find(|pair| pair[0].value() == flag)
+        .map(|pair| pair[1].value().to_string())
+}
+
+/// Whether an undo action holds a secret flag, and so maybe its value
+fn mentions_secret(action: &UndoAction) -> bool {
+    match action {
+        UndoAction::Insert { component, .. } | UndoAction::Delete { component, .. } => {
+            is_secret_flag(component.value())
+        }
+        UndoAction::Edit {
+            original_value,
+            updated_value,
+            ..
+        } => is_secret_flag(original_value) || is_secret_flag(updated_value),
+        UndoAction::Move { .. } => false,
+        UndoAction::Replace {
+            original, updated, ..
+        } => original
+            .iter()
+            .chain(updated)
+            .any(|component| is_secret_flag(component.value())),
+        UndoAction::Group(actions) => actions.iter().any(mentions_secret),
+    }
+}
+
+/// Creates `dir` and its parents, with only the owner allowed in the ones
+/// created
+fn create_private_dir(dir: &Path) -> std::io::Result<()> {
+    let mut builder = std::fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    builder.create(dir)
+}
+
+/// Writes `text` to a new file at `path` that only the owner can read
+fn write_private(path: &Path, text: &str) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    // The mode only applies to a file being created, so a temp file left
+    // from an older te is removed first
+    let _ = std::fs::remove_file(path);
+    options.open(path)?.write_all(text.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn components(words: &[&str]) -> Vec<Component> {
+        words
+            .iter()
+            .map(|w| Component::new(w.to_string()))
+            .collect()
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("te-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn test_save_load_and_remove() {
+        let dir = temp_dir("session");
+        let given = components(&["kubectl", "get", "pods", "-n", "prod"]);
+        let file = SessionFile::new(&dir, "kubectl get pods -n prod");
+        assert_eq!(file.load(&given), None);
+
+        let components = vec![
+            Component::new("kubectl".to_string()),
+            Component::operator("|"),
+        ];
+        let mut undo = Undo::default();
+        undo.push(
+            UndoAction::Delete {
+                position: 2,
+                component: Component::new("pods".to_string()),
+            },
+            true,
+        );
+        undo.push_redo(UndoAction::Move { from: 0, to: 1 });
+        file.save(&components, &undo).unwrap();
+
+        let session = file.load(&given).unwrap();
+        assert_eq!(session.components, components);
+        assert_eq!(session.undo, undo.undo_steps());
+        assert_eq!(session.redo, undo.redo_steps());
+
+        // Only the same command picks the session up
+        let other = SessionFile::new(&dir, "kubectl get pods");
+        assert_eq!(other.load(&given), None);
+
+        // The file is named by the command's hash, and private
+        let entries: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(entries.len(), 1);
+        assert!(!entries[0].contains("kubectl"));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(dir.join(&entries[0]))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        file.remove();
+        assert_eq!(file.load(&given), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_secrets_are_not_written() {
+        let dir = temp_dir("session-secrets");
+        let original = "mysql --password hunter2 --api-token=abc123 -h db";
+        let given = components(&[
+            "mysql",
+            "--password",
+            "hunter2",
+            "--api-token=abc123",
+            "-h",
+            "db",
+        ]);
+        let file = SessionFile::new(&dir, original);
+        let mut edited = given.clone();
+        edited[5].set_value("db2");
+        let mut undo = Undo::default();
+        undo.push(
+            UndoAction::Edit {
+                position: 5,
+                original_value: "db".to_string(),
+                updated_value: "db2".to_string(),
+            },
+            true,
+        );
+        file.save(&edited, &undo).unwrap();
+
+        let text = std::fs::read_to_string(&file.path).unwrap();
+        assert!(!text.contains("hunter2"), "{}", text);
+        assert!(!text.contains("abc123"), "{}", text);
+
+        // The secrets come back from the command as given, without the undo
+        // history that might have held them
+        let session = file.load(&given).unwrap();
+        assert_eq!(session.components, {
+            let mut restored = given.clone();
+            restored[5].set_value("db2");
+            restored
+        });
+        assert!(session.undo.is_empty());
+
+        // A deleted secret flag keeps the undo history out as well
+        let mut undo = Undo::default();
+        undo.push(
+            UndoAction::Delete {
+                position: 1,
+                component: Component::new("--password".to_string()),
+            },
+            true,
+        );
+        file.save(&components(&["mysql", "-h", "db"]), &undo)
+            .unwrap();
+        assert!(file.load(&given).unwrap().undo.is_empty());
+
+        file.remove();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_expired_session_is_removed() {
+        let dir = temp_dir("session-expired");
+        let given = components(&["ls"]);
+        let file = SessionFile::new(&dir, "ls");
+        file.save(&given, &Undo::default()).unwrap();
+        let mut session: Session =
+            serde_json::from_str(&std::fs::read_to_string(&file.path).unwrap()).unwrap();
+        session.saved_at -= SESSION_MAX_AGE.as_secs() + 1;
+        std::fs::write(&file.path, serde_json::to_string(&session).unwrap()).unwrap();
+
+        assert_eq!(file.load(&given), None);
+        assert!(!file.path.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_fnv1a() {
+        assert_eq!(fnv1a(""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a("a"), 0xaf63_dc4c_8601_ec8c);
+    }
+}
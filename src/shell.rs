@@ -1,111 +1,1204 @@
-pub fn generate_init_script(shell: &str, bindkey: Option<String>) -> Option<String> {
+use crate::history::{self, Shell, ShellOrigin};
+
+/// What the zsh widget does with the edited command
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum WidgetMode {
+    /// Replace the command line with it
+    #[default]
+    Buffer,
+    /// Put it on the command line, and bring the line being typed back
+    /// after it runs (`zle push-line`)
+    Stage,
+    /// Add it to the shell history, reachable with Up (`print -s`), leaving
+    /// the command line as it was
+    History,
+    /// Only edit the selected region, or else the part left of the cursor,
+    /// and put it back in place
+    Region,
+}
+
+/// What `te init` generates
+#[derive(Debug, Clone, PartialEq)]
+pub struct InitOptions {
+    /// The shell the script is for
+    pub shell: Shell,
+    /// Key binding for the widget, in the shell's notation, instead of Ctrl+T
+    pub bindkey: Option<String>,
+    /// Define `te-run`, which runs the edited command. Without it only the
+    /// key binding widget is defined, which puts the edited command on the
+    /// command line and leaves running it to the user.
+    pub eval: bool,
+    /// What the widget does with the edited command; only used for zsh
+    pub mode: WidgetMode,
+    /// Open te in a tmux popup when run inside tmux, for zsh, bash and fish
+    pub tmux_popup: bool,
+    /// Bind the widget to the key. Without it the binding is left in the
+    /// script as a commented-out example.
+    pub bind: bool,
+    /// What the functions the script defines are named after:
+    /// `<prefix>-run`, `<prefix>-widget` and helpers like `__<prefix>_edit`
+    pub prefix: String,
+}
+
+impl InitOptions {
+    /// The defaults of `te init <shell>`
+    pub fn new(shell: Shell) -> Self {
+        Self {
+            shell,
+            bindkey: None,
+            eval: true,
+            mode: WidgetMode::default(),
+            tmux_popup: false,
+            bind: true,
+            prefix: DEFAULT_PREFIX.to_string(),
+        }
+    }
+}
+
+/// Generate the integration script described by `options`, for a shell that
+/// [has one](Shell::has_init_script)
+pub fn generate_init_script(options: &InitOptions) -> String {
     let te_path = get_te_path();
-    match shell {
-        "zsh" => Some(generate_zsh_script(&te_path, bindkey)),
-        "bash" => Some(generate_bash_script(&te_path)),
-        "fish" => Some(generate_fish_script(&te_path)),
+    let names = Names::new(&options.prefix);
+    match options.shell {
+        Shell::Zsh => generate_zsh_script(&te_path, options, &names),
+        Shell::Bash => generate_bash_script(&te_path, options, &names),
+        Shell::Fish => generate_fish_script(&te_path, options, &names),
+        Shell::Pwsh => generate_pwsh_script(&te_path, options, &names),
+        // No te-run: te prints POSIX shell syntax, which these can't run
+        Shell::Elvish => generate_elvish_script(&te_path, options, &names),
+        Shell::Xonsh => generate_xonsh_script(&te_path, options, &names),
+        Shell::Nu => unreachable!("nu has no init script"),
+    }
+}
+
+/// The shell to generate a script for when none is given.
+///
+/// `--shell` and `TE_SHELL` decide if set. Otherwise `te init` usually runs
+/// from the rc file of the shell it's for, so that is the parent process,
+/// or else the basename of `$SHELL` (see [`history::resolve_shell`]).
+pub fn detect_init_shell() -> anyhow::Result<Option<Shell>> {
+    let resolved = history::resolve_shell()?;
+    if let Some((shell, ShellOrigin::Flag | ShellOrigin::Env)) = resolved {
+        return Ok(Some(shell));
+    }
+    let parent = history::parent_process_name()
+        // A login shell is named with a leading `-`
+        .and_then(|name| Shell::from_name(name.trim_start_matches('-')))
+        .filter(|shell| shell.has_init_script());
+    Ok(parent.or(resolved.map(|(shell, _)| shell)))
+}
+
+/// The key binding every script ends with: `code` after the `comment`
+/// explaining it, or with `bind` false, `code` commented out as an example
+fn binding(bind: bool, comment: &str, code: &str) -> String {
+    if bind {
+        return format!("{}\n{}\n", comment, code);
+    }
+    let mut commented =
+        String::from("# The widget isn't bound to a key (--no-bind). To bind it yourself:\n");
+    for line in code.lines() {
+        if line.is_empty() {
+            commented.push_str("#\n");
+        } else {
+            commented.push_str(&format!("#     {}\n", line));
+        }
+    }
+    commented
+}
+
+/// Turn a `--bindkey` spec into the key notation of `shell`.
+///
+/// zsh, bash and fish also take a portable spec: zsh's `^T`, or a chord of
+/// `ctrl` and `alt` with a character like `ctrl-t` or `alt-e`. Anything else
+/// is taken to be in the shell's own notation and only checked for what
+/// would break the generated script. Other shells get the spec unchanged.
+pub fn translate_bindkey(shell: Shell, spec: &str) -> anyhow::Result<String> {
+    if !matches!(shell, Shell::Zsh | Shell::Bash | Shell::Fish) {
+        return Ok(spec.to_string());
+    }
+    if spec.is_empty() || spec.contains(char::is_whitespace) {
+        anyhow::bail!("Invalid key binding '{}'", spec);
+    }
+
+    let chord = match spec.strip_prefix('^') {
+        Some(key) if key.chars().count() == 1 => {
+            Some(crate::keys::parse_key(&format!("ctrl-{}", key)))
+        }
+        _ if spec.contains('-') && !spec.contains('\\') => Some(crate::keys::parse_key(spec)),
         _ => None,
+    };
+    let Some(chord) = chord else {
+        // The shell's own notation
+        return match shell {
+            Shell::Zsh if spec.contains('\'') => {
+                anyhow::bail!("Key binding '{}' can't contain ' for zsh", spec)
+            }
+            Shell::Fish if spec.contains(['\'', '"', ';', '|', '&', '$', '#']) => {
+                anyhow::bail!(
+                    "Key binding '{}' can't contain quotes or ;|&$# for fish",
+                    spec
+                )
+            }
+            _ => Ok(spec.to_string()),
+        };
+    };
+
+    use crossterm::event::{KeyCode, KeyModifiers};
+    let chord = chord?;
+    let unsupported = || {
+        anyhow::anyhow!(
+            "Key binding '{}' can't be bound portably; use {}'s own key notation",
+            spec,
+            shell
+        )
+    };
+    let KeyCode::Char(key) = chord.code else {
+        return Err(unsupported());
+    };
+    if chord
+        .modifiers
+        .intersects(!(KeyModifiers::CONTROL | KeyModifiers::ALT))
+        || chord.modifiers.is_empty()
+    {
+        return Err(unsupported());
+    }
+    let ctrl = chord.modifiers.contains(KeyModifiers::CONTROL);
+    let alt = chord.modifiers.contains(KeyModifiers::ALT);
+    // Terminals only send control characters for letters and @[\]^_
+    if ctrl && !(key.is_ascii_alphabetic() || "@[\\]^_".contains(key)) {
+        return Err(unsupported());
+    }
+    let key = if ctrl { key.to_ascii_lowercase() } else { key };
+
+    let mut notation = String::new();
+    if alt {
+        notation.push_str(if shell == Shell::Zsh { "^[" } else { "\\e" });
+    }
+    match (shell, ctrl) {
+        (Shell::Zsh, true) => notation.push_str(&format!("^{}", key.to_ascii_uppercase())),
+        (Shell::Bash, true) => notation.push_str(&format!("\\C-{}", key)),
+        (Shell::Fish, true) => notation.push_str(&format!("\\c{}", key)),
+        _ => notation.push(key),
+    }
+    if shell == Shell::Fish && !ctrl && "'\";|&$#\\".contains(key) {
+        return Err(unsupported());
+    }
+    Ok(notation)
+}
+
+/// Prefix of the function names in the generated scripts
+pub const DEFAULT_PREFIX: &str = "te";
+
+/// Whether `prefix` can be used in function names in every supported shell
+pub fn is_valid_prefix(prefix: &str) -> bool {
+    prefix.starts_with(|c: char| c.is_ascii_alphabetic())
+        && prefix
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Names of the functions a script defines, after the prefix
+struct Names {
+    /// `<prefix>-run`
+    run: String,
+    /// `<prefix>-widget`
+    widget: String,
+    /// The prefix with `_` for `-`, for helpers like `__<ident>_edit` and
+    /// Python names in xonsh, which can't have a `-`
+    ident: String,
+    /// The prefix in PascalCase, for PowerShell's `Verb-Noun` names
+    pascal: String,
+}
+
+impl Names {
+    fn new(prefix: &str) -> Self {
+        let pascal = prefix
+            .split(['-', '_'])
+            .map(|word| {
+                let mut chars = word.chars();
+                chars.next().map_or(String::new(), |first| {
+                    first.to_ascii_uppercase().to_string() + chars.as_str()
+                })
+            })
+            .collect();
+        Self {
+            run: format!("{}-run", prefix),
+            widget: format!("{}-widget", prefix),
+            ident: prefix.replace('-', "_"),
+            pascal,
+        }
     }
 }
 
 fn get_te_path() -> String {
     // Get the path to the current executable
-    if let Ok(exe_path) = std::env::current_exe() {
-        // If running from target/debug or target/release, use the full path
-        if let Some(path_str) = exe_path.to_str() {
-            if path_str.contains("/target/debug/") || path_str.contains("/target/release/") {
-                return path_str.to_string();
-            }
-        }
+    // If running from target/debug or target/release, use the full path
+    if let Ok(exe_path) = std::env::current_exe()
+        && let Some(path_str) = exe_path.to_str()
+        && (path_str.contains("/target/debug/") || path_str.contains("/target/release/"))
+    {
+        return path_str.to_string();
     }
     // Otherwise, just use "te" (assumes it's in PATH)
     "te".to_string()
 }
 
-fn generate_zsh_script(te_path: &str, bindkey: Option<String>) -> String {
+/// Open te in a centered tmux popup, for a double-quoted string in zsh, bash
+/// or fish with `$tmp` and `$te_cmd` set. tmux runs it with its own
+/// default-shell, so te is started through `sh`: the command line is read
+/// from `$tmp.in`, the result written to `$tmp` and the exit status to
+/// `$tmp.ret`, which is missing if the popup couldn't be opened.
+fn tmux_popup_command(shell: Shell) -> String {
     format!(
-        r#"# te shell integration for zsh
+        r#"tmux display-popup -E -w 90% -h 60% "sh -c '\"\$2\" --shell {shell} --fullscreen --result-fd 3 -c \"\$(cat \"\$1.in\")\" 3>\"\$1\"; echo \$? >\"\$1.ret\"' sh '$tmp' '$te_cmd'""#
+    )
+}
 
+fn generate_zsh_script(te_path: &str, options: &InitOptions, names: &Names) -> String {
+    let InitOptions {
+        eval,
+        mode,
+        tmux_popup,
+        ..
+    } = *options;
+    let Names {
+        run, widget, ident, ..
+    } = names;
+    let te_run = if eval {
+        format!(
+            r#"
 # Function to run te and execute the resulting command
-te-run() {{
+{run}() {{
     local result
-    # Use TE_PATH if set, otherwise use default
-    local te_cmd="${{TE_PATH:-{}}}"
-    result=$($te_cmd "$@")
-    if [ $? -eq 0 ] && [ -n "$result" ]; then
+    __{ident}_edit "$@"
+    local ret=$?
+    result="$REPLY"
+    # 3: accepted with Ctrl+O to run right away, as it is here
+    [ $ret -eq 3 ] && ret=0
+    # 130: cancelled by the user, anything else non-zero: te failed
+    if [ $ret -eq 0 ] && [ -n "$result" ]; then
         eval "$result"
+    elif [ $ret -ne 130 ]; then
+        echo "te: failed with exit code $ret" >&2
     fi
+    return $ret
 }}
+"#
+        )
+    } else {
+        String::new()
+    };
+    let clear = match mode {
+        WidgetMode::Stage => {
+            "        # Set the command line aside until the edited command has run\n        zle push-line"
+        }
+        WidgetMode::Region => {
+            r#"        # Edit the selected region, or else the part left of the cursor
+        local original_cursor=$CURSOR start=0 end=$CURSOR
+        if (( REGION_ACTIVE )); then
+            start=$(( MARK < CURSOR ? MARK : CURSOR ))
+            end=$(( MARK < CURSOR ? CURSOR : MARK ))
+            (( end > ${#original_buffer} )) && end=${#original_buffer}
+        fi
+        # With nothing but blanks there, edit the whole line
+        if [[ ${original_buffer[start+1,end]} != *[^[:space:]]* ]]; then
+            start=0
+            end=${#original_buffer}
+        fi
+        # Clear the command line before invoking te
+        BUFFER="""#
+        }
+        _ => "        # Clear the command line before invoking te\n        BUFFER=\"\"",
+    };
+    let region = match mode {
+        WidgetMode::Region => "--region-start $start --region-end $end ",
+        _ => "",
+    };
+    let accepted = match mode {
+        WidgetMode::Buffer | WidgetMode::Stage => "            BUFFER=\"$result\"",
+        WidgetMode::History => {
+            "            print -s -- \"$result\"\n            BUFFER=\"$original_buffer\"\n            zle -M \"te: added to history\""
+        }
+        WidgetMode::Region => {
+            "            BUFFER=\"$result\"\n            # Put the cursor at the end of the edited region\n            CURSOR=$(( ${#result} - ${#original_buffer} + end ))"
+        }
+    };
+    let restore = match mode {
+        WidgetMode::Stage => "            zle get-line",
+        WidgetMode::Region => {
+            "            BUFFER=\"$original_buffer\"\n            CURSOR=$original_cursor"
+        }
+        _ => "            BUFFER=\"$original_buffer\"",
+    };
+    let run = match mode {
+        // Without eval nothing is run, and in history mode the edited command
+        // isn't on the command line to run
+        _ if !eval => "",
+        WidgetMode::History => "",
+        _ => {
+            "        if [ $run -eq 1 ] && [ -n \"$result\" ]; then\n            zle accept-line\n        fi"
+        }
+    };
+    let finish = match mode {
+        WidgetMode::Region => "        REGION_ACTIVE=0",
+        _ => "        zle end-of-line",
+    };
+    let (popup, edit) = if tmux_popup {
+        let popup = format!(
+            r#"
+# Inside tmux, run te in a popup instead, leaving the edited command in
+# $REPLY like __{ident}_edit. Outside tmux, or with a tmux too old for popups,
+# __{ident}_edit runs it inline.
+__{ident}_popup() {{
+    local te_cmd="${{TE_PATH:-{}}}"
+    local tmp ret
+    REPLY=""
+    if [ -n "$TMUX" ] && tmp=$(mktemp "${{TMPDIR:-/tmp}}/te.XXXXXX" 2>/dev/null); then
+        print -rn -- "$1" >"$tmp.in"
+        {} >/dev/null 2>&1
+        if [ -f "$tmp.ret" ]; then
+            ret=$(<"$tmp.ret")
+            REPLY=$(<"$tmp")
+            rm -f "$tmp" "$tmp.in" "$tmp.ret"
+            return $ret
+        fi
+        rm -f "$tmp" "$tmp.in"
+    fi
+    __{ident}_edit "$@"
+}}
+"#,
+            te_path,
+            tmux_popup_command(Shell::Zsh)
+        );
+        (popup, format!("__{}_popup", ident))
+    } else {
+        (String::new(), format!("__{}_edit", ident))
+    };
+    format!(
+        r#"# te shell integration for zsh
 
+# Run te, leaving the edited command in $REPLY. te writes it to a temp file
+# through --result-fd, so nothing else on stdout can end up in the command;
+# without a temp file it falls back to capturing stdout.
+__{ident}_edit() {{
+    # Use TE_PATH if set, otherwise use default
+    local te_cmd="${{TE_PATH:-{}}}"
+    local tmp ret
+    REPLY=""
+    if tmp=$(mktemp "${{TMPDIR:-/tmp}}/te.XXXXXX" 2>/dev/null); then
+        $te_cmd --shell zsh --result-fd 3 "$@" 3>"$tmp"
+        ret=$?
+        REPLY=$(<"$tmp")
+        rm -f "$tmp"
+        return $ret
+    fi
+    REPLY=$($te_cmd --shell zsh "$@")
+}}
+{}{}
 # Widget to invoke te with current buffer content
-te-widget() {{
+{widget}() {{
     if [ -n "$BUFFER" ]; then
         local original_buffer="$BUFFER"
-        # Clear the command line before invoking te
-        BUFFER=""
+{}
         zle reset-prompt
         local result
-        # Use TE_PATH if set, otherwise use default
-        local te_cmd="${{TE_PATH:-{}}}"
-        result=$($te_cmd "$original_buffer")
-        local ret=$?
+        {} {}"$original_buffer"
+        local ret=$? run=0
+        result="$REPLY"
+        # 3: accepted with Ctrl+O, to be run right away
+        if [ $ret -eq 3 ]; then
+            ret=0
+            run=1
+        fi
         if [ $ret -eq 0 ] && [ -n "$result" ]; then
-            BUFFER="$result"
+{}
         else
             # Restore original buffer if te was cancelled or failed
-            BUFFER="$original_buffer"
+{}
         fi
         zle reset-prompt
-        zle end-of-line
-        return $ret
+{}
+        # Cancelling (exit code 130) is not an error
+        if [ $ret -ne 0 ] && [ $ret -ne 130 ]; then
+            zle -M "te: failed with exit code $ret"
+            return $ret
+        fi
+{}
+        return 0
     fi
 }}
 
 # Register the widget
-zle -N te-widget
+zle -N {widget}
 
-# Bind Ctrl+T to the widget (you can customize this)
-bindkey '{}' te-widget
-"#,
-        te_path,
+{}"#,
         te_path,
-        bindkey.unwrap_or("^T".to_string())
+        popup,
+        te_run,
+        clear,
+        edit,
+        region,
+        accepted,
+        restore,
+        finish,
+        run,
+        binding(
+            options.bind,
+            "# Bind Ctrl+T to the widget (you can customize this)",
+            &format!(
+                "bindkey '{}' {}",
+                options.bindkey.as_deref().unwrap_or("^T"),
+                widget
+            ),
+        )
     )
 }
 
-fn generate_bash_script(te_path: &str) -> String {
-    format!(
-        r#"# te shell integration for bash
-
+fn generate_bash_script(te_path: &str, options: &InitOptions, names: &Names) -> String {
+    let InitOptions {
+        eval, tmux_popup, ..
+    } = *options;
+    let Names {
+        run, widget, ident, ..
+    } = names;
+    let te_run = if eval {
+        format!(
+            r#"
 # Function to run te and execute the resulting command
-te-run() {{
+{run}() {{
     local result
     # Use TE_PATH if set, otherwise use default
     local te_cmd="${{TE_PATH:-{}}}"
-    result=$($te_cmd "$@")
-    if [ $? -eq 0 ] && [ -n "$result" ]; then
+    result=$($te_cmd --shell bash "$@")
+    local ret=$?
+    # 3: accepted with Ctrl+O to run right away, as it is here
+    [ $ret -eq 3 ] && ret=0
+    # 130: cancelled by the user, anything else non-zero: te failed
+    if [ $ret -eq 0 ] && [ -n "$result" ]; then
         eval "$result"
+    elif [ $ret -ne 130 ]; then
+        echo "te: failed with exit code $ret" >&2
     fi
+    return $ret
 }}
 "#,
-        te_path
+            te_path
+        )
+    } else {
+        String::new()
+    };
+    let (popup, edit) = if tmux_popup {
+        let popup = format!(
+            r#"
+# Inside tmux, run te in a popup instead, printing the edited command like
+# te does. Outside tmux, or with a tmux too old for popups, te runs inline.
+__{ident}_popup() {{
+    local te_cmd="${{TE_PATH:-{}}}"
+    local tmp ret
+    if [ -n "$TMUX" ] && tmp=$(mktemp "${{TMPDIR:-/tmp}}/te.XXXXXX" 2>/dev/null); then
+        printf '%s' "$1" >"$tmp.in"
+        {} >/dev/null 2>&1
+        if [ -f "$tmp.ret" ]; then
+            ret=$(<"$tmp.ret")
+            cat "$tmp"
+            rm -f "$tmp" "$tmp.in" "$tmp.ret"
+            return "$ret"
+        fi
+        rm -f "$tmp" "$tmp.in"
+    fi
+    $te_cmd --shell bash "$@"
+}}
+"#,
+            te_path,
+            tmux_popup_command(Shell::Bash)
+        );
+        (popup, format!("__{}_popup", ident))
+    } else {
+        (String::new(), "$te_cmd --shell bash".to_string())
+    };
+    let run = if eval {
+        r#"        if [ $ret -eq 3 ] && [ -n "$result" ]; then
+            # Accepted with Ctrl+O: run it right away. bind -x can't accept
+            # the line, so it is run here and added to the history instead
+            READLINE_LINE=""
+            READLINE_POINT=0
+            history -s -- "$result"
+            printf '%s\n' "$result"
+            eval -- "$result"
+            return
+        fi
+"#
+    } else {
+        ""
+    };
+    let keyseq = bash_keyseq(options.bindkey.as_deref().unwrap_or(r"\C-t"));
+    let binding = binding(
+        options.bind,
+        "# Bind Ctrl+T to the widget (you can customize this) in both emacs and vi\n# insert mode. bind -x only exposes READLINE_LINE since bash 4.",
+        &format!(
+            r#"if [ "${{BASH_VERSINFO[0]:-0}}" -ge 4 ]; then
+    bind -m emacs -x '"{keyseq}": {widget}'
+    bind -m vi-insert -x '"{keyseq}": {widget}'
+fi"#
+        ),
+    );
+    format!(
+        r#"# te shell integration for bash
+{}{}
+# Widget to invoke te with the current command line, replacing it with the
+# result. The line is left alone when te is cancelled or fails.
+{widget}() {{
+    if [ -n "$READLINE_LINE" ]; then
+        local result
+        # Use TE_PATH if set, otherwise use default
+        local te_cmd="${{TE_PATH:-{}}}"
+        result=$({} "$READLINE_LINE")
+        local ret=$?
+{}        if [ $ret -eq 0 -o $ret -eq 3 ] && [ -n "$result" ]; then
+            READLINE_LINE="$result"
+            READLINE_POINT=${{#READLINE_LINE}}
+        elif [ $ret -ne 130 ]; then
+            # Cancelling (exit code 130) is not an error
+            echo "te: failed with exit code $ret" >&2
+        fi
+    fi
+}}
+
+{binding}"#,
+        te_run, popup, te_path, edit, run,
     )
 }
 
-fn generate_fish_script(te_path: &str) -> String {
+/// Escape a readline key sequence for `bind -x '"<keyseq>": ...'`: `"` would
+/// end the key sequence and `'` the single-quoted bind argument.
+fn bash_keyseq(bindkey: &str) -> String {
+    bindkey.replace('"', "\\\"").replace('\'', "'\\''")
+}
+
+fn generate_fish_script(te_path: &str, options: &InitOptions, names: &Names) -> String {
+    let InitOptions {
+        eval, tmux_popup, ..
+    } = *options;
+    let Names {
+        run, widget, ident, ..
+    } = names;
+    let te_run = if eval {
+        format!(
+            r#"
+# Function to run te and execute the resulting command
+function {run}
+    set -l result (__{ident}_cmd $argv)
+    set -l ret $status
+    # 3: accepted with Ctrl+O to run right away, as it is here
+    test $ret -eq 3; and set ret 0
+    # 130: cancelled by the user, anything else non-zero: te failed
+    if test $ret -eq 0 -a -n "$result"
+        eval (string join \n -- $result)
+    else if test $ret -ne 130
+        echo "te: failed with exit code $ret" >&2
+    end
+    return $ret
+end
+"#
+        )
+    } else {
+        String::new()
+    };
+    let (popup, edit) = if tmux_popup {
+        let popup = format!(
+            r#"
+# Inside tmux, run te in a popup instead, printing the edited command like
+# te does. Outside tmux, or with a tmux too old for popups, te runs inline.
+function __{ident}_popup
+    set -l te_cmd {}
+    set -q TE_PATH; and set te_cmd $TE_PATH
+    set -l dir /tmp
+    set -q TMPDIR; and set dir $TMPDIR
+    set -l tmp
+    set -q TMUX; and set tmp (mktemp $dir/te.XXXXXX 2>/dev/null)
+    if test -n "$tmp"
+        printf '%s' $argv[1] >$tmp.in
+        {} >/dev/null 2>&1
+        if test -f $tmp.ret
+            set -l ret (cat $tmp.ret)
+            cat $tmp
+            rm -f $tmp $tmp.in $tmp.ret
+            return $ret
+        end
+        rm -f $tmp $tmp.in
+    end
+    __{ident}_cmd $argv
+end
+"#,
+            te_path,
+            tmux_popup_command(Shell::Fish)
+        );
+        (popup, format!("__{}_popup", ident))
+    } else {
+        (String::new(), format!("__{}_cmd", ident))
+    };
     format!(
         r#"# te shell integration for fish
 
-# Function to run te and execute the resulting command
-function te-run
+function __{ident}_cmd
     # Use TE_PATH if set, otherwise use default
-    set -l te_cmd (if set -q TE_PATH; echo $TE_PATH; else; echo {}; end)
-    set -l result ($te_cmd $argv)
-    if test $status -eq 0 -a -n "$result"
-        eval $result
+    if set -q TE_PATH
+        $TE_PATH --shell fish $argv
+    else
+        {} --shell fish $argv
     end
 end
-"#,
-        te_path
+{}{}
+# Widget to invoke te with the current command line, replacing it with the
+# result. The original line is restored when te is cancelled or fails.
+function {widget}
+    set -l original (commandline -b | string collect)
+    if test -z "$original"
+        return
+    end
+    commandline -r ""
+    commandline -f repaint
+    set -l result ({} $original)
+    set -l ret $status
+    if test $ret -eq 0 -o $ret -eq 3; and test -n "$result"
+        # Replace with the full string so multi-line commands survive
+        commandline -r -- (string join \n -- $result)
+{}    else
+        commandline -r -- $original
+        # Cancelling (exit code 130) is not an error
+        if test $ret -ne 130
+            echo "te: failed with exit code $ret" >&2
+        end
+    end
+    commandline -f end-of-line repaint
+end
+
+{}"#,
+        te_path,
+        popup,
+        te_run,
+        edit,
+        if eval {
+            "        # 3: accepted with Ctrl+O, to be run right away\n        if test $ret -eq 3\n            commandline -f execute\n            return\n        end\n"
+        } else {
+            ""
+        },
+        binding(
+            options.bind,
+            "# Bind Ctrl+T to the widget (you can customize this) in default and vi insert mode",
+            &format!(
+                "bind {bindkey} {widget}\nbind -M insert {bindkey} {widget}",
+                bindkey = options.bindkey.as_deref().unwrap_or(r"\ct")
+            ),
+        )
+    )
+}
+
+fn generate_pwsh_script(te_path: &str, options: &InitOptions, names: &Names) -> String {
+    let eval = options.eval;
+    let Names { run, pascal, .. } = names;
+    let te_run = if eval {
+        format!(
+            r#"
+# Function to run te and execute the resulting command
+function {run} {{
+    $result = & (Get-{pascal}Command) --shell pwsh @args
+    $ret = $LASTEXITCODE
+    # 3: accepted with Ctrl+O to run right away, as it is here
+    if ($ret -eq 3) {{ $ret = 0 }}
+    # 130: cancelled by the user, anything else non-zero: te failed
+    if ($ret -eq 0 -and $result) {{
+        Invoke-Expression ($result -join "`n")
+    }} elseif ($ret -ne 130) {{
+        Write-Error "te: failed with exit code $ret"
+    }}
+}}
+"#
+        )
+    } else {
+        String::new()
+    };
+    format!(
+        r#"# te shell integration for PowerShell
+
+function Get-{pascal}Command {{
+    # Use TE_PATH if set, otherwise use default
+    if ($env:TE_PATH) {{ $env:TE_PATH }} else {{ {} }}
+}}
+{}
+# Widget to invoke te with the current command line, replacing it with the
+# result. The line is left alone when te is cancelled or fails.
+function Invoke-{pascal}Widget {{
+    $line = $null
+    $cursor = $null
+    [Microsoft.PowerShell.PSConsoleReadLine]::GetBufferState([ref]$line, [ref]$cursor)
+    if (-not $line) {{ return }}
+    # Piped rather than passed as an argument, which older PowerShell
+    # versions don't quote reliably for native commands
+    $result = $line | & (Get-{pascal}Command) --shell pwsh
+    $ret = $LASTEXITCODE
+    if (($ret -eq 0 -or $ret -eq 3) -and $result) {{
+        # Each line of the output is an element; keep multi-line commands whole
+        $text = $result -join "`n"
+        [Microsoft.PowerShell.PSConsoleReadLine]::Replace(0, $line.Length, $text)
+        [Microsoft.PowerShell.PSConsoleReadLine]::SetCursorPosition($text.Length)
+{}    }} elseif ($ret -ne 130) {{
+        # Cancelling (exit code 130) is not an error
+        Write-Host "te: failed with exit code $ret"
+    }}
+    [Microsoft.PowerShell.PSConsoleReadLine]::InvokePrompt()
+}}
+
+{}"#,
+        pwsh_quote(te_path),
+        te_run,
+        if eval {
+            "        # 3: accepted with Ctrl+O, to be run right away\n        if ($ret -eq 3) {\n            [Microsoft.PowerShell.PSConsoleReadLine]::AcceptLine()\n            return\n        }\n"
+        } else {
+            ""
+        },
+        binding(
+            options.bind,
+            "# Bind Ctrl+T to the widget (you can customize this)",
+            &format!(
+                "Set-PSReadLineKeyHandler -Chord {} -ScriptBlock {{ Invoke-{}Widget }}",
+                pwsh_quote(options.bindkey.as_deref().unwrap_or("Ctrl+t")),
+                pascal
+            ),
+        )
+    )
+}
+
+/// Quote a string as a PowerShell single-quoted literal, in which only `'`
+/// is special and is written twice
+fn pwsh_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+fn generate_elvish_script(te_path: &str, options: &InitOptions, names: &Names) -> String {
+    let widget = &names.widget;
+    format!(
+        r#"# te shell integration for elvish
+#
+# Load it from ~/.config/elvish/rc.elv with:
+#     eval (te init elvish | slurp)
+
+use str
+
+# Widget to invoke te with the current command line, replacing it with the
+# result. The line is left alone when te is cancelled or fails.
+fn {widget} {{
+    var line = $edit:current-command
+    if (eq (str:trim-space $line) '') {{
+        return
+    }}
+    # Use TE_PATH if set, otherwise use default
+    var te-cmd = {}
+    if (has-env TE_PATH) {{
+        set te-cmd = $E:TE_PATH
+    }}
+    try {{
+        var result = (str:trim-right ((external $te-cmd) $line | slurp) "\n")
+        if (not-eq $result '') {{
+            set edit:current-command = $result
+            set edit:-dot = (count $result)
+        }}
+    }} catch e {{
+        # Cancelling (exit code 130) is not an error
+        if (not (and (has-key $e[reason] exit-status) (== $e[reason][exit-status] 130))) {{
+            echo 'te: failed: '(repr $e[reason]) >&2
+        }}
+    }}
+}}
+
+{}"#,
+        elvish_quote(te_path),
+        binding(
+            options.bind,
+            "# Bind Ctrl+T to the widget (you can customize this)",
+            &format!(
+                "set edit:insert:binding[{}] = ${}~",
+                elvish_quote(options.bindkey.as_deref().unwrap_or("Ctrl-T")),
+                widget
+            ),
+        )
     )
 }
+
+/// Quote a string as an elvish single-quoted literal, in which only `'` is
+/// special and is written twice
+fn elvish_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+fn generate_xonsh_script(te_path: &str, options: &InitOptions, names: &Names) -> String {
+    let ident = &names.ident;
+    format!(
+        r#"# te shell integration for xonsh
+#
+# Load it from ~/.xonshrc with:
+#     execx($(te init xonsh))
+
+import subprocess as _{ident}_subprocess
+import sys as _{ident}_sys
+
+from prompt_toolkit.application import run_in_terminal as _{ident}_run_in_terminal
+
+
+# Widget to invoke te with the current command line, replacing it with the
+# result. The line is left alone when te is cancelled or fails.
+def _{ident}_widget(event):
+    buffer = event.current_buffer
+    line = buffer.text
+    if not line.strip():
+        return
+    # Use TE_PATH if set, otherwise use default
+    te_cmd = __xonsh__.env.get("TE_PATH") or {}
+
+    def edit():
+        proc = _{ident}_subprocess.run([te_cmd, line], stdout=_{ident}_subprocess.PIPE, text=True)
+        result = proc.stdout.rstrip("\n")
+        # 3: accepted with Ctrl+O to run right away, which needs POSIX syntax
+        if proc.returncode in (0, 3) and result:
+            buffer.text = result
+            buffer.cursor_position = len(result)
+        elif proc.returncode != 130:
+            # Cancelling (exit code 130) is not an error
+            print(f"te: failed with exit code {{proc.returncode}}", file=_{ident}_sys.stderr)
+
+    _{ident}_run_in_terminal(edit)
+
+
+{}"#,
+        python_quote(te_path),
+        binding(
+            options.bind,
+            "# Bind Ctrl+T to the widget (you can customize this)",
+            &format!(
+                "@events.on_ptk_create\ndef _{ident}_bind(prompter, history, completer, bindings, **kw):\n    bindings.add({})(_{ident}_widget)",
+                python_quote(options.bindkey.as_deref().unwrap_or("c-t"))
+            ),
+        )
+    )
+}
+
+/// Quote a string as a Python string literal
+fn python_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scripts_treat_cancel_as_quiet() {
+        for shell in [Shell::Zsh, Shell::Bash, Shell::Fish, Shell::Pwsh] {
+            let script = generate_init_script(&InitOptions::new(shell));
+            assert!(script.contains("-ne 130"), "{} script", shell);
+            assert!(
+                script.contains("te: failed with exit code"),
+                "{} script",
+                shell
+            );
+        }
+    }
+
+    #[test]
+    fn test_no_eval_scripts_only_replace_the_command_line() {
+        let zsh = generate_init_script(&InitOptions {
+            eval: false,
+            ..InitOptions::new(Shell::Zsh)
+        });
+        assert!(zsh.contains("BUFFER=\"$result\""));
+        assert!(!zsh.contains("te-run"));
+        assert!(!zsh.contains("eval"));
+
+        let bash = generate_init_script(&InitOptions {
+            eval: false,
+            ..InitOptions::new(Shell::Bash)
+        });
+        assert!(bash.contains("READLINE_LINE=\"$result\""));
+        assert!(bash.contains(r#"bind -m emacs -x '"\C-t": te-widget'"#));
+        assert!(!bash.contains("te-run"));
+        assert!(!bash.contains("eval"));
+
+        let fish = generate_init_script(&InitOptions {
+            eval: false,
+            ..InitOptions::new(Shell::Fish)
+        });
+        assert!(fish.contains("commandline -r -- (string join \\n -- $result)"));
+        assert!(fish.contains(r"bind \ct te-widget"));
+        assert!(!fish.contains("te-run"));
+        assert!(!fish.contains("eval"));
+    }
+
+    #[test]
+    fn test_bash_script_binds_the_widget() {
+        let bash = generate_init_script(&InitOptions::new(Shell::Bash));
+        assert!(bash.contains("te-run()"));
+        assert!(bash.contains("READLINE_POINT=${#READLINE_LINE}"));
+        assert!(bash.contains(r#"bind -m vi-insert -x '"\C-t": te-widget'"#));
+
+        let bash = generate_init_script(&InitOptions {
+            bindkey: Some(r#"\e"t'"#.to_string()),
+            ..InitOptions::new(Shell::Bash)
+        });
+        assert!(bash.contains(r#"bind -m emacs -x '"\e\"t'\''": te-widget'"#));
+    }
+
+    #[test]
+    fn test_pwsh_script() {
+        let pwsh = generate_init_script(&InitOptions::new(Shell::Pwsh));
+        assert!(pwsh.contains("function te-run"));
+        assert!(pwsh.contains("-Chord 'Ctrl+t' -ScriptBlock { Invoke-TeWidget }"));
+        assert!(pwsh.contains("$result = & (Get-TeCommand) --shell pwsh @args"));
+
+        let pwsh = generate_init_script(&InitOptions {
+            bindkey: Some("Alt+e".to_string()),
+            eval: false,
+            ..InitOptions::new(Shell::Pwsh)
+        });
+        assert!(pwsh.contains("-Chord 'Alt+e'"));
+        assert!(!pwsh.contains("te-run"));
+
+        assert_eq!(
+            pwsh_quote("C:\\Bob's tools\\te.exe"),
+            "'C:\\Bob''s tools\\te.exe'"
+        );
+    }
+
+    #[test]
+    fn test_elvish_and_xonsh_scripts() {
+        let elvish = generate_init_script(&InitOptions::new(Shell::Elvish));
+        assert!(elvish.contains("set edit:insert:binding['Ctrl-T'] = $te-widget~"));
+        assert!(elvish.contains("eval (te init elvish | slurp)"));
+
+        let xonsh = generate_init_script(&InitOptions {
+            bindkey: Some("c-e".to_string()),
+            ..InitOptions::new(Shell::Xonsh)
+        });
+        assert!(xonsh.contains("bindings.add('c-e')(_te_widget)"));
+        assert!(xonsh.contains("execx($(te init xonsh))"));
+
+        assert_eq!(elvish_quote("it's"), "'it''s'");
+        assert_eq!(python_quote(r"C:\it's"), r"'C:\\it\'s'");
+    }
+
+    #[test]
+    fn test_prefix_renames_functions() {
+        let zsh = generate_init_script(&InitOptions {
+            prefix: "te2".to_string(),
+            ..InitOptions::new(Shell::Zsh)
+        });
+        assert!(zsh.contains("te2-run() {"));
+        assert!(zsh.contains("zle -N te2-widget"));
+        assert!(zsh.contains("bindkey '^T' te2-widget"));
+        assert!(zsh.contains("__te2_edit \"$original_buffer\""));
+        assert!(!zsh.contains("te-widget"));
+
+        let bash = generate_init_script(&InitOptions {
+            prefix: "my-te".to_string(),
+            ..InitOptions::new(Shell::Bash)
+        });
+        assert!(bash.contains(r#"bind -m emacs -x '"\C-t": my-te-widget'"#));
+
+        let xonsh = generate_init_script(&InitOptions {
+            prefix: "my-te".to_string(),
+            ..InitOptions::new(Shell::Xonsh)
+        });
+        assert!(xonsh.contains("def _my_te_widget(event):"));
+        assert!(!xonsh.contains("as _te_"));
+
+        let pwsh = generate_init_script(&InitOptions {
+            prefix: "my-te".to_string(),
+            ..InitOptions::new(Shell::Pwsh)
+        });
+        assert!(pwsh.contains("function my-te-run {"));
+        assert!(pwsh.contains("-ScriptBlock { Invoke-MyTeWidget }"));
+        assert!(!pwsh.contains("Get-TeCommand"));
+
+        assert!(is_valid_prefix("te2"));
+        assert!(!is_valid_prefix("2te"));
+        assert!(!is_valid_prefix("te;rm"));
+    }
+
+    #[test]
+    fn test_translate_bindkey() {
+        let translate = |shell, spec| translate_bindkey(shell, spec).unwrap();
+        assert_eq!(translate(Shell::Zsh, "ctrl-e"), "^E");
+        assert_eq!(translate(Shell::Bash, "ctrl-e"), r"\C-e");
+        assert_eq!(translate(Shell::Fish, "ctrl-e"), r"\ce");
+        assert_eq!(translate(Shell::Bash, "^E"), r"\C-e");
+        assert_eq!(translate(Shell::Fish, "^E"), r"\ce");
+        assert_eq!(translate(Shell::Zsh, "alt-e"), "^[e");
+        assert_eq!(translate(Shell::Bash, "ctrl-alt-e"), r"\e\C-e");
+        assert_eq!(translate(Shell::Fish, "alt-e"), r"\ee");
+
+        // Native notation passes through
+        assert_eq!(translate(Shell::Bash, r"\C-x\C-e"), r"\C-x\C-e");
+        assert_eq!(translate(Shell::Fish, r"\ct"), r"\ct");
+        assert_eq!(translate(Shell::Pwsh, "Ctrl+t"), "Ctrl+t");
+
+        assert!(translate_bindkey(Shell::Bash, "f2").is_ok());
+        assert!(translate_bindkey(Shell::Bash, "ctrl-f2").is_err());
+        assert!(translate_bindkey(Shell::Zsh, "ctrl-1").is_err());
+        assert!(translate_bindkey(Shell::Fish, "x;rm").is_err());
+        assert!(translate_bindkey(Shell::Zsh, "it's").is_err());
+        assert!(translate_bindkey(Shell::Bash, "ctrl t").is_err());
+    }
+
+    #[test]
+    fn test_no_bind() {
+        for shell in [
+            Shell::Zsh,
+            Shell::Bash,
+            Shell::Fish,
+            Shell::Pwsh,
+            Shell::Elvish,
+            Shell::Xonsh,
+        ] {
+            let script = generate_init_script(&InitOptions {
+                bind: false,
+                ..InitOptions::new(shell)
+            });
+            assert!(script.contains("(--no-bind)"), "{} script", shell);
+            // Every line mentioning the key is commented out
+            for line in script.lines() {
+                let line = line.to_lowercase();
+                if line.contains("c-t") || line.contains("ctrl+t") || line.contains(r"\ct") {
+                    assert!(
+                        line.trim_start().starts_with('#'),
+                        "{} script: {}",
+                        shell,
+                        line
+                    );
+                }
+            }
+        }
+        let zsh = generate_init_script(&InitOptions {
+            bind: false,
+            ..InitOptions::new(Shell::Zsh)
+        });
+        assert!(zsh.contains("zle -N te-widget\n"));
+        assert!(zsh.ends_with("#     bindkey '^T' te-widget\n"));
+    }
+
+    #[test]
+    fn test_tmux_popup() {
+        for shell in [Shell::Zsh, Shell::Bash, Shell::Fish] {
+            let inline = generate_init_script(&InitOptions::new(shell));
+            assert!(!inline.contains("display-popup"), "{} script", shell);
+
+            let popup = generate_init_script(&InitOptions {
+                tmux_popup: true,
+                ..InitOptions::new(shell)
+            });
+            assert!(popup.contains("tmux display-popup -E"), "{} script", shell);
+            assert!(
+                popup.contains("--fullscreen --result-fd 3"),
+                "{} script",
+                shell
+            );
+        }
+        let zsh = generate_init_script(&InitOptions {
+            tmux_popup: true,
+            ..InitOptions::new(Shell::Zsh)
+        });
+        assert!(zsh.contains("__te_popup \"$original_buffer\""));
+        let bash = generate_init_script(&InitOptions {
+            tmux_popup: true,
+            ..InitOptions::new(Shell::Bash)
+        });
+        assert!(bash.contains("result=$(__te_popup \"$READLINE_LINE\")"));
+        let fish = generate_init_script(&InitOptions {
+            tmux_popup: true,
+            ..InitOptions::new(Shell::Fish)
+        });
+        assert!(fish.contains("set -l result (__te_popup $original)"));
+    }
+
+    #[test]
+    fn test_widgets_run_on_ctrl_o() {
+        let script = |shell, mode| {
+            generate_init_script(&InitOptions {
+                mode,
+                ..InitOptions::new(shell)
+            })
+        };
+        assert!(script(Shell::Zsh, WidgetMode::Buffer).contains("zle accept-line"));
+        assert!(script(Shell::Zsh, WidgetMode::Stage).contains("zle accept-line"));
+        assert!(!script(Shell::Zsh, WidgetMode::History).contains("zle accept-line"));
+        assert!(script(Shell::Bash, WidgetMode::Buffer).contains(r#"eval -- "$result""#));
+        assert!(script(Shell::Fish, WidgetMode::Buffer).contains("commandline -f execute"));
+        assert!(script(Shell::Pwsh, WidgetMode::Buffer).contains("AcceptLine()"));
+        assert!(!script(Shell::Xonsh, WidgetMode::Buffer).contains("validate_and_handle()"));
+    }
+
+    #[test]
+    fn test_zsh_region_mode() {
+        let region = generate_init_script(&InitOptions {
+            eval: false,
+            mode: WidgetMode::Region,
+            ..InitOptions::new(Shell::Zsh)
+        });
+        assert!(
+            region.contains(
+                r#"__te_edit --region-start $start --region-end $end "$original_buffer""#
+            )
+        );
+        assert!(region.contains("CURSOR=$(( ${#result} - ${#original_buffer} + end ))"));
+        assert!(!region.contains("zle end-of-line"));
+
+        let buffer = generate_init_script(&InitOptions {
+            eval: false,
+            ..InitOptions::new(Shell::Zsh)
+        });
+        assert!(buffer.contains(r#"__te_edit "$original_buffer""#));
+        assert!(buffer.contains("zle end-of-line"));
+    }
+
+    #[test]
+    fn test_zsh_widget_modes() {
+        let buffer = generate_init_script(&InitOptions {
+            eval: false,
+            ..InitOptions::new(Shell::Zsh)
+        });
+        assert!(!buffer.contains("push-line"));
+        assert!(!buffer.contains("print -s"));
+
+        let stage = generate_init_script(&InitOptions {
+            eval: false,
+            mode: WidgetMode::Stage,
+            ..InitOptions::new(Shell::Zsh)
+        });
+        assert!(stage.contains("zle push-line"));
+        assert!(stage.contains("zle get-line"));
+        assert!(stage.contains("BUFFER=\"$result\""));
+
+        let history = generate_init_script(&InitOptions {
+            eval: false,
+            mode: WidgetMode::History,
+            ..InitOptions::new(Shell::Zsh)
+        });
+        assert!(history.contains("print -s -- \"$result\""));
+        assert!(!history.contains("BUFFER=\"$result\""));
+    }
+
+    #[test]
+    fn test_zsh_script_reads_the_result_fd() {
+        for eval in [true, false] {
+            let zsh = generate_init_script(&InitOptions {
+                eval,
+                ..InitOptions::new(Shell::Zsh)
+            });
+            assert!(zsh.contains("--result-fd 3 \"$@\" 3>\"$tmp\""));
+            // Fallback for when there is no temp file
+            assert!(zsh.contains("REPLY=$($te_cmd --shell zsh \"$@\")"));
+            // The te generating the script has --result-fd, so it isn't
+            // checked for on every run
+            assert!(!zsh.contains("--help"));
+        }
+    }
+}
@@ -0,0 +1,356 @@
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{debug, flags::FlagValueCounts};
+
+/// Where to find Fig (Amazon Q) completion specs, from the `[specs]` table
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct SpecsConfig {
+    /// Directory of `<program>.json` specs
+    pub dir: Option<PathBuf>,
+}
+
+/// What a spec says about one flag
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FlagSpec {
+    /// Separate values the flag takes; optional ones don't count, as they
+    /// have to be written `--flag=value`
+    pub values: usize,
+    /// Values the spec suggests for it
+    pub choices: Vec<String>,
+    /// It takes a file or directory, from a `filepaths` or `folders`
+    /// template
+    pub paths: bool,
+}
+
+/// The parts of a completion spec te uses: a command's flags, keyed by
+/// every spelling, and its subcommands, keyed by every name
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Spec {
+    pub flags: HashMap<String, FlagSpec>,
+    pub subcommands: HashMap<String, Spec>,
+}
+
+impl Spec {
+    /// Read the subset te needs from a spec's JSON.
+    ///
+    /// Anything of an unexpected shape is skipped rather than failing the
+    /// whole spec, and a flag defined twice for the same command keeps its
+    /// first definition.
+    pub fn parse(json: &str) -> serde_json::Result<Self> {
+        let value: Value = serde_json::from_str(json)?;
+        let mut spec = Spec::default();
+        spec.add_options(&value);
+        Ok(spec)
+    }
+
+    /// The flags of the command reached through `subcommands`, like
+    /// `["log"]` for `git log`: those of the program, overridden by those of
+    /// each subcommand on the way. Words that aren't subcommands, like the
+    /// `pods` of `kubectl get pods`, end the way down.
+    pub fn for_command(&self, subcommands: &[String]) -> Spec {
+        let mut flags = self.flags.clone();
+        let mut command = self;
+        for word in subcommands {
+            let Some(subcommand) = command.subcommands.get(word) else {
+                break;
+            };
+            flags.extend(
+                subcommand
+                    .flags
+                    .iter()
+                    .map(|(name, flag)| (name.clone(), flag.clone())),
+            );
+            command = subcommand;
+        }
+        Spec {
+            flags,
+            subcommands: HashMap::new(),
+        }
+    }
+
+    fn add_options(&mut self, command: &Value) {
+        for option in command
+            .get("options")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+        {
+            let names = names(option.get("name"));
+            if names.is_empty() {
+                continue;
+            }
+            let flag = flag_spec(option);
+            for name in names {
+                self.flags.entry(name).or_insert_with(|| flag.clone());
+            }
+        }
+        for subcommand in command
+            .get("subcommands")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+        {
+            let names = names(subcommand.get("name"));
+            if names.is_empty() {
+                continue;
+            }
+            let mut spec = Spec::default();
+            spec.add_options(subcommand);
+            for name in names {
+                self.subcommands.entry(name).or_insert_with(|| spec.clone());
+            }
+        }
+    }
+
+    /// Value counts of the spec's flags, for those `counts` doesn't already
+    /// have, so the config keeps the last word
+    pub fn add_value_counts(&self, counts: &mut HashMap<String, usize>) {
+        for (flag, spec) in &self.flags {
+            counts.entry(flag.clone()).or_insert(spec.values);
+        }
+    }
+
+    /// The values the spec suggests for `flag`
+    pub fn choices(&self, flag: &str) -> &[String] {
+        self.flags.get(flag).map_or(&[], |spec| &spec.choices)
+    }
+
+    /// Whether `flag` takes a file or directory
+    pub fn takes_path(&self, flag: &str) -> bool {
+        self.flags.get(flag).is_some_and(|spec| spec.paths)
+    }
+}
+
+/// A `name` that is a string or a list of them
+fn names(name: Option<&Value>) -> Vec<String> {
+    match name {
+        Some(Value::String(name)) => vec![name.clone()],
+        Some(Value::Array(names)) => names
+            .iter()
+            .filter_map(Value::as_str)
+            .map(str::to_string)
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn flag_spec(option: &Value) -> FlagSpec {
+    let args: Vec<&Value> = match option.get("args") {
+        Some(Value::Array(args)) => args.iter().collect(),
+        Some(arg @ Value::Object(_)) => vec![arg],
+        _ => Vec::new(),
+    };
+    let required = args
+        .iter()
+        .filter(|arg| {
+            !arg.get("isOptional")
+                .and_then(Value::as_bool)
+                .unwrap_or(false)
+        })
+        .count();
+    let takes_argument = option.get("takesArgument").and_then(Value::as_bool);
+    let values = match takes_argument {
+        Some(true) => required.max(1),
+        Some(false) => 0,
+        None => required,
+    };
+
+    let mut choices: Vec<String> = Vec::new();
+    for suggestion in args
+        .iter()
+        .filter_map(|arg| arg.get("suggestions").and_then(Value::as_array))
+        .flatten()
+    {
+        let suggested = match suggestion {
+            Value::String(name) => vec![name.clone()],
+            _ => names(suggestion.get("name")),
+        };
+        for name in suggested {
+            if !choices.contains(&name) {
+                choices.push(name);
+            }
+        }
+    }
+    let paths = args
+        .iter()
+        .filter_map(|arg| arg.get("template"))
+        .flat_map(|template| match template {
+            Value::Array(templates) => templates.iter().collect(),
+            _ => vec![template],
+        })
+        .any(|template| matches!(template.as_str(), Some("filepaths" | "folders")));
+    FlagSpec {
+        values,
+        choices,
+        paths,
+    }
+}
+
+/// Completion specs read from a directory on first use, each at most once
+/// per run of te
+#[derive(Debug, Clone, Default)]
+pub struct Specs {
+    dir: Option<PathBuf>,
+    parsed: Arc<Mutex<HashMap<String, Option<Arc<Spec>>>>>,
+}
+
+impl Specs {
+    pub fn new(dir: Option<PathBuf>) -> Self {
+        Self {
+            dir,
+            parsed: Arc::default(),
+        }
+    }
+
+    /// The spec for `base_command`, like `["git", "log"]`: its program's,
+    /// with the flags of its subcommands
+    pub fn for_command(&self, base_command: &[String]) -> Option<Spec> {
+        let (program, subcommands) = base_command.split_first()?;
+        Some(self.get(program)?.for_command(subcommands))
+    }
+
+    /// Value counts for `base_command`: those of `counts`, and for the flags
+    /// they don't cover, what the spec of the command says
+    pub fn value_counts(
+        &self,
+        base_command: &[String],
+        counts: &FlagValueCounts,
+    ) -> FlagValueCounts {
+        let mut counts = counts.clone();
+        if let Some(spec) = self.for_command(base_command) {
+            spec.add_value_counts(counts.entry(base_command[0].clone()).or_default());
+        }
+        counts
+    }
+
+    /// The spec for `program`, if the directory has a readable one
+    pub fn get(&self, program: &str) -> Option<Arc<Spec>> {
+        let dir = self.dir.as_ref()?;
+        // A program name is a file name, never a path into or out of `dir`
+        if program.is_empty() || program.contains(['/', '\\']) || program.starts_with('.') {
+            return None;
+        }
+        let mut parsed = self.parsed.lock().ok()?;
+        if let Some(spec) = parsed.get(program) {
+            return spec.clone();
+        }
+        let path = dir.join(format!("{}.json", program));
+        let spec = match std::fs::read_to_string(&path) {
+            Ok(json) => Spec::parse(&json)
+                .map_err(|err| debug::log(&format!("spec {} is invalid: {}", path.display(), err)))
+                .ok()
+                .map(Arc::new),
+            Err(_) => None,
+        };
+        parsed.insert(program.to_string(), spec.clone());
+        spec
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KUBECTL: &str = r#"{
+        "name": "kubectl",
+        "options": [
+            {"name": ["-n", "--namespace"], "description": "Namespace", "args": {"name": "namespace"}},
+            {"name": "--all-namespaces", "description": "All of them"},
+            {"name": "-o", "args": {"suggestions": ["json", {"name": "yaml"}, {"name": ["wide"]}, 3]}},
+            {"name": "--color", "args": {"isOptional": true}},
+            {"name": "--pair", "args": [{"name": "a"}, {"name": "b"}]},
+            {"name": "--flagged", "takesArgument": true},
+            {"name": ["-f", "--filename"], "args": {"template": "filepaths"}},
+            {"name": "--kubeconfig", "args": {"template": ["folders", "history"]}},
+            {"description": "no name"},
+            "not an option"
+        ],
+        "subcommands": [
+            {"name": "get", "options": [
+                {"name": "-w", "description": "Watch"},
+                {"name": "-n", "description": "Shadowed"}
+            ]},
+            {"name": "logs", "options": "not a list"}
+        ]
+    }"#;
+
+    #[test]
+    fn test_parse_spec() {
+        let spec = Spec::parse(KUBECTL).unwrap();
+        assert_eq!(spec.flags["-n"].values, 1);
+        assert_eq!(spec.flags["--namespace"].values, 1);
+        assert_eq!(spec.flags["--all-namespaces"].values, 0);
+        assert_eq!(spec.flags["--color"].values, 0);
+        assert_eq!(spec.flags["--pair"].values, 2);
+        assert_eq!(spec.flags["--flagged"].values, 1);
+        assert!(!spec.flags.contains_key("-w"));
+        assert_eq!(spec.choices("-o"), ["json", "yaml", "wide"]);
+        assert!(spec.choices("-n").is_empty());
+        assert!(spec.choices("--unknown").is_empty());
+        assert!(spec.takes_path("-f"));
+        assert!(spec.takes_path("--kubeconfig"));
+        assert!(!spec.takes_path("-n"));
+        assert!(!spec.takes_path("--unknown"));
+
+        assert!(Spec::parse("not json").is_err());
+        assert_eq!(Spec::parse("[]").unwrap(), Spec::default());
+    }
+
+    #[test]
+    fn test_spec_for_command() {
+        let spec = Spec::parse(KUBECTL).unwrap();
+        let words =
+            |words: &[&str]| -> Vec<String> { words.iter().map(|w| w.to_string()).collect() };
+
+        // A subcommand's flags come on top of the program's
+        let get = spec.for_command(&words(&["get", "pods"]));
+        assert_eq!(get.flags["-w"].values, 0);
+        assert_eq!(get.flags["-n"].values, 0);
+        assert_eq!(get.flags["--pair"].values, 2);
+
+        // Other subcommands don't see them
+        let logs = spec.for_command(&words(&["logs"]));
+        assert_eq!(logs.flags["-n"].values, 1);
+        assert!(!logs.flags.contains_key("-w"));
+        assert_eq!(spec.for_command(&[]).flags, spec.flags);
+        assert_eq!(spec.for_command(&words(&["pods", "get"])).flags, spec.flags);
+    }
+
+    #[test]
+    fn test_spec_value_counts_stay_under_config() {
+        let spec = Spec::parse(KUBECTL).unwrap();
+        let mut counts = HashMap::from([("-n".to_string(), 0)]);
+        spec.add_value_counts(&mut counts);
+        assert_eq!(counts["-n"], 0);
+        assert_eq!(counts["--pair"], 2);
+    }
+
+    #[test]
+    fn test_specs_load_once() {
+        let dir = std::env::temp_dir().join(format!("te-specs-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("kubectl.json"), KUBECTL).unwrap();
+        std::fs::write(dir.join("broken.json"), "{").unwrap();
+
+        let specs = Specs::new(Some(dir.clone()));
+        let spec = specs.get("kubectl").unwrap();
+        assert_eq!(spec.flags["-n"].values, 1);
+        assert!(specs.get("broken").is_none());
+        assert!(specs.get("missing").is_none());
+        assert!(specs.get("../kubectl").is_none());
+
+        // The parsed spec is kept, even once the file is gone
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(Arc::ptr_eq(&specs.get("kubectl").unwrap(), &spec));
+
+        assert!(Specs::default().get("kubectl").is_none());
+    }
+}
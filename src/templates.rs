@@ -0,0 +1,87 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::config;
+
+/// `templates/` next to the config file, where `te save` keeps commands
+pub fn templates_dir() -> Option<PathBuf> {
+    Some(config::config_dir()?.join("templates"))
+}
+
+/// Check that `name` is a plain file name, so a template can't be written
+/// outside the templates directory
+fn check_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+        anyhow::bail!(
+            "Invalid template name '{}': use a plain name like 'deploy'",
+            name
+        );
+    }
+    Ok(())
+}
+
+/// Save `command` as the template `name` in `dir`, returning its path.
+/// An existing template is only replaced with `force`.
+pub fn save(dir: &Path, name: &str, command: &str, force: bool) -> Result<PathBuf> {
+    check_name(name)?;
+    let path = dir.join(name);
+    if path.exists() && !force {
+        anyhow::bail!(
+            "A template named '{}' already exists; pass --force to replace it",
+            name
+        );
+    }
+    std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    std::fs::write(&path, format!("{}\n", command))
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(path)
+}
+
+/// The command saved as the template `name` in `dir`
+pub fn load(dir: &Path, name: &str) -> Result<String> {
+    check_name(name)?;
+    let path = dir.join(name);
+    match std::fs::read_to_string(&path) {
+        Ok(text) => Ok(text.trim_end_matches('\n').to_string()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => anyhow::bail!(
+            "No template named '{}' (save one with `te save {} -- <command>`)",
+            name,
+            name
+        ),
+        Err(err) => Err(err).with_context(|| format!("Failed to read {}", path.display())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_and_load() {
+        let dir = std::env::temp_dir().join(format!("te-{}-templates", std::process::id()));
+        let command = "kubectl logs -n <namespace> deploy/<name> --since 1h";
+
+        let path = save(&dir, "logs", command, false).unwrap();
+        assert_eq!(path, dir.join("logs"));
+        assert_eq!(load(&dir, "logs").unwrap(), command);
+
+        // Replacing needs --force
+        assert!(save(&dir, "logs", "ls", false).is_err());
+        save(&dir, "logs", "ls", true).unwrap();
+        assert_eq!(load(&dir, "logs").unwrap(), "ls");
+
+        assert!(
+            load(&dir, "missing")
+                .unwrap_err()
+                .to_string()
+                .starts_with("No template named 'missing'")
+        );
+        for name in ["", "../config.toml", "a/b", ".hidden"] {
+            assert!(save(&dir, name, "ls", true).is_err(), "{}", name);
+            assert!(load(&dir, name).is_err(), "{}", name);
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,23 +1,57 @@
 use anyhow::Result;
 use crossterm::{
     cursor,
-    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEventKind},
+    event::{
+        self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEvent, KeyEventKind,
+    },
     execute,
-    terminal::{Clear, ClearType, disable_raw_mode, enable_raw_mode},
+    terminal::{
+        Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen, disable_raw_mode,
+        enable_raw_mode,
+    },
 };
 use ratatui::{
-    Terminal, TerminalOptions, Viewport,
-    backend::CrosstermBackend,
+    Frame, Terminal, TerminalOptions, Viewport,
+    backend::{CrosstermBackend, TestBackend},
     layout::Constraint,
     style::{Modifier, Style},
-    text::Text,
-    widgets::{Cell, Row, Table},
+    text::{Line, Span, Text},
+    widgets::{Cell, Paragraph, Row, Table},
 };
 
-/// Wrap text into lines that fit within the given width
-fn wrap_text(text: &str, width: usize) -> Vec<String> {
+/// Display width of `ch` in terminal columns.
+///
+/// Combining marks and other zero-width characters take no columns. Control
+/// characters have no width of their own: C0 controls are drawn in caret
+/// notation by [`wrap_text`], and the rest aren't drawn at all.
+pub fn char_width(ch: char) -> usize {
     use unicode_width::UnicodeWidthChar;
 
+    match caret_notation(ch) {
+        Some(_) => 2,
+        None => UnicodeWidthChar::width(ch).unwrap_or(0),
+    }
+}
+
+/// Display width of `text`, the sum of [`char_width`] over its characters
+pub fn text_width(text: &str) -> usize {
+    text.chars().map(char_width).sum()
+}
+
+/// The caret notation (`^A`, `^[`, `^?`) for a C0 control character or DEL
+fn caret_notation(ch: char) -> Option<[char; 2]> {
+    match ch {
+        '\0'..='\x1f' => Some(['^', (ch as u8 + b'@') as char]),
+        '\x7f' => Some(['^', '?']),
+        _ => None,
+    }
+}
+
+/// Wrap text into lines that fit within the given width.
+///
+/// C0 control characters other than newlines come out in caret notation, so
+/// they are visible and the line widths match [`text_width`].
+pub fn wrap_text(text: &str, width: usize) -> Vec<String> {
     if width == 0 {
         return vec![text.to_string()];
     }
@@ -35,13 +69,16 @@ fn wrap_text(text: &str, width: usize) -> Vec<String> {
             continue;
         }
 
-        let char_width = UnicodeWidthChar::width(ch).unwrap_or(1);
+        let char_width = char_width(ch);
         if current_width + char_width > width && !current_line.is_empty() {
             lines.push(current_line);
             current_line = String::new();
             current_width = 0;
         }
-        current_line.push(ch);
+        match caret_notation(ch) {
+            Some(caret) => current_line.extend(caret),
+            None => current_line.push(ch),
+        }
         current_width += char_width;
     }
 
@@ -53,16 +90,196 @@ fn wrap_text(text: &str, width: usize) -> Vec<String> {
 }
 use std::fs::OpenOptions;
 
-use crate::{app::App, command::Command};
+use std::{
+    sync::{Arc, Mutex, mpsc},
+    time::Duration,
+};
+
+use crate::{
+    app::{App, BatchProgress, PREFIX_WIDTH},
+    bash_completion::BashCompletion,
+    carapace::Carapace,
+    command::{self, Command},
+    flags::{FlagAliasConfig, FlagAliases, FlagArities, FlagValueCounts},
+    help::HelpChoices,
+    history::{self, HistoryCache, Suggestions},
+    keys::{Action, KeyMap},
+    profile::{self, Phase},
+    providers::Providers,
+    session::SessionFile,
+    specs::Specs,
+    undo::{DEFAULT_UNDO_LIMIT, Undo},
+};
+
+/// Options controlling how the TUI behaves
+pub struct TuiOptions {
+    /// Where suggestions are learned from
+    pub history: history::HistorySource,
+    /// How long to wait for history suggestions before opening the TUI
+    pub history_budget: Duration,
+    /// History read so far, shared by the commands of a batch
+    pub history_cache: Arc<Mutex<HistoryCache>>,
+    /// Extra flags that are expected to repeat, on top of the defaults
+    pub repeatable_flags: Vec<String>,
+    /// Commands that wrap the real command, skipped when matching history
+    pub wrappers: Vec<history::Wrapper>,
+    /// Only pick a history value for the trailing component
+    pub completions_only: bool,
+    /// How many of the most recent history entries to learn from
+    pub history_limit: Option<usize>,
+    /// Whether moving past the last or first component wraps around
+    pub wrap_navigation: bool,
+    /// Which action each key chord runs in Navigation Mode
+    pub keymap: KeyMap,
+    /// Commands configured to offer values, shared by the commands of a batch
+    pub providers: Providers,
+    /// Carapace's completers, shared by the commands of a batch
+    pub carapace: Carapace,
+    /// Bash's programmable completion, shared by the commands of a batch
+    pub bash_completion: BashCompletion,
+    /// Values learned from `--help`, shared by the commands of a batch
+    pub help: HelpChoices,
+    /// Completion specs, read once per program for a batch
+    pub specs: Specs,
+    /// Capture the mouse while the editor is open
+    pub mouse: bool,
+    /// Show the keys of the current mode in the status row
+    pub hints: bool,
+    /// How many values flags take, per program, on top of the built-in table
+    pub flag_value_counts: FlagValueCounts,
+    /// Long spellings of short flags, per program, on top of the built-in table
+    pub flag_aliases: FlagAliasConfig,
+    /// Progress through a batch of commands, if editing one
+    pub batch: Option<BatchProgress>,
+    /// Start on the last component and navigate towards the first
+    pub reverse: bool,
+    /// Draw from the top of the terminal instead of the cursor's line
+    pub fullscreen: bool,
+    /// Draw from the top of the alternate screen, leaving the screen as it
+    /// was on exit
+    pub top: bool,
+    /// Number the lines of a multi-line value while editing it
+    pub line_numbers: bool,
+    /// Show the command after brace expansion in the status row
+    pub brace_preview: bool,
+    /// How many undo steps to keep
+    pub undo_limit: usize,
+    /// Where the edit is saved as it goes, to be offered back if it doesn't
+    /// finish
+    pub session: Option<SessionFile>,
+}
+
+/// How an editing session ended
+pub enum Outcome {
+    /// The command was accepted, with the flag values taken from history
+    Accepted(Command, Vec<String>),
+    /// Ctrl+O: the command was accepted, to be run right away
+    AcceptedToRun(Command, Vec<String>),
+    /// Esc or q: editing this command was given up
+    Cancelled,
+    /// Ctrl-C, or Esc twice in a batch: editing was given up altogether
+    Interrupted,
+    /// The rest of the batch, this command included, is to be left as is
+    SkipRemaining,
+}
+
+/// Why the event loop stopped
+enum Exit {
+    Accept,
+    AcceptAndRun,
+    Cancel,
+    Interrupt,
+    SkipRemaining,
+}
+
+impl Exit {
+    fn into_outcome(self, app: App) -> Outcome {
+        let from_history = app.history_values();
+        match self {
+            Exit::Accept => Outcome::Accepted(app.cmd, from_history),
+            Exit::AcceptAndRun => Outcome::AcceptedToRun(app.cmd, from_history),
+            Exit::Cancel => Outcome::Cancelled,
+            Exit::Interrupt => Outcome::Interrupted,
+            Exit::SkipRemaining => Outcome::SkipRemaining,
+        }
+    }
+}
+
+impl Default for TuiOptions {
+    fn default() -> Self {
+        Self {
+            history: history::HistorySource::default(),
+            history_budget: history::DEFAULT_HISTORY_BUDGET,
+            history_cache: Arc::default(),
+            repeatable_flags: Vec::new(),
+            wrappers: history::default_wrappers(),
+            completions_only: false,
+            history_limit: None,
+            wrap_navigation: true,
+            keymap: KeyMap::default(),
+            providers: Providers::default(),
+            carapace: Carapace::default(),
+            bash_completion: BashCompletion::default(),
+            help: HelpChoices::default(),
+            specs: Specs::default(),
+            mouse: true,
+            hints: true,
+            flag_value_counts: FlagValueCounts::new(),
+            flag_aliases: FlagAliasConfig::new(),
+            batch: None,
+            reverse: false,
+            fullscreen: false,
+            top: false,
+            line_numbers: false,
+            brace_preview: false,
+            undo_limit: DEFAULT_UNDO_LIMIT,
+            session: None,
+        }
+    }
+}
+
+impl TuiOptions {
+    /// Create the editor state for `cmd` with these options applied
+    fn new_app(&self, mut cmd: Command, base_command: &[String]) -> App {
+        let counts = self
+            .specs
+            .value_counts(base_command, &self.flag_value_counts);
+        cmd.mark_scripts(&self.wrappers, &counts);
+        let mut app = App::new(cmd, 0);
+        app.repeatable_flags
+            .extend(self.repeatable_flags.iter().cloned());
+        app.wrap_navigation = self.wrap_navigation;
+        app.keymap = self.keymap.clone();
+        app.wrappers = self.wrappers.clone();
+        app.flag_arities = base_command
+            .first()
+            .map_or_else(FlagArities::default, |program| {
+                FlagArities::for_program(program, &counts)
+            });
+        app.flag_value_counts = counts;
+        app.spec = self.specs.for_command(base_command).map(Arc::new);
+        app.flag_aliases = FlagAliases::for_command(base_command, &self.flag_aliases);
+        app.batch = self.batch;
+        app.reverse = self.reverse;
+        app.line_numbers = self.line_numbers;
+        app.brace_preview = self.brace_preview;
+        app.hints = self.hints;
+        app.undo = Undo::with_limit(self.undo_limit);
+        if self.reverse {
+            app.select_last_component();
+        }
+        app
+    }
+}
 
 /// Prefix characters for row shortcuts: 1-9, then available letters (excluding reserved shortcuts)
-const PREFIX_CHARS: [char; 28] = [
-    '1', '2', '3', '4', '5', '6', '7', '8', '9', 'b', 'c', 'e', 'f', 'g', 'h', 'l', 'm', 'n', 'o',
-    'p', 'r', 's', 't', 'v', 'w', 'x', 'y', 'z',
+const PREFIX_CHARS: [char; 25] = [
+    '1', '2', '3', '4', '5', '6', '7', '8', '9', 'b', 'f', 'g', 'h', 'l', 'm', 'n', 'o', 'p', 'r',
+    't', 'v', 'w', 'x', 'y', 'z',
 ];
 
 /// Get prefix character for a given row index (0-based)
-fn get_prefix_char(index: usize) -> Option<char> {
+pub fn get_prefix_char(index: usize) -> Option<char> {
     PREFIX_CHARS.get(index).copied()
 }
 
@@ -71,6 +288,17 @@ fn get_index_for_prefix(c: char) -> Option<usize> {
     PREFIX_CHARS.iter().position(|&ch| ch == c)
 }
 
+/// Open the controlling terminal, with an error that says te needs one
+pub fn open_tty() -> Result<std::fs::File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+        .map_err(|err| {
+            anyhow::anyhow!("te needs a terminal, but /dev/tty can't be opened: {}", err)
+        })
+}
+
 /// Get cursor position by querying /dev/tty directly using ANSI escape codes
 fn get_cursor_position(tty: &mut std::fs::File) -> Result<(u16, u16)> {
     use std::io::{Read, Write};
@@ -96,38 +324,127 @@ fn get_cursor_position(tty: &mut std::fs::File) -> Result<(u16, u16)> {
         }
     }
 
-    // Parse response: ESC [ {row} ; {col} R
-    let response_str = String::from_utf8_lossy(&response);
-    if let Some(pos_str) = response_str.strip_prefix("\x1b[") {
-        if let Some(pos_str) = pos_str.strip_suffix('R') {
-            if let Some((row_str, col_str)) = pos_str.split_once(';') {
-                if let (Ok(row), Ok(col)) = (row_str.parse::<u16>(), col_str.parse::<u16>()) {
-                    // Convert from 1-based to 0-based
-                    return Ok((col.saturating_sub(1), row.saturating_sub(1)));
-                }
-            }
+    Ok(parse_cursor_position(&response).unwrap_or((0, 0)))
+}
+
+/// Parse a cursor position report, `ESC [ {row} ; {col} R`, into a 0-based
+/// `(column, row)`
+pub fn parse_cursor_position(response: &[u8]) -> Option<(u16, u16)> {
+    let response_str = String::from_utf8_lossy(response);
+    let pos_str = response_str.strip_prefix("\x1b[")?.strip_suffix('R')?;
+    let (row_str, col_str) = pos_str.split_once(';')?;
+    let (row, col) = (row_str.parse::<u16>().ok()?, col_str.parse::<u16>().ok()?);
+    // Convert from 1-based to 0-based
+    Some((col.saturating_sub(1), row.saturating_sub(1)))
+}
+
+/// Where on the terminal the editor is drawn
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Placement {
+    /// From the line the cursor is on, below what's already on the screen
+    Inline,
+    /// From the top of the cleared screen
+    Fullscreen,
+    /// From the top of the alternate screen, so the original screen comes
+    /// back on exit
+    Top,
+}
+
+impl TuiOptions {
+    fn placement(&self) -> Placement {
+        if self.top {
+            Placement::Top
+        } else if self.fullscreen {
+            Placement::Fullscreen
+        } else {
+            Placement::Inline
         }
     }
-
-    Ok((0, 0))
 }
 
-pub fn run_tui(command_str: &str) -> Result<Option<String>> {
-    let cmd: Command = command_str.try_into()?;
+pub fn run_tui(cmd: Command, options: &TuiOptions) -> Result<Outcome> {
+    // Load history suggestions, opening the TUI without them if this takes too long
+    let components: Vec<String> = cmd.iter_components().cloned().collect();
+    let base_command = history::base_command(&components, &options.wrappers);
+    let mut app = options.new_app(cmd, &base_command);
+    // Not in run_headless, whose outcome depends only on its keys
+    app.providers = options.providers.clone();
+    app.carapace = options.carapace.clone();
+    app.bash_completion = options.bash_completion.clone();
+    app.help = options.help.clone();
+    let (suggestions, pending_suggestions) = match history::load_history_in_background(
+        options.history.clone(),
+        options.history_cache.clone(),
+        base_command,
+        options.wrappers.clone(),
+        app.flag_arities.clone(),
+        options.history_limit,
+        options.history_budget,
+    ) {
+        Ok(suggestions) => (suggestions, None),
+        // The picker needs the values up front, so wait for them
+        Err(receiver) if options.completions_only => (receiver.recv().unwrap_or_default(), None),
+        Err(receiver) => (Suggestions::new(), Some(receiver)),
+    };
+
+    app.suggestions = suggestions;
+    if options.completions_only {
+        app.completions_only = true;
+        if !app.open_completions() {
+            anyhow::bail!("No history values to complete the trailing value with");
+        }
+    }
 
-    // Enable raw mode first to prevent escape sequences from echoing
+    // Open /dev/tty directly for both reading and writing (like fzf does)
+    // This allows the TUI to work inside command substitution. It is opened
+    // before raw mode is enabled, so a missing terminal leaves nothing behind
+    let mut tty_read = open_tty()?;
+    let mut tty = open_tty()?;
+
+    // Enable raw mode before querying the cursor to prevent escape sequences from echoing
     enable_raw_mode()?;
 
-    // Get cursor position from /dev/tty
-    let (cursor_x, cursor_y) = {
-        let mut tty_read = OpenOptions::new().read(true).write(true).open("/dev/tty")?;
-        get_cursor_position(&mut tty_read).unwrap_or((0, 0))
+    // Offer to pick up an edit of this same command that didn't finish
+    if let Some(file) = &options.session
+        && let Some(session) = file.load(app.cmd.components())
+    {
+        match ask_restore(&mut tty) {
+            Ok(true) => app.restore_session(session),
+            Ok(false) => {}
+            Err(err) => {
+                disable_raw_mode()?;
+                return Err(err);
+            }
+        }
+    }
+
+    // Get cursor position from /dev/tty. The other placements start from the
+    // top of a cleared screen, wherever the cursor was
+    let placement = options.placement();
+    let (cursor_x, cursor_y) = match placement {
+        Placement::Inline => get_cursor_position(&mut tty_read).unwrap_or((0, 0)),
+        Placement::Fullscreen | Placement::Top => (0, 0),
     };
 
-    // Open /dev/tty directly for both reading and writing (like fzf does)
-    // This allows the TUI to work inside command substitution
-    let mut tty = OpenOptions::new().read(true).write(true).open("/dev/tty")?;
-    execute!(tty, EnableMouseCapture)?;
+    let prepared = match placement {
+        Placement::Inline => Ok(()),
+        Placement::Fullscreen => execute!(tty, Clear(ClearType::All)),
+        Placement::Top => execute!(tty, EnterAlternateScreen, Clear(ClearType::All)),
+    }
+    .and_then(|()| {
+        if options.mouse {
+            execute!(tty, EnableMouseCapture)
+        } else {
+            Ok(())
+        }
+    });
+    if let Err(err) = prepared {
+        if placement == Placement::Top {
+            let _ = execute!(tty, LeaveAlternateScreen);
+        }
+        disable_raw_mode()?;
+        return Err(err.into());
+    }
 
     let backend = CrosstermBackend::new(tty);
     let mut terminal = Terminal::with_options(
@@ -138,248 +455,451 @@ pub fn run_tui(command_str: &str) -> Result<Option<String>> {
     )?;
 
     // Start TUI from the current line
-    let mut app = App::new(cmd, cursor_y);
-    let result = run_app(&mut terminal, &mut app);
+    app.cursor_y = cursor_y;
+    let mut read_event = |timeout: Option<Duration>| -> Result<Option<Event>> {
+        match timeout {
+            Some(timeout) if !event::poll(timeout)? => Ok(None),
+            _ => Ok(Some(event::read()?)),
+        }
+    };
+    let result = run_app(
+        &mut terminal,
+        &mut app,
+        pending_suggestions,
+        options.session.as_ref(),
+        &mut read_event,
+    );
 
     disable_raw_mode()?;
 
     // Clear the TUI content from the current line down
     let backend = terminal.backend_mut();
-    execute!(
-        backend,
-        DisableMouseCapture,
-        cursor::MoveTo(0, cursor_y),
-        Clear(ClearType::FromCursorDown),
-        cursor::MoveTo(cursor_x, cursor_y)
-    )?;
+    if options.mouse {
+        execute!(backend, DisableMouseCapture)?;
+    }
+    if placement == Placement::Top {
+        execute!(backend, LeaveAlternateScreen)?;
+    } else {
+        execute!(
+            backend,
+            cursor::MoveTo(0, cursor_y),
+            Clear(ClearType::FromCursorDown),
+            cursor::MoveTo(cursor_x, cursor_y)
+        )?;
+    }
     terminal.show_cursor()?;
 
-    match result {
-        Ok(should_execute) => {
-            if should_execute {
-                Ok(Some(app.cmd.to_shell_string()))
-            } else {
-                Ok(None)
-            }
-        }
-        Err(err) => Err(err),
+    // The edit ended normally, so there is nothing to pick up next time
+    if result.is_ok()
+        && let Some(file) = &options.session
+    {
+        file.remove();
     }
+    Ok(result?.into_outcome(app))
 }
 
-fn run_app<B: ratatui::backend::Backend>(
-    terminal: &mut Terminal<B>,
-    app: &mut App,
-) -> Result<bool> {
-    loop {
-        terminal.draw(|f| {
-            let area = f.area();
-
-            // Start from the cursor position
-            let start_y = app.cursor_y;
+/// Run the editor without a terminal, feeding it a scripted sequence of keys.
+///
+/// The screen is drawn to an in-memory buffer and history is not consulted,
+/// so the outcome depends only on the command, the keys and the editing
+/// options. Running out of keys before the command is accepted or cancelled
+/// is an error; keys left over are there for the next command of a batch.
+pub fn run_headless(
+    cmd: Command,
+    keys: &mut dyn Iterator<Item = KeyEvent>,
+    options: &TuiOptions,
+) -> Result<Outcome> {
+    let mut terminal = Terminal::new(TestBackend::new(80, 24))?;
+    let components: Vec<String> = cmd.iter_components().cloned().collect();
+    let base_command = history::base_command(&components, &options.wrappers);
+    let mut app = options.new_app(cmd, &base_command);
 
-            // Build vertical list of components
-            let selected = app.list_state.selected().unwrap_or(0);
-            let components: Vec<_> = app.cmd.iter_components().collect();
-
-            let prefix_width: u16 = 3; // " X " where X is the shortcut key
-            let text_width = area.width.saturating_sub(prefix_width) as usize;
+    // As in the terminal, the first key answers whether to pick up an edit of
+    // this command that didn't finish
+    if let Some(file) = &options.session
+        && let Some(session) = file.load(app.cmd.components())
+    {
+        match keys.next() {
+            Some(key) if matches!(key.code, KeyCode::Char('y' | 'Y')) => {
+                app.restore_session(session)
+            }
+            Some(_) => {}
+            None => anyhow::bail!("Key script ended before the restore prompt was answered"),
+        }
+    }
 
-            // Pre-calculate wrapped lines and total height
-            let mut wrapped_data: Vec<(String, Vec<String>)> = Vec::new();
-            let mut total_height: u16 = 0;
+    let mut read_event = |_timeout: Option<Duration>| -> Result<Option<Event>> {
+        match keys.next() {
+            Some(key) => Ok(Some(Event::Key(key))),
+            None => anyhow::bail!("Key script ended before the command was accepted or cancelled"),
+        }
+    };
 
-            for (i, component) in components.iter().enumerate() {
-                let text = if app.input_mode && i == selected {
-                    app.current_input.clone()
-                } else {
-                    component.to_string()
-                };
+    let exit = run_app(&mut terminal, &mut app, None, None, &mut read_event)?;
+    if let Some(file) = &options.session {
+        file.remove();
+    }
+    Ok(exit.into_outcome(app))
+}
 
-                let prefix_char = get_prefix_char(i)
-                    .map(|c| c.to_string())
-                    .unwrap_or_else(|| (i + 1).to_string());
-                let prefix = format!(" {} ", prefix_char);
+/// Draws the components, the status row and the picker, starting from the
+/// cursor's line
+fn draw(f: &mut Frame, app: &App) {
+    let area = f.area();
 
-                let wrapped_lines = wrap_text(&text, text_width);
-                total_height += wrapped_lines.len() as u16;
-                wrapped_data.push((prefix, wrapped_lines));
-            }
+    // Start from the cursor position
+    let start_y = app.cursor_y;
 
-            // Render area for the vertical list
-            let list_area = ratatui::layout::Rect {
+    // Too narrow for any component text, as in a terminal being resized:
+    // mark where te is and wait for more room
+    if area.width <= PREFIX_WIDTH {
+        if area.width > 0 && start_y < area.y + area.height {
+            let marker_area = ratatui::layout::Rect {
                 x: area.x,
                 y: start_y,
                 width: area.width,
-                height: total_height.min(area.height.saturating_sub(start_y)),
+                height: 1,
             };
+            f.render_widget(
+                Paragraph::new("…").style(Style::default().add_modifier(Modifier::DIM)),
+                marker_area,
+            );
+        }
+        return;
+    }
 
-            // Build rows for the table
-            let mut rows = Vec::new();
-            let mut cursor_row = 0u16;
-            let mut cursor_col = 0u16;
-            let mut cumulative_height: u16 = 0;
+    let spans = app.component_spans(area.width);
+    let total_height: u16 = spans.iter().map(|span| span.lines.len() as u16).sum();
 
-            for (i, (prefix, wrapped_lines)) in wrapped_data.into_iter().enumerate() {
-                let row_height = wrapped_lines.len() as u16;
+    // Render area for the vertical list
+    let list_area = ratatui::layout::Rect {
+        x: area.x,
+        y: start_y,
+        width: area.width,
+        height: total_height.min(area.height.saturating_sub(start_y)),
+    };
 
-                let style = if i == selected {
-                    if app.input_mode {
-                        Style::default().add_modifier(Modifier::BOLD)
-                    } else {
-                        Style::default().add_modifier(Modifier::REVERSED)
-                    }
+    // Build rows for the table
+    let cursor_offset = app.input_cursor_offset(&spans);
+    let rows: Vec<Row> = spans
+        .into_iter()
+        .map(|span| {
+            let row_height = span.lines.len() as u16;
+            let wrapped_text = if span.gutter.is_empty() {
+                Text::from(span.lines.join("\n"))
+            } else {
+                let dim = Style::default().add_modifier(Modifier::DIM);
+                Text::from(
+                    span.gutter
+                        .into_iter()
+                        .zip(span.lines)
+                        .map(|(number, line)| {
+                            Line::from(vec![Span::styled(number, dim), Span::raw(line)])
+                        })
+                        .collect::<Vec<_>>(),
+                )
+            };
+            Row::new(vec![
+                Cell::from(span.prefix).style(Style::default().add_modifier(Modifier::DIM)),
+                Cell::from(wrapped_text).style(span.style),
+            ])
+            .height(row_height)
+        })
+        .collect();
+
+    let table = Table::new(
+        rows,
+        [Constraint::Length(PREFIX_WIDTH), Constraint::Fill(1)],
+    );
+    f.render_widget(table, list_area);
+
+    // Status row directly below the components
+    let mut below_y = list_area.y + list_area.height;
+    if let Some(status) = app.status_line()
+        && below_y < area.y + area.height
+    {
+        let status_area = ratatui::layout::Rect {
+            x: area.x,
+            y: below_y,
+            width: area.width,
+            height: 1,
+        };
+        let paragraph = Paragraph::new(format!(" {}", status))
+            .style(Style::default().add_modifier(Modifier::DIM));
+        f.render_widget(paragraph, status_area);
+        below_y += 1;
+    }
+
+    // Dropdown of history values, lined up with the component text
+    if let Some(picker) = &app.picker {
+        let picker_area = ratatui::layout::Rect {
+            x: area.x + PREFIX_WIDTH,
+            y: below_y,
+            width: area.width.saturating_sub(PREFIX_WIDTH),
+            height: (picker.candidates.len() as u16)
+                .min((area.y + area.height).saturating_sub(below_y)),
+        };
+        let rows: Vec<Row> = picker
+            .candidates
+            .iter()
+            .enumerate()
+            .map(|(i, candidate)| {
+                let style = if i == picker.selected {
+                    Style::default().add_modifier(Modifier::REVERSED)
                 } else {
-                    Style::default()
+                    Style::default().add_modifier(Modifier::DIM)
                 };
-
-                if app.input_mode && i == selected {
-                    // Place cursor on the last wrapped line of the current input,
-                    // and at the end of that line using Unicode display width.
-                    use unicode_width::UnicodeWidthStr;
-                    let last_line_width = wrapped_lines
-                        .last()
-                        .map(|line| UnicodeWidthStr::width(line.as_str()) as u16)
-                        .unwrap_or(0);
-                    cursor_row = cumulative_height + row_height.saturating_sub(1);
-                    cursor_col = prefix_width + last_line_width;
+                // Values not from history say where they came from
+                let mut spans = vec![Span::raw(candidate.as_str())];
+                if let Some(Some(tag)) = picker.tags.get(i) {
+                    spans.push(Span::styled(
+                        format!("  {}", tag),
+                        Style::default().add_modifier(Modifier::ITALIC),
+                    ));
                 }
+                Row::new(vec![Cell::from(Line::from(spans))]).style(style)
+            })
+            .collect();
+        f.render_widget(Table::new(rows, [Constraint::Fill(1)]), picker_area);
+    }
 
-                let wrapped_text = Text::from(wrapped_lines.join("\n"));
-                let row = Row::new(vec![
-                    Cell::from(prefix).style(Style::default().add_modifier(Modifier::DIM)),
-                    Cell::from(wrapped_text).style(style),
-                ])
-                .height(row_height);
-                rows.push(row);
+    // Set cursor position if in input mode, and if it is on screen
+    if let Some((cursor_col, cursor_row)) = cursor_offset
+        && cursor_col < list_area.width
+        && cursor_row < list_area.height
+    {
+        f.set_cursor_position((list_area.x + cursor_col, list_area.y + cursor_row));
+    }
+}
 
-                cumulative_height += row_height;
-            }
+/// Asks on the terminal whether to pick up an unfinished edit, reading a
+/// single key: `y` picks it up, any other key starts afresh
+fn ask_restore(tty: &mut std::fs::File) -> Result<bool> {
+    use std::io::Write;
 
-            let table = Table::new(
-                rows,
-                [Constraint::Length(prefix_width), Constraint::Fill(1)],
-            );
-            f.render_widget(table, list_area);
+    write!(
+        tty,
+        "te: restore the unfinished edit of this command? [y/N] "
+    )?;
+    tty.flush()?;
+    let restore = loop {
+        if let Event::Key(key) = event::read()?
+            && key.kind == KeyEventKind::Press
+        {
+            break matches!(key.code, KeyCode::Char('y' | 'Y'));
+        }
+    };
+    execute!(tty, cursor::MoveToColumn(0), Clear(ClearType::CurrentLine))?;
+    Ok(restore)
+}
 
-            // Set cursor position if in input mode
-            if app.input_mode {
-                f.set_cursor_position((list_area.x + cursor_col, list_area.y + cursor_row));
+/// Runs the event loop until the command is accepted, cancelled or skipped.
+///
+/// `read_event` returns the next input event, or `None` if none arrived
+/// within the given timeout. Each change to the command is written to
+/// `session`, if given.
+fn run_app<B: ratatui::backend::Backend>(
+    terminal: &mut Terminal<B>,
+    app: &mut App,
+    mut pending_suggestions: Option<mpsc::Receiver<Suggestions>>,
+    session: Option<&SessionFile>,
+    read_event: &mut dyn FnMut(Option<Duration>) -> Result<Option<Event>>,
+) -> Result<Exit> {
+    let mut saved = app.cmd.components().to_vec();
+    loop {
+        // Save the session after every change, which a failure to write
+        // shouldn't interrupt
+        if let Some(file) = session
+            && app.cmd.components() != saved
+        {
+            let _ = file.save(app.cmd.components(), &app.undo);
+            saved = app.cmd.components().to_vec();
+        }
+
+        // Pick up history suggestions that finished loading after the TUI opened
+        if let Some(receiver) = &pending_suggestions {
+            match receiver.try_recv() {
+                Ok(suggestions) => {
+                    app.suggestions = suggestions;
+                    pending_suggestions = None;
+                }
+                Err(mpsc::TryRecvError::Disconnected) => pending_suggestions = None,
+                Err(mpsc::TryRecvError::Empty) => {}
             }
-        })?;
+        }
+
+        app.request_provided_values();
 
-        if let Event::Key(key) = event::read()? {
+        // Only the first frame is kept, the one the user waits for
+        profile::time(Phase::FirstDraw, || terminal.draw(|f| draw(f, app)))?;
+
+        // While suggestions are still loading, wake up periodically to check on them
+        let timeout = (pending_suggestions.is_some()
+            || app.providers.running()
+            || app.carapace.running()
+            || app.bash_completion.running()
+            || app.help.running())
+        .then_some(Duration::from_millis(50));
+        let Some(event) = read_event(timeout)? else {
+            continue;
+        };
+
+        if let Event::Key(key) = event {
             if key.kind != KeyEventKind::Press {
                 continue;
             }
+            app.notice = None;
+            let action = app.keymap.lookup(key);
+            // Cycling through options is undone in one step, up to the next other key
+            if app.input_mode
+                || app.picker.is_some()
+                || !matches!(action, Some(Action::NextOption | Action::PreviousOption))
+            {
+                app.end_cycling();
+            }
+            // The picker takes Enter with any modifiers to pick a value
+            let picks = app.picker.is_some() && key.code == KeyCode::Enter;
+            match app.keymap.exact(key) {
+                Some(Action::Accept) if !picks => return Ok(Exit::Accept),
+                Some(Action::AcceptAndRun) if !picks => return Ok(Exit::AcceptAndRun),
+                _ => {}
+            }
+            // A second Esc right after skipping a command cancels the whole batch
+            if let Some(batch) = &mut app.batch
+                && std::mem::take(&mut batch.esc_cancels_all)
+                && key.code == KeyCode::Esc
+            {
+                return Ok(Exit::Interrupt);
+            }
 
-            if app.input_mode {
+            if app.picker.is_some() {
                 match key.code {
-                    KeyCode::Enter if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
-                        return Ok(true);
+                    KeyCode::Char('c') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
+                        return Ok(Exit::Interrupt);
                     }
-                    KeyCode::Enter => app.confirm_input(),
-                    KeyCode::Esc => app.cancel_input(),
-                    KeyCode::Backspace => {
-                        app.current_input.pop();
+                    KeyCode::Char('n') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
+                        app.picker_next();
                     }
-                    KeyCode::Char('x') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
-                        return Ok(true);
+                    KeyCode::Char('p') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
+                        app.picker_previous();
                     }
-                    KeyCode::Char('c') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
-                        return Ok(false);
+                    KeyCode::Down | KeyCode::Tab | KeyCode::Char('j') => app.picker_next(),
+                    KeyCode::Up | KeyCode::BackTab | KeyCode::Char('k') => app.picker_previous(),
+                    KeyCode::Enter => {
+                        app.accept_picker();
+                        if app.completions_only {
+                            return Ok(Exit::Accept);
+                        }
                     }
-                    KeyCode::Char('u') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
-                        app.current_input.clear();
+                    KeyCode::Esc | KeyCode::Char('q') => {
+                        if app.completions_only {
+                            return Ok(Exit::Cancel);
+                        }
+                        app.close_picker();
                     }
-                    KeyCode::Char(c) => app.current_input.push(c),
                     _ => {}
                 }
-            } else {
+            } else if app.input_mode {
                 match key.code {
-                    // Ctrl+* shortcuts (must come before non-modifier versions)
-                    KeyCode::Char('r') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
-                        app.redo();
+                    KeyCode::Enter if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
+                        return Ok(Exit::Accept);
                     }
-                    KeyCode::Char('y') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
-                        app.redo();
+                    KeyCode::Enter if key.modifiers.contains(event::KeyModifiers::ALT) => {
+                        app.split_input();
                     }
-                    KeyCode::Char('n') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
-                        app.select_next_component();
+                    KeyCode::Enter => app.confirm_input(),
+                    KeyCode::Esc => app.cancel_input(),
+                    KeyCode::Backspace => app.delete_backward(),
+                    KeyCode::Delete => app.delete_forward(),
+                    KeyCode::Left => app.move_input_cursor(false),
+                    KeyCode::Right => app.move_input_cursor(true),
+                    KeyCode::Home => app.set_input_cursor(0),
+                    KeyCode::End => app.input_cursor = None,
+                    KeyCode::Char('c') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
+                        return Ok(Exit::Interrupt);
                     }
-                    KeyCode::Char('p') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
-                        app.select_previous_component();
+                    KeyCode::Char('u') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
+                        app.clear_input();
                     }
-                    KeyCode::Char('a') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
-                        app.list_state.select(Some(0));
+                    // For multi-line values such as jq and awk programs
+                    KeyCode::Char('j') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
+                        app.insert_char('\n');
                     }
-                    KeyCode::Char('e') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
-                        let count = app.cmd.iter_components().count();
-                        if count > 0 {
-                            app.list_state.select(Some(count - 1));
+                    KeyCode::Tab => app.complete_path(true),
+                    KeyCode::BackTab => app.complete_path(false),
+                    KeyCode::Char(c) => app.insert_char(c),
+                    _ => {}
+                }
+            } else {
+                // While peeking, Enter keeps the value and Esc discards it.
+                // Any other key discards it and then does what it always does
+                if app.peek.is_some() {
+                    match key.code {
+                        KeyCode::Enter if !key.modifiers.contains(event::KeyModifiers::CONTROL) => {
+                            app.commit_peek();
+                            continue;
                         }
+                        KeyCode::Esc => {
+                            app.cancel_peek();
+                            continue;
+                        }
+                        _ if matches!(
+                            action,
+                            Some(Action::PeekNextOption | Action::PeekPreviousOption)
+                        ) => {}
+                        _ => app.cancel_peek(),
                     }
-                    KeyCode::Char('z') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
-                        app.undo();
-                    }
-                    KeyCode::Char('Z') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
-                        app.redo();
-                    }
-                    KeyCode::Char('d') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
-                        app.delete_selected_component();
-                    }
-                    KeyCode::Enter if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
-                        return Ok(true);
-                    }
-                    KeyCode::Char('c') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
-                        return Ok(false);
-                    }
-                    KeyCode::Char('x') if key.modifiers.contains(event::KeyModifiers::CONTROL) => {
-                        return Ok(true);
+                }
+                match action {
+                    Some(Action::Accept) => return Ok(Exit::Accept),
+                    Some(Action::AcceptAndRun) => return Ok(Exit::AcceptAndRun),
+                    Some(Action::Cancel) => return Ok(Exit::Cancel),
+                    Some(Action::Interrupt) => return Ok(Exit::Interrupt),
+                    Some(Action::SkipRemaining) => {
+                        if app.batch.is_some() {
+                            return Ok(Exit::SkipRemaining);
+                        }
                     }
-                    // Non-modifier shortcuts
-                    KeyCode::Char('u') => {
-                        app.undo();
+                    Some(Action::Undo) => app.undo(),
+                    Some(Action::Redo) => app.redo(),
+                    Some(Action::NextComponent) => app.select_next_component(),
+                    Some(Action::PreviousComponent) => app.select_previous_component(),
+                    Some(Action::FirstComponent) => app.list_state.select(Some(0)),
+                    Some(Action::LastComponent) => app.select_last_component(),
+                    Some(Action::NextOption) => app.next_option(),
+                    Some(Action::PreviousOption) => app.previous_option(),
+                    Some(Action::PeekNextOption) => app.peek_next_option(),
+                    Some(Action::PeekPreviousOption) => app.peek_previous_option(),
+                    Some(Action::OpenPicker) => {
+                        app.open_picker();
                     }
-                    KeyCode::Char('i') => {
+                    Some(Action::EditComponent) => app.start_input(),
+                    Some(Action::ChangeComponent) => app.change_selected_component(),
+                    Some(Action::ClearComponent) => app.clear_selected_component(),
+                    Some(Action::InsertComponent) => {
                         app.insert_new_component();
                         app.start_input();
                     }
-                    KeyCode::Char('a') => {
+                    Some(Action::AppendComponent) => {
                         app.append_new_component();
                         app.start_input();
                     }
-                    KeyCode::Char('d') | KeyCode::Delete | KeyCode::Backspace => {
-                        app.delete_selected_component()
-                    }
-                    KeyCode::Down | KeyCode::Char('j') => app.select_next_component(),
-                    KeyCode::Up | KeyCode::Char('k') => app.select_previous_component(),
-                    KeyCode::Home => {
-                        app.list_state.select(Some(0));
-                    }
-                    KeyCode::End => {
-                        let count = app.cmd.iter_components().count();
-                        if count > 0 {
-                            app.list_state.select(Some(count - 1));
+                    Some(Action::DeleteComponent) => app.delete_selected_component(),
+                    Some(Action::ToggleSudo) => app.toggle_sudo(),
+                    Some(Action::ToggleFlagForm) => app.toggle_flag_form(),
+                    Some(Action::JoinWithNext) => app.merge_with_next(true),
+                    Some(Action::MergeWithNext) => app.merge_with_next(false),
+                    Some(Action::InsertPipe) => app.insert_operator(command::PIPE),
+                    Some(Action::InsertRedirect) => app.insert_operator(command::REDIRECT),
+                    // Keys without an action jump to the row with that prefix
+                    None => {
+                        if let KeyCode::Char(c) = key.code
+                            && let Some(index) = get_index_for_prefix(c)
+                            && index < app.cmd.component_count()
+                        {
+                            app.list_state.select(Some(index));
                         }
                     }
-                    KeyCode::Char('G') => {
-                        let count = app.cmd.iter_components().count();
-                        if count > 0 {
-                            app.list_state.select(Some(count - 1));
-                        }
-                    }
-                    KeyCode::Enter => app.start_input(),
-                    KeyCode::Char('q') => return Ok(false),
-                    KeyCode::Esc => return Ok(false),
-                    KeyCode::Char(c) => {
-                        if let Some(index) = get_index_for_prefix(c) {
-                            let component_count = app.cmd.iter_components().count();
-                            if index < component_count {
-                                app.list_state.select(Some(index));
-                            }
-                        }
-                    }
-                    _ => {}
                 }
             }
         }
@@ -390,6 +910,78 @@ fn run_app<B: ratatui::backend::Backend>(
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_placement() {
+        let placement = |fullscreen: bool, top: bool| {
+            TuiOptions {
+                fullscreen,
+                top,
+                ..TuiOptions::default()
+            }
+            .placement()
+        };
+        assert_eq!(placement(false, false), Placement::Inline);
+        assert_eq!(placement(true, false), Placement::Fullscreen);
+        assert_eq!(placement(false, true), Placement::Top);
+        // The alternate screen wins, as it leaves the screen untouched
+        assert_eq!(placement(true, true), Placement::Top);
+    }
+
+    #[test]
+    fn test_draw_in_narrow_area() {
+        let new_app = || {
+            let cmd: Command = "kubectl get pods -n prod".try_into().unwrap();
+            let mut app = App::new(cmd, 0);
+            app.list_state.select(Some(4));
+            app
+        };
+        let mut editing = new_app();
+        editing.change_selected_component();
+        editing.current_input = "staging\nprod".to_string();
+        editing.line_numbers = true;
+        let mut picking = new_app();
+        picking.picker = Some(crate::app::Picker {
+            position: 4,
+            flag: "-n".to_string(),
+            append: false,
+            input: false,
+            candidates: vec!["staging".to_string(), "dev".to_string()],
+            tags: vec![None, Some("kubectl get ns".to_string())],
+            selected: 0,
+        });
+
+        for width in [0, 1, 2, PREFIX_WIDTH, PREFIX_WIDTH + 1] {
+            for app in [&editing, &picking] {
+                let mut terminal = Terminal::new(TestBackend::new(width, 10)).unwrap();
+                terminal.draw(|f| draw(f, app)).unwrap();
+                let buffer = terminal.backend().buffer();
+                if width == 1 {
+                    assert_eq!(buffer[(0, 0)].symbol(), "…");
+                }
+                if width > PREFIX_WIDTH {
+                    assert_eq!(buffer[(0, 0)].symbol(), " ");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_draw_without_selection_or_components() {
+        let cmd: Command = "ls -la".try_into().unwrap();
+        let mut app = App::new(cmd, 0);
+        app.list_state.select(None);
+        let mut terminal = Terminal::new(TestBackend::new(40, 10)).unwrap();
+        terminal.draw(|f| draw(f, &app)).unwrap();
+
+        app.list_state.select(Some(0));
+        app.delete_selected_component();
+        app.delete_selected_component();
+        assert!(app.cmd.is_empty());
+        terminal.draw(|f| draw(f, &app)).unwrap();
+        app.list_state.select(None);
+        terminal.draw(|f| draw(f, &app)).unwrap();
+    }
+
     #[test]
     fn test_wrap_text_empty_string() {
         assert_eq!(wrap_text("", 10), vec![""]);
@@ -474,6 +1066,24 @@ mod tests {
         assert_eq!(wrap_text("hello ", 10), vec!["hello "]);
     }
 
+    #[test]
+    fn test_wrap_text_combining_accent() {
+        // "e" + U+0301 COMBINING ACUTE ACCENT is one column wide
+        assert_eq!(char_width('\u{301}'), 0);
+        assert_eq!(text_width("cafe\u{301}"), 4);
+        assert_eq!(wrap_text("cafe\u{301}s", 4), vec!["cafe\u{301}", "s"]);
+    }
+
+    #[test]
+    fn test_wrap_text_control_characters() {
+        assert_eq!(wrap_text("a\tb\x1b", 10), vec!["a^Ib^["]);
+        assert_eq!(text_width("a\tb\x1b"), text_width("a^Ib^["));
+        // A caret pair is never split across lines
+        assert_eq!(wrap_text("ab\x7f", 3), vec!["ab", "^?"]);
+        // C1 controls aren't drawn
+        assert_eq!(char_width('\u{85}'), 0);
+    }
+
     #[test]
     fn test_wrap_text_leading_space() {
         assert_eq!(wrap_text(" hello", 10), vec![" hello"]);
@@ -1,12 +1,22 @@
+use anyhow::{Result, ensure};
+use serde::{Deserialize, Serialize};
+
+use crate::command::Component;
+
 /// Represents an action that can be undone or redone.
 ///
-/// Each variant stores the necessary information to reverse or replay the action.
+/// Each variant stores the necessary information to replay the action, and
+/// [`UndoAction::invert`] gives the action that reverses it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum UndoAction {
     /// A component was inserted at the given position.
     ///
     /// To undo: delete the component at `position`.
-    /// To redo: re-insert the component at `position`.
-    Insert { position: usize },
+    /// To redo: re-insert `component` at `position`.
+    Insert {
+        position: usize,
+        component: Component,
+    },
 
     /// A component's value was edited.
     ///
@@ -20,56 +30,611 @@ pub enum UndoAction {
 
     /// A component was deleted from the given position.
     ///
-    /// To undo: re-insert `deleted_value` at `position`.
+    /// To undo: re-insert `component` at `position`, operator or script as it was.
     /// To redo: delete the component at `position`.
     Delete {
         position: usize,
-        deleted_value: String,
+        component: Component,
     },
+
+    /// The component at `from` was moved so that it ends up at `to`.
+    ///
+    /// To undo: move the component at `to` back to `from`.
+    /// To redo: move the component at `from` to `to`.
+    #[allow(dead_code)]
+    Move { from: usize, to: usize },
+
+    /// The run of components starting at `position` was replaced with another.
+    ///
+    /// To undo: put `original` back in place of `updated`.
+    /// To redo: put `updated` in place of `original`.
+    Replace {
+        position: usize,
+        original: Vec<Component>,
+        updated: Vec<Component>,
+    },
+
+    /// Actions taken as one step, like cycling through history values.
+    ///
+    /// To undo: undo each member, last first.
+    /// To redo: redo each member in order.
+    Group(Vec<UndoAction>),
+}
+
+impl UndoAction {
+    /// The action that reverses this one: undoing an action is applying its
+    /// inverse
+    pub fn invert(&self) -> UndoAction {
+        match self.clone() {
+            UndoAction::Insert {
+                position,
+                component,
+            } => UndoAction::Delete {
+                position,
+                component,
+            },
+            UndoAction::Edit {
+                position,
+                original_value,
+                updated_value,
+            } => UndoAction::Edit {
+                position,
+                original_value: updated_value,
+                updated_value: original_value,
+            },
+            UndoAction::Delete {
+                position,
+                component,
+            } => UndoAction::Insert {
+                position,
+                component,
+            },
+            UndoAction::Move { from, to } => UndoAction::Move { from: to, to: from },
+            UndoAction::Replace {
+                position,
+                original,
+                updated,
+            } => UndoAction::Replace {
+                position,
+                original: updated,
+                updated: original,
+            },
+            UndoAction::Group(actions) => {
+                UndoAction::Group(actions.iter().rev().map(UndoAction::invert).collect())
+            }
+        }
+    }
+
+    /// Performs the action on `components`.
+    ///
+    /// Fails, leaving `components` as they were, if a recorded position
+    /// doesn't fit them, which means the action doesn't belong to this list.
+    pub fn apply(&self, components: &mut Vec<Component>) -> Result<()> {
+        let len = components.len();
+        let check = |position: usize, limit: usize| {
+            ensure!(
+                position < limit,
+                "undo position {} is out of range for {} components",
+                position,
+                len
+            );
+            Ok(())
+        };
+        match self {
+            UndoAction::Insert {
+                position,
+                component,
+            } => {
+                check(*position, len + 1)?;
+                components.insert(*position, component.clone());
+            }
+            UndoAction::Edit {
+                position,
+                updated_value,
+                ..
+            } => {
+                check(*position, len)?;
+                components[*position].set_value(updated_value);
+            }
+            UndoAction::Delete { position, .. } => {
+                check(*position, len)?;
+                components.remove(*position);
+            }
+            UndoAction::Move { from, to } => {
+                check(*from, len)?;
+                check(*to, len)?;
+                let component = components.remove(*from);
+                components.insert(*to, component);
+            }
+            UndoAction::Replace {
+                position,
+                original,
+                updated,
+            } => {
+                check(*position + original.len(), len + 1)?;
+                components.splice(
+                    *position..*position + original.len(),
+                    updated.iter().cloned(),
+                );
+            }
+            UndoAction::Group(actions) => {
+                for (applied, action) in actions.iter().enumerate() {
+                    if let Err(err) = action.apply(components) {
+                        // Take back the members that went through
+                        for action in actions[..applied].iter().rev() {
+                            action.revert(components)?;
+                        }
+                        return Err(err);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Takes the action back from `components`, the way [`UndoAction::apply`]
+    /// performs it
+    pub fn revert(&self, components: &mut Vec<Component>) -> Result<()> {
+        self.invert().apply(components)
+    }
+}
+
+/// An action on the undo stack, with the component that was selected before
+/// and after it so undoing and redoing can select it again
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UndoStep {
+    pub action: UndoAction,
+    /// Selected again when the step is undone
+    pub selected_before: Option<usize>,
+    /// Selected again when the step is redone
+    pub selected_after: Option<usize>,
+}
+
+impl From<UndoAction> for UndoStep {
+    /// A step that doesn't know the selection around it
+    fn from(action: UndoAction) -> Self {
+        Self {
+            action,
+            selected_before: None,
+            selected_after: None,
+        }
+    }
 }
 
+/// How many undo steps are kept unless configured otherwise
+pub const DEFAULT_UNDO_LIMIT: usize = 200;
+
 /// Manages undo and redo stacks for tracking reversible actions.
 ///
 /// Uses two stacks to implement standard undo/redo behavior:
 /// - `undo_stack`: actions that can be undone (most recent at the top)
 /// - `redo_stack`: actions that have been undone and can be redone
-#[derive(Default)]
 pub struct Undo {
-    undo_stack: Vec<UndoAction>,
-    redo_stack: Vec<UndoAction>,
+    undo_stack: Vec<UndoStep>,
+    redo_stack: Vec<UndoStep>,
+    /// Steps pushed since [`Undo::begin_group`], to become one step
+    group: Vec<UndoStep>,
+    /// How many groups are open; nested ones are flattened into the outermost
+    group_depth: usize,
+    /// How many steps the undo stack keeps; the oldest are dropped past it
+    limit: usize,
+}
+
+impl Default for Undo {
+    fn default() -> Self {
+        Self::with_limit(DEFAULT_UNDO_LIMIT)
+    }
 }
 
 impl Undo {
-    /// Pushes an action onto the undo stack.
+    /// An empty history that keeps at most `limit` undo steps
+    pub fn with_limit(limit: usize) -> Self {
+        Self {
+            undo_stack: Vec::new(),
+            redo_stack: Vec::new(),
+            group: Vec::new(),
+            group_depth: 0,
+            limit,
+        }
+    }
+
+    /// Whether there is a step to undo
+    pub fn can_undo(&self) -> bool {
+        !self.undo_stack.is_empty()
+    }
+
+    /// Whether there is an undone step to redo
+    pub fn can_redo(&self) -> bool {
+        !self.redo_stack.is_empty()
+    }
+
+    /// How many steps can be undone
+    pub fn undo_len(&self) -> usize {
+        self.undo_stack.len()
+    }
+
+    /// How many undone steps can be redone
+    pub fn redo_len(&self) -> usize {
+        self.redo_stack.len()
+    }
+
+    /// The steps that can be undone, oldest first
+    pub fn undo_steps(&self) -> &[UndoStep] {
+        &self.undo_stack
+    }
+
+    /// The undone steps that can be redone, the one to redo next last
+    pub fn redo_steps(&self) -> &[UndoStep] {
+        &self.redo_stack
+    }
+
+    /// Replaces both stacks, as when picking up a saved session. The oldest
+    /// undo steps past the limit are dropped.
+    pub fn restore(&mut self, undo: Vec<UndoStep>, redo: Vec<UndoStep>) {
+        self.clear();
+        for step in undo {
+            self.push_step(step);
+        }
+        self.redo_stack = redo;
+    }
+
+    /// Forgets everything that could be undone or redone
+    pub fn clear(&mut self) {
+        self.clear_undo();
+        self.clear_redo();
+    }
+
+    /// Forgets what could be undone, keeping what could be redone
+    pub fn clear_undo(&mut self) {
+        self.undo_stack.clear();
+    }
+
+    /// Forgets what could be redone, keeping what could be undone
+    pub fn clear_redo(&mut self) {
+        self.redo_stack.clear();
+    }
+
+    /// Pushes a step onto the undo stack, dropping the oldest ones past the limit
+    fn push_step(&mut self, step: UndoStep) {
+        self.undo_stack.push(step);
+        let excess = self.undo_stack.len().saturating_sub(self.limit);
+        self.undo_stack.drain(..excess);
+    }
+
+    /// Pushes an action, or a step with the selection around it, onto the
+    /// undo stack.
     ///
     /// If `clear_redo` is `true`, the redo stack is cleared. This should be set
     /// to `true` for new user actions (to invalidate the redo history), and `false`
     /// when pushing as part of a redo operation.
-    pub fn push(&mut self, action: UndoAction, clear_redo: bool) {
-        self.undo_stack.push(action);
+    ///
+    /// While a group is open, the action is added to the group instead.
+    pub fn push(&mut self, step: impl Into<UndoStep>, clear_redo: bool) {
+        let step = step.into();
+        if self.group_depth > 0 {
+            self.group.push(step);
+        } else {
+            self.push_step(step);
+        }
         if clear_redo {
             self.redo_stack.clear();
         }
     }
 
-    /// Pops and returns the most recent action from the undo stack.
+    /// Starts collecting pushed actions into a single undo step. Groups
+    /// opened inside it are flattened into it.
+    pub fn begin_group(&mut self) {
+        self.group_depth += 1;
+    }
+
+    /// Ends the group opened with [`Undo::begin_group`], pushing what it
+    /// collected as one step once the outermost group ends. The step selects
+    /// what was selected before its first member and after its last.
+    pub fn end_group(&mut self) {
+        debug_assert!(self.group_depth > 0, "end_group without begin_group");
+        self.group_depth = self.group_depth.saturating_sub(1);
+        if self.group_depth > 0 {
+            return;
+        }
+        let mut steps = std::mem::take(&mut self.group);
+        match steps.len() {
+            0 => {}
+            1 => self.push_step(steps.remove(0)),
+            _ => {
+                let selected_before = steps[0].selected_before;
+                let selected_after = steps[steps.len() - 1].selected_after;
+                self.push_step(UndoStep {
+                    action: UndoAction::Group(steps.into_iter().map(|s| s.action).collect()),
+                    selected_before,
+                    selected_after,
+                });
+            }
+        }
+    }
+
+    /// Pops and returns the most recent step from the undo stack.
     ///
     /// Returns `None` if the undo stack is empty.
-    pub fn pop(&mut self) -> Option<UndoAction> {
+    pub fn pop(&mut self) -> Option<UndoStep> {
         self.undo_stack.pop()
     }
 
-    /// Pushes an action onto the redo stack.
+    /// Pushes a step onto the redo stack.
     ///
-    /// This is typically called after an undo operation to allow redoing the action.
-    pub fn push_redo(&mut self, action: UndoAction) {
-        self.redo_stack.push(action);
+    /// This is typically called after an undo operation to allow redoing the step.
+    pub fn push_redo(&mut self, step: impl Into<UndoStep>) {
+        self.redo_stack.push(step.into());
     }
 
-    /// Pops and returns the most recent action from the redo stack.
+    /// Pops and returns the most recent step from the redo stack.
     ///
     /// Returns `None` if the redo stack is empty.
-    pub fn pop_redo(&mut self) -> Option<UndoAction> {
+    pub fn pop_redo(&mut self) -> Option<UndoStep> {
         self.redo_stack.pop()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_invert_twice_is_identity() {
+        let actions = [
+            UndoAction::Insert {
+                position: 1,
+                component: Component::new("pods".to_string()),
+            },
+            UndoAction::Edit {
+                position: 2,
+                original_value: "prod".to_string(),
+                updated_value: "staging".to_string(),
+            },
+            UndoAction::Delete {
+                position: 3,
+                component: Component::operator("|"),
+            },
+            UndoAction::Move { from: 1, to: 4 },
+            UndoAction::Group(vec![
+                UndoAction::Move { from: 0, to: 1 },
+                UndoAction::Delete {
+                    position: 2,
+                    component: Component::new("x".to_string()),
+                },
+            ]),
+            UndoAction::Replace {
+                position: 0,
+                original: vec![Component::new("-n".to_string())],
+                updated: vec![
+                    Component::new("-n".to_string()),
+                    Component::new("prod".to_string()),
+                ],
+            },
+        ];
+        for action in actions {
+            assert_ne!(action.invert(), action);
+            assert_eq!(action.invert().invert(), action);
+        }
+    }
+
+    #[test]
+    fn test_invert() {
+        let component = Component::operator(">");
+        assert_eq!(
+            UndoAction::Delete {
+                position: 2,
+                component: component.clone(),
+            }
+            .invert(),
+            UndoAction::Insert {
+                position: 2,
+                component,
+            }
+        );
+        assert_eq!(
+            UndoAction::Move { from: 0, to: 3 }.invert(),
+            UndoAction::Move { from: 3, to: 0 }
+        );
+    }
+
+    #[test]
+    fn test_invert_group_reverses_members() {
+        let group = UndoAction::Group(vec![
+            UndoAction::Move { from: 0, to: 1 },
+            UndoAction::Move { from: 2, to: 3 },
+        ]);
+        assert_eq!(
+            group.invert(),
+            UndoAction::Group(vec![
+                UndoAction::Move { from: 3, to: 2 },
+                UndoAction::Move { from: 1, to: 0 },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_group() {
+        let step = |n| UndoAction::Move { from: n, to: n + 1 };
+        let mut undo = Undo::default();
+        undo.push(step(0), true);
+        undo.begin_group();
+        undo.push(step(1), true);
+        // Nested groups are flattened into the outer one
+        undo.begin_group();
+        undo.push(step(2), true);
+        undo.end_group();
+        undo.push(step(3), true);
+        undo.end_group();
+
+        assert_eq!(
+            undo.pop().map(|s| s.action),
+            Some(UndoAction::Group(vec![step(1), step(2), step(3)]))
+        );
+        assert_eq!(undo.pop(), Some(step(0).into()));
+
+        // A group of one is just that action, and an empty one leaves nothing
+        undo.begin_group();
+        undo.push(step(4), true);
+        undo.end_group();
+        undo.begin_group();
+        undo.end_group();
+        assert_eq!(undo.pop(), Some(step(4).into()));
+        assert_eq!(undo.pop(), None);
+    }
+
+    #[test]
+    fn test_limit_drops_oldest_steps() {
+        let step = |n| UndoAction::Move { from: n, to: n + 1 };
+        let (limit, extra) = (5, 3);
+        let mut undo = Undo::with_limit(limit);
+        for n in 0..limit + extra {
+            undo.push(step(n), true);
+        }
+        assert_eq!(undo.undo_len(), limit);
+
+        // The newest steps are still undone and redone in order
+        let popped = undo.pop().unwrap();
+        assert_eq!(popped.action, step(limit + extra - 1));
+        undo.push_redo(popped);
+        let redone = undo.pop_redo().unwrap();
+        undo.push(redone, false);
+        for n in (extra..limit + extra).rev() {
+            assert_eq!(undo.pop(), Some(step(n).into()));
+        }
+        assert_eq!(undo.pop(), None);
+
+        undo.push(step(0), true);
+        undo.clear();
+        assert!(!undo.can_undo());
+        assert_eq!(Undo::default().limit, DEFAULT_UNDO_LIMIT);
+    }
+
+    #[test]
+    fn test_new_action_clears_redo() {
+        let step = |n| UndoAction::Move { from: n, to: n + 1 };
+        let mut undo = Undo::default();
+        assert!(!undo.can_undo() && !undo.can_redo());
+
+        undo.push(step(0), true);
+        undo.push(step(1), true);
+        let undone = undo.pop().unwrap();
+        undo.push_redo(undone);
+        assert_eq!((undo.undo_len(), undo.redo_len()), (1, 1));
+        assert!(undo.can_undo() && undo.can_redo());
+
+        // Redoing keeps the rest of the redo stack, a new action drops it
+        let redone = undo.pop_redo().unwrap();
+        undo.push(redone, false);
+        let undone = undo.pop().unwrap();
+        undo.push_redo(undone);
+        undo.push(step(2), true);
+        assert_eq!((undo.undo_len(), undo.redo_len()), (2, 0));
+        assert!(!undo.can_redo());
+
+        // Also when the new action goes into a group
+        let undone = undo.pop().unwrap();
+        undo.push_redo(undone);
+        undo.begin_group();
+        undo.push(step(3), true);
+        assert!(!undo.can_redo());
+        undo.end_group();
+        assert_eq!(undo.undo_len(), 2);
+    }
+
+    fn components(values: &[&str]) -> Vec<Component> {
+        values
+            .iter()
+            .map(|v| Component::new(v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_apply_and_revert_replay_deterministically() {
+        let original = components(&["kubectl", "get", "pods", "-n", "prod"]);
+        let actions = [
+            UndoAction::Edit {
+                position: 4,
+                original_value: "prod".to_string(),
+                updated_value: "staging".to_string(),
+            },
+            UndoAction::Move { from: 2, to: 4 },
+            UndoAction::Delete {
+                position: 0,
+                component: Component::new("kubectl".to_string()),
+            },
+            UndoAction::Group(vec![
+                UndoAction::Insert {
+                    position: 3,
+                    component: Component::operator("|"),
+                },
+                UndoAction::Replace {
+                    position: 0,
+                    original: components(&["get", "-n"]),
+                    updated: components(&["describe"]),
+                },
+            ]),
+        ];
+
+        let mut list = original.clone();
+        for action in &actions {
+            action.apply(&mut list).unwrap();
+        }
+        assert_eq!(
+            list,
+            vec![
+                Component::new("describe".to_string()),
+                Component::new("staging".to_string()),
+                Component::operator("|"),
+                Component::new("pods".to_string()),
+            ]
+        );
+        let applied = list.clone();
+
+        for action in actions.iter().rev() {
+            action.revert(&mut list).unwrap();
+        }
+        assert_eq!(list, original);
+
+        // Replaying gives the same list again
+        for action in &actions {
+            action.apply(&mut list).unwrap();
+        }
+        assert_eq!(list, applied);
+    }
+
+    #[test]
+    fn test_apply_out_of_range_fails_without_changes() {
+        let original = components(&["ls", "-la"]);
+        for action in [
+            UndoAction::Insert {
+                position: 3,
+                component: Component::new("x".to_string()),
+            },
+            UndoAction::Delete {
+                position: 2,
+                component: Component::new("x".to_string()),
+            },
+            UndoAction::Move { from: 0, to: 2 },
+            UndoAction::Replace {
+                position: 1,
+                original: components(&["-la", "x"]),
+                updated: Vec::new(),
+            },
+            // The first member goes through and is taken back
+            UndoAction::Group(vec![
+                UndoAction::Delete {
+                    position: 0,
+                    component: Component::new("ls".to_string()),
+                },
+                UndoAction::Delete {
+                    position: 1,
+                    component: Component::new("x".to_string()),
+                },
+            ]),
+        ] {
+            let mut list = original.clone();
+            assert!(action.apply(&mut list).is_err(), "{:?}", action);
+            assert_eq!(list, original, "{:?}", action);
+        }
+    }
+}
@@ -0,0 +1,1334 @@
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+fn te(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_te"))
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .expect("failed to run te")
+}
+
+fn te_with_stdin(args: &[&str], input: &str) -> Output {
+    te_with_stdin_in(Command::new(env!("CARGO_BIN_EXE_te")), args, input)
+}
+
+fn te_with_stdin_in(mut command: Command, args: &[&str], input: &str) -> Output {
+    let mut child = command
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to run te");
+    // te may exit without reading, as when it rejects piped input
+    let written = child.stdin.take().unwrap().write_all(input.as_bytes());
+    if let Err(err) = written {
+        assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+    }
+    child.wait_with_output().expect("failed to wait for te")
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+#[test]
+fn test_accept_exits_zero() {
+    let output = te(&["--keys", "ctrl-x", "kubectl", "get", "pods"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "kubectl get pods\n");
+}
+
+#[test]
+fn test_accept_after_editing() {
+    let output = te(&["--keys", "j j d ctrl-x", "kubectl", "get", "pods", "-A"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "kubectl get -A\n");
+}
+
+#[test]
+fn test_split_value_at_cursor() {
+    // Edit "a b c", move before " c" and split, then again before " b"
+    let keys = "j enter left left alt-enter k enter left left alt-enter ctrl-x";
+    let output = te(&["--keys", keys, "echo", "a b c"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "echo a b c\n");
+}
+
+#[test]
+fn test_cancel_exits_130() {
+    let output = te(&["--keys", "esc", "kubectl", "get", "pods"]);
+    assert_eq!(output.status.code(), Some(130));
+    assert_eq!(stdout(&output), "");
+}
+
+#[test]
+fn test_parse_error_exits_2() {
+    let output = te(&["--keys", "ctrl-x", "echo 'unbalanced"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(stdout(&output), "");
+}
+
+#[test]
+fn test_other_errors_exit_1() {
+    // Key script ends without accepting or cancelling
+    let output = te(&["--keys", "j", "kubectl", "get", "pods"]);
+    assert_eq!(output.status.code(), Some(1));
+
+    // Invalid file descriptor
+    let output = te(&["--fd", "9999"]);
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn test_quotes_for_target_shell() {
+    let quoted = |shell: &str| {
+        let output = te(&["--shell", shell, "--keys", "ctrl-x", "echo", "it's $HOME"]);
+        assert_eq!(output.status.code(), Some(0));
+        stdout(&output)
+    };
+    assert_eq!(quoted("bash"), "echo 'it'\\''s $HOME'\n");
+    assert_eq!(quoted("fish"), "echo 'it\\'s $HOME'\n");
+    assert_eq!(quoted("pwsh"), "echo 'it''s $HOME'\n");
+}
+
+#[test]
+fn test_argv_keeps_argument_boundaries() {
+    let output = te(&["--keys", "ctrl-x", "echo", "hello world", "", "*.txt"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "echo 'hello world' '' '*.txt'\n");
+}
+
+#[test]
+fn test_argv_components_are_separate() {
+    // Deleting the second component removes the whole "hello world" argument
+    let output = te(&["--keys", "j d ctrl-x", "echo", "hello world", "!"]);
+    assert_eq!(stdout(&output), "echo '!'\n");
+}
+
+#[test]
+fn test_argv_after_double_dash() {
+    // As a shell passes `te -- echo "a b"`
+    let output = te(&["--keys", "ctrl-x", "--", "echo", "a b"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "echo 'a b'\n");
+
+    // Even a single argument stays one component instead of being parsed
+    let output = te(&["--keys", "j ctrl-x", "--", "ls -la"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "'ls -la'\n");
+
+    // Only the first `--` ends te's own options
+    let output = te(&["--keys", "ctrl-x", "--", "--", "x"]);
+    assert_eq!(stdout(&output), "-- x\n");
+}
+
+#[test]
+fn test_single_argument_is_parsed_as_command_line() {
+    let output = te(&["--keys", "ctrl-x", "echo \"hello world\" $HOME"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "echo \"hello world\" $HOME\n");
+}
+
+#[test]
+fn test_quote_style_backslash() {
+    let output = te(&["--keys", "ctrl-x", r"cp my\ file 'a b'"]);
+    assert_eq!(stdout(&output), "cp \"my file\" \"a b\"\n");
+
+    let output = te(&[
+        "--quote-style",
+        "backslash",
+        "--keys",
+        "ctrl-x",
+        r"cp my\ file 'a b'",
+    ]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "cp my\\ file a\\ b\n");
+}
+
+#[test]
+fn test_stdin_crlf_line_endings() {
+    let output = te_with_stdin(
+        &["--keys", "ctrl-x"],
+        "kubectl get pods \\\r\n  -n prod\r\n",
+    );
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "kubectl get pods -n prod\n");
+}
+
+#[test]
+fn test_stdin_trailing_blank_line() {
+    let output = te_with_stdin(&["--keys", "ctrl-x"], "ls -la\n\n");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "ls -la\n");
+}
+
+#[test]
+fn test_stdin_quoted_newline_is_one_command() {
+    let output = te_with_stdin(&["--keys", "ctrl-x"], "echo \"a\nb\"\n");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "echo \"a\nb\"\n");
+}
+
+#[test]
+fn test_stdin_multiple_commands_are_edited_in_turn() {
+    let input = "ls  -la\nkubectl get pods -n prod\npwd\n";
+
+    // Edit the first, skip the second with esc, accept the third
+    let output = te_with_stdin(&["--keys", "d ctrl-x esc ctrl-x"], input);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "-la\nkubectl get pods -n prod\npwd\n");
+
+    // S leaves the rest as given
+    let output = te_with_stdin(&["--keys", "ctrl-x S"], input);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "ls -la\nkubectl get pods -n prod\npwd\n");
+
+    // Esc twice in a row cancels the whole batch
+    let output = te_with_stdin(&["--keys", "esc esc"], input);
+    assert_eq!(output.status.code(), Some(130));
+    assert_eq!(stdout(&output), "");
+
+    // Esc ends editing a single command as before
+    let output = te_with_stdin(&["--keys", "esc"], "ls\n");
+    assert_eq!(output.status.code(), Some(130));
+}
+
+#[test]
+fn test_stdin_comment_lines() {
+    // A comment-only line belongs to the command after it
+    let output = te_with_stdin(&["--keys", "ctrl-x"], "# Deploy\nkubectl get pods\n");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "kubectl get pods\n");
+
+    let output = te_with_stdin(
+        &["--keys", "ctrl-x", "--keep-comments"],
+        "# Deploy\nkubectl get pods\n",
+    );
+    assert_eq!(stdout(&output), "# Deploy\nkubectl get pods\n");
+}
+
+#[test]
+fn test_stdin_parse_error_in_batch() {
+    let output = te_with_stdin(&["--keys", "ctrl-x"], "ls\necho 'oops\n");
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(stdout(&output), "");
+}
+
+#[cfg(unix)]
+#[test]
+fn test_stdin_without_tty_fails() {
+    use std::os::unix::process::CommandExt;
+
+    // Start a new session so te has no controlling terminal, even when the
+    // tests themselves run in one
+    let mut command = Command::new(env!("CARGO_BIN_EXE_te"));
+    unsafe {
+        command.pre_exec(|| {
+            libc::setsid();
+            Ok(())
+        });
+    }
+    let output = te_with_stdin_in(command, &[], "kubectl get pods\n");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stdout(&output), "");
+}
+
+#[test]
+fn test_command_string() {
+    let output = te(&["-c", "kubectl get pods -- -o json", "--keys", "ctrl-x"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "kubectl get pods -- -o json\n");
+}
+
+#[test]
+fn test_command_string_rejects_piped_stdin() {
+    let output = te_with_stdin(&["-c", "ls -la", "--keys", "ctrl-x"], "pwd\n");
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(stdout(&output), "");
+}
+
+#[test]
+fn test_command_string_conflicts() {
+    for args in [
+        &["-c", "ls", "pwd"][..],
+        &["-c", "ls", "--fd", "3"][..],
+        &["-c", "ls", "--last"][..],
+    ] {
+        let output = te(args);
+        assert_eq!(output.status.code(), Some(2), "{:?}", args);
+        assert!(
+            String::from_utf8_lossy(&output.stderr).contains("cannot be used with"),
+            "{:?}",
+            args
+        );
+    }
+}
+
+/// Write a bash history file for a test and return its path
+fn histfile(name: &str, lines: &[&str]) -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(format!("te-{}-{}", std::process::id(), name));
+    std::fs::write(&path, lines.join("\n") + "\n").unwrap();
+    path
+}
+
+#[test]
+fn test_history_subcommand() {
+    let path = histfile(
+        "history",
+        &[
+            "kubectl get pods -n prod",
+            "kubectl get pods -n staging --token=abc",
+            "kubectl get pods -n prod -o wide",
+        ],
+    );
+    let path = path.to_str().unwrap();
+
+    let output = te(&[
+        "history",
+        "--histfile",
+        path,
+        "--",
+        "kubectl",
+        "get",
+        "pods",
+    ]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        stdout(&output),
+        "-n\n  prod (2)\n  staging (1)\n-o\n  wide (1)\n"
+    );
+
+    let output = te(&[
+        "history",
+        "--histfile",
+        path,
+        "--flag",
+        "-o",
+        "--json",
+        "--",
+        "kubectl",
+        "get",
+        "pods",
+    ]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        stdout(&output),
+        "{\"-o\":[{\"count\":1,\"value\":\"wide\"}]}\n"
+    );
+
+    let output = te(&["history", "--no-history", "--", "kubectl", "get", "pods"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "");
+}
+
+#[test]
+fn test_no_wrap_nav() {
+    // Moving up from the first component and editing it
+    let keys = "k enter ctrl-u t a b enter ctrl-x";
+    let output = te(&["--keys", keys, "ls", "-la"]);
+    assert_eq!(stdout(&output), "ls tab\n");
+
+    let output = te(&["--no-wrap-nav", "--keys", keys, "ls", "-la"]);
+    assert_eq!(stdout(&output), "tab -la\n");
+}
+
+#[test]
+fn test_reverse() {
+    // Opens on the last component
+    let output = te(&["--reverse", "--keys", "d ctrl-x", "ls", "-la", "/tmp"]);
+    assert_eq!(stdout(&output), "ls -la\n");
+
+    // j moves towards the first
+    let output = te(&["--reverse", "--keys", "j d ctrl-x", "ls", "-la", "/tmp"]);
+    assert_eq!(stdout(&output), "ls /tmp\n");
+}
+
+fn te_with_env(args: &[&str], env: &[(&str, &str)]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_te"))
+        .args(args)
+        .envs(env.iter().copied())
+        .stdin(Stdio::null())
+        .output()
+        .expect("failed to run te")
+}
+
+#[test]
+fn test_save_and_edit_template() {
+    let dir = std::env::temp_dir().join(format!("te-{}-xdg", std::process::id()));
+    let env = [("XDG_CONFIG_HOME", dir.to_str().unwrap())];
+
+    let output = te_with_env(
+        &[
+            "save",
+            "logs",
+            "--",
+            "kubectl",
+            "logs",
+            "-n",
+            "<ns>",
+            "deploy/web",
+        ],
+        &env,
+    );
+    assert_eq!(output.status.code(), Some(0));
+    assert!(dir.join("te/templates/logs").exists());
+
+    // Fill in the placeholder
+    let output = te_with_env(
+        &[
+            "--template",
+            "logs",
+            "--keys",
+            "j j j c p r o d enter ctrl-x",
+        ],
+        &env,
+    );
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "kubectl logs -n prod deploy/web\n");
+
+    // Saving over it needs --force
+    let output = te_with_env(&["save", "logs", "--", "ls"], &env);
+    assert_eq!(output.status.code(), Some(1));
+
+    let output = te_with_env(&["--template", "nope", "--keys", "ctrl-x"], &env);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("No template named 'nope'"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_toggle_flag_form() {
+    let output = te(&[
+        "--keys",
+        "4 e ctrl-x",
+        "kubectl",
+        "get",
+        "pods",
+        "-n",
+        "prod",
+    ]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "kubectl get pods --namespace prod\n");
+}
+
+#[test]
+fn test_null_output() {
+    let output = te(&[
+        "--null",
+        "--keys",
+        "ctrl-x",
+        "-c",
+        "grep -e 'a b' \"it's\" file.txt",
+    ]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(output.stdout, b"grep\0-e\0a b\0it's\0file.txt\0");
+
+    // Other ways of writing out the result conflict with it
+    for args in [
+        &["--null", "--output", "json", "--keys", "ctrl-x", "ls"][..],
+        &["--null", "--copy-only", "--keys", "ctrl-x", "ls"],
+        &["--null", "--in-place", "-f", "script.sh"],
+    ] {
+        assert_eq!(te(args).status.code(), Some(2), "{:?}", args);
+    }
+
+    // xargs can't run a pipeline, but a quoted `|` is only a word
+    let output = te(&["--null", "--keys", "ctrl-x", "-c", "ls | grep x"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(stdout(&output), "");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--null can't print"));
+    let output = te(&["--null", "--keys", "ctrl-x", "-c", "grep '|' file"]);
+    assert_eq!(output.stdout, b"grep\0|\0file\0");
+}
+
+#[test]
+fn test_top_conflicts_with_fullscreen() {
+    let output = te(&["--top", "--fullscreen", "--keys", "ctrl-x", "ls"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(stdout(&output), "");
+}
+
+#[test]
+fn test_annotate() {
+    let output = te(&["--annotate", "--keys", "ctrl-x", "ls", "-la"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "# edited with te\nls -la\n");
+
+    // A command skipped in a batch is left as it was, without a comment
+    let output = te_with_stdin(&["--annotate", "--keys", "S"], "ls\npwd\n");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "ls\npwd\n");
+}
+
+#[test]
+fn test_config_file() {
+    let path = std::env::temp_dir().join(format!("te-{}-config.toml", std::process::id()));
+    std::fs::write(
+        &path,
+        "[keys]\naccept = \"alt-enter\"\n\n[navigation]\nwrap = false\nspeed = 3\n",
+    )
+    .unwrap();
+    let path = path.to_str().unwrap();
+
+    // The configured key accepts; wrapping is off, so `k` stays on `ls`
+    let output = te(&["--config", path, "--keys", "k d alt-enter", "ls", "-la"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "-la\n");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("unknown config key 'navigation.speed'"),
+        "{}",
+        stderr
+    );
+
+    // The environment takes precedence over the config file
+    let output = te_with_env(
+        &["--config", path, "--keys", "ctrl-y", "ls"],
+        &[("TE_ACCEPT_KEY", "ctrl-y")],
+    );
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "ls\n");
+}
+
+#[test]
+fn test_config_errors() {
+    let output = te(&["--config", "/nonexistent/te.toml", "--keys", "ctrl-x", "ls"]);
+    assert_eq!(output.status.code(), Some(1));
+
+    let path = std::env::temp_dir().join(format!("te-{}-bad-config.toml", std::process::id()));
+    std::fs::write(&path, "[history]\nlimit = \"lots\"\n").unwrap();
+    let output = te(&["--config", path.to_str().unwrap(), "--keys", "ctrl-x", "ls"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("line 2"));
+
+    std::fs::write(&path, "[keys]\nundo = \"U\"\njump = \"J\"\n").unwrap();
+    let output = te(&["--config", path.to_str().unwrap(), "--keys", "ctrl-x", "ls"]);
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("line 3"), "{}", stderr);
+    assert!(stderr.contains("unknown key action 'jump'"), "{}", stderr);
+}
+
+#[test]
+fn test_config_keymap() {
+    let path = std::env::temp_dir().join(format!("te-{}-keymap.toml", std::process::id()));
+    std::fs::write(
+        &path,
+        "[keys]\ndelete_component = [\"x\", \"d\"]\nnext_component = \"n\"\nundo = \"n\"\n",
+    )
+    .unwrap();
+
+    // `n` goes to next_component, the later of the two actions in the
+    // table, and `x` deletes
+    let output = te(&[
+        "--config",
+        path.to_str().unwrap(),
+        "--keys",
+        "n x ctrl-x",
+        "ls",
+        "-la",
+        "/tmp",
+    ]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "ls /tmp\n");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("key 'n' is bound to both undo and next_component"),
+        "{}",
+        stderr
+    );
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_completion_specs() {
+    let dir = std::env::temp_dir().join(format!("te-{}-specs", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("mytool.json"),
+        r#"{"name": "mytool", "options": [{"name": ["-v", "--verbose"]}, {"name": "-o", "args": {}}],
+            "subcommands": [
+                {"name": "log", "options": [{"name": "-n", "args": {}}]},
+                {"name": "clean", "options": [{"name": "-n"}]}
+            ]}"#,
+    )
+    .unwrap();
+    let config = dir.join("config.toml");
+    std::fs::write(
+        &config,
+        format!("[specs]\ndir = {:?}\n", dir.to_str().unwrap()),
+    )
+    .unwrap();
+
+    // The spec says -v takes no value, so the argument after it stays
+    let rm = |flag: &str, config: Option<&std::path::Path>| {
+        let mut args = vec!["rm", "-f", flag, "--", "mytool", "-v", "file", "-o", "out"];
+        if let Some(config) = config {
+            args.splice(0..0, ["--config", config.to_str().unwrap()]);
+        }
+        stdout(&te(&args))
+    };
+    assert_eq!(rm("-v", Some(&config)), "mytool file -o out\n");
+    assert_eq!(rm("-o", Some(&config)), "mytool -v file\n");
+    assert_eq!(rm("-v", None), "mytool -o out\n");
+
+    // Each subcommand has its own idea of a flag
+    let rm = |command: &[&str]| {
+        let config = config.to_str().unwrap();
+        stdout(&te(&[
+            &["--config", config, "rm", "-f", "-n", "--"],
+            command,
+        ]
+        .concat()))
+    };
+    assert_eq!(
+        rm(&["mytool", "log", "-n", "5", "file"]),
+        "mytool log file\n"
+    );
+    assert_eq!(
+        rm(&["mytool", "clean", "-n", "file"]),
+        "mytool clean file\n"
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_rm_keeps_the_rest_as_written() {
+    let command = "kubectl get pods \\\n  -n prod \\\n  -l 'app=web x' -o json";
+    let output = te(&["rm", "-f", "-n", "-f", "--context", "--", command]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        stdout(&output),
+        "kubectl get pods \\\n  -l 'app=web x' -o json\n"
+    );
+    // Even a jq script is left as it was written
+    let output = te(&["rm", "-f", "-r", "--", r#"jq -r ".[] | $v" file.json"#]);
+    assert_eq!(stdout(&output), "jq \".[] | $v\" file.json\n");
+}
+
+#[test]
+fn test_set_quotes_scripts_literally() {
+    let output = te(&[
+        "set",
+        "--flag",
+        "-n=1",
+        "--",
+        r#"head -n 5 | awk "{print $1}""#,
+    ]);
+    assert_eq!(stdout(&output), "head -n 1 | awk '{print $1}'\n");
+}
+
+#[test]
+fn test_result_fd() {
+    let path = std::env::temp_dir().join(format!("te-{}-result-fd", std::process::id()));
+    let script = format!(
+        "{} --result-fd 3 --keys ctrl-x ls -la 3>{}",
+        env!("CARGO_BIN_EXE_te"),
+        path.display()
+    );
+    let output = std::process::Command::new("sh")
+        .args(["-c", &script])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "");
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "ls -la\n");
+
+    let output = te(&["--result-fd", "9", "--keys", "ctrl-x", "ls"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stdout(&output), "");
+}
+
+#[test]
+fn test_wrap_output() {
+    let output = te(&[
+        "--wrap-output",
+        "sudo -u 'deploy user'",
+        "--keys",
+        "ctrl-x",
+        "systemctl",
+        "restart",
+        "nginx",
+    ]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        stdout(&output),
+        "sudo -u 'deploy user' systemctl restart nginx\n"
+    );
+
+    let output = te(&["--wrap-output", "time 'oops", "--keys", "ctrl-x", "ls"]);
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn test_strict_parse() {
+    // Lenient parsing guesses that `prod` is the value of -n
+    let output = te(&["--keys", "ctrl-x", "kubectl", "get", "pods", "-n", "prod"]);
+    assert_eq!(output.status.code(), Some(0));
+
+    let output = te(&[
+        "--strict-parse",
+        "--keys",
+        "ctrl-x",
+        "kubectl",
+        "get",
+        "pods",
+        "-n",
+        "prod",
+    ]);
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("'prod' could be the value of -n"));
+    assert!(stderr.contains("kubectl get pods -n prod\n                    ^^^^\n"));
+
+    assert!(stderr.contains("[flags.<program>]"));
+
+    // Flags with a known number of values aren't ambiguous
+    let output = te(&["--strict-parse", "--keys", "ctrl-x", "cp", "-r", "a", "b"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "cp -r a b\n");
+
+    // Config can't help with an operator, so there is no hint about it
+    let output = te(&["--strict-parse", "--keys", "ctrl-x", "-c", "echo done; ls"]);
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("'done;' is a shell operator"));
+    assert!(!stderr.contains("[flags.<program>]"));
+
+    // Quoted or escaped, it's only text
+    let output = te(&[
+        "--strict-parse",
+        "--keys",
+        "ctrl-x",
+        "-c",
+        "find . -exec rm {} \\;",
+    ]);
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn test_accept_and_run() {
+    // Ctrl+O accepts like Ctrl+X, with an exit code asking the shell to run it
+    let output = te(&["--keys", "ctrl-o", "ls", "-la"]);
+    assert_eq!(output.status.code(), Some(3));
+    assert_eq!(stdout(&output), "ls -la\n");
+}
+
+#[test]
+fn test_profile() {
+    let output = te(&["--profile", "--keys", "ctrl-x", "ls", "-la"]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "ls -la\n");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    for phase in ["parse", "first draw", "until first draw"] {
+        let line = stderr
+            .lines()
+            .find(|line| line.starts_with(&format!("te: profile: {} ", phase)))
+            .unwrap_or_else(|| panic!("no {} timing in {}", phase, stderr));
+        assert!(line.ends_with("ms"), "{}", line);
+    }
+
+    // Without --profile nothing is reported
+    let output = te(&["--keys", "ctrl-x", "ls", "-la"]);
+    assert!(!String::from_utf8_lossy(&output.stderr).contains("profile"));
+}
+
+#[test]
+fn test_region() {
+    // Only the part before the pipe is edited
+    let output = te(&[
+        "--region-start",
+        "0",
+        "--region-end",
+        "17",
+        "--keys",
+        "G a x enter ctrl-x",
+        "-c",
+        "kubectl get pods | tee out.json",
+    ]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "kubectl get pods x | tee out.json\n");
+
+    let output = te(&[
+        "--region-start",
+        "5",
+        "--region-end",
+        "99",
+        "--keys",
+        "ctrl-x",
+        "ls -la",
+    ]);
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn test_post_command() {
+    let path = std::env::temp_dir().join(format!("te-{}-post-config.toml", std::process::id()));
+    std::fs::write(&path, "[output]\npost_command = \"tr a-z A-Z\"\n").unwrap();
+    let path = path.to_str().unwrap();
+
+    let output = te(&["--config", path, "--keys", "ctrl-x", "ls", "-la"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "LS -LA\n");
+
+    // Output that doesn't parse is still used, with a warning
+    let bad = std::env::temp_dir().join(format!("te-{}-bad-post.toml", std::process::id()));
+    std::fs::write(
+        &bad,
+        "[output]\npost_command = \"echo \\\"echo 'oops\\\"\"\n",
+    )
+    .unwrap();
+    let output = te(&["--config", bad.to_str().unwrap(), "--keys", "ctrl-x", "ls"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "echo 'oops\n");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("doesn't parse"));
+}
+
+#[test]
+fn test_which_shell() {
+    let path = histfile("which-shell", &["ls -la"]);
+    let histfile = path.to_str().unwrap();
+
+    let output = te_with_env(
+        &["which-shell"],
+        &[("SHELL", "/usr/local/bin/zsh"), ("HISTFILE", histfile)],
+    );
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        stdout(&output),
+        format!(
+            "shell: zsh\nhistory file: {}\nexists: yes\nsize: 7 bytes\n",
+            histfile
+        )
+    );
+
+    let output = te_with_env(
+        &["which-shell"],
+        &[("SHELL", "/bin/bash"), ("HISTFILE", "/nonexistent/history")],
+    );
+    assert_eq!(
+        stdout(&output),
+        "shell: bash\nhistory file: /nonexistent/history\nexists: no\n"
+    );
+
+    let output = te_with_env(&["which-shell"], &[("SHELL", "/bin/tcsh")]);
+    assert_eq!(
+        stdout(&output),
+        "shell: unknown ($SHELL=/bin/tcsh)\nhistory file: unknown\n"
+    );
+}
+
+#[test]
+fn test_init_scripts_parse() {
+    for shell in ["zsh", "bash", "fish"] {
+        for args in [
+            &["init", shell][..],
+            &["init", shell, "--no-eval"],
+            &["init", shell, "--tmux-popup"],
+            &["init", shell, "--no-bind"],
+        ] {
+            let output = te(args);
+            assert_eq!(output.status.code(), Some(0), "{:?}", args);
+            let script = stdout(&output);
+            assert!(script.contains("te-widget"), "{:?}", args);
+
+            // Check the syntax with the shell itself where it's installed
+            let path =
+                std::env::temp_dir().join(format!("te-{}-init.{}", std::process::id(), shell));
+            std::fs::write(&path, &script).unwrap();
+            let check = match shell {
+                "fish" => Command::new(shell).arg("--no-execute").arg(&path).output(),
+                _ => Command::new(shell).arg("-n").arg(&path).output(),
+            };
+            if let Ok(check) = check {
+                assert!(
+                    check.status.success(),
+                    "{:?}: {}",
+                    args,
+                    String::from_utf8_lossy(&check.stderr)
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn test_init_detects_shell() {
+    // The test runner isn't a shell, so $SHELL decides
+    let output = te_with_env(&["init"], &[("SHELL", "/usr/bin/fish"), ("TE_SHELL", "")]);
+    assert_eq!(output.status.code(), Some(0));
+    assert!(stdout(&output).starts_with("# te shell integration for fish"));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("for fish"));
+
+    // Also shells te reads no history of
+    let output = te_with_env(&["init"], &[("SHELL", "/usr/bin/elvish"), ("TE_SHELL", "")]);
+    assert_eq!(output.status.code(), Some(0));
+    assert!(stdout(&output).starts_with("# te shell integration for elvish"));
+
+    // TE_SHELL and --shell name the shell as for everything else
+    let output = te_with_env(
+        &["init"],
+        &[("SHELL", "/usr/bin/fish"), ("TE_SHELL", "xonsh")],
+    );
+    assert!(stdout(&output).starts_with("# te shell integration for xonsh"));
+    let output = te_with_env(&["init", "--shell", "bash"], &[("TE_SHELL", "xonsh")]);
+    assert!(stdout(&output).starts_with("# te shell integration for bash"));
+
+    // nu has no init script
+    let output = te_with_env(&["init"], &[("SHELL", "/usr/bin/nu"), ("TE_SHELL", "")]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Supported shells"));
+    let output = te(&["init", "nu"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "Error: Unsupported shell: nu\nSupported shells: zsh, bash, fish, pwsh, elvish, xonsh\n"
+    );
+}
+
+#[test]
+fn test_completions_scripts_parse() {
+    for shell in ["zsh", "bash", "fish"] {
+        let output = te(&["completions", shell]);
+        assert_eq!(output.status.code(), Some(0), "{}", shell);
+        let script = stdout(&output);
+        assert!(script.contains("which-shell"), "{}", shell);
+
+        // Check the syntax with the shell itself where it's installed
+        let path =
+            std::env::temp_dir().join(format!("te-{}-completions.{}", std::process::id(), shell));
+        std::fs::write(&path, &script).unwrap();
+        let check = match shell {
+            "fish" => Command::new(shell).arg("--no-execute").arg(&path).output(),
+            _ => Command::new(shell).arg("-n").arg(&path).output(),
+        };
+        if let Ok(check) = check {
+            assert!(
+                check.status.success(),
+                "{}: {}",
+                shell,
+                String::from_utf8_lossy(&check.stderr)
+            );
+        }
+    }
+}
+
+#[test]
+fn test_output_json() {
+    let output = te(&[
+        "--output",
+        "json",
+        "--keys",
+        "G enter ctrl-u s t a g i n g enter a - w enter ctrl-x",
+        "kubectl get pods -n prod",
+    ]);
+    assert_eq!(output.status.code(), Some(0));
+
+    let json: serde_json::Value = serde_json::from_str(&stdout(&output)).unwrap();
+    assert_eq!(
+        json,
+        serde_json::json!({
+            "command": "kubectl get pods -n staging -w",
+            "components": ["kubectl", "get", "pods", "-n", "staging", "-w"],
+            "diff": {
+                "added": [{ "index": 5, "value": "-w" }],
+                "removed": [],
+                "changed": [{ "index": 4, "from": "prod", "to": "staging" }],
+            },
+        })
+    );
+    // Keys are printed in a stable order
+    assert!(stdout(&output).starts_with("{\"command\":"));
+}
+
+#[test]
+fn test_output_json_cancel_prints_nothing() {
+    let output = te(&["--output", "json", "--keys", "esc", "ls"]);
+    assert_eq!(output.status.code(), Some(130));
+    assert_eq!(stdout(&output), "");
+}
+
+/// Run te in a new session, so it has no controlling terminal
+#[cfg(unix)]
+fn te_without_tty(args: &[&str], env: &[(&str, &str)]) -> Output {
+    use std::os::unix::process::CommandExt;
+
+    let mut command = Command::new(env!("CARGO_BIN_EXE_te"));
+    command.envs(env.iter().copied());
+    unsafe {
+        command.pre_exec(|| {
+            libc::setsid();
+            Ok(())
+        });
+    }
+    command
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .expect("failed to run te")
+}
+
+#[cfg(unix)]
+#[test]
+fn test_copy_failure_only_warns() {
+    // No terminal for OSC 52 and no clipboard tools on $PATH
+    let env = [("PATH", "/nonexistent")];
+
+    let output = te_without_tty(&["--copy", "--keys", "ctrl-x", "ls", "-la"], &env);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "ls -la\n");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("could not copy to the clipboard"),
+        "{}",
+        stderr
+    );
+
+    let output = te_without_tty(&["--copy-only", "--keys", "ctrl-x", "ls", "-la"], &env);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "");
+}
+
+#[test]
+fn test_insert_pipe() {
+    let output = te(&[
+        "--keys",
+        "G | g r e p enter a w e b enter ctrl-x",
+        "kubectl",
+        "get",
+        "pods",
+    ]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "kubectl get pods | grep web\n");
+}
+
+#[test]
+fn test_width() {
+    let args = [
+        "--keys", "ctrl-x", "docker", "run", "-d", "--name", "web", "nginx",
+    ];
+
+    // Not a terminal: a single line by default
+    let output = te(&args);
+    assert_eq!(stdout(&output), "docker run -d --name web nginx\n");
+
+    let output = te(&[&["--width", "20"][..], &args].concat());
+    assert_eq!(stdout(&output), "docker run -d \\\n  --name web nginx\n");
+
+    let output = te(&[&["--width", "0"][..], &args].concat());
+    assert_eq!(stdout(&output), "docker run -d --name web nginx\n");
+}
+
+#[test]
+fn test_keep_comments() {
+    let command = "kubectl get pods # prod cluster";
+
+    let output = te(&["--keys", "ctrl-x", "--keep-comments", "-c", command]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "kubectl get pods # prod cluster\n");
+
+    let output = te(&["--keys", "ctrl-x", "-c", command]);
+    assert_eq!(stdout(&output), "kubectl get pods\n");
+}
+
+#[cfg(unix)]
+#[test]
+fn test_doctor() {
+    let dir = std::env::temp_dir().join(format!("te-test-doctor-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let config = dir.join("config.toml");
+    std::fs::write(&config, "[history]\nlimit = \"many\"\n").unwrap();
+
+    // A broken config is reported instead of stopping te
+    let output = te_without_tty(
+        &[
+            "doctor",
+            "--no-history",
+            "--config",
+            config.to_str().unwrap(),
+        ],
+        &[("SHELL", "/bin/unknown")],
+    );
+    let out = stdout(&output);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        out.contains("[fail] terminal: can't open /dev/tty"),
+        "{}",
+        out
+    );
+    assert!(
+        out.contains("[warn] shell: unknown shell ($SHELL=/bin/unknown)"),
+        "{}",
+        out
+    );
+    assert!(
+        out.contains("[warn] history file: history is disabled"),
+        "{}",
+        out
+    );
+    assert!(
+        out.contains("[fail] config: Failed to load config file"),
+        "{}",
+        out
+    );
+    assert!(out.contains("hint: fix the file"), "{}", out);
+
+    std::fs::write(&config, "[features]\nmouse = false\nhistory = false\n").unwrap();
+    let output = te_without_tty(
+        &["doctor", "--config", config.to_str().unwrap()],
+        &[("SHELL", "/bin/bash")],
+    );
+    let out = stdout(&output);
+    assert!(
+        out.contains("[pass] features: mouse off, hints on, history off, osc52 on"),
+        "{}",
+        out
+    );
+    assert!(
+        out.contains("[warn] history file: history is disabled"),
+        "{}",
+        out
+    );
+
+    let output = te_without_tty(
+        &["doctor", "--no-history", "--shell", "nu"],
+        &[("SHELL", "/bin/bash")],
+    );
+    let out = stdout(&output);
+    assert!(
+        out.contains("[pass] shell: nu (set with --shell)"),
+        "{}",
+        out
+    );
+}
+
+#[test]
+fn test_file() {
+    let path = std::env::temp_dir().join(format!("te-{}-deploy.sh", std::process::id()));
+    std::fs::write(&path, "#!/bin/sh\r\nkubectl get pods \\\r\n  -n prod\r\n").unwrap();
+    let file = path.to_str().unwrap();
+
+    let output = te(&["--keys", "ctrl-x", "--file", file]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "kubectl get pods -n prod\n");
+
+    // -n prod -> -n staging, written back with the shebang, its lines and a
+    // backup
+    let output = te(&[
+        "--keys",
+        "G enter ctrl-u s t a g i n g enter ctrl-x",
+        "--in-place",
+        "-f",
+        file,
+    ]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "");
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        "#!/bin/sh\nkubectl get pods \\\n  -n staging\n"
+    );
+    let backup = format!("{}.bak", file);
+    assert!(
+        std::fs::read_to_string(&backup)
+            .unwrap()
+            .contains("-n prod")
+    );
+    std::fs::remove_file(&backup).unwrap();
+
+    let output = te(&["--keys", "ctrl-x", "--in-place", "--no-backup", "-f", file]);
+    assert_eq!(output.status.code(), Some(0));
+    assert!(!std::path::Path::new(&backup).exists());
+
+    // Writing back keeps the comment
+    std::fs::write(&path, "kubectl get pods # prod cluster\n").unwrap();
+    let output = te(&["--keys", "ctrl-x", "--in-place", "--no-backup", "-f", file]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        "kubectl get pods # prod cluster\n"
+    );
+
+    // Quotes stay as written, unless --width wraps the command anew
+    std::fs::write(&path, "grep -e 'a b' \\\n  file.txt\n").unwrap();
+    let in_place = |extra: &[&str]| {
+        let args = [
+            &["--keys", "ctrl-x", "--in-place", "--no-backup"],
+            extra,
+            &["-f", file],
+        ];
+        assert_eq!(te(&args.concat()).status.code(), Some(0));
+        std::fs::read_to_string(&path).unwrap()
+    };
+    assert_eq!(in_place(&[]), "grep -e 'a b' \\\n  file.txt\n");
+    assert_eq!(
+        in_place(&["--width", "12"]),
+        "grep \\\n  -e \"a b\" file.txt\n"
+    );
+
+    // Header comments stay above the command, apostrophes and all
+    std::fs::write(
+        &path,
+        "#!/bin/sh\n# Deploy\n# don't run twice\nkubectl get pods -n prod # prod\n",
+    )
+    .unwrap();
+    let output = te(&["--keys", "ctrl-x", "-f", file]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "kubectl get pods -n prod\n");
+    let output = te(&["--keys", "ctrl-x", "--in-place", "--no-backup", "-f", file]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        "#!/bin/sh\n# Deploy\n# don't run twice\nkubectl get pods -n prod # prod\n"
+    );
+
+    let output = te_with_stdin(&["--keys", "ctrl-x", "-f", "-"], "ls -la\n");
+    assert_eq!(stdout(&output), "ls -la\n");
+}
+
+#[test]
+fn test_restore_unfinished_session() {
+    let cache = std::env::temp_dir().join(format!("te-{}-session-cache", std::process::id()));
+    let sessions = cache.join("te").join("sessions");
+    std::fs::create_dir_all(&sessions).unwrap();
+    // Named by the FNV-1a hash of the command, as te names it
+    let hash = "kubectl get pods -n prod"
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+        });
+    let path = sessions.join(format!("{:016x}.json", hash));
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let component =
+        |value: &str| format!(r#"{{"value":"{}","operator":false,"script":false}}"#, value);
+    let session = format!(
+        r#"{{"saved_at":{},"components":[{}],"redacted":[],"undo":[],"redo":[]}}"#,
+        now,
+        ["kubectl", "get", "pods", "-n", "staging"]
+            .map(component)
+            .join(",")
+    );
+    let env = [("XDG_CACHE_HOME", cache.to_str().unwrap())];
+
+    // The first key answers the prompt: anything but y starts afresh
+    std::fs::write(&path, &session).unwrap();
+    let output = te_with_env(
+        &["-c", "kubectl get pods -n prod", "--keys", "n ctrl-x"],
+        &env,
+    );
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "kubectl get pods -n prod\n");
+    assert!(!path.exists());
+
+    std::fs::write(&path, &session).unwrap();
+    let output = te_with_env(
+        &["-c", "kubectl get pods -n prod", "--keys", "y ctrl-x"],
+        &env,
+    );
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "kubectl get pods -n staging\n");
+    assert!(!path.exists());
+
+    // Another command isn't offered the session
+    std::fs::write(&path, &session).unwrap();
+    let output = te_with_env(&["-c", "kubectl get pods", "--keys", "ctrl-x"], &env);
+    assert_eq!(stdout(&output), "kubectl get pods\n");
+    assert!(path.exists());
+
+    std::fs::remove_dir_all(&cache).unwrap();
+}
+
+#[test]
+fn test_file_errors() {
+    let output = te(&["--keys", "ctrl-x", "-f", "/nonexistent/deploy.sh"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "Error: Cannot read /nonexistent/deploy.sh: no such file\n"
+    );
+
+    let output = te_with_stdin(&["--keys", "ctrl-x", "--in-place", "-f", "-"], "ls\n");
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn test_shell_override() {
+    let env = [
+        ("SHELL", "/bin/bash"),
+        ("TE_SHELL", "fish"),
+        ("XDG_DATA_HOME", "/nonexistent"),
+    ];
+    let output = te_with_env(&["which-shell"], &env);
+    assert_eq!(
+        stdout(&output),
+        "shell: fish\nhistory file: /nonexistent/fish/fish_history\nexists: no\n"
+    );
+
+    // --shell wins over TE_SHELL
+    let output = te_with_env(&["which-shell", "--shell", "pwsh"], &env);
+    assert!(stdout(&output).starts_with("shell: pwsh\n"));
+
+    let output = te_with_env(&["which-shell"], &[("TE_SHELL", "tcsh")]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "Error: Invalid value 'tcsh' for TE_SHELL: expected one of zsh, bash, fish, nu, pwsh, elvish, xonsh\n"
+    );
+
+    let output = te_with_env(&["which-shell", "--shell", "tcsh"], &[]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("zsh, bash, fish, nu, pwsh"));
+}
+
+#[test]
+fn test_quoting_for_the_target_shell() {
+    let set = ["set", "--flag", "-m=it's", "--", "git", "commit"];
+    // $SHELL doesn't say which shell reads the output
+    let output = te_with_env(&set, &[("SHELL", "/usr/bin/fish"), ("TE_SHELL", "")]);
+    assert_eq!(stdout(&output), "git commit -m 'it'\\''s'\n");
+    // --shell, as the integration passes it, does
+    let output = te(&[&["--shell", "fish"][..], &set].concat());
+    assert_eq!(stdout(&output), "git commit -m 'it\\'s'\n");
+
+    // A file is read by its own interpreter
+    let dir = std::env::temp_dir().join(format!("te-{}-target-shell", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let config = dir.join("config.toml");
+    std::fs::write(&config, "[output]\nquote_style = \"prefer-single\"\n").unwrap();
+    let script = dir.join("script.sh");
+    std::fs::write(&script, "echo a\n").unwrap();
+    let edit = |source: &[&str]| {
+        let args = [
+            &["--config", config.to_str().unwrap(), "--shell", "fish"][..],
+            &["--keys", "G enter ctrl-u i t ' s enter ctrl-x"],
+            source,
+        ]
+        .concat();
+        stdout(&te(&args))
+    };
+    assert_eq!(edit(&["-c", "echo a"]), "echo 'it\\'s'\n");
+    assert_eq!(edit(&["-f", script.to_str().unwrap()]), "echo 'it'\\''s'\n");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_passthrough_without_tty() {
+    let output = te_without_tty(&["-c", "kubectl  get pods # prod"], &[]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("te needs a terminal"));
+
+    let output = te_without_tty(&["--passthrough", "-c", "kubectl  get pods # prod"], &[]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "kubectl  get pods # prod\n");
+
+    let output = te_without_tty(&["--passthrough", "echo", "hello world"], &[]);
+    assert_eq!(stdout(&output), "echo 'hello world'\n");
+}